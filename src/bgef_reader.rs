@@ -0,0 +1,517 @@
+//! 读取已有的 bGEF 文件。
+//!
+//! 兼容本仓库与 geftools 写出的两种常见布局：根属性既可能是变长字符串标量，也可能是长度为 1
+//! 的定长字符串数组；`expression.count` 与 `exon` 可能是 uint16 或 uint32。读取时一律由 HDF5
+//! 转换为 `Expression`（count: u32）与 u32，调用方无需区分。
+
+use anyhow::{anyhow, bail, Context, Result};
+use hdf5::types::{FixedAscii, TypeDescriptor, VarLenAscii, VarLenUnicode};
+use hdf5::{Attribute, Dataset, File as H5File, Location};
+use ndarray::{s, Array2};
+
+use crate::{
+    bgef_writer::{Expression, GeneRec, SpotGene},
+    gem_reader::{GemColumns, GeneBins, Header},
+};
+
+/// bGEF 根属性，与 GEM 的 `Header` 对应
+#[derive(Debug, Clone)]
+pub struct BgefAttrs {
+    pub bin_type: String,        // bin_type
+    pub omics: String,           // omics
+    pub stereo_seq_chip: String, // sn
+    pub offset_x: i32,           // offsetX（写入时已加到坐标上的平移）
+    pub offset_y: i32,           // offsetY
+    pub gem_offset_x: i32,       // gem_offsetX（源 GEM 的 #OffsetX，缺失时同 offsetX）
+    pub gem_offset_y: i32,       // gem_offsetY
+    pub gef_area: f32,           // gef_area（mm²）
+    pub geftool_ver: [u32; 3],   // geftool_ver
+    pub version: u32,            // version
+}
+
+impl BgefAttrs {
+    /// 对应的 GEM 表头（列布局与 gef2gem 写出的一致），用于把 bGEF 重新写成 bGEF
+    pub fn header(&self, bin_size: u32, has_exon: bool) -> Header {
+        Header {
+            bin_type: self.bin_type.clone(),
+            bin_size,
+            omics: self.omics.clone(),
+            stereo_seq_chip: self.stereo_seq_chip.clone(),
+            offset_x: self.gem_offset_x,
+            offset_y: self.gem_offset_y,
+            has_exon,
+            has_gene_name: true,
+            columns: GemColumns {
+                gene_id: 0,
+                gene_name: Some(1),
+                x: 2,
+                y: 3,
+                mid: 4,
+                exon: has_exon.then_some(5),
+            },
+            header_line_index: 0,
+        }
+    }
+}
+
+/// `/wholeExp/binN` 的属性
+#[derive(Debug, Clone, Copy)]
+pub struct WholeExpInfo {
+    pub min_x: i32,
+    pub min_y: i32,
+    pub len_x: usize,
+    pub len_y: usize,
+}
+
+impl WholeExpInfo {
+    /// 矩阵最后一个 spot 左上角的 bin1 坐标 (x, y)；超出 int32 时报错
+    pub fn max_corner(&self, bin_size: u32) -> Result<(i32, i32)> {
+        let corner = |min: i32, len: usize| -> Result<i32> {
+            let v = i64::from(min) + (len.max(1) as i64 - 1) * i64::from(bin_size);
+            i32::try_from(v)
+                .map_err(|_| anyhow!("wholeExp/bin{} 的范围超出 int32: {}", bin_size, v))
+        };
+        Ok((
+            corner(self.min_x, self.len_x)?,
+            corner(self.min_y, self.len_y)?,
+        ))
+    }
+}
+
+/// `/wholeExp/binN` 中的一块：`spots[[i, j]]` 是左上角为 (x0 + i*N, y0 + j*N) 的 spot
+// 按区域读取 wholeExp 的公开接口，目前只有单元测试调用
+#[cfg_attr(not(test), allow(dead_code))]
+#[derive(Debug, Clone)]
+pub struct SpotTile {
+    pub bin_size: u32,
+    pub x0: i32,
+    pub y0: i32,
+    pub spots: Array2<SpotGene>,
+}
+
+/// 一个基因在某个 bin 下的全部表达记录
+#[derive(Debug, Clone)]
+pub struct GeneExpression {
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub gene: GeneRec,
+    pub expressions: Vec<Expression>,
+    /// 文件含 exon 时与 `expressions` 一一对应
+    pub exons: Option<Vec<u32>>,
+}
+
+pub struct BgefReader {
+    file: H5File,
+    attrs: BgefAttrs,
+    bin_sizes: Vec<u32>,
+}
+
+impl BgefReader {
+    /// 打开 bGEF，读取根属性并列出 `/geneExp` 下的 bin 尺寸
+    pub fn open(path: &str) -> Result<Self> {
+        let file = H5File::open(path).with_context(|| format!("open {}", path))?;
+        let attrs = read_root_attrs(&file).with_context(|| format!("{} 根属性", path))?;
+        let mut bin_sizes: Vec<u32> = file
+            .group("geneExp")
+            .with_context(|| format!("{} 缺少 /geneExp", path))?
+            .member_names()?
+            .iter()
+            .filter_map(|n| n.strip_prefix("bin")?.parse().ok())
+            .collect();
+        bin_sizes.sort_unstable();
+        Ok(Self {
+            file,
+            attrs,
+            bin_sizes,
+        })
+    }
+
+    pub fn attrs(&self) -> &BgefAttrs {
+        &self.attrs
+    }
+
+    /// 文件中已有的 bin 尺寸（升序）
+    pub fn bin_sizes(&self) -> &[u32] {
+        &self.bin_sizes
+    }
+
+    fn dataset(&self, bin_size: u32, name: &str) -> Result<Dataset> {
+        if !self.bin_sizes.contains(&bin_size) {
+            bail!("bGEF 中没有 bin{}（已有: {:?}）", bin_size, self.bin_sizes);
+        }
+        let path = format!("/geneExp/bin{}/{}", bin_size, name);
+        self.file.dataset(&path).with_context(|| path)
+    }
+
+    /// 该 bin 是否有 `/geneExp/binN/exon`
+    pub fn has_exon(&self, bin_size: u32) -> bool {
+        self.file.link_exists(&format!("/geneExp/bin{}/exon", bin_size))
+    }
+
+    /// `/geneExp/binN/expression` 的 resolution 属性；缺失时为 0
+    pub fn resolution(&self, bin_size: u32) -> Result<u32> {
+        let ds = self.dataset(bin_size, "expression")?;
+        Ok(read_num(&ds, "resolution").unwrap_or(0))
+    }
+
+    /// `/geneExp/binN/expression` 的 minX/minY/maxX/maxY 属性（含端）
+    pub fn expression_extent(&self, bin_size: u32) -> Result<((i32, i32), (i32, i32))> {
+        let ds = self.dataset(bin_size, "expression")?;
+        Ok((
+            (read_num(&ds, "minX")?, read_num(&ds, "minY")?),
+            (read_num(&ds, "maxX")?, read_num(&ds, "maxY")?),
+        ))
+    }
+
+    /// `/geneExp/binN/gene`
+    pub fn genes(&self, bin_size: u32) -> Result<Vec<GeneRec>> {
+        Ok(self.dataset(bin_size, "gene")?.read_raw::<GeneRec>()?)
+    }
+
+    /// 整个 `/geneExp/binN/expression`
+    pub fn expressions(&self, bin_size: u32) -> Result<Vec<Expression>> {
+        Ok(self.dataset(bin_size, "expression")?.read_raw::<Expression>()?)
+    }
+
+    /// 整个 `/geneExp/binN/exon`；没有 exon 时返回 None
+    pub fn exons(&self, bin_size: u32) -> Result<Option<Vec<u32>>> {
+        if !self.has_exon(bin_size) {
+            return Ok(None);
+        }
+        Ok(Some(self.dataset(bin_size, "exon")?.read_raw::<u32>()?))
+    }
+
+    /// 按 `GeneRec` 的 offset/count 读取该基因的表达记录
+    pub fn gene_slice(&self, bin_size: u32, gene: &GeneRec) -> Result<GeneExpression> {
+        let range = gene.offset as usize..(gene.offset as usize + gene.count as usize);
+        let expressions =
+            self.dataset(bin_size, "expression")?.read_slice_1d::<Expression, _>(range.clone())?;
+        let exons = if self.has_exon(bin_size) {
+            Some(self.dataset(bin_size, "exon")?.read_slice_1d::<u32, _>(range)?.to_vec())
+        } else {
+            None
+        };
+        Ok(GeneExpression {
+            gene: *gene,
+            expressions: expressions.to_vec(),
+            exons,
+        })
+    }
+
+    /// 把 `/geneExp/binN` 读成 `GeneBins`，只保留 `keep(x, y)` 为真的记录；没有剩余记录的基因略去
+    pub fn gene_bins<F>(&self, bin_size: u32, keep: F) -> Result<GeneBins>
+    where
+        F: Fn(i32, i32) -> bool,
+    {
+        let genes = self.genes(bin_size)?;
+        let expressions = self.expressions(bin_size)?;
+        let exons = self.exons(bin_size)?;
+        if exons.as_ref().is_some_and(|v| v.len() != expressions.len()) {
+            bail!("bin{} 的 exon 与 expression 长度不一致", bin_size);
+        }
+        let mut gene_bins = GeneBins::new();
+        for g in &genes {
+            let start = g.offset as usize;
+            let Some(exprs) = expressions.get(start..start + g.count as usize) else {
+                bail!(
+                    "bin{} 基因 {} 的 offset/count 超出 expression 长度 {}",
+                    bin_size,
+                    g.geneID.as_str(),
+                    expressions.len()
+                );
+            };
+            let spots: Vec<_> = exprs
+                .iter()
+                .enumerate()
+                .filter(|(_, e)| keep(e.x, e.y))
+                .map(|(i, e)| {
+                    let exon = exons.as_ref().map_or(0, |v| v[start + i]);
+                    ((e.x, e.y), (e.count, exon))
+                })
+                .collect();
+            if !spots.is_empty() {
+                let key = (
+                    g.geneID.as_str().to_string(),
+                    g.geneName.as_str().to_string(),
+                );
+                gene_bins.entry(key).or_default().extend(spots);
+            }
+        }
+        Ok(gene_bins)
+    }
+
+    /// 按基因名（其次按 geneID）查找基因并读取其表达记录；找不到时返回 None
+    // 与 `whole_exp_tile` 一样，目前只有单元测试调用
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn gene_expression(&self, bin_size: u32, name: &str) -> Result<Option<GeneExpression>> {
+        let genes = self.genes(bin_size)?;
+        let found = genes
+            .iter()
+            .find(|g| g.geneName.as_str() == name)
+            .or_else(|| genes.iter().find(|g| g.geneID.as_str() == name));
+        found.map(|g| self.gene_slice(bin_size, g)).transpose()
+    }
+
+    /// `/wholeExp/binN` 的 minX/minY/lenX/lenY
+    pub fn whole_exp_info(&self, bin_size: u32) -> Result<WholeExpInfo> {
+        let path = format!("/wholeExp/bin{}", bin_size);
+        let ds = self.file.dataset(&path).with_context(|| path.clone())?;
+        let shape = ds.shape();
+        if shape.len() != 2 {
+            bail!("{} 应为二维，实际 shape 为 {:?}", path, shape);
+        }
+        Ok(WholeExpInfo {
+            min_x: i32::try_from(read_num::<i64>(&ds, "minX")?)
+                .map_err(|_| anyhow!("{} 的 minX 超出 int32", path))?,
+            min_y: i32::try_from(read_num::<i64>(&ds, "minY")?)
+                .map_err(|_| anyhow!("{} 的 minY 超出 int32", path))?,
+            len_x: shape[0],
+            len_y: shape[1],
+        })
+    }
+
+    /// 读取 bin1 坐标矩形 `[x0, x1) x [y0, y1)` 覆盖的 wholeExp spot；矩形会被裁到矩阵范围内
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn whole_exp_tile(
+        &self,
+        bin_size: u32,
+        (x0, y0): (i32, i32),
+        (x1, y1): (i32, i32),
+    ) -> Result<SpotTile> {
+        let info = self.whole_exp_info(bin_size)?;
+        let step = i64::from(bin_size);
+        // bin1 坐标 -> 矩阵下标（向下取整），再裁到 [0, len]
+        let index = |v: i32, min: i32, len: usize, round_up: bool| -> usize {
+            let d = i64::from(v) - i64::from(min);
+            let i = if round_up {
+                (d + step - 1).div_euclid(step)
+            } else {
+                d.div_euclid(step)
+            };
+            i.clamp(0, len as i64) as usize
+        };
+        let (i0, i1) = (
+            index(x0, info.min_x, info.len_x, false),
+            index(x1, info.min_x, info.len_x, true),
+        );
+        let (j0, j1) = (
+            index(y0, info.min_y, info.len_y, false),
+            index(y1, info.min_y, info.len_y, true),
+        );
+        let spots = if i0 < i1 && j0 < j1 {
+            let ds = self.file.dataset(&format!("/wholeExp/bin{}", bin_size))?;
+            ds.read_slice_2d::<SpotGene, _>(s![i0..i1, j0..j1])?
+        } else {
+            Array2::default((i1.saturating_sub(i0), j1.saturating_sub(j0)))
+        };
+        Ok(SpotTile {
+            bin_size,
+            x0: info.min_x + (i0 as i64 * step) as i32,
+            y0: info.min_y + (j0 as i64 * step) as i32,
+            spots,
+        })
+    }
+}
+
+/// 读取数值属性（兼容标量与长度为 1 的数组，类型由 HDF5 转换）
+pub fn read_num<T: hdf5::H5Type + Copy>(loc: &Location, name: &str) -> Result<T> {
+    let v = loc.attr(name).with_context(|| format!("缺少属性 {}", name))?.read_raw::<T>()?;
+    v.first().copied().ok_or_else(|| anyhow!("属性 {} 为空", name))
+}
+
+/// 读取字符串属性：变长字符串标量或定长字符串（数组取第一个）
+fn read_str(attr: &Attribute) -> Result<String> {
+    let s = match attr.dtype()?.to_descriptor()? {
+        TypeDescriptor::VarLenUnicode => {
+            attr.read_raw::<VarLenUnicode>()?.first().map(|s| s.as_str().to_owned())
+        }
+        TypeDescriptor::VarLenAscii => {
+            attr.read_raw::<VarLenAscii>()?.first().map(|s| s.as_str().to_owned())
+        }
+        TypeDescriptor::FixedAscii(_) | TypeDescriptor::FixedUnicode(_) => {
+            attr.read_raw::<FixedAscii<256>>()?.first().map(|s| s.as_str().to_owned())
+        }
+        other => bail!("属性不是字符串类型: {}", other),
+    };
+    Ok(s.unwrap_or_default())
+}
+
+/// 读取根属性；缺失的可选属性（sn/offsetX/offsetY/gef_area）取默认值，
+/// 缺失的 gem_offsetX/gem_offsetY 取 offsetX/offsetY
+fn read_root_attrs(f: &H5File) -> Result<BgefAttrs> {
+    let opt_str = |name: &str| -> Result<String> {
+        match f.attr(name) {
+            Ok(attr) => read_str(&attr).with_context(|| name.to_string()),
+            Err(_) => Ok(String::new()),
+        }
+    };
+    let ver: Vec<u32> = match f.attr("geftool_ver") {
+        Ok(attr) => match attr.dtype()?.to_descriptor()? {
+            TypeDescriptor::FixedArray(..) => attr.read_scalar::<[u32; 3]>()?.to_vec(),
+            _ => attr.read_raw::<u32>()?,
+        },
+        Err(_) => Vec::new(),
+    };
+    let offset_x = read_num(f, "offsetX").unwrap_or(0);
+    let offset_y = read_num(f, "offsetY").unwrap_or(0);
+    let mut geftool_ver = [0u32; 3];
+    for (dst, src) in geftool_ver.iter_mut().zip(ver) {
+        *dst = src;
+    }
+    Ok(BgefAttrs {
+        bin_type: opt_str("bin_type")?,
+        omics: opt_str("omics")?,
+        stereo_seq_chip: opt_str("sn")?,
+        offset_x,
+        offset_y,
+        gem_offset_x: read_num(f, "gem_offsetX").unwrap_or(offset_x),
+        gem_offset_y: read_num(f, "gem_offsetY").unwrap_or(offset_y),
+        gef_area: read_num(f, "gef_area").unwrap_or(0.0),
+        geftool_ver,
+        version: read_num(f, "version")?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf, process};
+
+    use hdf5::H5Type;
+    use ndarray::arr2;
+
+    use super::*;
+    use crate::bgef_writer::str2fa64;
+
+    /// geftools 较早版本的 expression：count 为 uint16
+    #[repr(C)]
+    #[derive(H5Type, Clone, Copy)]
+    struct Expression16 {
+        x: i32,
+        y: i32,
+        count: u16,
+    }
+
+    /// 测试结束时删除的临时 bGEF
+    struct TempBgef(PathBuf);
+
+    impl Drop for TempBgef {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn spot(mid: u32, genes: u16) -> SpotGene {
+        SpotGene {
+            MIDcount: mid,
+            genecount: genes,
+        }
+    }
+
+    /// 只有 bin1 的小 bGEF：Gad1 在 (10,20)、(11,20)，Actb 在 (10,21)；`u16_counts` 时
+    /// expression.count 与 exon 写为 uint16
+    fn write_bgef(name: &str, u16_counts: bool) -> TempBgef {
+        let path =
+            std::env::temp_dir().join(format!("gem2gef-reader-{}-{}.bgef", process::id(), name));
+        let tmp = TempBgef(path);
+        let f = H5File::create(&tmp.0).unwrap();
+        f.new_attr::<u32>().create("version").unwrap().write_scalar(&4).unwrap();
+
+        let bin1 = f.create_group("geneExp").unwrap().create_group("bin1").unwrap();
+        let gene = |id: &str, name: &str, offset: u32, count: u32| GeneRec {
+            geneID: str2fa64(id),
+            geneName: str2fa64(name),
+            offset,
+            count,
+        };
+        let genes = [
+            gene("ENSMUSG01", "Gad1", 0, 2),
+            gene("ENSMUSG02", "Actb", 2, 1),
+        ];
+        bin1.new_dataset_builder().with_data(&genes).create("gene").unwrap();
+        let records = [(10, 20, 3u16, 1u16), (11, 20, 1, 0), (10, 21, 5, 2)];
+        if u16_counts {
+            let expr: Vec<_> =
+                records.iter().map(|&(x, y, count, _)| Expression16 { x, y, count }).collect();
+            let exon: Vec<u16> = records.iter().map(|r| r.3).collect();
+            bin1.new_dataset_builder().with_data(&expr).create("expression").unwrap();
+            bin1.new_dataset_builder().with_data(&exon).create("exon").unwrap();
+        } else {
+            let expr: Vec<_> = records
+                .iter()
+                .map(|&(x, y, count, _)| Expression {
+                    x,
+                    y,
+                    count: count.into(),
+                })
+                .collect();
+            let exon: Vec<u32> = records.iter().map(|r| r.3.into()).collect();
+            bin1.new_dataset_builder().with_data(&expr).create("expression").unwrap();
+            bin1.new_dataset_builder().with_data(&exon).create("exon").unwrap();
+        }
+
+        // 下标为 [x - 10, y - 20]
+        let whole = arr2(&[[spot(3, 1), spot(5, 1)], [spot(1, 1), spot(0, 0)]]);
+        let ds = f
+            .create_group("wholeExp")
+            .unwrap()
+            .new_dataset_builder()
+            .with_data(&whole)
+            .create("bin1")
+            .unwrap();
+        ds.new_attr::<u32>().create("minX").unwrap().write_scalar(&10).unwrap();
+        ds.new_attr::<u32>().create("minY").unwrap().write_scalar(&20).unwrap();
+        tmp
+    }
+
+    fn open(tmp: &TempBgef) -> BgefReader {
+        BgefReader::open(tmp.0.to_str().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn gene_expression_finds_genes_by_name_or_id() {
+        for (name, u16_counts) in [("u32", false), ("u16", true)] {
+            let tmp = write_bgef(&format!("genes-{}", name), u16_counts);
+            let reader = open(&tmp);
+            assert_eq!(reader.bin_sizes(), [1]);
+
+            // geneName 优先，其次 geneID
+            let actb = reader.gene_expression(1, "Actb").unwrap().unwrap();
+            assert_eq!(actb.gene.geneID.as_str(), "ENSMUSG02", "{}", name);
+            let got: Vec<_> = actb.expressions.iter().map(|e| (e.x, e.y, e.count)).collect();
+            assert_eq!(got, [(10, 21, 5)], "{}", name);
+            assert_eq!(actb.exons, Some(vec![2]), "{}", name);
+
+            let gad1 = reader.gene_expression(1, "ENSMUSG01").unwrap().unwrap();
+            assert_eq!(gad1.gene.geneName.as_str(), "Gad1", "{}", name);
+            let got: Vec<_> = gad1.expressions.iter().map(|e| (e.x, e.y, e.count)).collect();
+            assert_eq!(got, [(10, 20, 3), (11, 20, 1)], "{}", name);
+            assert_eq!(gad1.exons, Some(vec![1, 0]), "{}", name);
+
+            assert!(reader.gene_expression(1, "Xist").unwrap().is_none());
+            let err = reader.gene_expression(5, "Actb").unwrap_err().to_string();
+            assert!(err.contains("没有 bin5"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn whole_exp_tile_clips_rectangle_to_matrix() {
+        let tmp = write_bgef("tile", false);
+        let reader = open(&tmp);
+
+        // 矩形内的一列
+        let tile = reader.whole_exp_tile(1, (11, 20), (12, 22)).unwrap();
+        assert_eq!((tile.bin_size, tile.x0, tile.y0), (1, 11, 20));
+        assert_eq!(tile.spots.dim(), (1, 2));
+        assert_eq!(tile.spots[[0, 0]].MIDcount, 1);
+        assert_eq!(tile.spots[[0, 1]].MIDcount, 0);
+
+        // 超出矩阵的部分被裁掉
+        let tile = reader.whole_exp_tile(1, (0, 0), (100, 100)).unwrap();
+        assert_eq!((tile.x0, tile.y0), (10, 20));
+        let mids = tile.spots.map(|s| (s.MIDcount, s.genecount));
+        assert_eq!(mids, arr2(&[[(3, 1), (5, 1)], [(1, 1), (0, 0)]]));
+
+        // 与矩阵不相交时为空
+        let tile = reader.whole_exp_tile(1, (50, 50), (60, 60)).unwrap();
+        assert_eq!(tile.spots.len(), 0);
+    }
+}
@@ -1,14 +1,71 @@
-use std::collections::HashMap;
-use std::error::Error;
+use std::{collections::HashMap, error::Error};
 
+use hdf5::filters::{Filter, H5Z_filter_t};
 use hdf5::types::{FixedAscii, VarLenUnicode};
-use hdf5::{File as H5File, H5Type}; // 导入 Location trait
-use ndarray::Array2;
+use hdf5::{Dataset, File as H5File, Group, H5Type}; // 导入 Location trait
+use ndarray::{s, Array2};
 
-// 假设 gem_reader 模块提供了 pub fn map2mat 和 pub struct Header
-use crate::gem_reader::{map2mat, Header};
+use crate::{
+    binning::{bin_origin, BaseStats, BinData, SpotStat, SpotSummary, TileKey},
+    gem_reader::{DupPolicy, Header},
+    merge::{SampleLayer, MIXED_SAMPLE},
+};
 
 pub const GEFTOOL_RS_VERSION: u32 = 4;
+/// h5py/PyTables 注册的 LZF 过滤器号
+pub const H5Z_FILTER_LZF: H5Z_filter_t = 32000;
+
+/// HDF5 数据集的压缩与分块参数；`Default` 与 geftools 写出的 bGEF 一致
+#[derive(Debug, Clone)]
+pub struct DatasetOpts {
+    /// deflate(gzip) 压缩级别 1-9，None 为不压缩
+    pub deflate: Option<u8>,
+    /// 压缩前做 shuffle
+    pub shuffle: bool,
+    /// LZF 压缩（过滤器号 32000，需 HDF5 能从 `HDF5_PLUGIN_PATH` 加载 LZF 插件）
+    pub lzf: bool,
+    /// `/geneExp/binN/expression` 与 `exon` 的 chunk 长度（记录数）
+    pub expr_chunk: usize,
+    /// `/geneExp/binN/gene` 与 `/stat/gene` 的 chunk 长度（记录数）
+    pub gene_chunk: usize,
+    /// `/wholeExp/binN` 与 `/wholeExpExon/binN` 的 chunk 边长（以 bin 为单位），也是写入的 tile 大小
+    pub whole_chunk: usize,
+}
+
+impl Default for DatasetOpts {
+    fn default() -> Self {
+        Self {
+            deflate: Some(4),
+            shuffle: false,
+            lzf: false,
+            expr_chunk: 1 << 16,
+            gene_chunk: 1 << 12,
+            whole_chunk: 256,
+        }
+    }
+}
+
+impl DatasetOpts {
+    /// 按 shuffle -> deflate -> lzf 的顺序组装过滤器链
+    pub fn filters(&self) -> Vec<Filter> {
+        let mut filters = Vec::new();
+        if self.shuffle {
+            filters.push(Filter::Shuffle);
+        }
+        if let Some(level) = self.deflate {
+            filters.push(Filter::Deflate(level));
+        }
+        if self.lzf {
+            filters.push(Filter::User(H5Z_FILTER_LZF, Vec::new()));
+        }
+        filters
+    }
+
+    /// 定长数据集的 chunk 不能超过数据长度，且至少为 1
+    pub fn clamp_chunk(chunk: usize, len: usize) -> usize {
+        chunk.min(len).max(1)
+    }
+}
 
 #[repr(C)]
 #[derive(Clone, Copy, Debug, H5Type)]
@@ -38,169 +95,514 @@ pub struct SpotGene {
     pub genecount: u16,
 }
 
+/// `/stat/gene` 的一行：基因总 MID 数与 E10
+#[repr(C)]
+#[allow(non_snake_case)]
+#[derive(Clone, Copy, Debug, H5Type)]
+pub struct StatGene {
+    #[hdf5(name = "geneID")]
+    pub geneID: FixedAscii<64>,
+    #[hdf5(name = "geneName")]
+    pub geneName: FixedAscii<64>,
+    pub MIDcount: u32,
+    /// 该基因 MID >= 10 的 spot 占其全部 spot 的百分比
+    pub E10: f32,
+}
+
 // BgefWriter 现在持有所有预处理过的数据，准备写入
 pub struct BgefWriter {
     output: String,
-    expressions: Vec<Expression>,
-    genes_meta: Vec<GeneRec>,
-    exons: Vec<u32>,
-    spot_mid_map: HashMap<(i32, i32), SpotGene>,
-    spot_exon_map: HashMap<(i32, i32), u32>,
-    min_x: i32,
-    min_y: i32,
-    max_x: i32,
-    max_y: i32,
-    max_exp: u32,
-    max_exon: u32,
+    bins: Vec<BinData>,
     resolution: u16,
     has_exon: bool,
+    /// 已加到坐标上的 (OffsetX, OffsetY)
+    offset: (i32, i32),
+    /// 各数据集的压缩与分块参数
+    opts: DatasetOpts,
+    /// 最小 bin 的统计（`/stat/gene` 与 gef_area）
+    base: BaseStats,
+    /// 重复 (gene, x, y) 的处理方式与重复行数；None 时不写（如由已有 bGEF 裁剪得到）
+    duplicates: Option<(DupPolicy, u64)>,
+    /// 合并多个样本时每个 spot 的来源样本
+    samples: Option<SampleLayer>,
 }
 
 impl BgefWriter {
     // new 函数现在只接收数据，不进行处理
-    #[allow(clippy::too_many_arguments)] // 参数较多是合理的，因为数据都在 main 中处理
     pub fn new(
         output: String,
-        expressions: Vec<Expression>,
-        genes_meta: Vec<GeneRec>,
-        exons: Vec<u32>,
-        spot_mid_map: HashMap<(i32, i32), SpotGene>,
-        spot_exon_map: HashMap<(i32, i32), u32>,
-        min_x: i32,
-        min_y: i32,
-        max_x: i32,
-        max_y: i32,
-        max_exp: u32,
-        max_exon: u32,
+        bins: Vec<BinData>,
         resolution: u16,
         has_exon: bool,
+        offset: (i32, i32),
+        opts: DatasetOpts,
+        base: BaseStats,
     ) -> Self {
         Self {
             output,
-            expressions,
-            genes_meta,
-            exons,
-            spot_mid_map,
-            spot_exon_map,
-            min_x,
-            min_y,
-            max_x,
-            max_y,
-            max_exp,
-            max_exon,
+            bins,
             resolution,
             has_exon,
+            offset,
+            opts,
+            base,
+            duplicates: None,
+            samples: None,
         }
     }
 
+    /// 额外写入根属性 duplicate_policy/duplicate_rows
+    pub fn with_duplicates(mut self, policy: DupPolicy, rows: u64) -> Self {
+        self.duplicates = Some((policy, rows));
+        self
+    }
+
+    /// 额外写入 `/samples` 与 `/wholeExpSample/binN`（合并多个样本时）
+    pub fn with_samples(mut self, samples: SampleLayer) -> Self {
+        self.samples = Some(samples);
+        self
+    }
+
     /// 将所有数据写入 HDF5 文件
     pub fn write_all(self, hdr: &Header) -> Result<(), Box<dyn Error>> {
         // ------------ 1. 创建 HDF5 文件 ------------
         let f = H5File::create(&self.output)?;
 
-        // ------------ 2. 写入根属性 (使用安全且简洁的方式) ------------
-        // bin类型
-        let vstr = hdr.bin_type.parse::<VarLenUnicode>()?;
-        f.new_attr::<VarLenUnicode>().create("bin_type")?.write_scalar(&vstr)?;
-        // 组织区域+工具版本（硬编码）
-        f.new_attr::<f32>().create("gef_area")?.write_scalar(&0.0)?;
-        f.new_attr::<[u32; 3]>().create("geftool_ver")?.write_scalar(&[1, 1, 20])?;
-        // 组学类型
-        let vstr = hdr.omics.parse::<VarLenUnicode>()?;
-        f.new_attr::<VarLenUnicode>().create("omics")?.write_scalar(&vstr)?;
-        // 版本号
-        f.new_attr::<u32>().create("version")?.write_scalar(&GEFTOOL_RS_VERSION)?;
-        // 芯片代号
-        let vstr = hdr.stereo_seq_chip.parse::<VarLenUnicode>()?;
-        f.new_attr::<VarLenUnicode>().create("sn")?.write_scalar(&vstr)?;
-
-        // ------------ 3. 写入 /geneExp/bin1 ------------
+        // ------------ 2. 写入根属性 ------------
+        // 组织区域（mm²，由 GEM 本身的 bin 的被占据 spot 数估算，与写出哪些 bin 无关）
+        let area = self.base.area(self.resolution);
+        write_root_attrs(&f, hdr, area, self.offset)?;
+        if let Some((policy, rows)) = self.duplicates {
+            write_duplicate_attrs(&f, policy, rows)?;
+        }
+
         let gene_exp = f.create_group("geneExp")?;
-        let gene_exp_bin1 = gene_exp.create_group("bin1")?;
+        let whole_exp = f.create_group("wholeExp")?;
+        let whole_exon = f.create_group("wholeExpExon")?;
+        let filters = self.opts.filters();
 
-        // 写入 /geneExp/bin1/expression
-        let ds_expr = gene_exp_bin1
-            .new_dataset_builder()
-            .with_data(&self.expressions)
-            .create("expression")?;
-        // 写入 expression 属性
-        ds_expr.new_attr::<i32>().create("minX")?.write_scalar(&0)?;
-        ds_expr.new_attr::<i32>().create("minY")?.write_scalar(&0)?;
-        ds_expr.new_attr::<i32>().create("maxX")?.write_scalar(&self.max_x)?;
-        ds_expr.new_attr::<i32>().create("maxY")?.write_scalar(&self.max_y)?;
-        ds_expr.new_attr::<u32>().create("maxExp")?.write_scalar(&self.max_exp)?;
-        ds_expr.new_attr::<u32>().create("resolution")?.write_scalar(&self.resolution)?;
-
-        // 写入 /geneExp/bin1/exon (可选)
-        if self.has_exon {
-            debug_assert_eq!(self.exons.len(), self.expressions.len());
-            let ds_exon =
-                gene_exp_bin1.new_dataset_builder().with_data(&self.exons).create("exon")?;
-            ds_exon.new_attr::<u32>().create("maxExon")?.write_scalar(&self.max_exon)?;
-        }
-
-        // 写入 /geneExp/bin1/gene
-        let _ds_gene =
-            gene_exp_bin1.new_dataset_builder().with_data(&self.genes_meta).create("gene")?;
-
-        // ------------ 4. 写入 /wholeExp/bin1 ------------
-        let max_mid_per_bin_n = self.spot_mid_map.values().map(|&x| x.MIDcount).max().unwrap_or(0);
-        let max_gene = self.spot_mid_map.values().map(|&x| x.genecount).max().unwrap_or(0);
-        let number = self.spot_mid_map.len() as u64;
+        for bin in &self.bins {
+            let bin_name = format!("bin{}", bin.bin_size);
 
-        let whole_exp = f.create_group("wholeExp")?;
-        // 将字典(坐标-表达量)转化为矩阵
-        let (mat, mat_x, mat_y) = map2mat(&self.spot_mid_map, self.min_x, self.min_y,self.max_x, self.max_y)?;
-        // 创建数据节点
-        let whole_exp_bin1 = whole_exp
-            .new_dataset::<SpotGene>()
+            // ------------ 3. 写入 /geneExp/binN ------------
+            let gene_exp_bin = gene_exp.create_group(&bin_name)?;
+
+            // 写入 /geneExp/binN/expression
+            let expr_chunk = DatasetOpts::clamp_chunk(self.opts.expr_chunk, bin.expressions.len());
+            let ds_expr = gene_exp_bin
+                .new_dataset_builder()
+                .with_data(&bin.expressions)
+                .chunk(expr_chunk)
+                .set_filters(&filters)
+                .create("expression")?;
+            write_expression_attrs(&ds_expr, bin, self.resolution)?;
+
+            // 写入 /geneExp/binN/exon (可选)
+            if self.has_exon {
+                debug_assert_eq!(bin.exons.len(), bin.expressions.len());
+                let ds_exon = gene_exp_bin
+                    .new_dataset_builder()
+                    .with_data(&bin.exons)
+                    .chunk(expr_chunk)
+                    .set_filters(&filters)
+                    .create("exon")?;
+                ds_exon.new_attr::<u32>().create("maxExon")?.write_scalar(&bin.max_exon)?;
+            }
+
+            // 写入 /geneExp/binN/gene
+            write_gene(&gene_exp_bin, &bin.genes_meta, &self.opts)?;
+
+            // ------------ 4. 写入 /wholeExp/binN 与 /wholeExpExon/binN ------------
+            let spots = bin.spots_by_tile(self.opts.whole_chunk.max(1)).map(Ok);
+            write_whole_exp(
+                &whole_exp,
+                &whole_exon,
+                bin,
+                spots,
+                self.resolution,
+                &self.opts,
+            )?;
+        }
+
+        // ------------ 5. 写入 /stat/gene（由 GEM 本身的 bin 统计，与写出哪些 bin 无关） ------------
+        write_stat_gene(&f, &self.base.stats, &self.opts)?;
+
+        // ------------ 6. 写入 /samples 与 /wholeExpSample/binN（可选） ------------
+        if let Some(layer) = &self.samples {
+            write_samples(&f, &self.bins, layer, &self.opts)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// 写入 bGEF 根属性；`area` 为组织面积（mm²），`offset` 为已应用的坐标平移
+pub fn write_root_attrs(
+    f: &H5File,
+    hdr: &Header,
+    area: f32,
+    offset: (i32, i32),
+) -> Result<(), Box<dyn Error>> {
+    // bin类型
+    let vstr = hdr.bin_type.parse::<VarLenUnicode>()?;
+    f.new_attr::<VarLenUnicode>().create("bin_type")?.write_scalar(&vstr)?;
+    // 组织区域+工具版本（硬编码）
+    f.new_attr::<f32>().create("gef_area")?.write_scalar(&area)?;
+    f.new_attr::<[u32; 3]>().create("geftool_ver")?.write_scalar(&[1, 1, 20])?;
+    // 组学类型
+    let vstr = hdr.omics.parse::<VarLenUnicode>()?;
+    f.new_attr::<VarLenUnicode>().create("omics")?.write_scalar(&vstr)?;
+    // 版本号
+    f.new_attr::<u32>().create("version")?.write_scalar(&GEFTOOL_RS_VERSION)?;
+    // 芯片代号
+    let vstr = hdr.stereo_seq_chip.parse::<VarLenUnicode>()?;
+    f.new_attr::<VarLenUnicode>().create("sn")?.write_scalar(&vstr)?;
+    // 写入时已应用的坐标平移（未平移则为 0）
+    f.new_attr::<i32>().create("offsetX")?.write_scalar(&offset.0)?;
+    f.new_attr::<i32>().create("offsetY")?.write_scalar(&offset.1)?;
+    // 源 GEM 表头的 #OffsetX/#OffsetY（无论是否应用），gef2gem 据此还原表头
+    f.new_attr::<i32>().create("gem_offsetX")?.write_scalar(&hdr.offset_x)?;
+    f.new_attr::<i32>().create("gem_offsetY")?.write_scalar(&hdr.offset_y)?;
+    Ok(())
+}
+
+/// 写入重复 (gene, x, y) 行的处理方式（duplicate_policy）与行数（duplicate_rows）
+pub fn write_duplicate_attrs(
+    f: &H5File,
+    policy: DupPolicy,
+    rows: u64,
+) -> Result<(), Box<dyn Error>> {
+    let vstr = policy.name().parse::<VarLenUnicode>()?;
+    f.new_attr::<VarLenUnicode>().create("duplicate_policy")?.write_scalar(&vstr)?;
+    f.new_attr::<u64>().create("duplicate_rows")?.write_scalar(&rows)?;
+    Ok(())
+}
+
+/// 写入 `/geneExp/binN/expression` 的属性
+pub fn write_expression_attrs(
+    ds_expr: &Dataset,
+    bin: &BinData,
+    resolution: u16,
+) -> Result<(), Box<dyn Error>> {
+    ds_expr.new_attr::<i32>().create("minX")?.write_scalar(&bin.min_x)?;
+    ds_expr.new_attr::<i32>().create("minY")?.write_scalar(&bin.min_y)?;
+    ds_expr.new_attr::<i32>().create("maxX")?.write_scalar(&bin.max_x)?;
+    ds_expr.new_attr::<i32>().create("maxY")?.write_scalar(&bin.max_y)?;
+    ds_expr.new_attr::<u32>().create("maxExp")?.write_scalar(&bin.max_exp)?;
+    ds_expr.new_attr::<u32>().create("resolution")?.write_scalar(&resolution)?;
+    Ok(())
+}
+
+/// 由 spot 统计写入 `/wholeExp/binN` 与 `/wholeExpExon/binN`，返回写入的汇总属性。
+///
+/// `spots` 需按 `tile_key` 排序（内存中的 bin 见 `BinData::spots_by_tile`），两个矩阵逐个 tile
+/// 同时写入，内存中只保留当前 tile 的缓冲
+pub fn write_whole_exp<I>(
+    whole_exp: &Group,
+    whole_exon: &Group,
+    bin: &BinData,
+    spots: I,
+    resolution: u16,
+    opts: &DatasetOpts,
+) -> Result<SpotSummary, Box<dyn Error>>
+where
+    I: IntoIterator<Item = Result<SpotStat, Box<dyn Error>>>,
+{
+    let bin_name = format!("bin{}", bin.bin_size);
+
+    // 矩阵尺寸（以 bin 为单位，包含两端）；没有表达记录时报错
+    let (mat_x, mat_y) = bin.matrix_len()?;
+    let tile = opts.whole_chunk.max(1);
+    let chunk = [
+        DatasetOpts::clamp_chunk(tile, mat_x),
+        DatasetOpts::clamp_chunk(tile, mat_y),
+    ];
+    let filters = opts.filters();
+    // 创建分块数据节点，按 tile 写入被占据的区域；未写入的 chunk 读出时为 0
+    let whole_exp_bin = whole_exp
+        .new_dataset::<SpotGene>()
+        .chunk(chunk)
+        .set_filters(&filters)
+        .shape([mat_x, mat_y])
+        .create(bin_name.as_str())?;
+    let whole_exp_bin_exon = whole_exon
+        .new_dataset::<u32>()
+        .chunk(chunk)
+        .set_filters(&filters)
+        .shape([mat_x, mat_y])
+        .create(bin_name.as_str())?;
+
+    let mut summary = SpotSummary::default();
+    let mut mid_tiles = TileWriter::new(&whole_exp_bin, [mat_x, mat_y], tile);
+    let mut exon_tiles = TileWriter::new(&whole_exp_bin_exon, [mat_x, mat_y], tile);
+    for spot in spots {
+        let (xy, spot, exon) = spot?;
+        let key = bin.tile_key(xy, tile);
+        mid_tiles.push(key, spot)?;
+        exon_tiles.push(key, exon)?;
+        summary.add(&spot, exon);
+    }
+    mid_tiles.finish()?;
+    exon_tiles.finish()?;
+
+    // 写入 wholeExp 属性
+    // 稠密矩阵中非零点的数量
+    whole_exp_bin.new_attr::<u64>().create("number")?.write_scalar(&summary.number)?;
+    // 非零点x和y坐标最小值
+    write_min_attr(&whole_exp_bin, "minX", bin.min_x)?;
+    write_min_attr(&whole_exp_bin, "minY", bin.min_y)?;
+    // 矩阵第 0 维为 x、第 1 维为 y，长度以 bin 为单位
+    whole_exp_bin.new_attr::<u32>().create("lenX")?.write_scalar(&(mat_x as u32))?;
+    whole_exp_bin.new_attr::<u32>().create("lenY")?.write_scalar(&(mat_y as u32))?;
+    // spot中最大的 MID 计数
+    whole_exp_bin.new_attr::<u32>().create("maxMID")?.write_scalar(&summary.max_mid)?;
+    // spot中最大的基因类型计数
+    whole_exp_bin
+        .new_attr::<u32>()
+        .create("maxGene")?
+        .write_scalar(&u32::from(summary.max_gene))?;
+    whole_exp_bin.new_attr::<u32>().create("resolution")?.write_scalar(&resolution)?;
+
+    // 当分箱大小为 N 时，斑点中的最大外显子表达计数
+    whole_exp_bin_exon.new_attr::<u32>().create("maxExon")?.write_scalar(&summary.max_exon)?;
+    Ok(summary)
+}
+
+/// wholeExp 的 minX/minY：geftools 写为 uint32；坐标为负（--apply-offset 平移或 merge 变换后）
+/// 时 uint32 无法表示，改写为 int32
+fn write_min_attr(ds: &Dataset, name: &str, v: i32) -> Result<(), Box<dyn Error>> {
+    match u32::try_from(v) {
+        Ok(u) => ds.new_attr::<u32>().create(name)?.write_scalar(&u)?,
+        Err(_) => ds.new_attr::<i32>().create(name)?.write_scalar(&v)?,
+    }
+    Ok(())
+}
+
+/// 写入 `/samples` 与 `/wholeExpSample/binN`：后者与 `/wholeExp/binN` 同形，值为来源样本的序号
+/// （1-based，对应 `/samples` 的行），0 为空，binN 的 spot 含多个样本时为 `MIXED_SAMPLE`
+pub fn write_samples(
+    f: &H5File,
+    bins: &[BinData],
+    layer: &SampleLayer,
+    opts: &DatasetOpts,
+) -> Result<(), Box<dyn Error>> {
+    f.new_dataset_builder().with_data(&layer.recs).create("samples")?;
+    let group = f.create_group("wholeExpSample")?;
+    let filters = opts.filters();
+    let tile = opts.whole_chunk.max(1);
+    for bin in bins {
+        let mut codes: HashMap<(i32, i32), u16> = HashMap::with_capacity(bin.spot_mid_map.len());
+        for (&(x, y), &code) in &layer.spot_sample {
+            let key = (bin_origin(x, bin.bin_size), bin_origin(y, bin.bin_size));
+            let c = codes.entry(key).or_insert(code);
+            if *c != code {
+                *c = MIXED_SAMPLE;
+            }
+        }
+        let mut keys: Vec<(i32, i32)> = codes.keys().copied().collect();
+        keys.sort_unstable_by_key(|&xy| bin.tile_key(xy, tile));
+        let (mat_x, mat_y) = bin.matrix_len()?;
+        let ds = group
+            .new_dataset::<u16>()
+            .chunk([
+                DatasetOpts::clamp_chunk(tile, mat_x),
+                DatasetOpts::clamp_chunk(tile, mat_y),
+            ])
+            .set_filters(&filters)
             .shape([mat_x, mat_y])
-            .create("bin1")?;
-        // 保存矩阵
-        whole_exp_bin1
-            .write(&Array2::from_shape_vec([mat_x, mat_y], mat).unwrap())?;
-
-        // 写入 wholeExp 属性
-        // 稠密矩阵中非零点的数量
-        whole_exp_bin1.new_attr::<u64>().create("number")?.write_scalar(&number)?;
-        // 非零点x和y坐标最小值
-        whole_exp_bin1.new_attr::<i32>().create("minX")?.write_scalar(&self.min_x)?;
-        whole_exp_bin1.new_attr::<i32>().create("minY")?.write_scalar(&self.min_y)?;
-        // 非零点x和y坐标极差
-        whole_exp_bin1
-            .new_attr::<i32>()
-            .create("lenX")?
-            .write_scalar(&mat_x)?;
-        whole_exp_bin1
-            .new_attr::<i32>()
-            .create("lenY")?
-            .write_scalar(&mat_y)?;
-        // spot中最大的 MID 计数
-        whole_exp_bin1.new_attr::<u32>().create("maxMID")?.write_scalar(&max_mid_per_bin_n)?;
-        // spot中最大的基因类型计数
-        whole_exp_bin1.new_attr::<u32>().create("maxGene")?.write_scalar(&max_gene)?;
-        whole_exp_bin1.new_attr::<u32>().create("resolution")?.write_scalar(&self.resolution)?;
-
-        // 5. 写入 /wholeExpExon/bin1
-        let whole_exon = f.create_group("wholeExpExon")?;
-         // 将字典(坐标-表达量)转化为矩阵
-        let (exon_mat, exon_mat_x, exon_mat_y) = map2mat(&self.spot_exon_map, self.min_x, self.min_y,self.max_x, self.max_y)?;
-        // 创建数据节点
-        let whole_exp_bin1_exon = whole_exon
-            .new_dataset::<u32>()
-            .shape([exon_mat_x as usize, exon_mat_y as usize])
-            .create("bin1")?;
-        // 保存矩阵
-        whole_exp_bin1_exon
-            .write(&Array2::from_shape_vec([exon_mat_x, exon_mat_y], exon_mat).unwrap())?;
-        // 当分箱大小为 N 时，斑点中的最大外显子表达计数
-        let max_exon_spot = self.spot_exon_map.values().max().copied().unwrap_or(0);
-        whole_exp_bin1.new_attr::<u32>().create("maxExon")?.write_scalar(&max_exon_spot)?;
-
-        // (stat/gene 逻辑被注释掉了，这里也忽略)
+            .create(format!("bin{}", bin.bin_size).as_str())?;
+        let mut tiles = TileWriter::new(&ds, [mat_x, mat_y], tile);
+        for xy in keys {
+            tiles.push(bin.tile_key(xy, tile), codes[&xy])?;
+        }
+        tiles.finish()?;
+        ds.new_attr::<u16>().create("mixed")?.write_scalar(&MIXED_SAMPLE)?;
+    }
+    Ok(())
+}
+
+/// 按 `TileKey` 顺序接收 spot，逐个 `tile` x `tile` 块写入 shape 为 `[len_x, len_y]`、下标为
+/// `[x, y]` 的分块数据集；内存中只保留当前 tile 的缓冲
+struct TileWriter<'a, T> {
+    ds: &'a Dataset,
+    shape: [usize; 2],
+    tile: usize,
+    cur: Option<([usize; 2], Array2<T>)>,
+}
+
+impl<'a, T> TileWriter<'a, T>
+where
+    T: H5Type + Default + Clone,
+{
+    fn new(ds: &'a Dataset, shape: [usize; 2], tile: usize) -> Self {
+        Self {
+            ds,
+            shape,
+            tile,
+            cur: None,
+        }
+    }
+
+    fn push(&mut self, (t, [xi, yi]): TileKey, v: T) -> Result<(), Box<dyn Error>> {
+        if self.cur.as_ref().map(|(cur, _)| *cur) != Some(t) {
+            debug_assert!(
+                self.cur.as_ref().is_none_or(|(cur, _)| *cur < t),
+                "spot 未按 tile 排序"
+            );
+            self.flush()?;
+            let x1 = ((t[0] + 1) * self.tile).min(self.shape[0]);
+            let y1 = ((t[1] + 1) * self.tile).min(self.shape[1]);
+            let buf = Array2::<T>::default((x1 - t[0] * self.tile, y1 - t[1] * self.tile));
+            self.cur = Some((t, buf));
+        }
+        if let Some((_, buf)) = &mut self.cur {
+            buf[[xi % self.tile, yi % self.tile]] = v;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(([tx, ty], buf)) = self.cur.take() {
+            let (x0, y0) = (tx * self.tile, ty * self.tile);
+            let (w, h) = buf.dim();
+            self.ds.write_slice(&buf, s![x0..x0 + w, y0..y0 + h])?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<(), Box<dyn Error>> {
+        self.flush()
+    }
+}
+
+/// 写入 `/geneExp/binN/gene`
+pub fn write_gene(
+    group: &Group,
+    genes: &[GeneRec],
+    opts: &DatasetOpts,
+) -> Result<Dataset, Box<dyn Error>> {
+    Ok(group
+        .new_dataset_builder()
+        .with_data(genes)
+        .chunk(DatasetOpts::clamp_chunk(opts.gene_chunk, genes.len()))
+        .set_filters(&opts.filters())
+        .create("gene")?)
+}
+
+/// 写入 `/stat/gene`；`stats` 需已按 MIDcount 降序排列
+pub fn write_stat_gene(
+    f: &H5File,
+    stats: &[StatGene],
+    opts: &DatasetOpts,
+) -> Result<(), Box<dyn Error>> {
+    let stat = f.create_group("stat")?;
+    let ds_stat = stat
+        .new_dataset_builder()
+        .with_data(stats)
+        .chunk(DatasetOpts::clamp_chunk(opts.gene_chunk, stats.len()))
+        .set_filters(&opts.filters())
+        .create("gene")?;
+    let max_mid = stats.first().map(|s| s.MIDcount).unwrap_or(0);
+    let min_mid = stats.last().map(|s| s.MIDcount).unwrap_or(0);
+    let max_e10 = stats.iter().map(|s| s.E10).fold(0.0f32, f32::max);
+    ds_stat.new_attr::<u32>().create("minMIDcount")?.write_scalar(&min_mid)?;
+    ds_stat.new_attr::<u32>().create("maxMIDcount")?.write_scalar(&max_mid)?;
+    ds_stat.new_attr::<f32>().create("maxE10")?.write_scalar(&max_e10)?;
+    Ok(())
+}
+
+/// 以可扩展的分块数据集逐段追加 `/geneExp/binN/expression` 与 `exon`，
+/// 内存中最多保留 `buf_len` 条记录的缓冲
+pub struct ExpressionAppender {
+    ds_expr: Dataset,
+    ds_exon: Option<Dataset>,
+    buf_expr: Vec<Expression>,
+    buf_exon: Vec<u32>,
+    len: usize,
+    buf_len: usize,
+}
+
+impl ExpressionAppender {
+    /// 每条缓冲记录占用的字节数（expression + 可选的 exon）
+    pub fn record_bytes(has_exon: bool) -> usize {
+        std::mem::size_of::<Expression>()
+            + if has_exon {
+                std::mem::size_of::<u32>()
+            } else {
+                0
+            }
+    }
+
+    pub fn new(
+        group: &Group,
+        has_exon: bool,
+        opts: &DatasetOpts,
+        buf_len: usize,
+    ) -> Result<Self, Box<dyn Error>> {
+        let chunk = opts.expr_chunk.max(1);
+        let filters = opts.filters();
+        let ds_expr = group
+            .new_dataset::<Expression>()
+            .chunk(chunk)
+            .set_filters(&filters)
+            .shape(0..)
+            .create("expression")?;
+        let ds_exon = if has_exon {
+            Some(
+                group
+                    .new_dataset::<u32>()
+                    .chunk(chunk)
+                    .set_filters(&filters)
+                    .shape(0..)
+                    .create("exon")?,
+            )
+        } else {
+            None
+        };
+        let buf_len = buf_len.max(1);
+        Ok(Self {
+            ds_expr,
+            ds_exon,
+            buf_expr: Vec::with_capacity(buf_len),
+            buf_exon: Vec::with_capacity(if has_exon { buf_len } else { 0 }),
+            len: 0,
+            buf_len,
+        })
+    }
+
+    pub fn push(&mut self, x: i32, y: i32, mid: u32, exon: u32) -> Result<(), Box<dyn Error>> {
+        self.buf_expr.push(Expression { x, y, count: mid });
+        if self.ds_exon.is_some() {
+            self.buf_exon.push(exon);
+        }
+        if self.buf_expr.len() >= self.buf_len {
+            self.flush()?;
+        }
+        Ok(())
+    }
 
+    fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.buf_expr.is_empty() {
+            return Ok(());
+        }
+        let end = self.len + self.buf_expr.len();
+        self.ds_expr.resize(end)?;
+        self.ds_expr.write_slice(self.buf_expr.as_slice(), self.len..end)?;
+        if let Some(ds_exon) = &self.ds_exon {
+            ds_exon.resize(end)?;
+            ds_exon.write_slice(self.buf_exon.as_slice(), self.len..end)?;
+        }
+        self.len = end;
+        self.buf_expr.clear();
+        self.buf_exon.clear();
         Ok(())
     }
+
+    /// 写完剩余缓冲，返回 (expression, exon) 数据集以便补写属性
+    pub fn finish(mut self) -> Result<(Dataset, Option<Dataset>), Box<dyn Error>> {
+        self.flush()?;
+        Ok((self.ds_expr, self.ds_exon))
+    }
 }
 
 pub fn str2fa64(s: &str) -> FixedAscii<64> {
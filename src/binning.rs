@@ -0,0 +1,403 @@
+use std::{
+    cmp::{max, min},
+    collections::{HashMap, HashSet},
+    mem,
+};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{
+    bgef_writer::{str2fa64, Expression, GeneRec, SpotGene, StatGene},
+    gem_reader::GeneBins,
+    log::log_msg,
+};
+
+/// 单个 bin 尺寸下聚合完成、可直接写入 `/geneExp/binN`、`/wholeExp/binN` 的数据
+pub struct BinData {
+    pub bin_size: u32,
+    pub expressions: Vec<Expression>,
+    pub exons: Vec<u32>,
+    pub genes_meta: Vec<GeneRec>,
+    pub spot_mid_map: HashMap<(i32, i32), SpotGene>,
+    pub spot_exon_map: HashMap<(i32, i32), u32>,
+    /// 坐标范围（bin 左上角在 bin1 坐标系下的坐标，均为 bin_size 的整数倍）
+    pub min_x: i32,
+    pub min_y: i32,
+    pub max_x: i32,
+    pub max_y: i32,
+    pub max_exp: u32,
+    pub max_exon: u32,
+}
+
+impl BinData {
+    /// `/wholeExp/binN` 矩阵的 (lenX, lenY)（以 bin 为单位，含两端）；没有任何 spot 时报错
+    pub fn matrix_len(&self) -> Result<(usize, usize)> {
+        if self.min_x > self.max_x || self.min_y > self.max_y {
+            bail!(
+                "bin{} 没有任何表达记录，无法确定 wholeExp 的范围",
+                self.bin_size
+            );
+        }
+        // 在 i64 中计算，坐标跨度超过 i32 时也不会溢出
+        let step = i64::from(self.bin_size);
+        let len = |min: i32, max: i32| -> Result<usize> {
+            let n = (i64::from(max) - i64::from(min)) / step + 1;
+            u32::try_from(n)
+                .map(|n| n as usize)
+                .map_err(|_| anyhow!("bin{} 的 wholeExp 边长 {} 超出 uint32", self.bin_size, n))
+        };
+        Ok((len(self.min_x, self.max_x)?, len(self.min_y, self.max_y)?))
+    }
+
+    /// spot 写入 wholeExp 时的排序键，见 `tile_key`
+    pub fn tile_key(&self, xy: (i32, i32), tile: usize) -> TileKey {
+        tile_key((self.min_x, self.min_y), self.bin_size, tile, xy)
+    }
+
+    /// 按 `tile_key` 排列的全部 spot 统计；只对坐标排序，值在迭代时再查
+    pub fn spots_by_tile(&self, tile: usize) -> impl Iterator<Item = SpotStat> + '_ {
+        let mut keys: Vec<(i32, i32)> = self.spot_mid_map.keys().copied().collect();
+        keys.sort_unstable_by_key(|&xy| self.tile_key(xy, tile));
+        keys.into_iter().map(move |xy| {
+            let exon = self.spot_exon_map.get(&xy).copied().unwrap_or(0);
+            (xy, self.spot_mid_map[&xy], exon)
+        })
+    }
+
+    /// 由内存中的 spot 统计汇总 wholeExp 的属性
+    pub fn spot_summary(&self) -> SpotSummary {
+        let mut summary = SpotSummary::default();
+        for (xy, spot) in &self.spot_mid_map {
+            summary.add(spot, self.spot_exon_map.get(xy).copied().unwrap_or(0));
+        }
+        summary
+    }
+
+    /// 打印 `/wholeExp/binN` 与 `/wholeExpExon/binN` 的统计信息
+    pub fn log_info(&self, resolution: u16) {
+        self.log_summary(&self.spot_summary(), resolution);
+    }
+
+    /// 同 `log_info`，spot 的汇总由调用方给出（spot 统计已落盘时）
+    pub fn log_summary(&self, spots: &SpotSummary, resolution: u16) {
+        let (len_x, len_y) = self.matrix_len().unwrap_or((0, 0));
+        log_msg(&format!(
+            "/wholeExp/bin{} info:\n  number={}\n  minX={}  minY={}\n  lenX={}  lenY={}\n  maxMID={}  maxGene={} resolution={}",
+            self.bin_size,
+            spots.number,
+            self.min_x, self.min_y,
+            len_x, len_y,
+            spots.max_mid, spots.max_gene, resolution,
+        ));
+        log_msg(&format!(
+            "/wholeExpExon/bin{} info:\n  maxExon={}",
+            self.bin_size, spots.max_exon
+        ));
+    }
+}
+
+/// wholeExp 中的一个 spot：bin 左上角的 bin1 坐标、(MIDcount, genecount) 与外显子计数
+pub type SpotStat = ((i32, i32), SpotGene, u32);
+
+/// spot 在 wholeExp 矩阵中的 (tile 下标, 矩阵下标)；按它排序即可逐个 tile 写入
+pub type TileKey = ([usize; 2], [usize; 2]);
+
+/// binN 的 spot `xy` 在以 `origin`（该 bin 的 minX, minY）为 0 的矩阵中的 `TileKey`
+pub fn tile_key(origin: (i32, i32), bin_size: u32, tile: usize, (x, y): (i32, i32)) -> TileKey {
+    let step = i64::from(bin_size);
+    let xi = ((i64::from(x) - i64::from(origin.0)) / step) as usize;
+    let yi = ((i64::from(y) - i64::from(origin.1)) / step) as usize;
+    ([xi / tile, yi / tile], [xi, yi])
+}
+
+/// `/wholeExp/binN` 与 `/wholeExpExon/binN` 的汇总属性，逐个 spot 累计
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SpotSummary {
+    pub number: u64,
+    pub max_mid: u32,
+    pub max_gene: u16,
+    pub max_exon: u32,
+}
+
+impl SpotSummary {
+    pub fn add(&mut self, spot: &SpotGene, exon: u32) {
+        self.number += 1;
+        self.max_mid = max(self.max_mid, spot.MIDcount);
+        self.max_gene = max(self.max_gene, spot.genecount);
+        self.max_exon = max(self.max_exon, exon);
+    }
+}
+
+/// 将 bin1 坐标换算为所在 binN 的左上角坐标
+#[inline]
+pub fn bin_origin(v: i32, bin_size: u32) -> i32 {
+    let b = bin_size as i32;
+    v.div_euclid(b) * b
+}
+
+/// 一个基因在 binN 下按 (x,y) 排好序的记录：((x, y), (MIDCount, ExonCount))
+pub type GeneRecs = Vec<((i32, i32), (u32, u32))>;
+
+/// 逐基因聚合 binN 数据的累加器：维护 gene 行、spot 统计与坐标范围。
+///
+/// 每个基因的表达记录由 `push_gene` 返回给调用方，既可以收集到内存
+/// （`build_bin`），也可以直接流式写入 HDF5（`spill` 模块）。
+pub struct BinAccumulator {
+    bin_size: u32,
+    has_exon: bool,
+    genes_meta: Vec<GeneRec>,
+    spot_mid_map: HashMap<(i32, i32), SpotGene>,
+    spot_exon_map: HashMap<(i32, i32), u32>,
+    min_x: i32,
+    min_y: i32,
+    max_x: i32,
+    max_y: i32,
+    max_exp: u32,
+    max_exon: u32,
+    // 表达数据计数器
+    offset_u32: u32,
+}
+
+impl BinAccumulator {
+    pub fn new(bin_size: u32, has_exon: bool) -> Self {
+        Self {
+            bin_size,
+            has_exon,
+            genes_meta: Vec::new(),
+            spot_mid_map: HashMap::new(),
+            spot_exon_map: HashMap::new(),
+            min_x: i32::MAX,
+            min_y: i32::MAX,
+            max_x: i32::MIN,
+            max_y: i32::MIN,
+            max_exp: u32::MIN,
+            max_exon: u32::MIN,
+            offset_u32: 0,
+        }
+    }
+
+    /// 聚合一个基因：输入该基因的 bin1 记录（(x,y) 互不重复），
+    /// 返回按 (x,y) 排序的 binN 记录，调用方需按返回顺序写入 expression/exon。
+    ///
+    /// 计数超出 bGEF 字段类型（MID/exon 为 uint32，spot 的 genecount 为 uint16，gene offset 为
+    /// uint32）时报错，报错中包含 bin 尺寸、基因与坐标。
+    pub fn push_gene<I>(&mut self, gene_id: &str, gene_name: &str, bin1: I) -> Result<GeneRecs>
+    where
+        I: IntoIterator<Item = ((i32, i32), (u32, u32))>,
+    {
+        let bin_size = self.bin_size;
+        let overflow = |what: &str, (x, y): (i32, i32)| {
+            anyhow!(
+                "bin{} 基因 {} 在 ({}, {}) 的{}溢出 uint32",
+                bin_size,
+                gene_id,
+                x,
+                y,
+                what
+            )
+        };
+        // 1) 把 bin1 坐标合并到 binN 的格子里，收集该基因的所有 (x,y) 记录
+        let mut recs: GeneRecs = if bin_size == 1 {
+            bin1.into_iter().collect()
+        } else {
+            let mut m: HashMap<(i32, i32), (u32, u32)> = HashMap::new();
+            for ((x, y), (mid, exon_cnt)) in bin1 {
+                let key = (bin_origin(x, bin_size), bin_origin(y, bin_size));
+                let vals = m.entry(key).or_insert((0, 0));
+                vals.0 = vals.0.checked_add(mid).ok_or_else(|| overflow("MIDCount 合计", key))?;
+                vals.1 =
+                    vals.1.checked_add(exon_cnt).ok_or_else(|| overflow("ExonCount 合计", key))?;
+            }
+            m.into_iter().collect()
+        };
+        // 2) 排序，保证稳定性
+        recs.sort_unstable_by_key(|&((x, y), _)| (x, y));
+
+        // 3) 记录起始 offset: 该基因的数据从哪里开始
+        let start = self.offset_u32;
+        self.offset_u32 =
+            u32::try_from(recs.len()).ok().and_then(|n| start.checked_add(n)).ok_or_else(|| {
+                anyhow!(
+                    "bin{} 的表达记录数超过 uint32 上限（/geneExp/bin{}/gene 的 offset 为 uint32）",
+                    bin_size,
+                    bin_size
+                )
+            })?;
+
+        // 4) 维护 max 值与 spot 统计
+        for &((x, y), (mid, exon_cnt)) in &recs {
+            if self.has_exon {
+                self.max_exon = max(self.max_exon, exon_cnt);
+            }
+            self.max_exp = max(self.max_exp, mid);
+            self.min_x = min(self.min_x, x);
+            self.min_y = min(self.min_y, y);
+            self.max_x = max(self.max_x, x);
+            self.max_y = max(self.max_y, y);
+
+            let entry = self.spot_mid_map.entry((x, y)).or_default();
+            entry.MIDcount = entry
+                .MIDcount
+                .checked_add(mid)
+                .ok_or_else(|| overflow("spot MIDcount 合计", (x, y)))?;
+            entry.genecount = entry.genecount.checked_add(1).ok_or_else(|| {
+                anyhow!(
+                    "bin{} spot ({}, {}) 的基因数超过 65535（wholeExp 的 genecount 为 uint16）",
+                    bin_size,
+                    x,
+                    y
+                )
+            })?;
+            // exon 累加
+            let e = self.spot_exon_map.entry((x, y)).or_insert(0);
+            *e = e.checked_add(exon_cnt).ok_or_else(|| overflow("spot ExonCount 合计", (x, y)))?;
+        }
+
+        // 5) 构建 gene 行
+        self.genes_meta.push(GeneRec {
+            geneID: str2fa64(gene_id),
+            geneName: str2fa64(gene_name),
+            offset: start,
+            count: self.offset_u32 - start,
+        });
+        Ok(recs)
+    }
+
+    /// 内存中累计的 spot 数
+    pub fn spot_count(&self) -> usize {
+        self.spot_mid_map.len()
+    }
+
+    /// 取出内存中累计的 spot 统计，之后从空表重新累计；有内存上限的转换据此把 spot 统计落盘，
+    /// 同一 spot 在不同批次中的计数由调用方归并时相加
+    pub fn take_spots(&mut self) -> impl Iterator<Item = SpotStat> {
+        let mut exons = mem::take(&mut self.spot_exon_map);
+        mem::take(&mut self.spot_mid_map)
+            .into_iter()
+            .map(move |(xy, spot)| (xy, spot, exons.remove(&xy).unwrap_or(0)))
+    }
+
+    /// 结束聚合；流式写入时 expressions/exons 可传空 Vec
+    pub fn finish(self, expressions: Vec<Expression>, exons: Vec<u32>) -> BinData {
+        BinData {
+            bin_size: self.bin_size,
+            expressions,
+            exons,
+            genes_meta: self.genes_meta,
+            spot_mid_map: self.spot_mid_map,
+            spot_exon_map: self.spot_exon_map,
+            min_x: self.min_x,
+            min_y: self.min_y,
+            max_x: self.max_x,
+            max_y: self.max_y,
+            max_exp: self.max_exp,
+            max_exon: self.max_exon,
+        }
+    }
+}
+
+/// 由 bin1 的逐基因表达数据聚合出指定 bin 尺寸的数据。
+///
+/// 串接顺序：外层按基因（BTreeMap 已排序），内层按 (x,y) 排序；
+/// bin_size == 1 时结果与原始 bin1 数据一致。基因标识需已解析（见 `get_expression`）。
+pub fn build_bin(gene_bins: &GeneBins, bin_size: u32, has_exon: bool) -> Result<BinData> {
+    let total: usize = gene_bins.values().map(|coord_map| coord_map.len()).sum();
+
+    // 预分配 (为 geneExp/binN 准备)
+    let mut expressions: Vec<Expression> = Vec::with_capacity(total);
+    let mut exons: Vec<u32> = if has_exon {
+        Vec::with_capacity(total)
+    } else {
+        Vec::new()
+    };
+
+    let mut acc = BinAccumulator::new(bin_size, has_exon);
+    for ((gene_id, gene_name), coord_map) in gene_bins {
+        let recs = acc.push_gene(gene_id, gene_name, coord_map.iter().map(|(&k, &v)| (k, v)))?;
+        // 逐条推入 expression / exon
+        for ((x, y), (mid, exon_cnt)) in recs {
+            expressions.push(Expression { x, y, count: mid });
+            if has_exon {
+                exons.push(exon_cnt);
+            }
+        }
+    }
+    Ok(acc.finish(expressions, exons))
+}
+
+/// 统计一个基因的总 MID 与 E10（MID >= 10 的 spot 占比，百分数）；总 MID 超出 uint32 时报错
+pub fn stat_row<I>(gene_id: &str, gene_name: &str, counts: I) -> Result<StatGene>
+where
+    I: IntoIterator<Item = u32>,
+{
+    let (mut mid_count, mut e10, mut n) = (0u32, 0usize, 0usize);
+    for c in counts {
+        mid_count = mid_count.checked_add(c).ok_or_else(|| {
+            anyhow!(
+                "基因 {} 的总 MIDcount 溢出 uint32（/stat/gene 的 MIDcount 为 uint32）",
+                gene_id
+            )
+        })?;
+        if c >= 10 {
+            e10 += 1;
+        }
+        n += 1;
+    }
+    Ok(StatGene {
+        geneID: str2fa64(gene_id),
+        geneName: str2fa64(gene_name),
+        MIDcount: mid_count,
+        E10: if n == 0 {
+            0.0
+        } else {
+            e10 as f32 * 100.0 / n as f32
+        },
+    })
+}
+
+/// 按 MIDcount 降序（相同时按 geneID 升序）排列，与 geftools 的 `/stat/gene` 一致
+pub fn sort_stats(stats: &mut [StatGene]) {
+    stats.sort_by(|a, b| b.MIDcount.cmp(&a.MIDcount).then_with(|| a.geneID.cmp(&b.geneID)));
+}
+
+/// 由最小 bin（bin1；已分箱的 GEM 为其 #BinSize）得到的统计，与 `--bins` 写出哪些 bin 无关
+pub struct BaseStats {
+    /// `/stat/gene`，已按 `sort_stats` 排序
+    pub stats: Vec<StatGene>,
+    /// 最小 bin 的尺寸与其中被占据的 spot 数（用于 gef_area）
+    pub bin_size: u32,
+    pub spots: u64,
+}
+
+impl BaseStats {
+    /// 由最小 bin（尺寸为 `bin_size`）的逐基因数据统计每个基因的总 MID 与 E10，以及被占据的 spot 数
+    pub fn from_gene_bins(gene_bins: &GeneBins, bin_size: u32) -> Result<Self> {
+        let mut stats = gene_bins
+            .iter()
+            .map(|((gene_id, gene_name), coords)| {
+                stat_row(gene_id, gene_name, coords.values().map(|&(mid, _)| mid))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        sort_stats(&mut stats);
+        let spots: HashSet<(i32, i32)> =
+            gene_bins.values().flat_map(|m| m.keys().copied()).collect();
+        Ok(Self {
+            stats,
+            bin_size,
+            spots: spots.len() as u64,
+        })
+    }
+
+    /// 组织面积（mm²），见 `gef_area`
+    pub fn area(&self, resolution: u16) -> f32 {
+        gef_area(self.spots, self.bin_size, resolution)
+    }
+}
+
+/// 由被占据的 spot 数估算组织面积（mm²）。
+///
+/// 每个 binN spot 覆盖 `(bin_size * resolution)²` nm²，resolution 为相邻 DNB 中心距（nm）。
+pub fn gef_area(spots: u64, bin_size: u32, resolution: u16) -> f32 {
+    let side_mm = bin_size as f64 * resolution as f64 * 1e-6;
+    (spots as f64 * side_mm * side_mm) as f32
+}
@@ -0,0 +1,163 @@
+//! 基于 mask 的细胞修正：把标签 mask 中的细胞核向外扩展至多 `distance` 像素。
+//!
+//! 背景像素归属离它最近（欧氏距离）的细胞，距离超过 `distance` 的保持背景；与多个细胞等距时
+//! 归属编号较小的细胞，结果与线程数无关。
+//!
+//! 到最近细胞的距离由精确的欧氏距离变换（Felzenszwalb-Huttenlocher）求得：先逐列求每个像素在
+//! 本列中最近的前景行（上下等距时取编号较小的细胞），再逐行求下包络。等距的细胞只可能来自
+//! 各列的最近前景行，因此在 `[x - d, x + d]` 的列中找出距离恰为 d 的那些、取编号最小者，
+//! 不需要按距离预先生成圆周偏移表，内存与 `distance` 无关。
+//! 两遍分别按列块、行块多线程执行。
+
+use std::thread;
+
+use crate::mask::CellMask;
+
+/// 列中没有前景像素
+const NONE: u32 = u32::MAX;
+
+/// 把 `0..len` 均分成至多 `parts` 段，返回每段长度
+fn part_len(len: usize, parts: usize) -> usize {
+    len.div_ceil(parts.max(1)).max(1)
+}
+
+/// 第一遍：`near[x * h + y]` 为第 x 列中离 y 最近的前景行；上下等距时取编号较小的细胞
+fn nearest_in_columns(mask: &CellMask, near: &mut [u32], x0: usize) {
+    let (w, h) = (mask.width, mask.height);
+    for (i, col) in near.chunks_mut(h).enumerate() {
+        let x = x0 + i;
+        let label = |y: usize| mask.labels[y * w + x];
+        let fg = |y: usize| label(y) != 0;
+        let mut last = NONE;
+        for (y, v) in col.iter_mut().enumerate() {
+            if fg(y) {
+                last = y as u32;
+            }
+            *v = last;
+        }
+        let mut next = NONE;
+        for y in (0..h).rev() {
+            if fg(y) {
+                next = y as u32;
+            }
+            if next == NONE {
+                continue;
+            }
+            let prev = col[y];
+            let closer = prev == NONE || {
+                let (dn, dp) = (next as usize - y, y - prev as usize);
+                dn < dp || (dn == dp && label(next as usize) < label(prev as usize))
+            };
+            if closer {
+                col[y] = next;
+            }
+        }
+    }
+}
+
+/// 第二遍：第 y 行每个像素到最近前景像素的距离平方（下包络）
+fn row_envelope(near: &[u32], w: usize, h: usize, y: usize, out: &mut [u64]) {
+    let f = |q: usize| -> Option<f64> {
+        let r = near[q * h + y];
+        (r != NONE).then(|| (r as f64 - y as f64).powi(2))
+    };
+    // v：下包络中的抛物线顶点；z[k]..z[k+1] 为 v[k] 占优的区间
+    let mut v: Vec<usize> = Vec::new();
+    let mut z: Vec<f64> = Vec::new();
+    for q in 0..w {
+        let Some(fq) = f(q) else {
+            continue;
+        };
+        let hq = fq + (q * q) as f64;
+        loop {
+            let Some(&p) = v.last() else {
+                v.push(q);
+                z.clear();
+                z.extend([f64::NEG_INFINITY, f64::INFINITY]);
+                break;
+            };
+            let hp = f(p).unwrap_or(0.0) + (p * p) as f64;
+            let s = (hq - hp) / (2.0 * (q as f64 - p as f64));
+            if s <= z[v.len() - 1] {
+                v.pop();
+                z.pop();
+                if let Some(last) = z.last_mut() {
+                    *last = f64::INFINITY;
+                }
+            } else {
+                *z.last_mut().unwrap() = s;
+                v.push(q);
+                z.push(f64::INFINITY);
+                break;
+            }
+        }
+    }
+    if v.is_empty() {
+        out.fill(u64::MAX);
+        return;
+    }
+    let mut k = 0;
+    for (x, o) in out.iter_mut().enumerate() {
+        while z[k + 1] < x as f64 {
+            k += 1;
+        }
+        let p = v[k];
+        let dy = i64::from(near[p * h + y]) - y as i64;
+        let dx = x as i64 - p as i64;
+        *o = (dx * dx + dy * dy) as u64;
+    }
+}
+
+/// 扩展后的 mask：细胞数与编号不变
+pub fn expand_labels(mask: &CellMask, distance: u32, threads: usize) -> CellMask {
+    let (w, h) = (mask.width, mask.height);
+    let mut out = mask.clone();
+    if distance == 0 || w == 0 || h == 0 || mask.n_cells == 0 {
+        return out;
+    }
+    let max_d2 = u64::from(distance) * u64::from(distance);
+
+    // 第一遍：按列块并行
+    let mut near = vec![NONE; w * h];
+    let cols = part_len(w, threads);
+    thread::scope(|s| {
+        for (i, chunk) in near.chunks_mut(cols * h).enumerate() {
+            s.spawn(move || nearest_in_columns(mask, chunk, i * cols));
+        }
+    });
+
+    // 第二遍：按行块并行，求最近距离后在各列的最近前景行中取距离相同、编号最小的细胞
+    let rows = part_len(h, threads);
+    let near = &near;
+    thread::scope(|s| {
+        for (i, chunk) in out.labels.chunks_mut(rows * w).enumerate() {
+            s.spawn(move || {
+                let mut env = vec![0u64; w];
+                for (j, row) in chunk.chunks_mut(w).enumerate() {
+                    let y = i * rows + j;
+                    row_envelope(near, w, h, y, &mut env);
+                    for (x, px) in row.iter_mut().enumerate() {
+                        let d2 = env[x];
+                        if *px != 0 || d2 > max_d2 {
+                            continue;
+                        }
+                        let reach = d2.isqrt() as usize;
+                        *px = (x.saturating_sub(reach)..(x + reach + 1).min(w))
+                            .filter_map(|q| {
+                                let r = near[q * h + y];
+                                if r == NONE {
+                                    return None;
+                                }
+                                let (dx, dy) =
+                                    (q.abs_diff(x) as u64, (r as usize).abs_diff(y) as u64);
+                                (dx * dx + dy * dy == d2).then(|| mask.labels[r as usize * w + q])
+                            })
+                            .min()
+                            .unwrap_or(0);
+                    }
+                }
+            });
+        }
+    });
+    out
+}
@@ -0,0 +1,107 @@
+//! 读取 cGEF 的 `/cellBin`，并借助 `blockSize`/`blockIndex` 做矩形查询：
+//! 同一块行中相邻块的细胞在 `cell` 中是连续的，每个块行只需读一段。
+//!
+//! `blockIndex` 通常为块数 + 1 个元素（最后一个即细胞总数）；只有块数个元素的文件按细胞总数补齐。
+
+use anyhow::{bail, ensure, Context, Result};
+use hdf5::{Dataset, File as H5File};
+
+use crate::{
+    bgef_reader::read_num,
+    cgef_writer::{block_of, CellRec},
+};
+
+pub struct CgefReader {
+    file: H5File,
+    offset: (i32, i32),
+    block_size: [u32; 4],
+    block_index: Vec<u32>,
+}
+
+impl CgefReader {
+    /// 打开 cGEF，读取 offsetX/offsetY 与分块索引
+    pub fn open(path: &str) -> Result<Self> {
+        let file = H5File::open(path).with_context(|| format!("open {}", path))?;
+        let g = file.group("cellBin").with_context(|| format!("{} 缺少 /cellBin", path))?;
+        let size = g.dataset("blockSize")?.read_raw::<u32>()?;
+        let mut block_index = g.dataset("blockIndex")?.read_raw::<u32>()?;
+        let Ok(block_size) = <[u32; 4]>::try_from(size.as_slice()) else {
+            bail!("{} 的 blockSize 应有 4 个元素，实际 {:?}", path, size);
+        };
+        let blocks = block_size[2] as usize * block_size[3] as usize;
+        let cells = g.dataset("cell")?.size();
+        if block_index.len() == blocks {
+            block_index.push(u32::try_from(cells)?);
+        }
+        ensure!(
+            block_size.iter().all(|&v| v > 0) && block_index.len() == blocks + 1,
+            "{} 的 blockSize {:?} 与 blockIndex 长度 {} 不符",
+            path,
+            block_size,
+            block_index.len()
+        );
+        ensure!(
+            block_index.windows(2).all(|w| w[0] <= w[1])
+                && block_index.last().is_some_and(|&n| n as usize <= cells),
+            "{} 的 blockIndex 不是单调的，或超出细胞数 {}",
+            path,
+            cells
+        );
+        let offset = (
+            read_num(&file, "offsetX").unwrap_or(0),
+            read_num(&file, "offsetY").unwrap_or(0),
+        );
+        Ok(Self {
+            file,
+            offset,
+            block_size,
+            block_index,
+        })
+    }
+
+    /// 根属性 offsetX/offsetY：细胞坐标加上它即为芯片（bin1）坐标
+    pub fn offset(&self) -> (i32, i32) {
+        self.offset
+    }
+
+    /// [块宽, 块高, 块列数, 块行数]
+    pub fn block_size(&self) -> [u32; 4] {
+        self.block_size
+    }
+
+    fn cell_dataset(&self) -> Result<Dataset> {
+        Ok(self.file.dataset("/cellBin/cell")?)
+    }
+
+    /// 质心落在芯片坐标 `[x0, x1) x [y0, y1)` 内的细胞（返回的 `CellRec` 坐标仍相对 offsetX/offsetY）
+    pub fn cells_in_rect(
+        &self,
+        (x0, y0): (i32, i32),
+        (x1, y1): (i32, i32),
+    ) -> Result<Vec<CellRec>> {
+        // 分块网格与细胞坐标都以 offsetX/offsetY 为原点，先把查询矩形换到这个坐标系
+        let (ox, oy) = self.offset;
+        let (x0, x1) = (x0.saturating_sub(ox), x1.saturating_sub(ox));
+        let (y0, y1) = (y0.saturating_sub(oy), y1.saturating_sub(oy));
+        if x1 <= x0 || y1 <= y0 {
+            return Ok(Vec::new());
+        }
+        let nx = self.block_size[2] as usize;
+        let (b0, b1) = (
+            block_of(&self.block_size, x0, y0),
+            block_of(&self.block_size, x1 - 1, y1 - 1),
+        );
+        let (bx0, by0, bx1, by1) = (b0 % nx, b0 / nx, b1 % nx, b1 / nx);
+        let ds = self.cell_dataset()?;
+        let mut out = Vec::new();
+        for by in by0..=by1 {
+            let a = self.block_index[by * nx + bx0] as usize;
+            let b = self.block_index[by * nx + bx1 + 1] as usize;
+            if a < b {
+                let cells = ds.read_slice_1d::<CellRec, _>(a..b)?;
+                out.extend(cells.iter().filter(|c| c.x >= x0 && c.x < x1 && c.y >= y0 && c.y < y1));
+            }
+        }
+        Ok(out)
+    }
+}
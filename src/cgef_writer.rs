@@ -0,0 +1,485 @@
+//! bGEF bin1 + 细胞 mask -> cell-bin GEF（cGEF）。
+//!
+//! 与 stereopy `CellCut` 输出的布局一致：根属性 `bin_type=CellBin`，数据在 `/cellBin` 下：
+//! - `cell`：每个细胞的质心（相对根属性 offsetX/offsetY，即表达区域左上角）、在 `cellExp` 中的
+//!   offset、geneCount/expCount/dnbCount/area；细胞按所在 block 排序后依次编号；
+//! - `blockSize`/`blockIndex`：以 256×256 为块把表达区域（wholeExp/bin1 的范围，原点为
+//!   offsetX/offsetY）分格，细胞按相对坐标归块，第 b 块的细胞为
+//!   `cell[blockIndex[b]..blockIndex[b + 1]]`，块号按行优先（`by * 块列数 + bx`）；
+//! - `cellBorder`：(细胞数, 32, 2) 的 int16 轮廓顶点，为相对质心的偏移，不足 32 个时以 32767 填充；
+//! - `cellExp`（按细胞）与 `geneExp`（按基因）：同一批 (细胞, 基因, MID) 记录的两种排列；
+//! - `gene`：每个基因在 `geneExp` 中的 offset、cellCount、expCount、maxMIDcount；
+//! - `cellExon`/`cellExpExon`/`geneExon`/`geneExpExon`：bGEF 含 exon 时写出；
+//! - `cellTypeList`：只有 `default` 一类。
+//!
+//! 表中 uint16/uint32 的计数按 geftools 的 dtype 写出，超出范围时截到该类型的最大值，并在日志中
+//! 按字段警告被截断的条数。
+
+use std::{collections::BTreeMap, error::Error};
+
+use hdf5::filters::Filter;
+use hdf5::types::{FixedAscii, VarLenUnicode};
+use hdf5::{Dataset, File as H5File, Group, H5Type};
+use ndarray::Array3;
+
+use crate::{
+    bgef_reader::BgefReader,
+    bgef_writer::{DatasetOpts, GEFTOOL_RS_VERSION},
+    log::log_msg,
+    mask::{simplify_polygon, CellMask},
+};
+
+/// 每个细胞轮廓的顶点数上限
+pub const BORDER_CNT: usize = 32;
+/// `cellBorder` 中未用顶点的填充值
+pub const BORDER_PAD: i16 = i16::MAX;
+/// 空间分块的边长（bin1）
+pub const BLOCK_LEN: u32 = 256;
+
+/// `blockSize`：[块宽, 块高, 块列数, 块行数]；`len_x`/`len_y` 为表达区域的宽高
+pub fn block_size(len_x: usize, len_y: usize) -> [u32; 4] {
+    let blocks = |len: usize| (len.div_ceil(BLOCK_LEN as usize)).max(1) as u32;
+    [BLOCK_LEN, BLOCK_LEN, blocks(len_x), blocks(len_y)]
+}
+
+/// 细胞坐标（相对 offsetX/offsetY）所在的块号；超出网格的坐标归到边缘块
+pub fn block_of(size: &[u32; 4], x: i32, y: i32) -> usize {
+    let bx = (x.max(0) as u32 / size[0]).min(size[2] - 1);
+    let by = (y.max(0) as u32 / size[1]).min(size[3] - 1);
+    (by * size[2] + bx) as usize
+}
+
+/// `/cellBin/cell` 的一行
+#[repr(C)]
+#[allow(non_snake_case)]
+#[derive(Clone, Copy, Debug, H5Type, Default)]
+pub struct CellRec {
+    pub id: u32,
+    /// 质心的 bin1 坐标
+    pub x: i32,
+    pub y: i32,
+    /// 在 `cellExp` 中的起始位置
+    pub offset: u32,
+    pub geneCount: u16,
+    pub expCount: u16,
+    /// 有表达的 bin1 spot 数
+    pub dnbCount: u16,
+    /// mask 像素数
+    pub area: u16,
+    pub cellTypeID: u16,
+    pub clusterID: u16,
+}
+
+/// `/cellBin/cellExp` 的一行：`geneID` 是基因在 `/cellBin/gene` 中的下标
+#[repr(C)]
+#[allow(non_snake_case)]
+#[derive(Clone, Copy, Debug, H5Type)]
+pub struct CellExp {
+    pub geneID: u32,
+    pub count: u16,
+}
+
+/// `/cellBin/geneExp` 的一行
+#[repr(C)]
+#[allow(non_snake_case)]
+#[derive(Clone, Copy, Debug, H5Type)]
+pub struct GeneExp {
+    pub cellID: u32,
+    pub count: u16,
+}
+
+/// `/cellBin/gene` 的一行
+#[repr(C)]
+#[allow(non_snake_case)]
+#[derive(Clone, Copy, Debug, H5Type)]
+pub struct CellGene {
+    #[hdf5(name = "geneID")]
+    pub geneID: FixedAscii<64>,
+    #[hdf5(name = "geneName")]
+    pub geneName: FixedAscii<64>,
+    /// 在 `geneExp` 中的起始位置
+    pub offset: u32,
+    pub cellCount: u32,
+    pub expCount: u32,
+    pub maxMIDcount: u16,
+}
+
+/// `/cellBin` 的 exon 数据集
+pub struct CellBinExon {
+    pub cell_exon: Vec<u16>,
+    pub cell_exp_exon: Vec<u16>,
+    pub gene_exon: Vec<u32>,
+    pub gene_exp_exon: Vec<u16>,
+}
+
+/// 组装好的 `/cellBin` 数据
+pub struct CellBin {
+    pub cells: Vec<CellRec>,
+    /// 与 `cells` 一一对应的轮廓顶点 (dx, dy)
+    pub borders: Vec<[[i16; 2]; BORDER_CNT]>,
+    pub cell_exp: Vec<CellExp>,
+    pub genes: Vec<CellGene>,
+    pub gene_exp: Vec<GeneExp>,
+    /// bGEF 含 exon 时为 Some
+    pub exon: Option<CellBinExon>,
+    pub block_size: [u32; 4],
+    /// 长度为块数 + 1
+    pub block_index: Vec<u32>,
+    /// bin1 表达的坐标范围 (minX, minY, maxX, maxY)
+    pub extent: (i32, i32, i32, i32),
+    pub resolution: u32,
+}
+
+/// 超出 cGEF 字段类型、被截到最大值的条数（按字段），结束时汇总成一条警告
+#[derive(Default)]
+struct Clamped(BTreeMap<&'static str, u64>);
+
+impl Clamped {
+    fn u16(&mut self, field: &'static str, v: u64) -> u16 {
+        u16::try_from(v).unwrap_or_else(|_| {
+            *self.0.entry(field).or_default() += 1;
+            u16::MAX
+        })
+    }
+
+    fn u32(&mut self, field: &'static str, v: u64) -> u32 {
+        u32::try_from(v).unwrap_or_else(|_| {
+            *self.0.entry(field).or_default() += 1;
+            u32::MAX
+        })
+    }
+
+    fn warn(&self) {
+        if self.0.is_empty() {
+            return;
+        }
+        let detail: Vec<String> = self.0.iter().map(|(f, n)| format!("{}={}", f, n)).collect();
+        log_msg(&format!(
+            "warning: values exceeding the cGEF field type were clamped to its maximum: {}",
+            detail.join(" ")
+        ));
+    }
+}
+
+/// 把 bGEF bin1 的表达按 mask 分配到细胞；mask 外的 spot 丢弃
+pub fn build_cell_bin(reader: &BgefReader, mask: &CellMask) -> Result<CellBin, Box<dyn Error>> {
+    let genes = reader.genes(1)?;
+    let expressions = reader.expressions(1)?;
+    let exons = reader.exons(1)?;
+    let info = reader.whole_exp_info(1)?;
+    let n = mask.n_cells;
+
+    // 1. 每个细胞的面积、像素坐标和（算质心）与扫描顺序的第一个像素（轮廓起点）
+    let mut area = vec![0u64; n];
+    let mut first = vec![0usize; n];
+    let mut sum_xy = vec![(0i64, 0i64); n];
+    for (i, &l) in mask.labels.iter().enumerate() {
+        if l == 0 {
+            continue;
+        }
+        let c = l as usize - 1;
+        if area[c] == 0 {
+            first[c] = i;
+        }
+        area[c] += 1;
+        sum_xy[c].0 += (i % mask.width) as i64;
+        sum_xy[c].1 += (i / mask.width) as i64;
+    }
+
+    // 2. 逐基因分配表达：同一基因在同一细胞内的多个 spot 合并，细胞内的基因自然按下标升序
+    let mut per_cell: Vec<Vec<(u32, u64, u64)>> = vec![Vec::new(); n];
+    let mut dnb = vec![0u64; n];
+    let mut seen = vec![false; mask.labels.len()];
+    for (gi, g) in genes.iter().enumerate() {
+        let range = g.offset as usize..g.offset as usize + g.count as usize;
+        for k in range {
+            let e = &expressions[k];
+            let Some(p) = mask.index(e.x, e.y) else {
+                continue;
+            };
+            let l = mask.labels[p];
+            if l == 0 {
+                continue;
+            }
+            let c = l as usize - 1;
+            if !seen[p] {
+                seen[p] = true;
+                dnb[c] += 1;
+            }
+            let exon = exons.as_ref().map_or(0, |v| u64::from(v[k]));
+            match per_cell[c].last_mut() {
+                Some(last) if last.0 == gi as u32 => {
+                    last.1 += u64::from(e.count);
+                    last.2 += exon;
+                }
+                _ => per_cell[c].push((gi as u32, u64::from(e.count), exon)),
+            }
+        }
+    }
+
+    // 3. 质心（bin1 坐标）与所在块；细胞按块号稳定排序，排序后的位置即细胞 id
+    let (min_x, min_y) = (info.min_x, info.min_y);
+    let block_size = block_size(info.len_x, info.len_y);
+    let centroids: Vec<(i32, i32)> = (0..n)
+        .map(|c| {
+            let a = area[c].max(1) as f64;
+            (
+                mask.x0 + (sum_xy[c].0 as f64 / a).round() as i32,
+                mask.y0 + (sum_xy[c].1 as f64 / a).round() as i32,
+            )
+        })
+        .collect();
+    let blocks: Vec<usize> =
+        centroids.iter().map(|&(x, y)| block_of(&block_size, x - min_x, y - min_y)).collect();
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by_key(|&c| blocks[c]);
+    let mut block_index = vec![0u32; (block_size[2] * block_size[3]) as usize + 1];
+    for &b in &blocks {
+        block_index[b + 1] += 1;
+    }
+    for b in 1..block_index.len() {
+        block_index[b] += block_index[b - 1];
+    }
+
+    // 4. cell + cellExp（按细胞）
+    let mut clamped = Clamped::default();
+    let mut cells = Vec::with_capacity(n);
+    let mut cell_exp = Vec::new();
+    let mut cell_exp_exon = Vec::new();
+    let mut cell_exon = Vec::with_capacity(n);
+    let mut gene_cells = vec![0u32; genes.len()];
+    for (id, &c) in order.iter().enumerate() {
+        let list = &per_cell[c];
+        cells.push(CellRec {
+            id: id as u32,
+            x: centroids[c].0 - min_x,
+            y: centroids[c].1 - min_y,
+            offset: u32::try_from(cell_exp.len())?,
+            geneCount: clamped.u16("cell.geneCount", list.len() as u64),
+            expCount: clamped.u16("cell.expCount", list.iter().map(|r| r.1).sum()),
+            dnbCount: clamped.u16("cell.dnbCount", dnb[c]),
+            area: clamped.u16("cell.area", area[c]),
+            cellTypeID: 0,
+            clusterID: 0,
+        });
+        for &(gi, count, exon) in list {
+            cell_exp.push(CellExp {
+                geneID: gi,
+                count: clamped.u16("cellExp.count", count),
+            });
+            cell_exp_exon.push(clamped.u16("cellExpExon", exon));
+            gene_cells[gi as usize] += 1;
+        }
+        cell_exon.push(clamped.u16("cellExon", list.iter().map(|r| r.2).sum()));
+    }
+
+    // 轮廓：顶点写成相对质心的偏移
+    let borders = order
+        .iter()
+        .map(|&c| {
+            let mut border = [[BORDER_PAD; 2]; BORDER_CNT];
+            let polygon = simplify_polygon(&mask.contour(first[c]), BORDER_CNT);
+            let (cx, cy) = centroids[c];
+            for (dst, (x, y)) in border.iter_mut().zip(polygon) {
+                let clamp = |v: i32| v.clamp(-i32::from(i16::MAX) + 1, i32::from(i16::MAX) - 1);
+                *dst = [clamp(x - cx) as i16, clamp(y - cy) as i16];
+            }
+            border
+        })
+        .collect();
+
+    // 5. gene + geneExp（按基因；基因内细胞按 id 升序）
+    let mut offsets = Vec::with_capacity(genes.len());
+    let mut total = 0u32;
+    for &k in &gene_cells {
+        offsets.push(total);
+        total += k;
+    }
+    let mut next = offsets.clone();
+    let mut gene_exp = vec![
+        GeneExp {
+            cellID: 0,
+            count: 0
+        };
+        total as usize
+    ];
+    let mut gene_exp_exon = vec![0u16; total as usize];
+    let mut gene_mid = vec![0u64; genes.len()];
+    let mut gene_max = vec![0u16; genes.len()];
+    let mut gene_exon = vec![0u64; genes.len()];
+    for (id, &c) in order.iter().enumerate() {
+        for &(gi, count, exon) in &per_cell[c] {
+            let gi = gi as usize;
+            let k = next[gi] as usize;
+            next[gi] += 1;
+            gene_exp[k] = GeneExp {
+                cellID: id as u32,
+                count: clamped.u16("geneExp.count", count),
+            };
+            gene_exp_exon[k] = clamped.u16("geneExpExon", exon);
+            gene_mid[gi] += count;
+            gene_max[gi] = gene_max[gi].max(gene_exp[k].count);
+            gene_exon[gi] += exon;
+        }
+    }
+    let cell_genes = genes
+        .iter()
+        .enumerate()
+        .map(|(gi, g)| CellGene {
+            geneID: g.geneID,
+            geneName: g.geneName,
+            offset: offsets[gi],
+            cellCount: gene_cells[gi],
+            expCount: clamped.u32("gene.expCount", gene_mid[gi]),
+            maxMIDcount: gene_max[gi],
+        })
+        .collect();
+    let gene_exon = gene_exon.iter().map(|&v| clamped.u32("geneExon", v)).collect();
+    clamped.warn();
+
+    Ok(CellBin {
+        cells,
+        borders,
+        cell_exp,
+        genes: cell_genes,
+        gene_exp,
+        exon: exons.map(|_| CellBinExon {
+            cell_exon,
+            cell_exp_exon,
+            gene_exon,
+            gene_exp_exon,
+        }),
+        block_size,
+        block_index,
+        extent: (
+            info.min_x,
+            info.min_y,
+            info.min_x + info.len_x as i32 - 1,
+            info.min_y + info.len_y as i32 - 1,
+        ),
+        resolution: reader.resolution(1)?,
+    })
+}
+
+/// 一维数据集；非空时按 `chunk` 分块，并加上 `filters`
+fn write_records<T: H5Type>(
+    g: &Group,
+    name: &str,
+    data: &[T],
+    chunk: usize,
+    filters: &[Filter],
+) -> Result<Dataset, Box<dyn Error>> {
+    let builder = g.new_dataset_builder().with_data(data);
+    Ok(if data.is_empty() {
+        builder.create(name)?
+    } else {
+        builder
+            .chunk(DatasetOpts::clamp_chunk(chunk, data.len()))
+            .set_filters(filters)
+            .create(name)?
+    })
+}
+
+/// `/cellBin/cell` 的统计属性：average/median 为 f32，max/min 为 u16
+fn write_cell_attrs(ds: &Dataset, cells: &[CellRec]) -> Result<(), Box<dyn Error>> {
+    type Column = (&'static str, fn(&CellRec) -> u16);
+    let columns: [Column; 4] = [
+        ("Area", |c| c.area),
+        ("DnbCount", |c| c.dnbCount),
+        ("ExpCount", |c| c.expCount),
+        ("GeneCount", |c| c.geneCount),
+    ];
+    for (name, get) in columns {
+        let mut v: Vec<u16> = cells.iter().map(get).collect();
+        v.sort_unstable();
+        let average = if v.is_empty() {
+            0.0
+        } else {
+            v.iter().map(|&x| f64::from(x)).sum::<f64>() / v.len() as f64
+        };
+        let median = match v.len() {
+            0 => 0.0,
+            len if len % 2 == 1 => f64::from(v[len / 2]),
+            len => (f64::from(v[len / 2 - 1]) + f64::from(v[len / 2])) / 2.0,
+        };
+        let avg_name = format!("average{}", name);
+        ds.new_attr::<f32>().create(avg_name.as_str())?.write_scalar(&(average as f32))?;
+        let median_name = format!("median{}", name);
+        ds.new_attr::<f32>().create(median_name.as_str())?.write_scalar(&(median as f32))?;
+        let max_name = format!("max{}", name);
+        let max = v.last().copied().unwrap_or(0);
+        ds.new_attr::<u16>().create(max_name.as_str())?.write_scalar(&max)?;
+        let min_name = format!("min{}", name);
+        let min = v.first().copied().unwrap_or(0);
+        ds.new_attr::<u16>().create(min_name.as_str())?.write_scalar(&min)?;
+    }
+    let xs = || cells.iter().map(|c| c.x);
+    let ys = || cells.iter().map(|c| c.y);
+    ds.new_attr::<i32>().create("minX")?.write_scalar(&xs().min().unwrap_or(0))?;
+    ds.new_attr::<i32>().create("maxX")?.write_scalar(&xs().max().unwrap_or(0))?;
+    ds.new_attr::<i32>().create("minY")?.write_scalar(&ys().min().unwrap_or(0))?;
+    ds.new_attr::<i32>().create("maxY")?.write_scalar(&ys().max().unwrap_or(0))?;
+    Ok(())
+}
+
+/// 写出 cGEF；根属性 sn/omics 沿用输入 bGEF。cell/gene 类数据集按 `opts.gene_chunk` 分块，
+/// cellExp/geneExp 类按 `opts.expr_chunk`
+pub fn write_cgef(
+    reader: &BgefReader,
+    cell_bin: &CellBin,
+    output: &str,
+    opts: &DatasetOpts,
+) -> Result<(), Box<dyn Error>> {
+    let attrs = reader.attrs();
+    let filters = opts.filters();
+    let f = H5File::create(output)?;
+
+    let (min_x, min_y, max_x, max_y) = cell_bin.extent;
+    let vstr = "CellBin".parse::<VarLenUnicode>()?;
+    f.new_attr::<VarLenUnicode>().create("bin_type")?.write_scalar(&vstr)?;
+    f.new_attr::<[u32; 3]>().create("geftool_ver")?.write_scalar(&[1, 1, 20])?;
+    f.new_attr::<i32>().create("maxX")?.write_scalar(&max_x)?;
+    f.new_attr::<i32>().create("maxY")?.write_scalar(&max_y)?;
+    // cGEF 的 offsetX/offsetY 为表达区域左上角，细胞坐标相对于它（与 geftools 一致）
+    f.new_attr::<i32>().create("offsetX")?.write_scalar(&min_x)?;
+    f.new_attr::<i32>().create("offsetY")?.write_scalar(&min_y)?;
+    let vstr = attrs.omics.parse::<VarLenUnicode>()?;
+    f.new_attr::<VarLenUnicode>().create("omics")?.write_scalar(&vstr)?;
+    f.new_attr::<u32>().create("resolution")?.write_scalar(&cell_bin.resolution)?;
+    let vstr = attrs.stereo_seq_chip.parse::<VarLenUnicode>()?;
+    f.new_attr::<VarLenUnicode>().create("sn")?.write_scalar(&vstr)?;
+    f.new_attr::<u32>().create("version")?.write_scalar(&GEFTOOL_RS_VERSION)?;
+
+    let g = f.create_group("cellBin")?;
+    let ds_cell = write_records(&g, "cell", &cell_bin.cells, opts.gene_chunk, &filters)?;
+    write_cell_attrs(&ds_cell, &cell_bin.cells)?;
+    let flat: Vec<i16> = cell_bin.borders.iter().flatten().flatten().copied().collect();
+    let borders = Array3::from_shape_vec((cell_bin.borders.len(), BORDER_CNT, 2), flat)?;
+    g.new_dataset_builder().with_data(&borders).create("cellBorder")?;
+    write_records(&g, "cellExp", &cell_bin.cell_exp, opts.expr_chunk, &filters)?;
+    write_records(&g, "gene", &cell_bin.genes, opts.gene_chunk, &filters)?;
+    write_records(&g, "geneExp", &cell_bin.gene_exp, opts.expr_chunk, &filters)?;
+    if let Some(exon) = &cell_bin.exon {
+        write_records(&g, "cellExon", &exon.cell_exon, opts.gene_chunk, &filters)?;
+        write_records(
+            &g,
+            "cellExpExon",
+            &exon.cell_exp_exon,
+            opts.expr_chunk,
+            &filters,
+        )?;
+        write_records(&g, "geneExon", &exon.gene_exon, opts.gene_chunk, &filters)?;
+        write_records(
+            &g,
+            "geneExpExon",
+            &exon.gene_exp_exon,
+            opts.expr_chunk,
+            &filters,
+        )?;
+    }
+    g.new_dataset_builder().with_data(&cell_bin.block_size).create("blockSize")?;
+    g.new_dataset_builder().with_data(&cell_bin.block_index).create("blockIndex")?;
+    let types = [FixedAscii::<32>::from_ascii("default")?];
+    g.new_dataset_builder().with_data(&types).create("cellTypeList")?;
+    Ok(())
+}
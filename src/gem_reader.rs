@@ -1,12 +1,17 @@
 use anyhow::*;
 use flate2::read::GzDecoder;
 use std::cmp::{max, min};
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{hash_map::Entry, BTreeMap, HashMap};
 use std::fs::File;
 use std::io::{BufRead, BufReader, Read};
 
+use crate::{
+    gene_code::{GeneMap, GeneResolver},
+    roi::Roi,
+};
+
 /// 处理gz文件
-fn open_text(path: &str) -> Result<Box<dyn Read>> {
+pub fn open_text(path: &str) -> Result<Box<dyn Read>> {
     let f = File::open(path).with_context(|| format!("open {}", path))?;
     if path.ends_with(".gz") {
         Ok(Box::new(GzDecoder::new(f)))
@@ -15,6 +20,165 @@ fn open_text(path: &str) -> Result<Box<dyn Read>> {
     }
 }
 
+/// (geneID, geneName) -> (x, y) -> (MIDCount, ExonCount)
+///
+/// GEM 只有一列基因标识且没有基因对照表时 geneName 与 geneID 相同
+pub type GeneBins = BTreeMap<(String, String), HashMap<(i32, i32), (u32, u32)>>;
+
+/// 同一 (geneID, geneName, x, y) 出现多行时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum DupPolicy {
+    /// MID 与 exon 分别相加
+    #[default]
+    Sum,
+    /// 保留文件中最先出现的一行
+    KeepFirst,
+    /// 保留 MID 最大的一行（相同时保留先出现的）
+    KeepMax,
+    /// 报错退出
+    Error,
+}
+
+impl DupPolicy {
+    /// 命令行与 bGEF 属性中的写法
+    pub fn name(self) -> &'static str {
+        match self {
+            DupPolicy::Sum => "sum",
+            DupPolicy::KeepFirst => "keep-first",
+            DupPolicy::KeepMax => "keep-max",
+            DupPolicy::Error => "error",
+        }
+    }
+
+    /// 把后出现的重复行 `new` 并入已有的 `cur`（均为 (MID, exon)）
+    pub fn merge(self, cur: &mut (u32, u32), new: (u32, u32)) -> Result<()> {
+        match self {
+            DupPolicy::Sum => {
+                let mid = cur.0.checked_add(new.0);
+                let exon = cur.1.checked_add(new.1);
+                *cur = mid.zip(exon).ok_or_else(|| {
+                    anyhow!(
+                        "重复行相加溢出 uint32：MIDCount {} + {}，ExonCount {} + {}",
+                        cur.0,
+                        new.0,
+                        cur.1,
+                        new.1
+                    )
+                })?;
+            }
+            DupPolicy::KeepFirst => {}
+            DupPolicy::KeepMax => {
+                if new.0 > cur.0 {
+                    *cur = new;
+                }
+            }
+            DupPolicy::Error => bail!("重复的 (gene, x, y) 记录（--duplicates error）"),
+        }
+        Ok(())
+    }
+}
+
+/// GEM 表头各列的位置（0-based），由列名确定
+#[derive(Debug, Clone)]
+pub struct GemColumns {
+    pub gene_id: usize,
+    pub gene_name: Option<usize>,
+    pub x: usize,
+    pub y: usize,
+    pub mid: usize,
+    pub exon: Option<usize>,
+}
+
+/// 按列名取出的一行字段
+pub struct GemRow<'a> {
+    pub gene_id: Option<&'a str>,
+    pub gene_name: Option<&'a str>,
+    pub x: Option<&'a str>,
+    pub y: Option<&'a str>,
+    pub mid: Option<&'a str>,
+    pub exon: Option<&'a str>,
+}
+
+impl GemColumns {
+    const GENE_ID: &'static [&'static str] = &["geneID", "geneId", "gene_id", "gene"];
+    const GENE_NAME: &'static [&'static str] = &["geneName", "gene_name"];
+    const X: &'static [&'static str] = &["x"];
+    const Y: &'static [&'static str] = &["y"];
+    const MID: &'static [&'static str] = &[
+        "MIDCount",
+        "MIDCounts",
+        "MIDcount",
+        "UMICount",
+        "UMICounts",
+        "count",
+    ];
+    const EXON: &'static [&'static str] = &["ExonCount", "exonCount", "ExonCounts"];
+
+    /// 由表头行建立列映射；缺少必需列时报错并列出全部缺失列
+    pub fn from_header(header: &str) -> Result<Self> {
+        let names: Vec<&str> =
+            header.trim_end_matches(['\r', '\n']).split('\t').map(str::trim).collect();
+        let find = |aliases: &[&str]| names.iter().position(|n| aliases.contains(n));
+
+        // 只有 geneName 列时用它充当 geneID
+        let gene_name = find(Self::GENE_NAME);
+        let gene_id = find(Self::GENE_ID).or(gene_name);
+        let gene_name = gene_name.filter(|&c| Some(c) != gene_id);
+        let x = find(Self::X);
+        let y = find(Self::Y);
+        let mid = find(Self::MID);
+        let missing: Vec<&str> = [("geneID", gene_id), ("x", x), ("y", y), ("MIDCount", mid)]
+            .iter()
+            .filter(|(_, pos)| pos.is_none())
+            .map(|(name, _)| *name)
+            .collect();
+        if !missing.is_empty() {
+            bail!(
+                "GEM 表头缺少必需列: {}（表头: {}）",
+                missing.join(", "),
+                names.join(" | ")
+            );
+        }
+
+        Ok(Self {
+            gene_id: gene_id.unwrap(),
+            gene_name,
+            x: x.unwrap(),
+            y: y.unwrap(),
+            mid: mid.unwrap(),
+            exon: find(Self::EXON),
+        })
+    }
+
+    /// 按列映射拆分一行（调用方需先去掉行尾换行）
+    pub fn pick<'a>(&self, row: &'a str) -> GemRow<'a> {
+        let mut r = GemRow {
+            gene_id: None,
+            gene_name: None,
+            x: None,
+            y: None,
+            mid: None,
+            exon: None,
+        };
+        for (ci, field) in row.split('\t').enumerate() {
+            if ci == self.gene_id {
+                r.gene_id = Some(field);
+            } else if Some(ci) == self.gene_name {
+                r.gene_name = Some(field);
+            } else if ci == self.x {
+                r.x = Some(field);
+            } else if ci == self.y {
+                r.y = Some(field);
+            } else if ci == self.mid {
+                r.mid = Some(field);
+            } else if Some(ci) == self.exon {
+                r.exon = Some(field);
+            }
+        }
+        r
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Header {
     pub bin_type: String,         // #BinType
@@ -23,7 +187,9 @@ pub struct Header {
     pub stereo_seq_chip: String,  // #Stereo-seqChip
     pub offset_x: i32,            // #OffsetX
     pub offset_y: i32,            // #OffsetY
-    pub has_exon: bool,           // gene 表头列数==5 则有 ExonCount
+    pub has_exon: bool,           // 表头含 ExonCount 列
+    pub has_gene_name: bool,      // 表头含 geneName 列（SAW 8 六列格式）
+    pub columns: GemColumns,      // 表头列名 -> 列位置
     pub header_line_index: usize, // gene 表头所在的行号（0-based）
 }
 
@@ -37,7 +203,9 @@ pub struct Header {
 ///     stereo_seq_chip: sn,
 ///     offset_x: ox,
 ///     offset_y: oy,
-///     has_exon: cols == 5,
+///     has_exon: 表头含 ExonCount,
+///     has_gene_name: 表头含 geneName,
+///     columns: 列映射,
 ///     header_line_index: idx,
 pub fn parse_header(path: &str) -> Result<Header> {
     // 读取函数实例加载
@@ -73,14 +241,15 @@ pub fn parse_header(path: &str) -> Result<Header> {
             ox = _rest.trim().parse()?;
         } else if let Some(_rest) = line.strip_prefix("#OffsetY=") {
             oy = _rest.trim().parse()?;
-        } else if line.starts_with("geneID") {
+        } else if !line.starts_with('#') && !line.trim().is_empty() {
+            // 第一条非注释行即为列名表头
             header_line = Some((i, line.clone()));
             break;
         }
         i += 1;
     }
     let (idx, header) = header_line.ok_or_else(|| anyhow!("未找到表头 geneID"))?;
-    let cols = header.matches('\t').count() + 1;
+    let columns = GemColumns::from_header(&header)?;
     Ok(Header {
         bin_type,
         bin_size,
@@ -88,40 +257,71 @@ pub fn parse_header(path: &str) -> Result<Header> {
         stereo_seq_chip: sn,
         offset_x: ox,
         offset_y: oy,
-        has_exon: cols == 5,
+        has_exon: columns.exon.is_some(),
+        has_gene_name: columns.gene_name.is_some(),
+        columns,
         header_line_index: idx,
     })
 }
 
-/// 遍历gem所有表达量行
-pub fn get_expression(
-    path: &str,
-    header_line_index: usize,
-    has_exon: bool,
-) -> Result<(
-    BTreeMap<String, HashMap<(i32, i32), (u32, u32)>>,
-    i32,
-    i32,
-    i32,
-    i32,
-    u32,
-    u32,
-)> {
+/// binN 序号坐标 -> bin1 坐标，再加上平移量；溢出时返回 None
+pub fn to_bin1(v: i32, bin_size: u32, offset: i32) -> Option<i32> {
+    v.checked_mul(i32::try_from(bin_size).ok()?)?.checked_add(offset)
+}
+
+/// 数值字段：缺失或解析失败时报出列名与原值
+fn parse_field<T: std::str::FromStr>(field: Option<&str>, name: &str) -> Result<T> {
+    let s = field.ok_or_else(|| anyhow!("缺少 {} 字段", name))?;
+    s.parse().map_err(|_| anyhow!("{}={:?} 不是合法的数值", name, s))
+}
+
+/// 一条表达记录：(geneID, geneName, x, y, MIDCount, ExonCount)
+type Record<'a> = (&'a str, &'a str, i32, i32, u32, u32);
+
+/// 解析一行正文；geneID 为空的行返回 None（跳过）
+fn parse_row<'a>(hdr: &Header, offset: (i32, i32), line: &'a str) -> Result<Option<Record<'a>>> {
+    let row = hdr.columns.pick(line);
+    let gene = match row.gene_id {
+        Some(s) if !s.is_empty() => s,
+        _ => return Ok(None),
+    };
+    let gene_name = if hdr.has_gene_name {
+        row.gene_name.ok_or_else(|| anyhow!("缺少 geneName 字段"))?
+    } else {
+        gene
+    };
+    let x: i32 = parse_field(row.x, "x")?;
+    let y: i32 = parse_field(row.y, "y")?;
+    // 已分箱的 GEM（#BinSize=N）坐标为 bin 序号，先换算回 bin1 坐标再平移
+    let x = to_bin1(x, hdr.bin_size, offset.0)
+        .ok_or_else(|| anyhow!("x={} 换算为 bin1 坐标并平移 {} 后溢出", x, offset.0))?;
+    let y = to_bin1(y, hdr.bin_size, offset.1)
+        .ok_or_else(|| anyhow!("y={} 换算为 bin1 坐标并平移 {} 后溢出", y, offset.1))?;
+    let mid: u32 = parse_field(row.mid, "MIDCount")?;
+    let exon: u32 = if hdr.has_exon {
+        parse_field(row.exon, "ExonCount")?
+    } else {
+        0
+    };
+    Ok(Some((gene, gene_name, x, y, mid, exon)))
+}
+
+/// 逐行解析 gem 表达量，对每条记录调用 `f(geneID, geneName, x, y, MIDCount, ExonCount)`。
+///
+/// 传给 `f` 的坐标一律为 bin1 坐标：`#BinSize=N` 的 GEM 坐标会乘以 N；
+/// `offset` 会加到每一行的 (x, y) 上（例如 `#OffsetX/#OffsetY`），不需要平移时传 `(0, 0)`。
+/// 没有 ExonCount 列时传 0。出错时报出文件名与行号（1-based）。返回读取的总行数。
+pub fn for_each_record<F>(path: &str, hdr: &Header, offset: (i32, i32), mut f: F) -> Result<usize>
+where
+    F: FnMut(&str, &str, i32, i32, u32, u32) -> Result<()>,
+{
     // 读取函数实例加载
     let rdr = open_text(path)?;
     // 读取函数实例加载进缓冲区
     let mut br = BufReader::new(rdr);
     // 计数器
     let mut i = 0usize;
-    // 缓存变量
-    //
-    let mut gene_bins: BTreeMap<String, HashMap<(i32, i32), (u32, u32)>> = BTreeMap::new();
-    let mut min_x = i32::MAX;
-    let mut min_y = i32::MAX;
-    let mut max_x = i32::MIN;
-    let mut max_y = i32::MIN;
-    let mut max_exp = u32::MIN;
-    let mut max_exon = u32::MIN;
+    let header_line_index = hdr.header_line_index;
 
     // 逐行读取正文
     let mut line = String::new();
@@ -134,19 +334,50 @@ pub fn get_expression(
         i += 1;
         if i <= header_line_index + 1 {
             continue;
-        } else if i % 10000000 == 0 {
+        } else if i.is_multiple_of(10000000) {
             println!("Processed {:>10} lines...", i);
         }
-        // 注意去掉行尾 \r\n
-        let mut it = line.trim_end_matches(&['\r', '\n'][..]).split('\t');
-        // 依次读取：geneID  x  y  MIDCount [exon]
-        let gene = match it.next() {
-            Some(s) if !s.is_empty() => s,
-            _ => continue,
+        // 注意去掉行尾 \r\n，按表头列映射取字段
+        let Some((gene, gene_name, x, y, mid, exon)) =
+            parse_row(hdr, offset, line.trim_end_matches(&['\r', '\n'][..]))
+                .with_context(|| format!("{} 第 {} 行", path, i))?
+        else {
+            continue;
         };
-        let x: i32 = it.next().ok_or_else(|| anyhow::anyhow!("missing x"))?.parse()?;
-        let y: i32 = it.next().ok_or_else(|| anyhow::anyhow!("missing y"))?.parse()?;
-        let mid: u32 = it.next().ok_or_else(|| anyhow::anyhow!("missing MIDCount"))?.parse()?;
+        f(gene, gene_name, x, y, mid, exon).with_context(|| format!("{} 第 {} 行", path, i))?;
+    }
+    println!("Processed all lines: {:>10} ", i);
+    Ok(i)
+}
+
+/// 遍历gem所有表达量行；同一基因同一坐标的重复行按 `policy` 处理，最后一项为重复行数。
+/// 提供 `gene_map` 时先把基因标识解析为 (geneID, geneName)，解析为同一基因的标识归并到一起。
+/// 给出 `roi` 时只保留落在其中的记录（`roi` 需与平移后的坐标一致），范围与最大值只统计保留的记录
+#[allow(clippy::type_complexity)]
+pub fn get_expression(
+    path: &str,
+    hdr: &Header,
+    offset: (i32, i32),
+    gene_map: Option<&GeneMap>,
+    policy: DupPolicy,
+    roi: Option<&Roi>,
+) -> Result<(GeneBins, i32, i32, i32, i32, u32, u32, u64)> {
+    // 缓存变量
+    let mut gene_bins: GeneBins = BTreeMap::new();
+    let mut min_x = i32::MAX;
+    let mut min_y = i32::MAX;
+    let mut max_x = i32::MIN;
+    let mut max_y = i32::MIN;
+    let mut max_exp = u32::MIN;
+    let mut max_exon = u32::MIN;
+    let mut duplicates = 0u64;
+    let mut resolver = GeneResolver::new(gene_map);
+
+    for_each_record(path, hdr, offset, |gene, gene_name, x, y, mid, exon| {
+        if roi.is_some_and(|r| !r.contains(x, y)) {
+            return Ok(());
+        }
+        let (gene, gene_name) = resolver.resolve(gene, gene_name);
         // 更新最小和最大坐标范围
         min_x = min(min_x, x);
         min_y = min(min_y, y);
@@ -154,74 +385,89 @@ pub fn get_expression(
         max_y = max(max_y, y);
         // gene_bins.entry(k)：进入最外层 BTreeMap 的“入口”，只查一次键 k
         // .or_default()：如果这个基因不存在，就插入默认值（HashMap::default()）；存在就直接返回那个值的可变引用
-        let inner = gene_bins.entry(gene.to_string()).or_default();
-        // 索引特定bin上的gene表达量
-        let vals = inner.entry((x, y)).or_insert((0, 0));
-        vals.0 += mid; // 合并本行 MID 到该格
+        let inner = gene_bins.entry((gene.to_string(), gene_name.to_string())).or_default();
+        // 索引特定bin上的gene表达量；已存在即为重复行，按策略合并
+        let vals = match inner.entry((x, y)) {
+            Entry::Vacant(e) => e.insert((mid, exon)),
+            Entry::Occupied(e) => {
+                duplicates += 1;
+                let vals = e.into_mut();
+                policy
+                    .merge(vals, (mid, exon))
+                    .with_context(|| format!("gene={} x={} y={}", gene, x, y))?;
+                vals
+            }
+        };
         max_exp = max(max_exp, vals.0); // 更新合并后的最大值
-        if has_exon {
-            // 有exon的情况下新建
-            let exon: u32 = it.next().ok_or_else(|| anyhow::anyhow!("missing Exon"))?.parse()?;
-            vals.1 += exon; // 合并本行 exon 到该格
-            max_exon = max(max_exon, vals.1); // 更新合并后的最大值
-        }
-    }
-    println!("Processed all lines: {:>10} ", i);
-    Ok((gene_bins, min_x, max_x, min_y, max_y, max_exp, max_exon))
+        max_exon = max(max_exon, vals.1); // 没有 ExonCount 列时 exon 恒为 0
+        Ok(())
+    })?;
+    Ok((
+        gene_bins, min_x, max_x, min_y, max_y, max_exp, max_exon, duplicates,
+    ))
 }
 
-/// 将 HashMap 坐标数据截取在指定范围 (min_x, max_x) × (min_y, max_y) 内，
-/// 并转换为局部矩阵坐标（从 0 开始）。
-///
-/// # Params
-///
-/// - `spot_map`: `HashMap<(i32, i32), T>`  
-///   原始坐标与值的映射，键为 (x, y)，必须为 `i32` 类型；  
-///
-/// - `min_x`, `min_y`: `i32`  
-///   截取矩形区域的左上角坐标；  
-///
-/// - `max_x`, `max_y`: `i32`  
-///   截取矩形区域的右下角坐标；  
-///
-/// - `len_x`, `len_y`: `usize`  
-///   输出矩阵的宽度与高度（可由 `max_x - min_x + 1`、`max_y - min_y + 1` 计算得到）；  
-///
-/// # Returns
-///
-/// - `Result<Vec<Vec<T>>>`  
-///   返回截取并重置坐标后的稠密矩阵，
-///   其中矩阵尺寸为 `[len_y][len_x]`，空缺位置以 `T::default()` 填充。
-pub fn map2mat<T>(
-    spot_map: &HashMap<(i32, i32), T>,
-    min_x: i32,
-    min_y: i32,
-    max_x: i32,
-    max_y: i32,
-) -> Result<(Vec<T>, usize, usize)>
-where
-    T: Default + Clone,
-{
-    // 计算宽高（注意这里 +1 是为了包含边界）
-    let width = (max_x - min_x + 1) as usize;
-    let height = (max_y - min_y + 1) as usize;
-
-    // 初始化默认值矩阵
-    let mut mat: Vec<Vec<T>> = vec![vec![T::default(); width]; height];
-
-    // 遍历所有点，筛选落在指定范围内的
-    for (&(x, y), value) in spot_map.iter() {
-        if x >= min_x && x <= max_x && y >= min_y && y <= max_y {
-            // 转换为矩阵坐标（从 0 开始）
-            let xi = (x - min_x) as usize;
-            let yi = (y - min_y) as usize;
-            mat[yi][xi] = value.clone();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn columns_accept_aliases() {
+        for (header, mid) in [
+            ("geneID\tx\ty\tMIDCount", 3),
+            ("geneID\tx\ty\tMIDCounts", 3),
+            ("geneID\tx\ty\tUMICount", 3),
+            ("gene\tx\ty\tMIDcount\tExonCount", 3),
+        ] {
+            let c = GemColumns::from_header(header).unwrap();
+            assert_eq!((c.gene_id, c.x, c.y, c.mid), (0, 1, 2, mid), "{}", header);
+            assert_eq!(c.gene_name, None, "{}", header);
         }
+        let c = GemColumns::from_header("geneID\tx\ty\tMIDCount\tExonCount\r\n").unwrap();
+        assert_eq!(c.exon, Some(4));
     }
 
-    // 将二维矩阵展开为一维向量
-    let vector = mat.into_iter().flat_map(|row| row).collect();
+    #[test]
+    fn columns_follow_header_order() {
+        // 列顺序打乱，并夹有不认识的列（CellID）
+        let c =
+            GemColumns::from_header("x\tCellID\ty\tgeneName\tUMICount\tgeneID\tExonCount").unwrap();
+        assert_eq!(
+            (c.gene_id, c.gene_name, c.x, c.y, c.mid, c.exon),
+            (5, Some(3), 0, 2, 4, Some(6))
+        );
+        let r = c.pick("10\tcell_7\t20\tGad1\t3\tENSMUSG01\t1");
+        assert_eq!(
+            (r.gene_id, r.gene_name, r.x, r.y, r.mid, r.exon),
+            (
+                Some("ENSMUSG01"),
+                Some("Gad1"),
+                Some("10"),
+                Some("20"),
+                Some("3"),
+                Some("1")
+            )
+        );
 
-    Ok((vector, width, height))
-}
+        // 行字段不足时缺失的列为 None
+        let r = c.pick("10\tcell_7\t20");
+        assert_eq!((r.x, r.y, r.gene_id), (Some("10"), Some("20"), None));
+    }
 
+    #[test]
+    fn gene_name_only_serves_as_gene_id() {
+        let c = GemColumns::from_header("geneName\tx\ty\tMIDCount").unwrap();
+        assert_eq!((c.gene_id, c.gene_name), (0, None));
+    }
+
+    #[test]
+    fn missing_columns_are_all_listed() {
+        let err = GemColumns::from_header("geneID\tCellID\tMIDCount").unwrap_err().to_string();
+        assert!(err.contains("x, y"), "{}", err);
+        assert!(!err.contains("geneID,"), "{}", err);
+
+        let err = GemColumns::from_header("x\ty").unwrap_err().to_string();
+        assert!(err.contains("geneID, MIDCount"), "{}", err);
+        assert!(err.contains("x | y"), "{}", err);
+    }
+}
@@ -0,0 +1,267 @@
+//! GEM 校验：逐行扫描整个文件，报出每个问题所在的行号（1-based），最后输出一行 JSON 汇总。
+//!
+//! 检查项：无法识别的表头键与非法的表头值、缺少必需列、列数与表头不一致、缺少字段、
+//! 非数值、负坐标或超出芯片范围的坐标、空的 geneID/geneName。
+//! 坐标检查使用芯片坐标，即 `x * BinSize + OffsetX`（y 同理）。
+
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader};
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::gem_reader::{open_text, to_bin1, GemColumns};
+
+/// 能识别的 `#Key=Value` 表头键；FileFormat/SortedBy 由 SAW 写出，转换时忽略
+const HEADER_KEYS: &[&str] = &[
+    "BinType",
+    "BinSize",
+    "Omics",
+    "Stereo-seqChip",
+    "OffsetX",
+    "OffsetY",
+    "FileFormat",
+    "SortedBy",
+];
+
+/// 一个问题
+#[derive(Debug, Clone)]
+pub struct Problem {
+    pub line: usize,
+    pub kind: &'static str,
+    pub message: String,
+}
+
+/// 校验汇总（以 JSON 输出）
+#[derive(Debug, Clone, Serialize)]
+pub struct ValidateSummary {
+    pub file: String,
+    /// 读取的总行数
+    pub lines: usize,
+    /// 正文记录行数（不含表头与空行）
+    pub records: usize,
+    pub problems: usize,
+    /// 至少有一个问题的行数
+    pub problem_lines: usize,
+    /// 问题类型 -> 个数
+    pub by_kind: BTreeMap<&'static str, usize>,
+    /// 问题类型 -> 第一次出现的行号
+    pub first_line: BTreeMap<&'static str, usize>,
+    pub valid: bool,
+}
+
+/// 逐行校验 `path`，每发现一个问题调用一次 `report`
+pub fn validate_gem<F>(
+    path: &str,
+    chip_size: Option<(i32, i32)>,
+    mut report: F,
+) -> Result<ValidateSummary>
+where
+    F: FnMut(&Problem),
+{
+    let mut br = BufReader::new(open_text(path)?);
+    let mut summary = ValidateSummary {
+        file: path.to_string(),
+        lines: 0,
+        records: 0,
+        problems: 0,
+        problem_lines: 0,
+        by_kind: BTreeMap::new(),
+        first_line: BTreeMap::new(),
+        valid: true,
+    };
+    let mut last_problem_line = 0;
+    let mut problem =
+        |summary: &mut ValidateSummary, line: usize, kind: &'static str, message: String| {
+            summary.problems += 1;
+            *summary.by_kind.entry(kind).or_default() += 1;
+            summary.first_line.entry(kind).or_insert(line);
+            if last_problem_line != line {
+                summary.problem_lines += 1;
+                last_problem_line = line;
+            }
+            report(&Problem {
+                line,
+                kind,
+                message,
+            });
+        };
+
+    let (max_x, max_y) = chip_size.unzip();
+    let (mut bin_size, mut offset) = (1u32, (0i32, 0i32));
+    let mut columns: Option<(GemColumns, usize)> = None;
+    let mut header_seen = false;
+    let mut buf = String::new();
+    loop {
+        buf.clear();
+        if br.read_line(&mut buf)? == 0 {
+            break;
+        }
+        summary.lines += 1;
+        let n = summary.lines;
+        let line = buf.trim_end_matches(['\r', '\n']);
+
+        if !header_seen {
+            if let Some(comment) = line.strip_prefix('#') {
+                let Some((key, value)) = comment.split_once('=') else {
+                    problem(
+                        &mut summary,
+                        n,
+                        "unknown_header_key",
+                        format!("无法识别的表头行 {:?}", line),
+                    );
+                    continue;
+                };
+                let value = value.trim();
+                let bad = match key {
+                    "BinSize" => match value.parse::<u32>() {
+                        Ok(v) if v > 0 => {
+                            bin_size = v;
+                            false
+                        }
+                        _ => true,
+                    },
+                    "OffsetX" => value.parse().map(|v| offset.0 = v).is_err(),
+                    "OffsetY" => value.parse().map(|v| offset.1 = v).is_err(),
+                    k if HEADER_KEYS.contains(&k) => false,
+                    _ => {
+                        problem(
+                            &mut summary,
+                            n,
+                            "unknown_header_key",
+                            format!("无法识别的表头键 #{}", key),
+                        );
+                        false
+                    }
+                };
+                if bad {
+                    problem(
+                        &mut summary,
+                        n,
+                        "bad_header_value",
+                        format!("#{}={:?} 不是合法的值", key, value),
+                    );
+                }
+                continue;
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+            // 第一条非注释行即为列名表头
+            header_seen = true;
+            match GemColumns::from_header(line) {
+                Ok(cols) => columns = Some((cols, line.split('\t').count())),
+                Err(e) => problem(&mut summary, n, "missing_columns", e.to_string()),
+            }
+            continue;
+        }
+        // 表头不可用时只统计行数
+        let Some((cols, n_cols)) = &columns else {
+            continue;
+        };
+        if line.is_empty() {
+            continue;
+        }
+        summary.records += 1;
+
+        let fields = line.split('\t').count();
+        if fields != *n_cols {
+            problem(
+                &mut summary,
+                n,
+                "column_count",
+                format!("有 {} 列，表头有 {} 列", fields, n_cols),
+            );
+        }
+        let mut required = vec![
+            ("geneID", cols.gene_id),
+            ("x", cols.x),
+            ("y", cols.y),
+            ("MIDCount", cols.mid),
+        ];
+        required.extend(cols.gene_name.map(|c| ("geneName", c)));
+        required.extend(cols.exon.map(|c| ("ExonCount", c)));
+        for (name, c) in required {
+            if c >= fields {
+                problem(
+                    &mut summary,
+                    n,
+                    "missing_field",
+                    format!("缺少 {} 字段（第 {} 列）", name, c + 1),
+                );
+            }
+        }
+        let row = cols.pick(line);
+        for (name, field) in [("geneID", row.gene_id), ("geneName", row.gene_name)] {
+            if field.is_some_and(|s| s.trim().is_empty()) {
+                problem(&mut summary, n, "blank_gene_id", format!("{} 为空", name));
+            }
+        }
+        for (name, field) in [("MIDCount", row.mid), ("ExonCount", row.exon)] {
+            if let Some(s) = field {
+                if s.parse::<u32>().is_err() {
+                    problem(
+                        &mut summary,
+                        n,
+                        "non_numeric",
+                        format!("{}={:?} 不是非负整数", name, s),
+                    );
+                }
+            }
+        }
+        for (name, field, off, limit) in
+            [("x", row.x, offset.0, max_x), ("y", row.y, offset.1, max_y)]
+        {
+            let Some(s) = field else {
+                continue;
+            };
+            let Ok(v) = s.parse::<i32>() else {
+                problem(
+                    &mut summary,
+                    n,
+                    "non_numeric",
+                    format!("{}={:?} 不是整数", name, s),
+                );
+                continue;
+            };
+            match to_bin1(v, bin_size, off) {
+                None => problem(
+                    &mut summary,
+                    n,
+                    "coordinate_overflow",
+                    format!("{}={} 换算为芯片坐标后溢出", name, v),
+                ),
+                Some(c) if c < 0 => problem(
+                    &mut summary,
+                    n,
+                    "negative_coordinate",
+                    format!("{}={} 的芯片坐标 {} 为负", name, v, c),
+                ),
+                Some(c) if limit.is_some_and(|l| c >= l) => problem(
+                    &mut summary,
+                    n,
+                    "out_of_chip",
+                    format!(
+                        "{}={} 的芯片坐标 {} 超出芯片范围 [0, {})",
+                        name,
+                        v,
+                        c,
+                        limit.unwrap_or(0)
+                    ),
+                ),
+                Some(_) => {}
+            }
+        }
+    }
+    if !header_seen {
+        let n = summary.lines;
+        problem(
+            &mut summary,
+            n,
+            "missing_columns",
+            "未找到表头 geneID".to_string(),
+        );
+    }
+    summary.valid = summary.problems == 0;
+    Ok(summary)
+}
@@ -0,0 +1,125 @@
+//! bGEF -> GEM 导出。
+//!
+//! 按 `/geneExp/binN/gene` 的 offset/count 逐基因读取 expression（与 exon），写成带
+//! `#BinType/#BinSize/#Omics/#Stereo-seqChip/#OffsetX/#OffsetY` 表头的 GEM：
+//! - binN（N > 1）按 `#BinSize=N` 写 bin 序号坐标，再次转换时由 `#BinSize` 还原为 bin1 坐标；
+//! - 表头与坐标满足 `x * BinSize + #OffsetX` = 芯片坐标（bGEF 坐标减去已应用的 offsetX，再加上源 GEM
+//!   的 #OffsetX，即根属性 gem_offsetX）：#OffsetX 为源 GEM 的值减去已应用平移除以 N 的余数，
+//!   bin1 或平移是 N 的倍数时就是源值，因此无论转换时是否 `--apply-offset`，用同样的参数再次转换
+//!   都能得到相同的坐标；
+//! - 有基因的 geneName 与 geneID 不同时写 SAW 8 六列格式，否则只写 geneID 一列；
+//! - 可按 GeoJSON 多边形 ROI 只导出其中的 spot。
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use anyhow::{anyhow, Context, Result};
+use flate2::{write::GzEncoder, Compression};
+
+use crate::{bgef_reader::BgefReader, roi::Roi};
+
+/// 输出文件以 .gz 结尾时 gzip 压缩
+fn create_text(path: &str) -> Result<Box<dyn Write>> {
+    let f = File::create(path).with_context(|| format!("create {}", path))?;
+    if path.ends_with(".gz") {
+        Ok(Box::new(BufWriter::new(GzEncoder::new(
+            f,
+            Compression::default(),
+        ))))
+    } else {
+        Ok(Box::new(BufWriter::new(f)))
+    }
+}
+
+/// 把 bGEF 的 `/geneExp/bin{bin_size}` 写成 GEM，返回写出的记录数；
+/// 给出 `roi` 时只写出坐标（bGEF 中的坐标，即 spot 左上角）落在其中的记录
+pub fn write_gem(
+    reader: &BgefReader,
+    bin_size: u32,
+    output: &str,
+    roi: Option<&Roi>,
+) -> Result<usize> {
+    let attrs = reader.attrs();
+    let genes = reader.genes(bin_size)?;
+    let has_exon = reader.has_exon(bin_size);
+    let has_gene_name = genes.iter().any(|g| g.geneID.as_str() != g.geneName.as_str());
+    let step = i64::from(bin_size);
+    // 已应用的平移按 N 拆成整 bin 部分（从坐标中减去）与余数（并入表头）
+    let shift = |applied: i32, gem: i32| -> Result<(i64, i32)> {
+        let (applied, gem) = (i64::from(applied), i64::from(gem));
+        let header = i32::try_from(gem - applied.rem_euclid(step))
+            .map_err(|_| anyhow!("bin{} 的表头平移超出 int32", bin_size))?;
+        Ok((applied.div_euclid(step), header))
+    };
+    let (bins_x, header_x) = shift(attrs.offset_x, attrs.gem_offset_x)?;
+    let (bins_y, header_y) = shift(attrs.offset_y, attrs.gem_offset_y)?;
+
+    let mut w = create_text(output)?;
+    writeln!(w, "#FileFormat=GEMv0.1")?;
+    writeln!(w, "#SortedBy=None")?;
+    writeln!(
+        w,
+        "#BinType={}",
+        if attrs.bin_type.is_empty() {
+            "Bin"
+        } else {
+            &attrs.bin_type
+        }
+    )?;
+    writeln!(w, "#BinSize={}", bin_size)?;
+    writeln!(w, "#Omics={}", attrs.omics)?;
+    writeln!(w, "#Stereo-seqChip={}", attrs.stereo_seq_chip)?;
+    writeln!(w, "#OffsetX={}", header_x)?;
+    writeln!(w, "#OffsetY={}", header_y)?;
+    let mut columns = vec!["geneID"];
+    if has_gene_name {
+        columns.push("geneName");
+    }
+    columns.extend(["x", "y", "MIDCount"]);
+    if has_exon {
+        columns.push("ExonCount");
+    }
+    writeln!(w, "{}", columns.join("\t"))?;
+
+    let mut rows = 0usize;
+    for g in &genes {
+        let ge = reader.gene_slice(bin_size, g)?;
+        let (gene_id, gene_name) = (g.geneID.as_str(), g.geneName.as_str());
+        for (i, e) in ge.expressions.iter().enumerate() {
+            if roi.is_some_and(|r| !r.contains(e.x, e.y)) {
+                continue;
+            }
+            // 换算为 binN 序号，再去掉已应用平移中的整 bin 部分
+            let (x, y) = (i64::from(e.x), i64::from(e.y));
+            if x % step != 0 || y % step != 0 {
+                return Err(anyhow!(
+                    "bin{} 中基因 {} 的坐标 ({}, {}) 不是 {} 的整数倍",
+                    bin_size,
+                    gene_id,
+                    e.x,
+                    e.y,
+                    bin_size
+                ));
+            }
+            if has_gene_name {
+                write!(w, "{}\t{}\t", gene_id, gene_name)?;
+            } else {
+                write!(w, "{}\t", gene_id)?;
+            }
+            write!(
+                w,
+                "{}\t{}\t{}",
+                x / step - bins_x,
+                y / step - bins_y,
+                e.count
+            )?;
+            match &ge.exons {
+                Some(exons) => writeln!(w, "\t{}", exons[i])?,
+                None => writeln!(w)?,
+            }
+            rows += 1;
+        }
+    }
+    w.flush()?;
+    Ok(rows)
+}
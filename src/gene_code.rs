@@ -5,102 +5,137 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs::{self, File};
 use std::io::{BufRead, BufReader, Read};
-use std::path::Path;
 
-#[derive(Debug, Serialize, Deserialize)]
-struct GeneMap {
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct GeneMap {
     /// 小写基因名 -> ENSMUSG...
     by_symbol: HashMap<String, String>,
+    /// ENSMUSG... -> 基因名（保留原始大小写）；旧版本生成的表没有该字段
+    #[serde(default)]
+    by_id: HashMap<String, String>,
 }
 
-/// 读取 Ensembl 小鼠 GTF（或 .gtf.gz），抽取 gene 级注释，更新/重建本地 JSON 表。
-/// 返回写入的条目数。
-pub fn update_table_from_gtf(gtf_path: &str, db_json_path: &str) -> Result<usize> {
-    // 允许覆盖写入：不存在则新建，存在则重建（简单粗暴，避免脏并发）
-    let mut by_symbol: HashMap<String, String> = HashMap::new();
-
-    // GTF 第9列 attributes 的简单解析： gene_id "ENSMUSG..."; gene_name "P2ry12";
-    let re_gene_id = Regex::new(r#"gene_id\s+"([^"]+)""#).unwrap();
-    let re_gene_name = Regex::new(r#"gene_name\s+"([^"]+)""#).unwrap();
+impl GeneMap {
+    /// 读取 GTF（或 .gtf.gz）中 feature == "gene" 的行，构建 ID <-> symbol 双向表
+    pub fn from_gtf(gtf_path: &str) -> Result<Self> {
+        let mut map = GeneMap::default();
 
-    let reader: Box<dyn Read> = if gtf_path.ends_with(".gz") {
-        Box::new(GzDecoder::new(
-            File::open(gtf_path).with_context(|| gtf_path.to_string())?,
-        ))
-    } else {
-        Box::new(File::open(gtf_path).with_context(|| gtf_path.to_string())?)
-    };
+        // GTF 第9列 attributes 的简单解析： gene_id "ENSMUSG..."; gene_name "P2ry12";
+        let re_gene_id = Regex::new(r#"gene_id\s+"([^"]+)""#).unwrap();
+        let re_gene_name = Regex::new(r#"gene_name\s+"([^"]+)""#).unwrap();
 
-    let buf = BufReader::new(reader);
-    for line in buf.lines() {
-        let line = line?;
-        if line.is_empty() || line.starts_with('#') {
-            continue;
-        }
-        // GTF: chr, source, feature, start, end, score, strand, frame, attributes
-        // 只看 feature == "gene"
-        let mut it = line.split('\t');
-        let _chr = match it.next() {
-            Some(v) => v,
-            None => continue,
-        };
-        let _src = match it.next() {
-            Some(v) => v,
-            None => continue,
+        let reader: Box<dyn Read> = if gtf_path.ends_with(".gz") {
+            Box::new(GzDecoder::new(
+                File::open(gtf_path).with_context(|| gtf_path.to_string())?,
+            ))
+        } else {
+            Box::new(File::open(gtf_path).with_context(|| gtf_path.to_string())?)
         };
-        let feature = match it.next() {
-            Some(v) => v,
-            None => continue,
-        };
-        if feature != "gene" {
-            continue;
+
+        let buf = BufReader::new(reader);
+        for line in buf.lines() {
+            let line = line?;
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            // GTF: chr, source, feature, start, end, score, strand, frame, attributes
+            // 只看 feature == "gene"
+            let mut it = line.split('\t');
+            let feature = match it.nth(2) {
+                Some(v) => v,
+                None => continue,
+            };
+            if feature != "gene" {
+                continue;
+            }
+            // 跳过 start,end,score,strand,frame
+            let attr = match it.nth(5) {
+                Some(v) => v,
+                None => continue,
+            };
+
+            let gid =
+                re_gene_id.captures(attr).and_then(|c| c.get(1).map(|m| m.as_str().to_string()));
+            let gname =
+                re_gene_name.captures(attr).and_then(|c| c.get(1).map(|m| m.as_str().to_string()));
+
+            let (gid, gname) = match (gid, gname) {
+                (Some(gid), Some(gname)) => (gid, gname),
+                _ => continue,
+            };
+
+            // 基因名大小写不敏感；若重复，保持首次写入（避免把主名被别名覆盖）
+            map.by_symbol.entry(gname.to_lowercase()).or_insert_with(|| gid.clone());
+            map.by_id.entry(gid).or_insert(gname);
         }
-        // 跳过 start,end,score,strand,frame
-        for _ in 0..5 {
-            it.next();
+        Ok(map)
+    }
+
+    /// 读取 `update_table_from_gtf` 生成的 JSON 表
+    pub fn load(db_json_path: &str) -> Result<Self> {
+        let f = File::open(db_json_path).with_context(|| db_json_path.to_string())?;
+        let mut s = String::new();
+        BufReader::new(f).read_to_string(&mut s)?;
+        let mut map: GeneMap = serde_json::from_str(&s)?;
+        // 旧表只有 by_symbol，反推 by_id（此时基因名只能取小写）
+        if map.by_id.is_empty() {
+            for (sym, gid) in &map.by_symbol {
+                map.by_id.entry(gid.clone()).or_insert_with(|| sym.clone());
+            }
         }
-        let attr = match it.next() {
-            Some(v) => v,
-            None => continue,
-        };
+        Ok(map)
+    }
 
-        let gid = re_gene_id
-            .captures(attr)
-            .and_then(|c| c.get(1).map(|m| m.as_str().to_string()));
-        let gname = re_gene_name
-            .captures(attr)
-            .and_then(|c| c.get(1).map(|m| m.as_str().to_string()));
+    /// 给定 GEM 中的基因标识（ID 或 symbol），返回 (geneID, geneName)；查不到时两列都用原值。
+    /// 由 symbol 查到时 geneName 取表中该 ID 的基因名，大小写不同的 symbol 解析为同一个基因
+    pub fn resolve(&self, key: &str) -> (String, String) {
+        if let Some(name) = self.by_id.get(key) {
+            (key.to_string(), name.clone())
+        } else if let Some(gid) = self.by_symbol.get(&key.to_lowercase()) {
+            let name = self.by_id.get(gid).cloned().unwrap_or_else(|| key.to_string());
+            (gid.clone(), name)
+        } else {
+            (key.to_string(), key.to_string())
+        }
+    }
+}
 
-        let (gid, gname) = match (gid, gname) {
-            (Some(gid), Some(gname)) => (gid, gname),
-            _ => continue,
-        };
+/// 带缓存地把 GEM 中的基因标识解析为 (geneID, geneName)，同一标识只查一次表。
+///
+/// 不同标识（如大小写不同的 symbol 与 geneID）可能解析为同一个基因，调用方按解析结果归并，
+/// 同一坐标上的记录即为重复行。
+pub struct GeneResolver<'a> {
+    map: Option<&'a GeneMap>,
+    cache: HashMap<String, (String, String)>,
+}
 
-        // 只收 gene_id 形如 ENSMUSG...
-        if !gid.starts_with("ENSMUSG") {
-            continue;
+impl<'a> GeneResolver<'a> {
+    pub fn new(map: Option<&'a GeneMap>) -> Self {
+        Self {
+            map,
+            cache: HashMap::new(),
         }
+    }
 
-        // 基因名大小写不敏感；若重复，保持首次写入（避免把主名被别名覆盖）
-        let key = gname.to_lowercase();
-        by_symbol.entry(key).or_insert(gid);
+    /// GEM 已同时给出 geneID 与 geneName（或没有对照表）时原样返回，否则查对照表
+    pub fn resolve<'s>(&'s mut self, gene_id: &'s str, gene_name: &'s str) -> (&'s str, &'s str) {
+        let Some(map) = self.map.filter(|_| gene_id == gene_name) else {
+            return (gene_id, gene_name);
+        };
+        if !self.cache.contains_key(gene_id) {
+            self.cache.insert(gene_id.to_string(), map.resolve(gene_id));
+        }
+        let (id, name) = &self.cache[gene_id];
+        (id, name)
     }
+}
 
-    let map = GeneMap { by_symbol };
+/// 读取 Ensembl GTF（或 .gtf.gz），抽取 gene 级注释，更新/重建本地 JSON 表。
+/// 返回写入的条目数。
+pub fn update_table_from_gtf(gtf_path: &str, db_json_path: &str) -> Result<usize> {
+    // 允许覆盖写入：不存在则新建，存在则重建（简单粗暴，避免脏并发）
+    let map = GeneMap::from_gtf(gtf_path)?;
     let json = serde_json::to_string_pretty(&map)?;
     fs::write(db_json_path, json).with_context(|| db_json_path.to_string())?;
     Ok(map.by_symbol.len())
 }
-
-/// 查表：给定 symbol（如 "P2ry12"），返回 Some("ENSMUSG...") 或 None。
-pub fn query_gene_id(db_json_path: &str, symbol: &str) -> Result<Option<String>> {
-    if !Path::new(db_json_path).exists() {
-        // 没有表直接返回 None（或你也可以改成 Err 提示先更新）
-        return Ok(None);
-    }
-    let f = File::open(db_json_path).with_context(|| db_json_path.to_string())?;
-    let mut s = String::new();
-    BufReader::new(f).read_to_string(&mut s)?;
-    let map: GeneMap = serde_json::from_str(&s)?;
-    Ok(map.by_symbol.get(&symbol.to_lowercase()).cloned())
-}
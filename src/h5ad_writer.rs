@@ -0,0 +1,170 @@
+//! bGEF 某个 bin -> AnnData `.h5ad`（anndata >= 0.8 的 HDF5 编码）。
+//!
+//! - `X`：CSR 稀疏矩阵（bin × 基因），float32；
+//! - `obs`：索引为 `x_y`，列 x、y（binN 左上角 bin1 坐标）、total_counts、n_genes_by_counts；
+//! - `var`：索引为 geneID，列 geneID、geneName；
+//! - `obsm/spatial`：(x, y)；
+//! - `uns`：sn、bin_size、bin_type、omics、resolution。
+
+use std::{collections::HashMap, error::Error};
+
+use hdf5::types::VarLenUnicode;
+use hdf5::{File as H5File, Group, H5Type, Location};
+
+use crate::bgef_reader::BgefReader;
+
+fn vstr(s: &str) -> Result<VarLenUnicode, Box<dyn Error>> {
+    Ok(s.parse::<VarLenUnicode>()?)
+}
+
+/// 写入 anndata 的 `encoding-type` / `encoding-version` 属性
+fn set_encoding(loc: &Location, kind: &str, version: &str) -> Result<(), Box<dyn Error>> {
+    loc.new_attr::<VarLenUnicode>().create("encoding-type")?.write_scalar(&vstr(kind)?)?;
+    loc.new_attr::<VarLenUnicode>().create("encoding-version")?.write_scalar(&vstr(version)?)?;
+    Ok(())
+}
+
+/// 空的 dict 组（layers/obsp/varm/varp 等）
+fn dict_group(parent: &Group, name: &str) -> Result<Group, Box<dyn Error>> {
+    let g = parent.create_group(name)?;
+    set_encoding(&g, "dict", "0.1.0")?;
+    Ok(g)
+}
+
+/// 数值数组列
+fn write_array<T: H5Type>(g: &Group, name: &str, data: &[T]) -> Result<(), Box<dyn Error>> {
+    let ds = g.new_dataset_builder().with_data(data).create(name)?;
+    set_encoding(&ds, "array", "0.2.0")?;
+    Ok(())
+}
+
+/// 字符串数组列
+fn write_strings(g: &Group, name: &str, data: &[String]) -> Result<(), Box<dyn Error>> {
+    let v: Vec<VarLenUnicode> = data.iter().map(|s| vstr(s)).collect::<Result<_, _>>()?;
+    let ds = g.new_dataset_builder().with_data(&v).create(name)?;
+    set_encoding(&ds, "string-array", "0.2.0")?;
+    Ok(())
+}
+
+/// dataframe 组：`_index` 为行名，`columns` 为列顺序
+fn dataframe(
+    parent: &Group,
+    name: &str,
+    index: &[String],
+    columns: &[&str],
+) -> Result<Group, Box<dyn Error>> {
+    let g = parent.create_group(name)?;
+    set_encoding(&g, "dataframe", "0.2.0")?;
+    g.new_attr::<VarLenUnicode>().create("_index")?.write_scalar(&vstr("_index")?)?;
+    let order: Vec<VarLenUnicode> = columns.iter().map(|c| vstr(c)).collect::<Result<_, _>>()?;
+    g.new_attr_builder().with_data(&order).create("column-order")?;
+    write_strings(&g, "_index", index)?;
+    Ok(g)
+}
+
+fn uns_string(uns: &Group, name: &str, value: &str) -> Result<(), Box<dyn Error>> {
+    let ds = uns.new_dataset::<VarLenUnicode>().shape(()).create(name)?;
+    ds.write_scalar(&vstr(value)?)?;
+    set_encoding(&ds, "string", "0.2.0")?;
+    Ok(())
+}
+
+fn uns_number<T: H5Type>(uns: &Group, name: &str, value: T) -> Result<(), Box<dyn Error>> {
+    let ds = uns.new_dataset::<T>().shape(()).create(name)?;
+    ds.write_scalar(&value)?;
+    set_encoding(&ds, "numeric-scalar", "0.2.0")?;
+    Ok(())
+}
+
+/// 把 `/geneExp/bin{bin_size}` 写成 `.h5ad`，返回 (bin 数, 基因数)
+pub fn write_h5ad(
+    reader: &BgefReader,
+    bin_size: u32,
+    output: &str,
+) -> Result<(usize, usize), Box<dyn Error>> {
+    let genes = reader.genes(bin_size)?;
+    let expressions = reader.expressions(bin_size)?;
+    let attrs = reader.attrs();
+
+    // 1. 给每个被占据的 spot 编号（按 x、y 升序），并统计每行非零元个数
+    let mut spots: Vec<(i32, i32)> = expressions.iter().map(|e| (e.x, e.y)).collect();
+    spots.sort_unstable();
+    spots.dedup();
+    let row_of: HashMap<(i32, i32), usize> =
+        spots.iter().enumerate().map(|(i, &xy)| (xy, i)).collect();
+    let (n_obs, n_vars) = (spots.len(), genes.len());
+
+    let mut indptr = vec![0i64; n_obs + 1];
+    for e in &expressions {
+        indptr[row_of[&(e.x, e.y)] + 1] += 1;
+    }
+    for i in 0..n_obs {
+        indptr[i + 1] += indptr[i];
+    }
+
+    // 2. 按基因顺序填充：同一行内的列号自然升序
+    let mut next: Vec<usize> = indptr[..n_obs].iter().map(|&p| p as usize).collect();
+    let mut indices = vec![0i32; expressions.len()];
+    let mut data = vec![0f32; expressions.len()];
+    let mut total_counts = vec![0u64; n_obs];
+    let mut n_genes = vec![0u32; n_obs];
+    for (col, g) in genes.iter().enumerate() {
+        let range = g.offset as usize..g.offset as usize + g.count as usize;
+        for e in &expressions[range] {
+            let row = row_of[&(e.x, e.y)];
+            let k = next[row];
+            next[row] += 1;
+            indices[k] = i32::try_from(col)?;
+            data[k] = e.count as f32;
+            total_counts[row] += u64::from(e.count);
+            n_genes[row] += 1;
+        }
+    }
+
+    // 3. 写文件
+    let f = H5File::create(output)?;
+    set_encoding(&f, "anndata", "0.1.0")?;
+
+    let x = f.create_group("X")?;
+    set_encoding(&x, "csr_matrix", "0.1.0")?;
+    x.new_attr_builder().with_data(&[n_obs as i64, n_vars as i64]).create("shape")?;
+    x.new_dataset_builder().with_data(&data).create("data")?;
+    x.new_dataset_builder().with_data(&indices).create("indices")?;
+    x.new_dataset_builder().with_data(&indptr).create("indptr")?;
+
+    let obs_index: Vec<String> = spots.iter().map(|(x, y)| format!("{}_{}", x, y)).collect();
+    let obs = dataframe(
+        &f,
+        "obs",
+        &obs_index,
+        &["x", "y", "total_counts", "n_genes_by_counts"],
+    )?;
+    write_array(&obs, "x", &spots.iter().map(|s| s.0).collect::<Vec<_>>())?;
+    write_array(&obs, "y", &spots.iter().map(|s| s.1).collect::<Vec<_>>())?;
+    write_array(&obs, "total_counts", &total_counts)?;
+    write_array(&obs, "n_genes_by_counts", &n_genes)?;
+
+    let gene_ids: Vec<String> = genes.iter().map(|g| g.geneID.as_str().to_owned()).collect();
+    let gene_names: Vec<String> = genes.iter().map(|g| g.geneName.as_str().to_owned()).collect();
+    let var = dataframe(&f, "var", &gene_ids, &["geneID", "geneName"])?;
+    write_strings(&var, "geneID", &gene_ids)?;
+    write_strings(&var, "geneName", &gene_names)?;
+
+    let obsm = dict_group(&f, "obsm")?;
+    let spatial: Vec<[i32; 2]> = spots.iter().map(|&(x, y)| [x, y]).collect();
+    let spatial = ndarray::Array2::from_shape_vec((n_obs, 2), spatial.concat())?;
+    let ds = obsm.new_dataset_builder().with_data(&spatial).create("spatial")?;
+    set_encoding(&ds, "array", "0.2.0")?;
+
+    let uns = dict_group(&f, "uns")?;
+    uns_string(&uns, "sn", &attrs.stereo_seq_chip)?;
+    uns_number(&uns, "bin_size", bin_size)?;
+    uns_string(&uns, "bin_type", &attrs.bin_type)?;
+    uns_string(&uns, "omics", &attrs.omics)?;
+    uns_number(&uns, "resolution", reader.resolution(bin_size)?)?;
+
+    for name in ["layers", "obsp", "varm", "varp"] {
+        dict_group(&f, name)?;
+    }
+    Ok((n_obs, n_vars))
+}
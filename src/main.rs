@@ -1,20 +1,216 @@
-use std::{collections::HashMap, error::Error};
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    time::Instant,
+};
 
 use anyhow::Result;
-use clap::Parser;
-use hdf5::H5Type;
+use clap::{Parser, Subcommand};
 
+mod bgef_reader;
 mod bgef_writer;
+mod binning;
+mod cell_correct;
+mod cgef_reader;
+mod cgef_writer;
 mod gem_reader;
+mod gem_validate;
+mod gem_writer;
+mod gene_code;
+mod h5ad_writer;
 mod log;
+mod mask;
+mod merge;
+mod roi;
+mod spill;
+mod tiff;
 
 use crate::{
-    bgef_writer::{str2fa64, BgefWriter, Expression, GeneRec, SpotGene},
-    gem_reader::{get_expression, parse_header},
+    bgef_reader::BgefReader,
+    bgef_writer::{BgefWriter, DatasetOpts},
+    binning::{build_bin, BaseStats, BinData},
+    cell_correct::expand_labels,
+    cgef_reader::CgefReader,
+    cgef_writer::{build_cell_bin, write_cgef},
+    gem_reader::{get_expression, parse_header, DupPolicy},
+    gem_validate::validate_gem,
+    gene_code::{update_table_from_gtf, GeneMap},
     log::log_msg,
+    mask::{crop_mask, CellMask},
+    merge::{merge_samples, side_by_side, SampleData, Transform},
+    roi::Roi,
 };
 
+/// 不带子命令时执行 GEM -> bGEF 转换（与旧版命令行兼容）
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    gem2gef: Args,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// bGEF -> GEM（输出以 .gz 结尾时 gzip 压缩）
+    Gef2gem(Gef2GemArgs),
+    /// bGEF 的某个 bin -> AnnData .h5ad
+    Gef2h5ad(Gef2H5adArgs),
+    /// bGEF + 细胞分割 mask TIFF -> cell-bin GEF（cGEF）
+    Cellcut(CellcutArgs),
+    /// 列出 cGEF 中质心落在矩形内的细胞（按 blockIndex 只读取相关的块），输出 TSV
+    Cells(CellsArgs),
+    /// 把配准到芯片的 mask TIFF 裁成 bGEF bin1 的表达范围
+    Cropmask(CropmaskArgs),
+    /// 校验 GEM：报出每个问题的行号，最后输出一行 JSON 汇总；有问题时退出码非 0
+    Validate(ValidateArgs),
+    /// 按 GeoJSON 多边形 ROI 裁剪 bGEF，由最小的 bin 重新聚合各 bin 并重算属性
+    Crop(CropArgs),
+    /// 把多个芯片/切片（GEM 或 bGEF）按各自的坐标变换合并为一个 bGEF
+    Merge(MergeArgs),
+}
+
+#[derive(clap::Args)]
+struct MergeArgs {
+    /// 输入 GEM/GEM.GZ 或 bGEF，可重复；按给出的顺序编号为样本 1, 2, …
+    #[arg(short, long, required = true)]
+    input: Vec<String>,
+    /// 各输入的坐标变换，与 -i 按顺序一一对应：dx,dy（平移）或 a,b,c,d,e,f（x' = a*x + b*y + c，y' = d*x + e*y + f）
+    #[arg(short, long, allow_hyphen_values = true)]
+    transform: Vec<Transform>,
+    /// 样本名，与 -i 按顺序一一对应 [default: 输入文件名]
+    #[arg(long)]
+    name: Vec<String>,
+    /// 不给出 --transform 时沿 x 方向依次排开，相邻样本的间隔（bin1 坐标）
+    #[arg(long, default_value_t = 100)]
+    gap: u32,
+    /// 输出 bGEF
+    #[arg(short, long)]
+    output: String,
+    /// 逗号分隔的 bin 列表 [default: 1,20,50,100；输入已分箱时为其 bin]
+    #[arg(short, long, value_delimiter = ',')]
+    bins: Option<Vec<u32>>,
+    /// 顶层属性：resolution
+    #[arg(long, default_value_t = 500)]
+    resolution: u16,
+    #[command(flatten)]
+    dataset: DatasetArgs,
+}
+
+#[derive(clap::Args)]
+struct CropArgs {
+    /// 输入 bGEF
+    #[arg(short, long)]
+    input: String,
+    /// ROI：GeoJSON 的 Polygon/MultiPolygon（芯片坐标，即 bGEF 中的坐标）
+    #[arg(short, long)]
+    roi: String,
+    /// 输出 bGEF
+    #[arg(short, long)]
+    output: String,
+    #[command(flatten)]
+    dataset: DatasetArgs,
+}
+
+#[derive(clap::Args)]
+struct ValidateArgs {
+    /// 输入 GEM 或 GEM.GZ
+    #[arg(short, long)]
+    input: String,
+    /// 芯片宽,高（bin1 坐标）；给出时检查芯片坐标是否超出该范围
+    #[arg(long, value_delimiter = ',', num_args = 2)]
+    chip_size: Option<Vec<i32>>,
+}
+
+#[derive(clap::Args)]
+struct CellsArgs {
+    /// 输入 cGEF
+    #[arg(short, long)]
+    input: String,
+    /// 矩形 x0,y0,x1,y1（芯片坐标，含 x0/y0、不含 x1/y1）
+    #[arg(
+        long,
+        value_delimiter = ',',
+        num_args = 4,
+        required = true,
+        allow_hyphen_values = true
+    )]
+    rect: Vec<i32>,
+    /// 输出 TSV：id、质心 x/y（芯片坐标）、geneCount、expCount、dnbCount、area
+    #[arg(short, long)]
+    output: String,
+}
+
+#[derive(clap::Args)]
+struct CropmaskArgs {
+    /// 输入 bGEF（需含 bin1）
+    #[arg(short, long)]
+    input: String,
+    /// 配准后的 mask TIFF（像素坐标与 bin1 坐标对齐）
+    #[arg(short, long)]
+    mask: String,
+    /// 输出 TIFF（无压缩）
+    #[arg(short, long)]
+    output: String,
+}
+
+#[derive(clap::Args)]
+struct CellcutArgs {
+    /// 输入 bGEF（需含 bin1）
+    #[arg(short, long)]
+    input: String,
+    /// 细胞 mask TIFF（标签或二值图，像素坐标与 bin1 坐标对齐）
+    #[arg(short, long)]
+    mask: String,
+    /// 输出 cGEF
+    #[arg(short, long)]
+    output: String,
+    /// 细胞修正：把细胞核向外扩展的最大距离（像素），等距时归属编号较小的细胞
+    #[arg(long)]
+    correct: Option<u32>,
+    /// 修正后的 mask 输出路径 [default: 输出 cGEF 换扩展名为 .mask.tif]
+    #[arg(long)]
+    corrected_mask: Option<String>,
+    /// 细胞修正的线程数 [default: CPU 核数]
+    #[arg(long)]
+    threads: Option<usize>,
+    #[command(flatten)]
+    dataset: DatasetArgs,
+}
+
+#[derive(clap::Args)]
+struct Gef2H5adArgs {
+    /// 输入 bGEF
+    #[arg(short, long)]
+    input: String,
+    /// 输出 .h5ad
+    #[arg(short, long)]
+    output: String,
+    /// 导出的 bin [default: 文件中最小的 bin]
+    #[arg(short, long)]
+    bin: Option<u32>,
+}
+
+#[derive(clap::Args)]
+struct Gef2GemArgs {
+    /// 输入 bGEF
+    #[arg(short, long)]
+    input: String,
+    /// 输出 GEM 或 GEM.GZ
+    #[arg(short, long)]
+    output: String,
+    /// 导出的 bin [default: 文件中最小的 bin]
+    #[arg(short, long)]
+    bin: Option<u32>,
+    /// 只导出落在 GeoJSON 多边形 ROI（芯片坐标）内的 spot
+    #[arg(long)]
+    roi: Option<String>,
+}
+
+#[derive(clap::Args)]
 struct Args {
     /// 输入 GEM 或 GEM.GZ
     #[arg(short, long, default_value = "test10000.gem.gz")]
@@ -22,30 +218,445 @@ struct Args {
     /// 输出 bGEF (HDF5)
     #[arg(short, long, default_value = "dummy.bgef")]
     output: String,
-    /// 逗号分隔的 bin 列表
-    #[arg(short, long, value_delimiter = ',', default_value = "1,20,50,100")]
-    bins: Vec<u32>,
+    /// 逗号分隔的 bin 列表 [default: 1,20,50,100；已分箱的 GEM 默认只写 #BinSize]
+    #[arg(short, long, value_delimiter = ',')]
+    bins: Option<Vec<u32>>,
     /// 顶层属性：resolution
     #[arg(long, default_value_t = 500)]
     resolution: u16,
+    /// GTF 注释（可为 .gz），用于把 GEM 中的基因标识解析为 geneID/geneName
+    #[arg(long)]
+    gtf: Option<String>,
+    /// 基因对照表（JSON）；与 --gtf 同时给出时先由 GTF 重建该表
+    #[arg(long)]
+    gene_table: Option<String>,
+    /// 把表头 #OffsetX/#OffsetY 加到输出坐标上（裁剪过的 GEM 还原到芯片坐标）
+    #[arg(long)]
+    apply_offset: bool,
+    /// 同一基因同一坐标出现多行时的处理方式；行数与策略记录在根属性 duplicate_rows/duplicate_policy
+    #[arg(long, value_enum, default_value_t = DupPolicy::Sum)]
+    duplicates: DupPolicy,
+    /// 只转换落在 GeoJSON 多边形 ROI 内的记录；ROI 为芯片坐标（x * BinSize + #OffsetX）
+    #[arg(long)]
+    roi: Option<String>,
+    /// 内存上限（MiB）；给出时改用外部排序 + 流式写入，适合整张大芯片
+    #[arg(long)]
+    max_mem: Option<usize>,
+    /// --max-mem 模式下临时 run 文件所在目录 [default: 输出文件所在目录]
+    #[arg(long)]
+    tmp_dir: Option<String>,
+    /// --max-mem 模式下每个 run 的记录数（默认由 --max-mem 推算；供测试强制切分多个 run）
+    #[arg(long, hide = true)]
+    spill_records: Option<usize>,
+    /// --max-mem 模式下每个 bin 在内存中累计的 spot 数上限（默认由 --max-mem 推算；供测试强制落盘）
+    #[arg(long, hide = true)]
+    spill_spots: Option<usize>,
+    #[command(flatten)]
+    dataset: DatasetArgs,
 }
 
-#[repr(C)]
-#[derive(Clone, Copy, Debug, H5Type)]
-struct StatGene {
-    gene_id: u32,
-    gene_name: u32,
-    mid_count: u32,
-    log10_mid: f32,
+/// 写 bGEF/cGEF 的命令共用的 HDF5 压缩与分块参数
+#[derive(clap::Args)]
+struct DatasetArgs {
+    /// deflate(gzip) 压缩级别，0 为不压缩
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u8).range(0..=9))]
+    deflate: u8,
+    /// 压缩前对数据做 shuffle
+    #[arg(long)]
+    shuffle: bool,
+    /// 改用 LZF 压缩（替代 deflate；需通过 HDF5_PLUGIN_PATH 提供 LZF 过滤器插件）
+    #[arg(long)]
+    lzf: bool,
+    /// expression/exon 数据集的 chunk 长度（记录数）
+    #[arg(long, default_value_t = 65536)]
+    expr_chunk: usize,
+    /// gene 与 stat/gene 数据集的 chunk 长度（记录数）
+    #[arg(long, default_value_t = 4096)]
+    gene_chunk: usize,
+    /// wholeExp 矩阵的 chunk 边长（以 bin 为单位）
+    #[arg(long, default_value_t = 256)]
+    whole_chunk: usize,
+}
+
+impl DatasetArgs {
+    fn opts(&self) -> Result<DatasetOpts, Box<dyn Error>> {
+        if self.expr_chunk == 0 || self.gene_chunk == 0 || self.whole_chunk == 0 {
+            return Err("--expr-chunk/--gene-chunk/--whole-chunk 必须为正整数".into());
+        }
+        let opts = DatasetOpts {
+            deflate: (!self.lzf && self.deflate > 0).then_some(self.deflate),
+            shuffle: self.shuffle,
+            lzf: self.lzf,
+            expr_chunk: self.expr_chunk,
+            gene_chunk: self.gene_chunk,
+            whole_chunk: self.whole_chunk,
+        };
+        log_msg(&format!("dataset options: {:?}", opts));
+        Ok(opts)
+    }
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
-    // 1. 命令行参数
-    let args = Args::parse();
-    // 2. 读取 gem 文件头
+    let cli = Cli::parse();
+    match cli.command {
+        Some(Command::Gef2gem(args)) => gef2gem(args),
+        Some(Command::Gef2h5ad(args)) => gef2h5ad(args),
+        Some(Command::Cellcut(args)) => cellcut(args),
+        Some(Command::Cells(args)) => cells(args),
+        Some(Command::Cropmask(args)) => cropmask(args),
+        Some(Command::Validate(args)) => validate(args),
+        Some(Command::Crop(args)) => crop(args),
+        Some(Command::Merge(args)) => merge(args),
+        None => gem2gef(cli.gem2gef),
+    }
+}
+
+/// bGEF -> GEM
+fn gef2gem(args: Gef2GemArgs) -> Result<(), Box<dyn Error>> {
+    let reader = BgefReader::open(&args.input)?;
+    let bin_size = match args.bin {
+        Some(b) => b,
+        None => *reader.bin_sizes().first().ok_or("bGEF 中没有任何 /geneExp/binN")?,
+    };
+    log_msg(&format!(
+        "bGEF info:\n  bins={:?}  export bin{}\n  BinType={}  Omics={}  Chip={}  Offset=({}, {})  GemOffset=({}, {})\n  version={}  geftool_ver={:?}  gef_area={:.4} mm²",
+        reader.bin_sizes(),
+        bin_size,
+        reader.attrs().bin_type,
+        reader.attrs().omics,
+        reader.attrs().stereo_seq_chip,
+        reader.attrs().offset_x,
+        reader.attrs().offset_y,
+        reader.attrs().gem_offset_x,
+        reader.attrs().gem_offset_y,
+        reader.attrs().version,
+        reader.attrs().geftool_ver,
+        reader.attrs().gef_area,
+    ));
+    let roi = args.roi.as_deref().map(Roi::load).transpose()?;
+    if let Some(roi) = &roi {
+        log_roi(roi);
+    }
+    let rows = gem_writer::write_gem(&reader, bin_size, &args.output, roi.as_ref())?;
+    log_msg(&format!("wrote {} rows", rows));
+    println!("wrote {}!", &args.output);
+    Ok(())
+}
+
+/// bGEF -> .h5ad
+fn gef2h5ad(args: Gef2H5adArgs) -> Result<(), Box<dyn Error>> {
+    let reader = BgefReader::open(&args.input)?;
+    let bin_size = match args.bin {
+        Some(b) => b,
+        None => *reader.bin_sizes().first().ok_or("bGEF 中没有任何 /geneExp/binN")?,
+    };
+    let (n_obs, n_vars) = h5ad_writer::write_h5ad(&reader, bin_size, &args.output)?;
+    log_msg(&format!(
+        "AnnData bin{}: n_obs={} n_vars={}",
+        bin_size, n_obs, n_vars
+    ));
+    println!("wrote {}!", &args.output);
+    Ok(())
+}
+
+/// 校验 GEM：逐行报告问题并输出 JSON 汇总
+fn validate(args: ValidateArgs) -> Result<(), Box<dyn Error>> {
+    let chip_size = args.chip_size.map(|v| (v[0], v[1]));
+    let summary = validate_gem(&args.input, chip_size, |p| {
+        println!("{}:{}: {}: {}", args.input, p.line, p.kind, p.message);
+    })?;
+    println!("{}", serde_json::to_string(&summary)?);
+    if !summary.valid {
+        return Err(format!(
+            "{} 有 {} 个问题（涉及 {} 行）",
+            args.input, summary.problems, summary.problem_lines
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// 记录 ROI 的多边形个数与外接矩形
+fn log_roi(roi: &Roi) {
+    log_msg(&format!(
+        "roi: {} polygon(s)  x={}..={} y={}..={}",
+        roi.n_polygons(),
+        roi.min.0,
+        roi.max.0,
+        roi.min.1,
+        roi.max.1
+    ));
+}
+
+/// bGEF + ROI -> bGEF
+fn crop(args: CropArgs) -> Result<(), Box<dyn Error>> {
+    let opts = args.dataset.opts()?;
+    let reader = BgefReader::open(&args.input)?;
+    let roi = Roi::load(&args.roi)?;
+    log_roi(&roi);
+    let bin_sizes = reader.bin_sizes().to_vec();
+    let base = *bin_sizes.first().ok_or("bGEF 中没有任何 /geneExp/binN")?;
+    if let Some(b) = bin_sizes.iter().find(|&&b| b % base != 0) {
+        return Err(format!(
+            "bin{} 不是最小的 bin{} 的整数倍，无法由 bin{} 重新聚合",
+            b, base, base
+        )
+        .into());
+    }
+
+    // 按最小的 bin 裁剪，再重新聚合出文件中已有的每个 bin
+    let gene_bins = reader.gene_bins(base, |x, y| roi.contains(x, y))?;
+    if gene_bins.is_empty() {
+        return Err(format!("{} 在 ROI {} 内没有表达记录", args.input, args.roi).into());
+    }
+    let has_exon = reader.has_exon(base);
+    let resolution = u16::try_from(reader.resolution(base)?)
+        .map_err(|_| format!("{} 的 resolution 超出 uint16", args.input))?;
+    let mut bins: Vec<BinData> = Vec::with_capacity(bin_sizes.len());
+    for &bin_size in &bin_sizes {
+        let bin = build_bin(&gene_bins, bin_size, has_exon)?;
+        bin.log_info(resolution);
+        bins.push(bin);
+    }
+    let base_stats = BaseStats::from_gene_bins(&gene_bins, base)?;
+    drop(gene_bins);
+
+    let attrs = reader.attrs();
+    let writer = BgefWriter::new(
+        args.output.clone(),
+        bins,
+        resolution,
+        has_exon,
+        (attrs.offset_x, attrs.offset_y),
+        opts,
+        base_stats,
+    );
+    writer.write_all(&attrs.header(base, has_exon))?;
+    println!("wrote {}!", &args.output);
+    Ok(())
+}
+
+/// 多个 GEM/bGEF -> 一个 bGEF
+fn merge(args: MergeArgs) -> Result<(), Box<dyn Error>> {
+    let n = args.input.len();
+    if !args.transform.is_empty() && args.transform.len() != n {
+        return Err(format!(
+            "--transform 有 {} 个，输入有 {} 个",
+            args.transform.len(),
+            n
+        )
+        .into());
+    }
+    if !args.name.is_empty() && args.name.len() != n {
+        return Err(format!("--name 有 {} 个，输入有 {} 个", args.name.len(), n).into());
+    }
+    let opts = args.dataset.opts()?;
+
+    // 1. 读入各样本（最小 bin）
+    let mut samples = Vec::with_capacity(n);
+    for (i, path) in args.input.iter().enumerate() {
+        let sample = SampleData::load(path, args.name.get(i).cloned())?;
+        let ((min_x, min_y), (max_x, max_y)) = sample.extent();
+        log_msg(&format!(
+            "sample {} {}: {}  bin{}  genes={}  x={}..={} y={}..={}",
+            i + 1,
+            sample.name,
+            sample.path,
+            sample.bin_size,
+            sample.gene_bins.len(),
+            min_x,
+            max_x,
+            min_y,
+            max_y
+        ));
+        samples.push(sample);
+    }
+    let base = samples[0].bin_size;
+    if let Some(s) = samples.iter().find(|s| s.bin_size != base) {
+        return Err(format!(
+            "样本 {} 的最小 bin 为 bin{}，与样本 {} 的 bin{} 不一致",
+            s.name, s.bin_size, samples[0].name, base
+        )
+        .into());
+    }
+    let mut bin_sizes = match &args.bins {
+        Some(bins) => bins.clone(),
+        None if base > 1 => vec![base],
+        None => vec![1, 20, 50, 100],
+    };
+    bin_sizes.sort_unstable();
+    bin_sizes.dedup();
+    if bin_sizes.is_empty() || bin_sizes.iter().any(|&b| b == 0 || b % base != 0) {
+        return Err(format!("--bins 必须是输入 bin{} 的正整数倍", base).into());
+    }
+    if bin_sizes[bin_sizes.len() - 1] > i32::MAX as u32 {
+        return Err(format!("--bins 不能超过 {}", i32::MAX).into());
+    }
+
+    // 2. 放到同一画布上并按 geneID 合并
+    let transforms = if args.transform.is_empty() {
+        side_by_side(&samples, args.gap, base)?
+    } else {
+        args.transform.clone()
+    };
+    for (s, t) in samples.iter().zip(&transforms) {
+        log_msg(&format!("transform {}: {:?}", s.name, t.0));
+    }
+    let has_exon = samples.iter().all(|s| s.header.has_exon);
+    if !has_exon && samples.iter().any(|s| s.header.has_exon) {
+        log_msg("部分样本没有 ExonCount，输出不含 exon");
+    }
+    let mut hdr = samples[0].header.clone();
+    let mut chips: Vec<&str> = Vec::new();
+    for s in &samples {
+        let sn = s.header.stereo_seq_chip.as_str();
+        if !sn.is_empty() && !chips.contains(&sn) {
+            chips.push(sn);
+        }
+    }
+    hdr.stereo_seq_chip = chips.join(",");
+    hdr.offset_x = 0;
+    hdr.offset_y = 0;
+    hdr.bin_size = base;
+    hdr.has_exon = has_exon;
+    let merged = merge_samples(samples, &transforms)?;
+    log_msg(&format!(
+        "merged: genes={} spots={} collisions={} genes with differing geneName={}",
+        merged.gene_bins.len(),
+        merged.layer.spot_sample.len(),
+        merged.collisions,
+        merged.renamed
+    ));
+
+    // 3. 聚合各 bin 并写出
+    let mut bins: Vec<BinData> = Vec::with_capacity(bin_sizes.len());
+    for &bin_size in &bin_sizes {
+        let bin = build_bin(&merged.gene_bins, bin_size, has_exon)?;
+        bin.log_info(args.resolution);
+        bins.push(bin);
+    }
+    let base_stats = BaseStats::from_gene_bins(&merged.gene_bins, base)?;
+    let writer = BgefWriter::new(
+        args.output.clone(),
+        bins,
+        args.resolution,
+        has_exon,
+        (0, 0),
+        opts,
+        base_stats,
+    )
+    .with_samples(merged.layer);
+    writer.write_all(&hdr)?;
+    println!("wrote {}!", &args.output);
+    Ok(())
+}
+
+/// cGEF 按矩形查询细胞 -> TSV
+fn cells(args: CellsArgs) -> Result<(), Box<dyn Error>> {
+    let reader = CgefReader::open(&args.input)?;
+    let [x0, y0, x1, y1] = <[i32; 4]>::try_from(args.rect.as_slice())
+        .map_err(|_| "--rect 需要 4 个值: x0,y0,x1,y1")?;
+    let cells = reader.cells_in_rect((x0, y0), (x1, y1))?;
+    let (ox, oy) = reader.offset();
+    let mut w = BufWriter::new(File::create(&args.output)?);
+    writeln!(w, "id\tx\ty\tgeneCount\texpCount\tdnbCount\tarea")?;
+    for c in &cells {
+        writeln!(
+            w,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            c.id,
+            c.x + ox,
+            c.y + oy,
+            c.geneCount,
+            c.expCount,
+            c.dnbCount,
+            c.area
+        )?;
+    }
+    w.flush()?;
+    log_msg(&format!(
+        "cells in x=[{}, {}) y=[{}, {}): {}  blockSize={:?}",
+        x0,
+        x1,
+        y0,
+        y1,
+        cells.len(),
+        reader.block_size()
+    ));
+    println!("wrote {}!", &args.output);
+    Ok(())
+}
+
+/// 配准后的 mask TIFF -> 按 bGEF bin1 表达范围裁剪的 TIFF
+fn cropmask(args: CropmaskArgs) -> Result<(), Box<dyn Error>> {
+    let reader = BgefReader::open(&args.input)?;
+    if !reader.bin_sizes().contains(&1) {
+        return Err(format!("{} 中没有 bin1，无法确定表达范围", args.input).into());
+    }
+    let (min, max) = reader.expression_extent(1)?;
+    let bits = crop_mask(&args.mask, min, max, &args.output)?;
+    log_msg(&format!(
+        "crop mask: x={}..={} y={}..={} {}-bit",
+        min.0, max.0, min.1, max.1, bits
+    ));
+    println!("wrote {}!", &args.output);
+    Ok(())
+}
+
+/// bGEF + mask -> cGEF
+fn cellcut(args: CellcutArgs) -> Result<(), Box<dyn Error>> {
+    let opts = args.dataset.opts()?;
+    let reader = BgefReader::open(&args.input)?;
+    if !reader.bin_sizes().contains(&1) {
+        return Err(format!("{} 中没有 bin1，无法按 mask 分配表达", args.input).into());
+    }
+    // 只读取与表达区域重叠的那部分 mask
+    let info = reader.whole_exp_info(1)?;
+    let mut mask = CellMask::load(&args.mask, (info.min_x, info.min_y), info.max_corner(1)?)?;
+    log_msg(&format!(
+        "mask window: x={}..{} y={}..{}  cells={}",
+        mask.x0,
+        mask.x0 + mask.width as i32,
+        mask.y0,
+        mask.y0 + mask.height as i32,
+        mask.n_cells
+    ));
+    if let Some(distance) = args.correct {
+        let threads = args
+            .threads
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+        let t0 = Instant::now();
+        mask = expand_labels(&mask, distance, threads.max(1));
+        let path = args.corrected_mask.clone().unwrap_or_else(|| {
+            Path::new(&args.output).with_extension("mask.tif").to_string_lossy().into_owned()
+        });
+        mask.save(&path)?;
+        log_msg(&format!(
+            "cell correction: distance={} threads={} {:.2}s -> {}",
+            distance,
+            threads,
+            t0.elapsed().as_secs_f64(),
+            path
+        ));
+    }
+    let cell_bin = build_cell_bin(&reader, &mask)?;
+    log_msg(&format!(
+        "cellBin: cells={} genes={} cellExp={}",
+        cell_bin.cells.len(),
+        cell_bin.genes.len(),
+        cell_bin.cell_exp.len()
+    ));
+    write_cgef(&reader, &cell_bin, &args.output, &opts)?;
+    println!("wrote {}!", &args.output);
+    Ok(())
+}
+
+/// GEM -> bGEF
+fn gem2gef(args: Args) -> Result<(), Box<dyn Error>> {
+    // 1. 读取 gem 文件头
     let hdr = parse_header(&args.input)?;
     log_msg(&format!(
-    "Header info:\n  BinType={}  BinSize={}\n  Omics={}  Chip={}\n  Offset=({}, {})  HasExon={}  HeaderLineIndex={}",
+    "Header info:\n  BinType={}  BinSize={}\n  Omics={}  Chip={}\n  Offset=({}, {})  HasExon={}  HasGeneName={}  HeaderLineIndex={}",
     hdr.bin_type,
     hdr.bin_size,
     hdr.omics,
@@ -53,128 +664,166 @@ fn main() -> Result<(), Box<dyn Error>> {
     hdr.offset_x,
     hdr.offset_y,
     hdr.has_exon,
+    hdr.has_gene_name,
     hdr.header_line_index,
     ));
 
-    // 读取和处理 geneExp 数据
-    let (gene_bins, min_x, max_x, min_y, max_y, mut max_exp, mut max_exon) =
-        get_expression(&args.input, hdr.header_line_index, hdr.has_exon)
-            .expect("get_expression 输入有问题");
+    // 确定输出的 bin 列表（去重、升序）；已分箱的 GEM 只能输出 #BinSize 的整数倍
+    let mut bin_sizes = match &args.bins {
+        Some(bins) => bins.clone(),
+        None if hdr.bin_size > 1 => vec![hdr.bin_size],
+        None => vec![1, 20, 50, 100],
+    };
+    bin_sizes.sort_unstable();
+    bin_sizes.dedup();
+    if bin_sizes.is_empty() || bin_sizes[0] == 0 || bin_sizes[bin_sizes.len() - 1] > i32::MAX as u32
+    {
+        return Err(format!("--bins 必须是 1..={} 的整数列表", i32::MAX).into());
+    }
+    if hdr.bin_size > 1 {
+        let bad: Vec<String> =
+            bin_sizes.iter().filter(|&&b| b % hdr.bin_size != 0).map(|b| b.to_string()).collect();
+        if !bad.is_empty() {
+            return Err(format!(
+                "输入 GEM 已按 #BinSize={} 分箱，无法输出 bin {}；--bins 必须是 {} 的整数倍",
+                hdr.bin_size,
+                bad.join(","),
+                hdr.bin_size
+            )
+            .into());
+        }
+    }
+
+    // HDF5 压缩与分块参数
+    let opts = args.dataset.opts()?;
 
-    // 计算总条数
-    let total: usize = gene_bins.values().map(|coord_map| coord_map.len()).sum();
+    // 基因 ID <-> symbol 对照表（可选）
+    let gene_map = match (&args.gtf, &args.gene_table) {
+        (Some(gtf), Some(table)) => {
+            let n = update_table_from_gtf(gtf, table)?;
+            log_msg(&format!(
+                "gene table {} rebuilt from {}: {} genes",
+                table, gtf, n
+            ));
+            Some(GeneMap::load(table)?)
+        }
+        (Some(gtf), None) => Some(GeneMap::from_gtf(gtf)?),
+        (None, Some(table)) => Some(GeneMap::load(table)?),
+        (None, None) => None,
+    };
 
-    // 预分配 (为 geneExp/bin1 准备)
-    let mut expressions: Vec<Expression> = Vec::with_capacity(total);
-    let mut exons: Vec<u32> = if hdr.has_exon {
-        Vec::with_capacity(total)
+    let offset = if args.apply_offset {
+        (hdr.offset_x, hdr.offset_y)
     } else {
-        Vec::new()
+        (0, 0)
     };
-    let mut genes_meta: Vec<GeneRec> = Vec::with_capacity(gene_bins.len());
-
-    // 预分配 (为 wholeExp 和 wholeExpExon 准备)
-    let mut spot_mid_map: HashMap<(i32, i32), SpotGene> = HashMap::new();
-    let mut spot_exon_map: HashMap<(i32, i32), u32> = HashMap::new();
-    let mut max_mid_per_bin_n = u32::MIN;
-
-    // 串接顺序：外层按基因（BTreeMap 已排序），内层按 (x,y) 排序
-    // 表达数据计数器
-    let mut offset_u32: u32 = 0;
-    for (gene_key, coord_map) in &gene_bins {
-        // 1) 收集并排序该基因的所有 (x,y) 记录，保证稳定性
-        let mut recs: Vec<_> = coord_map.into_iter().collect(); // Vec<((x,y), (mid,exon))>
-        recs.sort_by_key(|&((x, y), _)| (x, y));
-
-        // 2) 记录起始 offset: gene_key基因的数据从哪里开始
-        let start = offset_u32;
-
-        // 3) 逐条推入 expression / exon，并维护 max 值
-        for (&(x, y), &(mid, exon_cnt)) in recs {
-            // --- (原 Loop 1 的逻辑) ---
-            expressions.push(Expression { x, y, count: mid });
-            if hdr.has_exon {
-                exons.push(exon_cnt);
-            }
-            if mid > max_exp {
-                max_exp = mid;
-            }
-            if hdr.has_exon && exon_cnt > max_exon {
-                max_exon = exon_cnt;
-            }
-            offset_u32 = offset_u32.saturating_add(1);
-
-            // --- (原 Loop 2 的逻辑 - 合并于此) ---
-            let entry = spot_mid_map.entry((x, y)).or_insert(SpotGene::default());
-            entry.MIDcount += mid;
-            entry.genecount += 1;
-            if entry.MIDcount > max_mid_per_bin_n {
-                max_mid_per_bin_n = entry.MIDcount;
-            }
-            // exon 累加 (注意变量名是 exon_cnt)
-            *spot_exon_map.entry((x, y)).or_insert(0) =
-                spot_exon_map.get(&(x, y)).copied().unwrap_or(0).saturating_add(exon_cnt);
+    if args.apply_offset {
+        log_msg(&format!("apply offset: x+={} y+={}", offset.0, offset.1));
+    }
+
+    // ROI 为芯片坐标；平移到与输出坐标一致（未 --apply-offset 时减去表头平移）
+    let roi = match &args.roi {
+        Some(path) => {
+            let roi = Roi::load(path)?;
+            log_roi(&roi);
+            Some(roi.translate(
+                f64::from(offset.0) - f64::from(hdr.offset_x),
+                f64::from(offset.1) - f64::from(hdr.offset_y),
+            ))
         }
+        None => None,
+    };
 
-        // 4) 构建 gene 行
-        let gene_id = str2fa64(gene_key); // 这里需要补充转化为geneid的代码
-        let gene_name = str2fa64(gene_key);
+    // 有内存上限：外部排序 + 流式写入
+    if let Some(max_mem_mb) = args.max_mem {
+        let tmp_dir = match &args.tmp_dir {
+            Some(dir) => PathBuf::from(dir),
+            None => Path::new(&args.output)
+                .parent()
+                .filter(|p| !p.as_os_str().is_empty())
+                .unwrap_or(Path::new("."))
+                .to_path_buf(),
+        };
+        log_msg(&format!(
+            "bounded-memory mode: max_mem={} MiB tmp_dir={}",
+            max_mem_mb,
+            tmp_dir.display()
+        ));
+        spill::convert(
+            &args.input,
+            &hdr,
+            offset,
+            &bin_sizes,
+            gene_map.as_ref(),
+            &args.output,
+            args.resolution,
+            max_mem_mb.saturating_mul(1 << 20),
+            args.spill_records,
+            args.spill_spots,
+            &tmp_dir,
+            &opts,
+            args.duplicates,
+            roi.as_ref(),
+        )?;
+        println!("wrote {}!", &args.output);
+        return Ok(());
+    }
 
-        genes_meta.push(GeneRec {
-            geneID: gene_id,
-            geneName: gene_name,
-            offset: start,
-            count: offset_u32 - start,
-        });
+    // 读取和处理 geneExp 数据；基因标识在读取时解析，同一基因的不同标识归并到一起
+    let (gene_bins, min_x, max_x, min_y, max_y, max_exp, max_exon, duplicates) = get_expression(
+        &args.input,
+        &hdr,
+        offset,
+        gene_map.as_ref(),
+        args.duplicates,
+        roi.as_ref(),
+    )?;
+    if gene_bins.is_empty() {
+        return Err(format!("{} 中没有表达记录", args.input).into());
     }
+    log_msg(&format!(
+        "duplicate (gene, x, y) rows: {} (policy={})",
+        duplicates,
+        args.duplicates.name()
+    ));
 
     log_msg(&format!(
-        "/geneExp/bin1 info:\n  minX={}  maxX={}\n  minY={}  maxY={}\n  maxExp={} maxExon={} resolution={}",
+        "GEM bin{} info (bin1 coordinates):\n  minX={}  maxX={}\n  minY={}  maxY={}\n  maxExp={} maxExon={} resolution={}",
+        hdr.bin_size,
         min_x, max_x,
         min_y, max_y,
         max_exp, max_exon, args.resolution,
         ));
 
-    // 4. 处理 wholeExp 和 wholeExpExon 数据
-    let len_x = (max_x - min_x + 1) as i32;
-    let len_y = (max_y - min_y + 1) as i32;
-
-    // 计算统计数据
-    let max_gene = spot_mid_map.values().map(|&x| x.genecount).max().unwrap_or(0);
-    let number = spot_mid_map.len() as u64;
-    let max_exon_spot = spot_exon_map.values().max().copied().unwrap_or(0);
-
-    log_msg(&format!(
-        "/wholeExp/bin1 info:\n  number={}\n  minX={}  minY={}\n  lenX={}  lenY={}\n  maxMID={}  maxGene={} resolution={}",
-        number,
-        min_x, len_x,
-        min_y, len_y,
-        max_mid_per_bin_n, max_gene, args.resolution,
-        ));
+    // 3. 按 --bins 逐个聚合
+    let mut bins: Vec<BinData> = Vec::with_capacity(bin_sizes.len());
+    for &bin_size in &bin_sizes {
+        let bin = build_bin(&gene_bins, bin_size, hdr.has_exon)?;
+        bin.log_info(args.resolution);
+        bins.push(bin);
+    }
+    // /stat/gene 与 gef_area 由 GEM 本身的 bin 统计；先统计，溢出时不留下写了一半的文件
+    let base_stats = BaseStats::from_gene_bins(&gene_bins, hdr.bin_size)?;
     log_msg(&format!(
-        "/wholeExpExon/bin1 info:\n  maxExon={}",
-        max_exon_spot
+        "gef_area={:.4} mm²",
+        base_stats.area(args.resolution)
     ));
+    // bin1 原始数据已全部聚合，提前释放
+    drop(gene_bins);
 
-    // 5. 实例化 BgefWriter 并传入所有数据
+    // 4. 实例化 BgefWriter 并传入所有数据
     let writer = BgefWriter::new(
         args.output.clone(),
-        expressions,
-        genes_meta,
-        exons,
-        spot_mid_map,
-        spot_exon_map,
-        min_x,
-        min_y,
-        max_x,
-        max_y,
-        max_exp,
-        max_exon,
+        bins,
         args.resolution,
         hdr.has_exon,
-    );
+        offset,
+        opts,
+        base_stats,
+    )
+    .with_duplicates(args.duplicates, duplicates);
 
-    // 6. 执行写入
+    // 5. 执行写入
     writer.write_all(&hdr)?;
 
     println!("wrote {}!", &args.output);
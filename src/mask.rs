@@ -0,0 +1,281 @@
+//! 细胞分割 mask：像素 (x, y) 与 bin1 坐标 (x, y) 对齐（配准到整张芯片的 mask）。
+//!
+//! - 标签 mask（每个细胞一个非零值）：按原标签值升序重新编号为 1..=n；
+//! - 二值 mask（只有一种非零值，如 0/1 或 0/255）：按 8 连通域标记细胞，编号按扫描顺序。
+//!
+//! 细胞轮廓用 Moore 邻域追踪得到外边界像素，再用 Douglas-Peucker 简化为不超过 32 个顶点。
+
+use anyhow::{ensure, Result};
+
+use crate::tiff::{read_tiff, write_tiff, Raster, TiffImage};
+
+/// 已编号的细胞 mask；`labels[row * width + col]` 为 0（背景）或细胞序号 + 1
+#[derive(Debug, Clone)]
+pub struct CellMask {
+    /// 窗口左上角的 bin1 坐标
+    pub x0: i32,
+    pub y0: i32,
+    pub width: usize,
+    pub height: usize,
+    pub labels: Vec<u32>,
+    pub n_cells: usize,
+}
+
+impl CellMask {
+    /// 读取 mask TIFF 中 bin1 坐标 `[x0, x1] x [y0, y1]`（含端）的部分并编号细胞
+    pub fn load(path: &str, (x0, y0): (i32, i32), (x1, y1): (i32, i32)) -> Result<Self> {
+        let (x0, y0) = (x0.max(0), y0.max(0));
+        let w = (i64::from(x1) - i64::from(x0) + 1).max(0) as usize;
+        let h = (i64::from(y1) - i64::from(y0) + 1).max(0) as usize;
+        let raster = read_tiff(path, Some((x0 as usize, y0 as usize, w, h)))?;
+        let mut mask = Self {
+            x0: raster.x0 as i32,
+            y0: raster.y0 as i32,
+            width: raster.width,
+            height: raster.height,
+            labels: raster.data,
+            n_cells: 0,
+        };
+
+        let mut values: Vec<u32> = mask.labels.iter().copied().filter(|&v| v != 0).collect();
+        values.sort_unstable();
+        values.dedup();
+        if values.len() == 1 {
+            mask.label_components();
+        } else {
+            // 原标签值 -> 连续编号（二分查找，避免为大标签值建表）
+            for v in mask.labels.iter_mut().filter(|v| **v != 0) {
+                *v = values.binary_search(v).map_or(0, |i| i as u32 + 1);
+            }
+            mask.n_cells = values.len();
+        }
+        Ok(mask)
+    }
+
+    /// 写成与芯片坐标对齐的标签 TIFF（窗口左上方补 0，Deflate 压缩）；细胞数不超过 65535 时
+    /// 为 16 位，否则为 32 位
+    pub fn save(&self, path: &str) -> Result<()> {
+        let (x0, y0) = (self.x0.max(0) as usize, self.y0.max(0) as usize);
+        let width = x0 + self.width;
+        let mut data = vec![0u32; width * (y0 + self.height)];
+        for (row, src) in self.labels.chunks(self.width.max(1)).enumerate() {
+            let start = (y0 + row) * width + x0;
+            data[start..start + src.len()].copy_from_slice(src);
+        }
+        let raster = Raster {
+            x0: 0,
+            y0: 0,
+            width,
+            height: y0 + self.height,
+            data,
+        };
+        let bits = if self.n_cells <= usize::from(u16::MAX) {
+            16
+        } else {
+            32
+        };
+        write_tiff(path, &raster, bits, true)
+    }
+
+    /// 二值 mask 的 8 连通域标记
+    fn label_components(&mut self) {
+        let (w, h) = (self.width, self.height);
+        // 先把前景统一标成 u32::MAX，表示“未编号”
+        for v in self.labels.iter_mut().filter(|v| **v != 0) {
+            *v = u32::MAX;
+        }
+        let mut next = 0u32;
+        let mut stack = Vec::new();
+        for start in 0..w * h {
+            if self.labels[start] != u32::MAX {
+                continue;
+            }
+            next += 1;
+            self.labels[start] = next;
+            stack.push(start);
+            while let Some(p) = stack.pop() {
+                let (px, py) = (p % w, p / w);
+                for ny in py.saturating_sub(1)..=(py + 1).min(h - 1) {
+                    for nx in px.saturating_sub(1)..=(px + 1).min(w - 1) {
+                        let q = ny * w + nx;
+                        if self.labels[q] == u32::MAX {
+                            self.labels[q] = next;
+                            stack.push(q);
+                        }
+                    }
+                }
+            }
+        }
+        self.n_cells = next as usize;
+    }
+
+    /// bin1 坐标在 `labels` 中的下标；窗口外为 None
+    #[inline]
+    pub fn index(&self, x: i32, y: i32) -> Option<usize> {
+        let (dx, dy) = (
+            i64::from(x) - i64::from(self.x0),
+            i64::from(y) - i64::from(self.y0),
+        );
+        if dx < 0 || dy < 0 || dx >= self.width as i64 || dy >= self.height as i64 {
+            return None;
+        }
+        Some(dy as usize * self.width + dx as usize)
+    }
+
+    /// bin1 坐标处的细胞编号（1..=n）；背景或窗口外为 0
+    #[allow(dead_code)]
+    #[inline]
+    pub fn label_at(&self, x: i32, y: i32) -> u32 {
+        self.index(x, y).map_or(0, |i| self.labels[i])
+    }
+
+    /// 从 `start`（该细胞按行扫描的第一个像素）出发，沿 8 连通外边界顺时针追踪，返回 bin1 坐标
+    pub fn contour(&self, start: usize) -> Vec<(i32, i32)> {
+        // 顺时针（y 向下）：W, NW, N, NE, E, SE, S, SW
+        const DIRS: [(i64, i64); 8] = [
+            (-1, 0),
+            (-1, -1),
+            (0, -1),
+            (1, -1),
+            (1, 0),
+            (1, 1),
+            (0, 1),
+            (-1, 1),
+        ];
+        let label = self.labels[start];
+        let (w, h) = (self.width as i64, self.height as i64);
+        let inside = |(x, y): (i64, i64)| {
+            x >= 0 && y >= 0 && x < w && y < h && self.labels[(y * w + x) as usize] == label
+        };
+        let s = ((start % self.width) as i64, (start / self.width) as i64);
+        let mut points = vec![s];
+        // 起点是最上一行的最左像素，其西侧必为背景
+        let (mut p, mut back) = (s, 0usize);
+        let mut first_move = None;
+        for _ in 0..4 * self.labels.len() + 8 {
+            let found = (1..=8).map(|k| (back + k) % 8).find_map(|d| {
+                let q = (p.0 + DIRS[d].0, p.1 + DIRS[d].1);
+                inside(q).then_some((q, d))
+            });
+            let Some((q, d)) = found else {
+                break; // 孤立像素
+            };
+            // 新的回溯点：q 之前检查过的那个背景邻居，换算为相对 q 的方向
+            let b = (p.0 + DIRS[(d + 7) % 8].0, p.1 + DIRS[(d + 7) % 8].1);
+            let nb = DIRS.iter().position(|&(dx, dy)| (q.0 + dx, q.1 + dy) == b).unwrap_or(0);
+            // Jacob 停止条件：回到起点且将要重复第一步
+            if p == s && first_move == Some((q, nb)) {
+                break;
+            }
+            if first_move.is_none() {
+                first_move = Some((q, nb));
+            }
+            if q != s {
+                points.push(q);
+            }
+            p = q;
+            back = nb;
+        }
+        points.into_iter().map(|(x, y)| (self.x0 + x as i32, self.y0 + y as i32)).collect()
+    }
+}
+
+/// 把配准到芯片的 mask 裁成 bin1 坐标 `[x0, x1] x [y0, y1]`（含端）并写成无压缩 TIFF；
+/// ROI 超出 mask 范围时报错。像素值都不超过 255 时写 8 位（与 cellcut.py 的 uint8 输出一致），
+/// 否则按最大值写 16/32 位，避免截断标签。返回输出的位深
+pub fn crop_mask(
+    path: &str,
+    (x0, y0): (i32, i32),
+    (x1, y1): (i32, i32),
+    output: &str,
+) -> Result<usize> {
+    let image = TiffImage::open(path)?;
+    let (w, h) = image.size();
+    ensure!(
+        x0 <= x1 && y0 <= y1,
+        "ROI 为空: x={}..={} y={}..={}",
+        x0,
+        x1,
+        y0,
+        y1
+    );
+    ensure!(
+        x0 >= 0 && y0 >= 0 && (x1 as i64) < w as i64 && (y1 as i64) < h as i64,
+        "ROI x={}..={} y={}..={} 超出 mask 范围（{} 宽 {} 高 {}）",
+        x0,
+        x1,
+        y0,
+        y1,
+        path,
+        w,
+        h
+    );
+    let (x0, y0) = (x0 as usize, y0 as usize);
+    let (cw, ch) = (x1 as usize - x0 + 1, y1 as usize - y0 + 1);
+    let raster = image.read(Some((x0, y0, cw, ch)))?;
+    let bits = match raster.data.iter().copied().max().unwrap_or(0) {
+        0..=0xff => 8,
+        0x100..=0xffff => 16,
+        _ => 32,
+    };
+    write_tiff(output, &raster, bits, false)?;
+    Ok(bits)
+}
+
+/// 折线 `points`（含两端）的 Douglas-Peucker 简化，结果含两端
+fn douglas_peucker(points: &[(i32, i32)], eps: f64, out: &mut Vec<(i32, i32)>) {
+    let (a, b) = (points[0], points[points.len() - 1]);
+    let (dx, dy) = (f64::from(b.0 - a.0), f64::from(b.1 - a.1));
+    let len = dx.hypot(dy);
+    let dist = |p: &(i32, i32)| {
+        let (px, py) = (f64::from(p.0 - a.0), f64::from(p.1 - a.1));
+        if len == 0.0 {
+            px.hypot(py)
+        } else {
+            (px * dy - py * dx).abs() / len
+        }
+    };
+    let far = (1..points.len().saturating_sub(1))
+        .map(|i| (i, dist(&points[i])))
+        .max_by(|x, y| x.1.total_cmp(&y.1));
+    match far {
+        Some((i, d)) if d > eps => {
+            douglas_peucker(&points[..=i], eps, out);
+            out.pop();
+            douglas_peucker(&points[i..], eps, out);
+        }
+        _ => {
+            out.push(a);
+            out.push(b);
+        }
+    }
+}
+
+/// 把闭合轮廓简化到不超过 `max_points` 个顶点：从离起点最远的点切成两段分别简化，
+/// 容差从 0.5 像素起逐步放大
+pub fn simplify_polygon(points: &[(i32, i32)], max_points: usize) -> Vec<(i32, i32)> {
+    if points.len() < 3 {
+        return points.to_vec();
+    }
+    let p0 = points[0];
+    let far = (1..points.len())
+        .max_by_key(|&i| {
+            let (dx, dy) = (i64::from(points[i].0 - p0.0), i64::from(points[i].1 - p0.1));
+            dx * dx + dy * dy
+        })
+        .unwrap_or(1);
+    let mut closed = points.to_vec();
+    closed.push(p0);
+    let mut eps = 0.5;
+    loop {
+        let mut out = Vec::new();
+        douglas_peucker(&closed[..=far], eps, &mut out);
+        out.pop();
+        douglas_peucker(&closed[far..], eps, &mut out);
+        out.pop(); // 末尾是重复的起点
+        if out.len() <= max_points {
+            return out;
+        }
+        eps *= 1.25;
+    }
+}
@@ -0,0 +1,272 @@
+//! 多个芯片/切片合并为一个 bGEF。
+//!
+//! 每个输入（GEM 或 bGEF）先读成最小 bin 的 `GeneBins`，坐标按各自的平移或仿射变换放到同一张画布上，
+//! 再按 geneID 合并基因列表（geneName 取最先出现的样本）。不同样本落在同一个 spot 时报错；
+//! 同一样本经仿射变换后落在同一个 spot 的记录相加。
+//! 每个 spot 的来源样本记录在 `/wholeExpSample/binN`，样本名与变换记录在 `/samples`。
+
+use std::collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context, Result};
+use hdf5::types::FixedAscii;
+use hdf5::H5Type;
+
+use crate::{
+    bgef_reader::BgefReader,
+    bgef_writer::str2fa64,
+    gem_reader::{get_expression, parse_header, DupPolicy, GeneBins, Header},
+};
+
+/// `/wholeExpSample/binN` 中含有多个样本的 binN spot
+pub const MIXED_SAMPLE: u16 = u16::MAX;
+
+/// 坐标变换：x' = a·x + b·y + c，y' = d·x + e·y + f（结果四舍五入到整数）
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform(pub [f64; 6]);
+
+impl Transform {
+    pub fn translate(dx: f64, dy: f64) -> Self {
+        Self([1.0, 0.0, dx, 0.0, 1.0, dy])
+    }
+
+    /// 变换一个点；结果超出 int32 时返回 None
+    pub fn apply(&self, x: i32, y: i32) -> Option<(i32, i32)> {
+        let [a, b, c, d, e, f] = self.0;
+        let (x, y) = (f64::from(x), f64::from(y));
+        let round = |v: f64| {
+            let v = v.round();
+            (v >= f64::from(i32::MIN) && v <= f64::from(i32::MAX)).then_some(v as i32)
+        };
+        Some((round(a * x + b * y + c)?, round(d * x + e * y + f)?))
+    }
+}
+
+impl FromStr for Transform {
+    type Err = String;
+
+    /// `dx,dy`（平移）或 `a,b,c,d,e,f`（仿射）
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let v = s
+            .split(',')
+            .map(|t| t.trim().parse::<f64>().ok().filter(|v| v.is_finite()))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| format!("{:?} 不是逗号分隔的数值", s))?;
+        match v[..] {
+            [dx, dy] => Ok(Self::translate(dx, dy)),
+            [a, b, c, d, e, f] => Ok(Self([a, b, c, d, e, f])),
+            _ => Err(format!("{:?} 应为 dx,dy 或 a,b,c,d,e,f", s)),
+        }
+    }
+}
+
+/// `/samples` 中的一行；第 i 行（0-based）对应 `/wholeExpSample/binN` 中的值 i + 1
+#[allow(non_snake_case)]
+#[repr(C)]
+#[derive(Clone, Copy, Debug, H5Type)]
+pub struct SampleRec {
+    pub name: FixedAscii<64>,
+    /// 仿射变换 [a, b, c, d, e, f]
+    pub transform: [f64; 6],
+    /// 该样本在画布上占据的（最小 bin 的）spot 数
+    pub spots: u64,
+    pub MIDcount: u64,
+}
+
+/// 合并后的样本信息：样本表与每个最小 bin spot 的来源样本（1-based）
+#[derive(Debug, Clone)]
+pub struct SampleLayer {
+    pub recs: Vec<SampleRec>,
+    pub spot_sample: HashMap<(i32, i32), u16>,
+}
+
+/// 一个已读入的输入
+pub struct SampleData {
+    pub name: String,
+    pub path: String,
+    pub header: Header,
+    /// 最小 bin 的尺寸（GEM 为 #BinSize）
+    pub bin_size: u32,
+    pub gene_bins: GeneBins,
+}
+
+impl SampleData {
+    /// 读入 GEM（坐标加上 #OffsetX/#OffsetY，即芯片坐标）或 bGEF（最小的 bin，坐标即文件中的坐标）
+    pub fn load(path: &str, name: Option<String>) -> Result<Self> {
+        let name = name.unwrap_or_else(|| {
+            let file = Path::new(path).file_name().map(|n| n.to_string_lossy());
+            let file = file.unwrap_or_else(|| path.into());
+            let stem = [".gem.gz", ".gem", ".bgef", ".gef", ".gz"]
+                .iter()
+                .find_map(|ext| file.strip_suffix(ext))
+                .unwrap_or(&file);
+            stem.to_string()
+        });
+        let (header, bin_size, gene_bins) = if is_hdf5(path)? {
+            let reader = BgefReader::open(path)?;
+            let bin_size = *reader
+                .bin_sizes()
+                .first()
+                .ok_or_else(|| anyhow!("{} 中没有任何 /geneExp/binN", path))?;
+            let has_exon = reader.has_exon(bin_size);
+            let gene_bins = reader.gene_bins(bin_size, |_, _| true)?;
+            (
+                reader.attrs().header(bin_size, has_exon),
+                bin_size,
+                gene_bins,
+            )
+        } else {
+            let hdr = parse_header(path)?;
+            let offset = (hdr.offset_x, hdr.offset_y);
+            let (gene_bins, ..) = get_expression(path, &hdr, offset, None, DupPolicy::Sum, None)?;
+            let bin_size = hdr.bin_size;
+            (hdr, bin_size, gene_bins)
+        };
+        if gene_bins.is_empty() {
+            bail!("{} 中没有表达记录", path);
+        }
+        Ok(Self {
+            name,
+            path: path.to_string(),
+            header,
+            bin_size,
+            gene_bins,
+        })
+    }
+
+    /// 坐标范围 ((minX, minY), (maxX, maxY))
+    pub fn extent(&self) -> ((i32, i32), (i32, i32)) {
+        let (mut min, mut max) = ((i32::MAX, i32::MAX), (i32::MIN, i32::MIN));
+        for &(x, y) in self.gene_bins.values().flat_map(|m| m.keys()) {
+            min = (min.0.min(x), min.1.min(y));
+            max = (max.0.max(x), max.1.max(y));
+        }
+        (min, max)
+    }
+}
+
+/// 文件是否以 HDF5 签名开头
+fn is_hdf5(path: &str) -> Result<bool> {
+    let mut magic = [0u8; 8];
+    let mut f = File::open(path).with_context(|| format!("open {}", path))?;
+    let n = f.read(&mut magic)?;
+    Ok(n == magic.len() && magic == *b"\x89HDF\r\n\x1a\n")
+}
+
+/// 没有给出变换时的排布：各样本左上角对齐到 y = 0，沿 x 方向依次排开，相邻样本间隔 `gap`；
+/// 每个样本的起点对齐到 `bin_size` 的整数倍
+pub fn side_by_side(samples: &[SampleData], gap: u32, bin_size: u32) -> Result<Vec<Transform>> {
+    let step = i64::from(bin_size);
+    let mut cursor = 0i64;
+    let mut transforms = Vec::with_capacity(samples.len());
+    for s in samples {
+        let ((min_x, min_y), (max_x, _)) = s.extent();
+        transforms.push(Transform::translate(
+            (cursor - i64::from(min_x)) as f64,
+            -f64::from(min_y),
+        ));
+        cursor += i64::from(max_x) - i64::from(min_x) + 1 + i64::from(gap);
+        cursor = (cursor + step - 1) / step * step;
+        if cursor > i64::from(i32::MAX) {
+            bail!("横向排布后的画布宽度超出 int32");
+        }
+    }
+    Ok(transforms)
+}
+
+/// (x, y) -> (MIDCount, ExonCount)
+type Spots = HashMap<(i32, i32), (u32, u32)>;
+
+/// 合并结果
+pub struct Merged {
+    pub gene_bins: GeneBins,
+    pub layer: SampleLayer,
+    /// 同一样本经变换后落在同一 spot 而相加的记录数
+    pub collisions: u64,
+    /// geneID 相同但 geneName 不同（取最先出现的 geneName）的基因数
+    pub renamed: usize,
+}
+
+/// 按各自的变换把样本放到同一画布上，按 geneID 合并
+pub fn merge_samples(samples: Vec<SampleData>, transforms: &[Transform]) -> Result<Merged> {
+    if samples.len() >= usize::from(MIXED_SAMPLE) {
+        bail!("样本数不能超过 {}", MIXED_SAMPLE - 1);
+    }
+    // geneID -> (geneName, (x, y) -> (MID, exon))
+    let mut genes: BTreeMap<String, (String, Spots)> = BTreeMap::new();
+    let mut spot_sample: HashMap<(i32, i32), u16> = HashMap::new();
+    let mut recs: Vec<SampleRec> = Vec::with_capacity(samples.len());
+    let (mut collisions, mut renamed) = (0u64, BTreeSet::new());
+
+    for (i, (s, t)) in samples.into_iter().zip(transforms).enumerate() {
+        let code = i as u16 + 1;
+        let mut mid_total = 0u64;
+        let mut spots = 0u64;
+        for ((gene_id, gene_name), coords) in s.gene_bins {
+            let (name, merged) =
+                genes.entry(gene_id.clone()).or_insert_with(|| (gene_name.clone(), HashMap::new()));
+            if *name != gene_name {
+                renamed.insert(gene_id.clone());
+            }
+            for ((x, y), (mid, exon)) in coords {
+                let (cx, cy) = t
+                    .apply(x, y)
+                    .ok_or_else(|| anyhow!("样本 {} 的 ({}, {}) 变换后超出 int32", s.name, x, y))?;
+                if cx < 0 || cy < 0 {
+                    bail!(
+                        "样本 {} 的 ({}, {}) 变换后为 ({}, {})，画布坐标不能为负",
+                        s.name,
+                        x,
+                        y,
+                        cx,
+                        cy
+                    );
+                }
+                match spot_sample.entry((cx, cy)) {
+                    Entry::Vacant(e) => {
+                        e.insert(code);
+                        spots += 1;
+                    }
+                    // 先前的样本已写入 recs
+                    Entry::Occupied(e) if *e.get() != code => bail!(
+                        "样本 {} 与 {} 在画布坐标 ({}, {}) 重叠",
+                        recs[usize::from(*e.get()) - 1].name.as_str(),
+                        s.name,
+                        cx,
+                        cy
+                    ),
+                    Entry::Occupied(_) => {}
+                }
+                mid_total += u64::from(mid);
+                match merged.entry((cx, cy)) {
+                    Entry::Vacant(e) => {
+                        e.insert((mid, exon));
+                    }
+                    Entry::Occupied(mut e) => {
+                        collisions += 1;
+                        DupPolicy::Sum.merge(e.get_mut(), (mid, exon)).with_context(|| {
+                            format!("样本 {} 基因 {} 在 ({}, {})", s.name, gene_id, cx, cy)
+                        })?;
+                    }
+                }
+            }
+        }
+        recs.push(SampleRec {
+            name: str2fa64(&s.name),
+            transform: t.0,
+            spots,
+            MIDcount: mid_total,
+        });
+    }
+
+    let gene_bins = genes.into_iter().map(|(id, (name, coords))| ((id, name), coords)).collect();
+    Ok(Merged {
+        gene_bins,
+        layer: SampleLayer { recs, spot_sample },
+        collisions,
+        renamed: renamed.len(),
+    })
+}
@@ -0,0 +1,177 @@
+//! GeoJSON 多边形 ROI（例如 StereoMap 中圈选的组织区域）。
+//!
+//! 支持 `FeatureCollection`、`Feature`、`GeometryCollection` 中的 `Polygon` 与 `MultiPolygon`，
+//! 坐标为芯片坐标（bin1）。多边形的第一个环为外环，其余为洞；spot 按其坐标点判断，
+//! 落在边界上的点算作在多边形内（洞的边界也算在多边形内）。
+
+use std::fs;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::Value;
+
+/// 点与环的位置关系
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Inside,
+    Boundary,
+    Outside,
+}
+
+/// 一个多边形：`rings[0]` 为外环，其余为洞；环首尾不重复
+#[derive(Debug, Clone)]
+struct Polygon {
+    rings: Vec<Vec<(f64, f64)>>,
+}
+
+/// 若干多边形的并集
+#[derive(Debug, Clone)]
+pub struct Roi {
+    polygons: Vec<Polygon>,
+    /// 外接矩形（含端）
+    pub min: (f64, f64),
+    pub max: (f64, f64),
+}
+
+impl Roi {
+    /// 读取 GeoJSON 文件
+    pub fn load(path: &str) -> Result<Self> {
+        let text = fs::read_to_string(path).with_context(|| format!("open {}", path))?;
+        let json: Value =
+            serde_json::from_str(&text).with_context(|| format!("{} 不是合法的 JSON", path))?;
+        Self::from_geojson(&json).with_context(|| format!("读取 ROI {}", path))
+    }
+
+    /// 由 GeoJSON 对象建立 ROI
+    pub fn from_geojson(json: &Value) -> Result<Self> {
+        let mut polygons = Vec::new();
+        collect(json, &mut polygons)?;
+        if polygons.is_empty() {
+            bail!("GeoJSON 中没有 Polygon/MultiPolygon");
+        }
+        let outer = polygons.iter().flat_map(|p| &p.rings[0]);
+        let (mut min, mut max) = ((f64::MAX, f64::MAX), (f64::MIN, f64::MIN));
+        for &(x, y) in outer {
+            min = (min.0.min(x), min.1.min(y));
+            max = (max.0.max(x), max.1.max(y));
+        }
+        Ok(Self { polygons, min, max })
+    }
+
+    /// 多边形个数
+    pub fn n_polygons(&self) -> usize {
+        self.polygons.len()
+    }
+
+    /// 整体平移 (dx, dy)
+    pub fn translate(mut self, dx: f64, dy: f64) -> Self {
+        for p in self.polygons.iter_mut().flat_map(|p| p.rings.iter_mut().flatten()) {
+            *p = (p.0 + dx, p.1 + dy);
+        }
+        self.min = (self.min.0 + dx, self.min.1 + dy);
+        self.max = (self.max.0 + dx, self.max.1 + dy);
+        self
+    }
+
+    /// 点 (x, y) 是否落在任一多边形内（含边界）
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        let p = (f64::from(x), f64::from(y));
+        if p.0 < self.min.0 || p.0 > self.max.0 || p.1 < self.min.1 || p.1 > self.max.1 {
+            return false;
+        }
+        self.polygons.iter().any(|poly| {
+            locate(&poly.rings[0], p) != Side::Outside
+                && poly.rings[1..].iter().all(|hole| locate(hole, p) != Side::Inside)
+        })
+    }
+}
+
+/// 递归收集 GeoJSON 对象中的多边形
+fn collect(json: &Value, out: &mut Vec<Polygon>) -> Result<()> {
+    let kind = json.get("type").and_then(Value::as_str).ok_or_else(|| anyhow!("缺少 type"))?;
+    let field = |name: &str| json.get(name).ok_or_else(|| anyhow!("{} 缺少 {}", kind, name));
+    match kind {
+        "FeatureCollection" => {
+            let features =
+                field("features")?.as_array().ok_or_else(|| anyhow!("features 不是数组"))?;
+            for (i, feature) in features.iter().enumerate() {
+                collect(feature, out).with_context(|| format!("features[{}]", i))?;
+            }
+        }
+        "Feature" => collect(field("geometry")?, out)?,
+        "GeometryCollection" => {
+            let geometries =
+                field("geometries")?.as_array().ok_or_else(|| anyhow!("geometries 不是数组"))?;
+            for (i, geometry) in geometries.iter().enumerate() {
+                collect(geometry, out).with_context(|| format!("geometries[{}]", i))?;
+            }
+        }
+        "Polygon" => out.push(polygon(field("coordinates")?)?),
+        "MultiPolygon" => {
+            let polygons =
+                field("coordinates")?.as_array().ok_or_else(|| anyhow!("coordinates 不是数组"))?;
+            for (i, p) in polygons.iter().enumerate() {
+                out.push(polygon(p).with_context(|| format!("coordinates[{}]", i))?);
+            }
+        }
+        other => bail!("不支持的几何类型 {}（只支持 Polygon/MultiPolygon）", other),
+    }
+    Ok(())
+}
+
+/// Polygon 的 coordinates：环的数组，每个环为 [x, y] 的数组
+fn polygon(coords: &Value) -> Result<Polygon> {
+    let rings = coords.as_array().ok_or_else(|| anyhow!("Polygon 的 coordinates 不是数组"))?;
+    if rings.is_empty() {
+        bail!("Polygon 没有外环");
+    }
+    let rings = rings
+        .iter()
+        .enumerate()
+        .map(|(i, ring)| {
+            let mut pts = ring
+                .as_array()
+                .ok_or_else(|| anyhow!("环 {} 不是数组", i))?
+                .iter()
+                .map(|pos| {
+                    let xy = pos.as_array().filter(|a| a.len() >= 2);
+                    xy.and_then(|a| Some((a[0].as_f64()?, a[1].as_f64()?)))
+                        .ok_or_else(|| anyhow!("环 {} 中的坐标 {} 不是 [x, y]", i, pos))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            // GeoJSON 的环首尾相同；去掉重复的终点
+            if pts.len() > 1 && pts.first() == pts.last() {
+                pts.pop();
+            }
+            if pts.len() < 3 {
+                bail!("环 {} 少于 3 个顶点", i);
+            }
+            Ok(pts)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Polygon { rings })
+}
+
+/// 射线法判断点与环的位置关系；先检查是否落在边上
+fn locate(ring: &[(f64, f64)], (px, py): (f64, f64)) -> Side {
+    let mut inside = false;
+    for (i, &(ax, ay)) in ring.iter().enumerate() {
+        let (bx, by) = ring[(i + 1) % ring.len()];
+        let cross = (bx - ax) * (py - ay) - (by - ay) * (px - ax);
+        if cross == 0.0
+            && px >= ax.min(bx)
+            && px <= ax.max(bx)
+            && py >= ay.min(by)
+            && py <= ay.max(by)
+        {
+            return Side::Boundary;
+        }
+        if (ay > py) != (by > py) && px < ax + (py - ay) * (bx - ax) / (by - ay) {
+            inside = !inside;
+        }
+    }
+    if inside {
+        Side::Inside
+    } else {
+        Side::Outside
+    }
+}
@@ -0,0 +1,710 @@
+//! 有内存上限的 GEM -> bGEF 转换。
+//!
+//! 1. 逐行读取 GEM，把 (gene, x, y, MID, exon) 记录攒到内存缓冲；缓冲写满时按 (gene, x, y)
+//!    排序、按 `--duplicates` 合并重复坐标，落盘成一个有序 run 文件，并记下每个基因在文件中的片段；
+//! 2. 读完后按 (geneID, geneName) 排序，逐个基因从各 run 读出片段归并，同时喂给每个
+//!    bin 尺寸的 `BinAccumulator`，表达记录直接追加写入 HDF5；各 bin 的 spot 统计攒满上限后
+//!    按 tile 顺序落盘成 spot run；
+//! 3. 写 wholeExp 时归并各 bin 的 spot run，逐个 tile 写入。
+//!
+//! `--max-mem` 按 `MemBudget` 分给记录缓冲、各 bin 的追加缓冲与各 bin 的 spot 统计，三者都不会
+//! 超出各自的份额；此外只有单个基因的记录、gene 表与各 run 的基因索引（随基因数增长）常驻内存。
+//! 输出先写到同目录下的临时文件，成功后才改名为目标文件；输出内容与内存路径（`get_expression` +
+//! `build_bin`）一致。
+
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
+    error::Error,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    mem,
+    path::{Path, PathBuf},
+    process,
+};
+
+use anyhow::{Context, Result};
+use hdf5::File as H5File;
+
+use crate::{
+    bgef_writer::{
+        write_duplicate_attrs, write_expression_attrs, write_gene, write_root_attrs,
+        write_stat_gene, write_whole_exp, DatasetOpts, ExpressionAppender, SpotGene,
+    },
+    binning::{
+        bin_origin, gef_area, sort_stats, stat_row, tile_key, BinAccumulator, SpotStat, TileKey,
+    },
+    gem_reader::{for_each_record, DupPolicy, Header},
+    gene_code::{GeneMap, GeneResolver},
+    log::log_msg,
+    roi::Roi,
+};
+
+/// 落盘记录的字节数：gene u32 | x i32 | y i32 | MID u32 | exon u32，小端
+const REC_BYTES: usize = 20;
+/// HDF5 追加写入前缓冲的记录数
+const APPEND_BUF: usize = 1 << 20;
+/// 落盘 spot 统计的字节数：x i32 | y i32 | MIDcount u32 | genecount u16 | exon u32，小端
+const SPOT_BYTES: usize = 18;
+/// 内存中一个 spot 的统计占用的字节数估算：`BinAccumulator` 的两张 HashMap（含装载因子余量）
+/// 加上落盘前排序用的 `SpotStat`
+const SPOT_MEM: usize = 64;
+
+/// `--max-mem` 在各缓冲之间的分配（字节）。
+///
+/// 记录缓冲只在读入阶段使用，追加缓冲与 spot 统计只在归并阶段使用；仍各取一份，
+/// 留出 gene 表、run 索引与单个基因记录的余量
+#[derive(Debug, Clone, Copy)]
+struct MemBudget {
+    /// 读入阶段的记录缓冲
+    records: usize,
+    /// 各 bin 的 expression/exon 追加缓冲合计
+    append: usize,
+    /// 各 bin（以及另行计数的最小 bin）的 spot 统计合计
+    spots: usize,
+}
+
+impl MemBudget {
+    fn split(max_mem: usize) -> Self {
+        let records = max_mem / 2;
+        let append = max_mem / 4;
+        Self {
+            records,
+            append,
+            spots: max_mem - records - append,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Rec {
+    gene: u32,
+    x: i32,
+    y: i32,
+    mid: u32,
+    exon: u32,
+}
+
+impl Rec {
+    fn encode(&self) -> [u8; REC_BYTES] {
+        let mut b = [0u8; REC_BYTES];
+        let vals = [self.gene, self.x as u32, self.y as u32, self.mid, self.exon];
+        for (i, v) in vals.iter().enumerate() {
+            b[i * 4..i * 4 + 4].copy_from_slice(&v.to_le_bytes());
+        }
+        b
+    }
+
+    fn decode(b: &[u8; REC_BYTES]) -> Self {
+        let f = |i: usize| u32::from_le_bytes([b[i * 4], b[i * 4 + 1], b[i * 4 + 2], b[i * 4 + 3]]);
+        Rec {
+            gene: f(0),
+            x: f(1) as i32,
+            y: f(2) as i32,
+            mid: f(3),
+            exon: f(4),
+        }
+    }
+}
+
+/// 按 (gene, x, y) 排序后按 `policy` 合并同一基因同一坐标的记录，返回合并掉的重复行数；
+/// 合并失败（`policy` 为 error 或相加溢出）时返回出错的记录与原因。
+/// 排序是稳定的，同一坐标的记录保持读入顺序
+fn sort_and_merge(recs: &mut Vec<Rec>, policy: DupPolicy) -> Result<u64, (Rec, anyhow::Error)> {
+    recs.sort_by_key(|r| (r.gene, r.x, r.y));
+    let mut dups = 0;
+    let mut bad = None;
+    recs.dedup_by(|cur, prev| {
+        if bad.is_some() || (cur.gene, cur.x, cur.y) != (prev.gene, prev.x, prev.y) {
+            return false;
+        }
+        dups += 1;
+        let mut v = (prev.mid, prev.exon);
+        if let Err(e) = policy.merge(&mut v, (cur.mid, cur.exon)) {
+            bad = Some((*cur, e));
+        }
+        (prev.mid, prev.exon) = v;
+        true
+    });
+    bad.map_or(Ok(dups), Err)
+}
+
+/// 一个有序 run 文件；`segments`: gene 序号 -> (起始记录号, 记录数)
+struct Run {
+    file: File,
+    segments: HashMap<u32, (u64, u64)>,
+}
+
+/// 临时目录，Drop 时连同 run 文件一起删除
+struct SpillDir(PathBuf);
+
+impl SpillDir {
+    /// 在 `tmp_dir` 下建立本进程专用的子目录 `gem2gef-{name}-{pid}`
+    fn new(tmp_dir: &Path, name: &str) -> Result<Self> {
+        let dir = tmp_dir.join(format!("gem2gef-{}-{}", name, process::id()));
+        fs::create_dir_all(&dir).with_context(|| format!("create {}", dir.display()))?;
+        Ok(Self(dir))
+    }
+
+    /// 新建可读写的 run 文件
+    fn create(&self, name: &str) -> Result<File> {
+        let path = self.0.join(name);
+        File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .with_context(|| format!("create {}", path.display()))
+    }
+}
+
+impl Drop for SpillDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// 输出先写到目标文件旁的临时文件，`commit` 时改名为目标文件；未提交（出错）时 Drop 删除临时文件
+struct PartialOutput {
+    tmp: PathBuf,
+    dst: Option<PathBuf>,
+}
+
+impl PartialOutput {
+    fn new(output: &str) -> Self {
+        let dst = PathBuf::from(output);
+        let name = dst.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        let tmp = dst.with_file_name(format!(".{}.{}.tmp", name, process::id()));
+        Self {
+            tmp,
+            dst: Some(dst),
+        }
+    }
+
+    fn commit(mut self) -> io::Result<()> {
+        let dst = self.dst.take().expect("PartialOutput committed twice");
+        fs::rename(&self.tmp, dst)
+    }
+}
+
+impl Drop for PartialOutput {
+    fn drop(&mut self) {
+        if self.dst.is_some() {
+            let _ = fs::remove_file(&self.tmp);
+        }
+    }
+}
+
+/// 外部排序器：攒记录、落盘 run、按基因归并
+pub struct SpillSorter {
+    dir: SpillDir,
+    cap: usize,
+    buf: Vec<Rec>,
+    runs: Vec<Run>,
+    /// gene 序号 -> (geneID, geneName)
+    genes: Vec<(String, String)>,
+    /// geneID -> [(geneName, gene 序号)]，按 &str 查询不必为每行分配字符串
+    gene_idx: HashMap<String, Vec<(String, u32)>>,
+    pub min_x: i32,
+    pub min_y: i32,
+    pub max_x: i32,
+    pub max_y: i32,
+    pub records: u64,
+    /// 重复 (gene, x, y) 的处理方式与已合并的重复行数
+    policy: DupPolicy,
+    pub duplicates: u64,
+}
+
+impl SpillSorter {
+    /// `max_mem` 为记录缓冲可用的字节数，`cap` 给出时直接指定每个 run 的记录数；
+    /// run 文件写在 `tmp_dir` 下的独立子目录中
+    pub fn new(
+        tmp_dir: &Path,
+        max_mem: usize,
+        cap: Option<usize>,
+        policy: DupPolicy,
+    ) -> Result<Self> {
+        let cap = cap.unwrap_or((max_mem / mem::size_of::<Rec>()).max(1 << 10)).max(1);
+        Ok(Self {
+            dir: SpillDir::new(tmp_dir, "spill")?,
+            cap,
+            buf: Vec::with_capacity(cap),
+            runs: Vec::new(),
+            genes: Vec::new(),
+            gene_idx: HashMap::new(),
+            min_x: i32::MAX,
+            min_y: i32::MAX,
+            max_x: i32::MIN,
+            max_y: i32::MIN,
+            records: 0,
+            policy,
+            duplicates: 0,
+        })
+    }
+
+    fn gene_index(&mut self, gene: &str, gene_name: &str) -> u32 {
+        if let Some(names) = self.gene_idx.get(gene) {
+            if let Some(&(_, idx)) = names.iter().find(|(n, _)| n == gene_name) {
+                return idx;
+            }
+        }
+        let idx = self.genes.len() as u32;
+        self.genes.push((gene.to_string(), gene_name.to_string()));
+        self.gene_idx.entry(gene.to_string()).or_default().push((gene_name.to_string(), idx));
+        idx
+    }
+
+    pub fn push(
+        &mut self,
+        gene: &str,
+        gene_name: &str,
+        x: i32,
+        y: i32,
+        mid: u32,
+        exon: u32,
+    ) -> Result<()> {
+        let gene = self.gene_index(gene, gene_name);
+        self.min_x = self.min_x.min(x);
+        self.min_y = self.min_y.min(y);
+        self.max_x = self.max_x.max(x);
+        self.max_y = self.max_y.max(y);
+        self.records += 1;
+        self.buf.push(Rec {
+            gene,
+            x,
+            y,
+            mid,
+            exon,
+        });
+        if self.buf.len() >= self.cap {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// 合并重复记录失败时，在原因上补充基因与坐标
+    fn dup_error(&self, (r, e): (Rec, anyhow::Error)) -> anyhow::Error {
+        let (gene_id, gene_name) = &self.genes[r.gene as usize];
+        e.context(format!(
+            "geneID={} geneName={} x={} y={}",
+            gene_id, gene_name, r.x, r.y
+        ))
+    }
+
+    /// 把缓冲排序、合并后写成一个 run 文件
+    fn spill(&mut self) -> Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        self.duplicates +=
+            sort_and_merge(&mut self.buf, self.policy).map_err(|e| self.dup_error(e))?;
+        let file = self.dir.create(&format!("run{:05}.bin", self.runs.len()))?;
+        let mut segments: HashMap<u32, (u64, u64)> = HashMap::new();
+        let mut w = BufWriter::new(&file);
+        for (i, r) in self.buf.iter().enumerate() {
+            segments.entry(r.gene).or_insert((i as u64, 0)).1 += 1;
+            w.write_all(&r.encode())?;
+        }
+        w.flush()?;
+        drop(w);
+        log_msg(&format!(
+            "spilled run {} ({} records)",
+            self.runs.len(),
+            self.buf.len()
+        ));
+        self.runs.push(Run { file, segments });
+        self.buf.clear();
+        Ok(())
+    }
+
+    /// 读入结束：把剩余缓冲落盘并释放记录缓冲，之后的内存留给第 2 步
+    pub fn finish_input(&mut self) -> Result<()> {
+        self.spill()?;
+        self.buf = Vec::new();
+        Ok(())
+    }
+
+    /// 按 (geneID, geneName) 升序依次产出每个基因合并后的 bin1 记录（按 (x,y) 排序）；
+    /// 返回全部重复行数
+    pub fn for_each_gene<F>(mut self, mut f: F) -> Result<u64, Box<dyn Error>>
+    where
+        F: FnMut(&str, &str, &[((i32, i32), (u32, u32))]) -> Result<(), Box<dyn Error>>,
+    {
+        self.finish_input()?;
+
+        let mut order: Vec<u32> = (0..self.genes.len() as u32).collect();
+        order.sort_by(|&a, &b| self.genes[a as usize].cmp(&self.genes[b as usize]));
+
+        let mut recs: Vec<Rec> = Vec::new();
+        let mut out: Vec<((i32, i32), (u32, u32))> = Vec::new();
+        let mut b = [0u8; REC_BYTES];
+        for g in order {
+            // 1) 从各 run 读出该基因的片段
+            recs.clear();
+            for run in &mut self.runs {
+                let Some(&(start, count)) = run.segments.get(&g) else {
+                    continue;
+                };
+                run.file.seek(SeekFrom::Start(start * REC_BYTES as u64))?;
+                let mut r = BufReader::new(&run.file).take(count * REC_BYTES as u64);
+                for _ in 0..count {
+                    r.read_exact(&mut b)?;
+                    recs.push(Rec::decode(&b));
+                }
+            }
+            // 2) 归并不同 run 中的相同坐标（run 按读入顺序排列）
+            self.duplicates +=
+                sort_and_merge(&mut recs, self.policy).map_err(|e| self.dup_error(e))?;
+            out.clear();
+            out.extend(recs.iter().map(|r| ((r.x, r.y), (r.mid, r.exon))));
+
+            let (gene_id, gene_name) = &self.genes[g as usize];
+            f(gene_id, gene_name, &out)?;
+        }
+        Ok(self.duplicates)
+    }
+}
+
+/// 一个 bin 的 spot 统计落盘后的 run：每个 run 按 `TileKey` 排序，归并时同一 spot 的计数相加
+struct SpotRuns {
+    name: String,
+    bin_size: u32,
+    /// 该 bin 的 (minX, minY)，`TileKey` 的原点
+    origin: (i32, i32),
+    tile: usize,
+    /// 内存中最多累计的 spot 数
+    cap: usize,
+    /// 内存中累计过的最大 spot 数
+    peak: usize,
+    /// (run 文件, spot 数)
+    runs: Vec<(File, u64)>,
+}
+
+impl SpotRuns {
+    fn new(name: String, bin_size: u32, origin: (i32, i32), tile: usize, cap: usize) -> Self {
+        Self {
+            name,
+            bin_size,
+            origin,
+            tile,
+            cap: cap.max(1),
+            peak: 0,
+            runs: Vec::new(),
+        }
+    }
+
+    fn key(&self, xy: (i32, i32)) -> TileKey {
+        tile_key(self.origin, self.bin_size, self.tile, xy)
+    }
+
+    /// 把内存中累计的 spot 统计按 `TileKey` 排序后写成一个 run
+    fn spill<I>(&mut self, dir: &SpillDir, spots: I) -> Result<()>
+    where
+        I: IntoIterator<Item = SpotStat>,
+    {
+        let mut spots: Vec<SpotStat> = spots.into_iter().collect();
+        if spots.is_empty() {
+            return Ok(());
+        }
+        spots.sort_unstable_by_key(|s| self.key(s.0));
+        let file = dir.create(&format!("{}-{:05}.bin", self.name, self.runs.len()))?;
+        let mut w = BufWriter::new(&file);
+        for &((x, y), spot, exon) in &spots {
+            w.write_all(&x.to_le_bytes())?;
+            w.write_all(&y.to_le_bytes())?;
+            w.write_all(&spot.MIDcount.to_le_bytes())?;
+            w.write_all(&spot.genecount.to_le_bytes())?;
+            w.write_all(&exon.to_le_bytes())?;
+        }
+        w.flush()?;
+        drop(w);
+        self.runs.push((file, spots.len() as u64));
+        Ok(())
+    }
+
+    /// 归并全部 run，按 `TileKey` 顺序产出每个 spot 的统计
+    fn merge(self) -> Result<SpotMerge> {
+        log_msg(&format!(
+            "spot statistics {}: runs={} peak={} limit={}",
+            self.name,
+            self.runs.len(),
+            self.peak,
+            self.cap
+        ));
+        let mut merge = SpotMerge {
+            readers: Vec::with_capacity(self.runs.len()),
+            heads: Vec::with_capacity(self.runs.len()),
+            heap: BinaryHeap::with_capacity(self.runs.len()),
+            runs: self,
+        };
+        for (mut file, count) in mem::take(&mut merge.runs.runs) {
+            file.seek(SeekFrom::Start(0))?;
+            merge.readers.push((BufReader::new(file), count));
+            merge.heads.push(((0, 0), SpotGene::default(), 0));
+            merge.advance(merge.readers.len() - 1)?;
+        }
+        Ok(merge)
+    }
+}
+
+/// 多个 spot run 的 k 路归并
+struct SpotMerge {
+    runs: SpotRuns,
+    /// (run 读取器, 剩余 spot 数)
+    readers: Vec<(BufReader<File>, u64)>,
+    /// 每个 run 当前的 spot
+    heads: Vec<SpotStat>,
+    heap: BinaryHeap<Reverse<(TileKey, usize)>>,
+}
+
+impl SpotMerge {
+    /// 读入第 `i` 个 run 的下一个 spot
+    fn advance(&mut self, i: usize) -> io::Result<()> {
+        let (r, left) = &mut self.readers[i];
+        if *left == 0 {
+            return Ok(());
+        }
+        *left -= 1;
+        let mut b = [0u8; SPOT_BYTES];
+        r.read_exact(&mut b)?;
+        let f = |i: usize| [b[i], b[i + 1], b[i + 2], b[i + 3]];
+        let xy = (i32::from_le_bytes(f(0)), i32::from_le_bytes(f(4)));
+        let spot = SpotGene {
+            MIDcount: u32::from_le_bytes(f(8)),
+            genecount: u16::from_le_bytes([b[12], b[13]]),
+        };
+        self.heads[i] = (xy, spot, u32::from_le_bytes(f(14)));
+        self.heap.push(Reverse((self.runs.key(xy), i)));
+        Ok(())
+    }
+
+    /// 取出下一个 spot，并把其他 run 中同一 spot 的计数加到它上面；相加超出 wholeExp 字段类型时报错
+    fn next_spot(&mut self) -> Result<Option<SpotStat>, Box<dyn Error>> {
+        let Some(Reverse((key, i))) = self.heap.pop() else {
+            return Ok(None);
+        };
+        let (xy, mut spot, mut exon) = self.heads[i];
+        self.advance(i)?;
+        while let Some(&Reverse((k, j))) = self.heap.peek() {
+            if k != key {
+                break;
+            }
+            self.heap.pop();
+            let (_, other, other_exon) = self.heads[j];
+            let overflow = |what: &str| {
+                format!(
+                    "bin{} spot ({}, {}) 的{}",
+                    self.runs.bin_size, xy.0, xy.1, what
+                )
+            };
+            spot.MIDcount = spot
+                .MIDcount
+                .checked_add(other.MIDcount)
+                .ok_or_else(|| overflow("MIDcount 合计溢出 uint32"))?;
+            spot.genecount = spot
+                .genecount
+                .checked_add(other.genecount)
+                .ok_or_else(|| overflow("基因数超过 65535（wholeExp 的 genecount 为 uint16）"))?;
+            exon = exon
+                .checked_add(other_exon)
+                .ok_or_else(|| overflow("ExonCount 合计溢出 uint32"))?;
+            self.advance(j)?;
+        }
+        Ok(Some((xy, spot, exon)))
+    }
+}
+
+impl Iterator for SpotMerge {
+    type Item = Result<SpotStat, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_spot().transpose()
+    }
+}
+
+/// 有内存上限的完整转换：GEM -> 外部排序 -> 逐基因流式写入 bGEF。
+///
+/// `spill_records`/`spill_spots` 给出时按该数目切分记录 run 与 spot run（而非由 `max_mem` 推算），
+/// 用于测试多 run 归并
+#[allow(clippy::too_many_arguments)]
+pub fn convert(
+    input: &str,
+    hdr: &Header,
+    offset: (i32, i32),
+    bin_sizes: &[u32],
+    gene_map: Option<&GeneMap>,
+    output: &str,
+    resolution: u16,
+    max_mem: usize,
+    spill_records: Option<usize>,
+    spill_spots: Option<usize>,
+    tmp_dir: &Path,
+    opts: &DatasetOpts,
+    policy: DupPolicy,
+    roi: Option<&Roi>,
+) -> Result<(), Box<dyn Error>> {
+    let budget = MemBudget::split(max_mem);
+
+    // 1. 读取 GEM 并分段落盘（给出 roi 时只保留其中的记录）；基因标识先按对照表解析
+    let mut sorter = SpillSorter::new(tmp_dir, budget.records, spill_records, policy)?;
+    let mut resolver = GeneResolver::new(gene_map);
+    for_each_record(input, hdr, offset, |gene, gene_name, x, y, mid, exon| {
+        if roi.is_some_and(|r| !r.contains(x, y)) {
+            return Ok(());
+        }
+        let (gene, gene_name) = resolver.resolve(gene, gene_name);
+        sorter.push(gene, gene_name, x, y, mid, exon)
+    })?;
+    sorter.finish_input()?;
+    log_msg(&format!(
+        "GEM bin{} info (bin1 coordinates):\n  records={}  runs={}\n  minX={}  maxX={}\n  minY={}  maxY={}",
+        hdr.bin_size,
+        sorter.records,
+        sorter.runs.len(),
+        sorter.min_x, sorter.max_x,
+        sorter.min_y, sorter.max_y,
+    ));
+    if sorter.records == 0 {
+        return Err(format!("{} 中没有表达记录", input).into());
+    }
+
+    // 2. 为每个 bin 建立可追加的 expression/exon 数据集与 spot run；
+    //    gef_area 只看 GEM 本身的 bin：最小输出 bin 即为它时直接用其 spot 数，否则另行计数
+    let base_is_first = bin_sizes.first() == Some(&hdr.bin_size);
+    let spot_tables = bin_sizes.len() + usize::from(!base_is_first);
+    let rec_bytes = ExpressionAppender::record_bytes(hdr.has_exon);
+    let append_len =
+        (budget.append / (bin_sizes.len().max(1) * rec_bytes)).clamp(1 << 8, APPEND_BUF);
+    let spot_cap = spill_spots.unwrap_or(budget.spots / (spot_tables * SPOT_MEM));
+    log_msg(&format!(
+        "memory budget: records={} KiB  append={} KiB ({} records per bin)  spots={} KiB ({} spots per bin)",
+        budget.records >> 10,
+        budget.append >> 10,
+        append_len,
+        budget.spots >> 10,
+        spot_cap,
+    ));
+    let tile = opts.whole_chunk.max(1);
+    let origin = |bin_size| {
+        (
+            bin_origin(sorter.min_x, bin_size),
+            bin_origin(sorter.min_y, bin_size),
+        )
+    };
+    let spot_dir = SpillDir::new(tmp_dir, "spots")?;
+    let mut base = (!base_is_first).then(|| {
+        let runs = SpotRuns::new(
+            format!("base-bin{}", hdr.bin_size),
+            hdr.bin_size,
+            origin(hdr.bin_size),
+            tile,
+            spot_cap,
+        );
+        (HashSet::new(), runs)
+    });
+    let out = PartialOutput::new(output);
+    {
+        let f = H5File::create(&out.tmp)?;
+        let gene_exp = f.create_group("geneExp")?;
+        let whole_exp = f.create_group("wholeExp")?;
+        let whole_exon = f.create_group("wholeExpExon")?;
+        let mut levels = Vec::with_capacity(bin_sizes.len());
+        for &bin_size in bin_sizes {
+            let group = gene_exp.create_group(&format!("bin{}", bin_size))?;
+            let appender = ExpressionAppender::new(&group, hdr.has_exon, opts, append_len)?;
+            let name = format!("bin{}", bin_size);
+            let spots = SpotRuns::new(name, bin_size, origin(bin_size), tile, spot_cap);
+            levels.push((
+                group,
+                BinAccumulator::new(bin_size, hdr.has_exon),
+                appender,
+                spots,
+            ));
+        }
+
+        // 3. 逐基因归并并写入各 bin；/stat/gene 由 GEM 本身的 bin（bin1 或 #BinSize）统计。
+        //    基因加入前先把 spot 统计落盘，内存中的 spot 数不超过上限（单个基因的 spot 数本身超过时除外）
+        let mut stats = Vec::new();
+        let duplicates = sorter.for_each_gene(|gene_id, gene_name, bin1| {
+            stats.push(stat_row(
+                gene_id,
+                gene_name,
+                bin1.iter().map(|&(_, (mid, _))| mid),
+            )?);
+            if let Some((set, runs)) = &mut base {
+                if set.len() + bin1.len() > runs.cap {
+                    runs.spill(
+                        &spot_dir,
+                        set.drain().map(|xy| (xy, SpotGene::default(), 0)),
+                    )?;
+                }
+                set.extend(bin1.iter().map(|&(xy, _)| xy));
+                runs.peak = runs.peak.max(set.len());
+            }
+            for (_, acc, appender, spots) in levels.iter_mut() {
+                if acc.spot_count() + bin1.len() > spots.cap {
+                    spots.spill(&spot_dir, acc.take_spots())?;
+                }
+                let recs = acc.push_gene(gene_id, gene_name, bin1.iter().copied())?;
+                for ((x, y), (mid, exon)) in recs {
+                    appender.push(x, y, mid, exon)?;
+                }
+                spots.peak = spots.peak.max(acc.spot_count());
+            }
+            Ok(())
+        })?;
+        log_msg(&format!(
+            "duplicate (gene, x, y) rows: {} (policy={})",
+            duplicates,
+            policy.name()
+        ));
+
+        // 4. 补写各 bin 的属性与 gene 表，归并 spot run 写入 wholeExp
+        let mut spots = 0;
+        if let Some((set, mut runs)) = base {
+            runs.spill(
+                &spot_dir,
+                set.into_iter().map(|xy| (xy, SpotGene::default(), 0)),
+            )?;
+            for spot in runs.merge()? {
+                spot?;
+                spots += 1;
+            }
+        }
+        for (li, (group, mut acc, appender, mut runs)) in levels.into_iter().enumerate() {
+            let (ds_expr, ds_exon) = appender.finish()?;
+            runs.spill(&spot_dir, acc.take_spots())?;
+            let bin = acc.finish(Vec::new(), Vec::new());
+            debug_assert_eq!(runs.origin, (bin.min_x, bin.min_y));
+            write_expression_attrs(&ds_expr, &bin, resolution)?;
+            if let Some(ds_exon) = ds_exon {
+                ds_exon.new_attr::<u32>().create("maxExon")?.write_scalar(&bin.max_exon)?;
+            }
+            write_gene(&group, &bin.genes_meta, opts)?;
+            let summary = write_whole_exp(
+                &whole_exp,
+                &whole_exon,
+                &bin,
+                runs.merge()?,
+                resolution,
+                opts,
+            )?;
+            bin.log_summary(&summary, resolution);
+            if li == 0 && base_is_first {
+                spots = summary.number;
+            }
+        }
+        let area = gef_area(spots, hdr.bin_size, resolution);
+        log_msg(&format!("gef_area={:.4} mm²", area));
+
+        // 5. 根属性与 /stat/gene
+        write_root_attrs(&f, hdr, area, offset)?;
+        write_duplicate_attrs(&f, policy, duplicates)?;
+        sort_stats(&mut stats);
+        write_stat_gene(&f, &stats, opts)?;
+    }
+    // 6. 文件句柄全部关闭后再改名为目标文件
+    out.commit().with_context(|| format!("rename to {}", output))?;
+    Ok(())
+}
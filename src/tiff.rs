@@ -0,0 +1,544 @@
+//! 读写单通道 TIFF（细胞分割 mask）。
+//!
+//! 读取只实现 mask 常见的 baseline 子集：经典 TIFF 与 BigTIFF、大小端、strip 与 tile、
+//! 无压缩 / LZW / Deflate / PackBits、水平差分 predictor、1/8/16/32 位无符号或有符号整数。
+//! 多通道图只取第一个通道，多页图只读第一页。
+//! 写出为 8/16/32 位无符号灰度、按 strip 存储，可选 Deflate 压缩；超过 4 GiB 时自动写 BigTIFF。
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Write};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+
+/// 解码后的单通道图像，`data[row * width + col]`，像素 (x=col, y=row)
+#[derive(Debug, Clone)]
+pub struct Raster {
+    /// 窗口左上角在原图中的位置
+    pub x0: usize,
+    pub y0: usize,
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<u32>,
+}
+
+struct Cursor<'a> {
+    buf: &'a [u8],
+    big_endian: bool,
+}
+
+impl Cursor<'_> {
+    fn bytes(&self, pos: u64, n: usize) -> Result<&[u8]> {
+        let pos = usize::try_from(pos)?;
+        self.buf.get(pos..pos + n).ok_or_else(|| anyhow!("TIFF 在偏移 {} 处被截断", pos))
+    }
+
+    fn uint(&self, pos: u64, n: usize) -> Result<u64> {
+        let b = self.bytes(pos, n)?;
+        let mut v = 0u64;
+        if self.big_endian {
+            for &x in b {
+                v = (v << 8) | u64::from(x);
+            }
+        } else {
+            for &x in b.iter().rev() {
+                v = (v << 8) | u64::from(x);
+            }
+        }
+        Ok(v)
+    }
+}
+
+/// 第一页 IFD 中用到的标签
+#[derive(Default)]
+struct Ifd {
+    width: usize,
+    height: usize,
+    bits: usize,
+    compression: u64,
+    photometric: u64,
+    samples: usize,
+    planar: u64,
+    predictor: u64,
+    sample_format: u64,
+    rows_per_strip: usize,
+    strip_offsets: Vec<u64>,
+    strip_counts: Vec<u64>,
+    tile_width: usize,
+    tile_height: usize,
+    tile_offsets: Vec<u64>,
+    tile_counts: Vec<u64>,
+}
+
+fn read_ifd(c: &Cursor) -> Result<Ifd> {
+    let magic = c.uint(2, 2)?;
+    let big_tiff = match magic {
+        42 => false,
+        43 => true,
+        _ => bail!("不是 TIFF 文件（magic={}）", magic),
+    };
+    let (off_size, ifd_pos) = if big_tiff {
+        (8, c.uint(8, 8)?)
+    } else {
+        (4, c.uint(4, 4)?)
+    };
+    let (count, entry_size, mut pos) = if big_tiff {
+        (c.uint(ifd_pos, 8)?, 20u64, ifd_pos + 8)
+    } else {
+        (c.uint(ifd_pos, 2)?, 12u64, ifd_pos + 2)
+    };
+
+    let mut ifd = Ifd {
+        bits: 1,
+        compression: 1,
+        photometric: 1,
+        samples: 1,
+        planar: 1,
+        predictor: 1,
+        sample_format: 1,
+        ..Default::default()
+    };
+    for _ in 0..count {
+        let tag = c.uint(pos, 2)?;
+        let ty = c.uint(pos + 2, 2)?;
+        let n = c.uint(pos + 4, off_size)? as usize;
+        let size = match ty {
+            1 | 2 | 6 | 7 => 1,
+            3 | 8 => 2,
+            4 | 9 | 11 | 13 => 4,
+            16..=18 => 8,
+            _ => 0, // 用不到的类型（RATIONAL 等）
+        };
+        let value_pos = pos + 4 + off_size as u64;
+        let data_pos = if size * n <= off_size {
+            value_pos
+        } else {
+            c.uint(value_pos, off_size)?
+        };
+        let values = || -> Result<Vec<u64>> {
+            (0..n).map(|i| c.uint(data_pos + (i * size) as u64, size)).collect()
+        };
+        let first = || -> Result<u64> {
+            values()?.first().copied().ok_or_else(|| anyhow!("空标签 {}", tag))
+        };
+        match tag {
+            256 => ifd.width = first()? as usize,
+            257 => ifd.height = first()? as usize,
+            258 => ifd.bits = first()? as usize,
+            259 => ifd.compression = first()?,
+            262 => ifd.photometric = first()?,
+            273 => ifd.strip_offsets = values()?,
+            277 => ifd.samples = first()? as usize,
+            278 => ifd.rows_per_strip = first()? as usize,
+            279 => ifd.strip_counts = values()?,
+            284 => ifd.planar = first()?,
+            317 => ifd.predictor = first()?,
+            322 => ifd.tile_width = first()? as usize,
+            323 => ifd.tile_height = first()? as usize,
+            324 => ifd.tile_offsets = values()?,
+            325 => ifd.tile_counts = values()?,
+            339 => ifd.sample_format = first()?,
+            _ => {}
+        }
+        pos += entry_size;
+    }
+    ensure!(ifd.width > 0 && ifd.height > 0, "TIFF 宽高无效");
+    ensure!(
+        matches!(ifd.bits, 1 | 8 | 16 | 32),
+        "不支持 {} 位的 TIFF",
+        ifd.bits
+    );
+    ensure!(
+        matches!(ifd.sample_format, 1 | 2),
+        "mask 必须是整数 TIFF（SampleFormat={}）",
+        ifd.sample_format
+    );
+    if ifd.rows_per_strip == 0 {
+        ifd.rows_per_strip = ifd.height;
+    }
+    Ok(ifd)
+}
+
+/// TIFF 变体 LZW（MSB 优先，提前一位扩展码宽）
+fn lzw_decode(input: &[u8]) -> Result<Vec<u8>> {
+    const CLEAR: usize = 256;
+    const EOI: usize = 257;
+    let mut table: Vec<Vec<u8>> = (0..=255u8).map(|b| vec![b]).collect();
+    table.push(Vec::new());
+    table.push(Vec::new());
+    let mut out = Vec::new();
+    let (mut width, mut bitpos) = (9usize, 0usize);
+    let mut prev: Option<usize> = None;
+    while bitpos + width <= input.len() * 8 {
+        let mut code = 0usize;
+        for i in 0..width {
+            let bit = (input[(bitpos + i) / 8] >> (7 - (bitpos + i) % 8)) & 1;
+            code = (code << 1) | bit as usize;
+        }
+        bitpos += width;
+        if code == CLEAR {
+            table.truncate(258);
+            width = 9;
+            prev = None;
+            continue;
+        }
+        if code == EOI {
+            break;
+        }
+        let entry = match (code < table.len(), prev) {
+            (true, _) => table[code].clone(),
+            (false, Some(p)) if code == table.len() => {
+                let mut e = table[p].clone();
+                e.push(table[p][0]);
+                e
+            }
+            _ => bail!("LZW 数据损坏（code={}）", code),
+        };
+        out.extend_from_slice(&entry);
+        if let Some(p) = prev {
+            let mut e = table[p].clone();
+            e.push(entry[0]);
+            table.push(e);
+        }
+        prev = Some(code);
+        if table.len() + 1 >= 1 << width && width < 12 {
+            width += 1;
+        }
+    }
+    Ok(out)
+}
+
+fn packbits_decode(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < input.len() {
+        let n = input[i] as i8;
+        i += 1;
+        if n >= 0 {
+            let end = (i + n as usize + 1).min(input.len());
+            out.extend_from_slice(&input[i..end]);
+            i = end;
+        } else if n != -128 {
+            if let Some(&b) = input.get(i) {
+                out.extend(std::iter::repeat_n(b, (1 - n as isize) as usize));
+            }
+            i += 1;
+        }
+    }
+    out
+}
+
+fn decompress(ifd: &Ifd, raw: &[u8]) -> Result<Vec<u8>> {
+    Ok(match ifd.compression {
+        1 => raw.to_vec(),
+        5 => lzw_decode(raw)?,
+        8 | 32946 => {
+            let mut out = Vec::new();
+            ZlibDecoder::new(raw).read_to_end(&mut out)?;
+            out
+        }
+        32773 => packbits_decode(raw),
+        other => bail!("不支持的 TIFF 压缩方式 {}", other),
+    })
+}
+
+/// 把一个 strip/tile 解成 `rows` 行、每行 `row_samples` 个样本
+fn unpack(
+    ifd: &Ifd,
+    c: &Cursor,
+    bytes: &[u8],
+    rows: usize,
+    row_samples: usize,
+) -> Result<Vec<u32>> {
+    let mut out = Vec::with_capacity(rows * row_samples);
+    if ifd.bits == 1 {
+        let row_bytes = row_samples.div_ceil(8);
+        ensure!(bytes.len() >= rows * row_bytes, "TIFF 数据块长度不足");
+        let invert = ifd.photometric == 0;
+        for r in 0..rows {
+            for i in 0..row_samples {
+                let bit = u32::from((bytes[r * row_bytes + i / 8] >> (7 - i % 8)) & 1);
+                out.push(if invert { 1 - bit } else { bit });
+            }
+        }
+        return Ok(out);
+    }
+    let size = ifd.bits / 8;
+    ensure!(
+        bytes.len() >= rows * row_samples * size,
+        "TIFF 数据块长度不足"
+    );
+    let cur = Cursor {
+        buf: bytes,
+        big_endian: c.big_endian,
+    };
+    for i in 0..rows * row_samples {
+        out.push(cur.uint((i * size) as u64, size)? as u32);
+    }
+    if ifd.predictor == 2 {
+        let spp = if ifd.planar == 2 { 1 } else { ifd.samples };
+        let mask = if ifd.bits == 32 {
+            u32::MAX
+        } else {
+            (1u32 << ifd.bits) - 1
+        };
+        for row in out.chunks_mut(row_samples) {
+            for i in spp..row.len() {
+                row[i] = row[i].wrapping_add(row[i - spp]) & mask;
+            }
+        }
+    } else if ifd.predictor != 1 {
+        bail!("不支持的 TIFF predictor {}", ifd.predictor);
+    }
+    Ok(out)
+}
+
+/// 已解析首个 IFD 的 TIFF 文件
+pub struct TiffImage {
+    buf: Vec<u8>,
+    big_endian: bool,
+    ifd: Ifd,
+}
+
+impl TiffImage {
+    pub fn open(path: &str) -> Result<Self> {
+        let buf = fs::read(path).with_context(|| format!("open {}", path))?;
+        ensure!(buf.len() >= 8, "{} 不是 TIFF 文件", path);
+        let big_endian = match &buf[..2] {
+            b"II" => false,
+            b"MM" => true,
+            _ => bail!("{} 不是 TIFF 文件", path),
+        };
+        let c = Cursor {
+            buf: &buf,
+            big_endian,
+        };
+        let ifd = read_ifd(&c).with_context(|| path.to_string())?;
+        Ok(Self {
+            buf,
+            big_endian,
+            ifd,
+        })
+    }
+
+    /// 第一页的宽、高
+    pub fn size(&self) -> (usize, usize) {
+        (self.ifd.width, self.ifd.height)
+    }
+
+    /// 解码第一页的第一个通道；`window = (x0, y0, w, h)` 时只保留该窗口（会被裁到图像范围内）
+    pub fn read(&self, window: Option<(usize, usize, usize, usize)>) -> Result<Raster> {
+        let ifd = &self.ifd;
+        let c = Cursor {
+            buf: &self.buf,
+            big_endian: self.big_endian,
+        };
+        let (x0, y0, w, h) = window.unwrap_or((0, 0, ifd.width, ifd.height));
+        let x0 = x0.min(ifd.width);
+        let y0 = y0.min(ifd.height);
+        let (x1, y1) = ((x0 + w).min(ifd.width), (y0 + h).min(ifd.height));
+        let mut raster = Raster {
+            x0,
+            y0,
+            width: x1 - x0,
+            height: y1 - y0,
+            data: vec![0; (x1 - x0) * (y1 - y0)],
+        };
+        // 行内相邻像素的样本间隔（planar=2 时第一个平面的样本是连续的）
+        let spp = if ifd.planar == 2 { 1 } else { ifd.samples };
+
+        // 把一个块 (bx, by, bw, bh) 的第一通道写进窗口
+        let mut place =
+            |bx: usize, by: usize, bw: usize, bh: usize, samples: &[u32], stride: usize| {
+                for r in 0..bh {
+                    let y = by + r;
+                    if y < y0 || y >= y1 {
+                        continue;
+                    }
+                    for col in 0..bw {
+                        let x = bx + col;
+                        if x >= x0 && x < x1 {
+                            raster.data[(y - y0) * raster.width + (x - x0)] =
+                                samples[r * stride + col * spp];
+                        }
+                    }
+                }
+            };
+
+        if ifd.tile_width > 0 && ifd.tile_height > 0 {
+            let (tw, th) = (ifd.tile_width, ifd.tile_height);
+            let across = ifd.width.div_ceil(tw);
+            let down = ifd.height.div_ceil(th);
+            ensure!(
+                ifd.tile_offsets.len() >= across * down,
+                "TileOffsets 数量不足"
+            );
+            for ty in 0..down {
+                for tx in 0..across {
+                    let (bx, by) = (tx * tw, ty * th);
+                    if bx >= x1 || bx + tw <= x0 || by >= y1 || by + th <= y0 {
+                        continue;
+                    }
+                    let i = ty * across + tx;
+                    let count = *ifd
+                        .tile_counts
+                        .get(i)
+                        .ok_or_else(|| anyhow!("TileByteCounts 数量不足"))?;
+                    let raw = c.bytes(ifd.tile_offsets[i], count as usize)?;
+                    let samples = unpack(ifd, &c, &decompress(ifd, raw)?, th, tw * spp)?;
+                    place(
+                        bx,
+                        by,
+                        (ifd.width - bx).min(tw),
+                        (ifd.height - by).min(th),
+                        &samples,
+                        tw * spp,
+                    );
+                }
+            }
+        } else {
+            let rps = ifd.rows_per_strip;
+            let strips = ifd.height.div_ceil(rps);
+            ensure!(ifd.strip_offsets.len() >= strips, "StripOffsets 数量不足");
+            for s in 0..strips {
+                let by = s * rps;
+                let bh = (ifd.height - by).min(rps);
+                if by >= y1 || by + bh <= y0 {
+                    continue;
+                }
+                let count =
+                    *ifd.strip_counts.get(s).ok_or_else(|| anyhow!("StripByteCounts 数量不足"))?;
+                let raw = c.bytes(ifd.strip_offsets[s], count as usize)?;
+                let samples = unpack(ifd, &c, &decompress(ifd, raw)?, bh, ifd.width * spp)?;
+                place(0, by, ifd.width, bh, &samples, ifd.width * spp);
+            }
+        }
+        Ok(raster)
+    }
+}
+
+/// 读取 TIFF 第一页的第一个通道；`window = (x0, y0, w, h)` 时只保留该窗口（会被裁到图像范围内）
+pub fn read_tiff(path: &str, window: Option<(usize, usize, usize, usize)>) -> Result<Raster> {
+    TiffImage::open(path)?.read(window)
+}
+
+/// 把 `raster` 写成单页灰度 TIFF；`bits` 为 8/16/32，超出位宽的值会报错
+pub fn write_tiff(path: &str, raster: &Raster, bits: usize, deflate: bool) -> Result<()> {
+    ensure!(matches!(bits, 8 | 16 | 32), "不支持写出 {} 位的 TIFF", bits);
+    let (w, h) = (raster.width, raster.height);
+    ensure!(w > 0 && h > 0, "TIFF 宽高不能为 0");
+    let max = if bits == 32 {
+        u32::MAX
+    } else {
+        (1u32 << bits) - 1
+    };
+    if let Some(v) = raster.data.iter().find(|&&v| v > max) {
+        bail!("像素值 {} 超出 {} 位 TIFF 的范围", v, bits);
+    }
+
+    // 每个 strip 约 1 MiB
+    let row_bytes = w * bits / 8;
+    let rows_per_strip = ((1 << 20) / row_bytes).clamp(1, h);
+    let mut strips = Vec::new();
+    for rows in raster.data.chunks(rows_per_strip * w) {
+        let mut raw = Vec::with_capacity(rows.len() * bits / 8);
+        for &v in rows {
+            match bits {
+                8 => raw.push(v as u8),
+                16 => raw.extend_from_slice(&(v as u16).to_le_bytes()),
+                _ => raw.extend_from_slice(&v.to_le_bytes()),
+            }
+        }
+        if deflate {
+            let mut enc = ZlibEncoder::new(Vec::new(), Compression::default());
+            enc.write_all(&raw)?;
+            raw = enc.finish()?;
+        }
+        strips.push(raw);
+    }
+
+    let data_len: u64 = strips.iter().map(|s| s.len() as u64).sum();
+    let big = data_len + 4096 + strips.len() as u64 * 16 > u64::from(u32::MAX);
+    let (off_size, header_len) = if big { (8usize, 16u64) } else { (4, 8) };
+    let mut offsets = Vec::with_capacity(strips.len());
+    let mut pos = header_len;
+    for s in &strips {
+        offsets.push(pos);
+        pos += s.len() as u64;
+    }
+    let counts: Vec<u64> = strips.iter().map(|s| s.len() as u64).collect();
+    let ifd_pos = pos + pos % 2;
+
+    // (tag, 类型, 值)；类型 3 = SHORT，4 = LONG，16 = LONG8
+    let long = if big { 16u16 } else { 4 };
+    let tags: [(u16, u16, Vec<u64>); 10] = [
+        (256, 4, vec![w as u64]),
+        (257, 4, vec![h as u64]),
+        (258, 3, vec![bits as u64]),
+        (259, 3, vec![if deflate { 8 } else { 1 }]),
+        (262, 3, vec![1]),
+        (273, long, offsets),
+        (277, 3, vec![1]),
+        (278, 4, vec![rows_per_strip as u64]),
+        (279, long, counts),
+        (284, 3, vec![1]),
+    ];
+    let entry_len = if big { 20 } else { 12 };
+    // 放不进条目的数组紧跟在 IFD（条目数 + 条目 + 下一页偏移）之后
+    let count_len = if big { 8 } else { 2 };
+    let mut extra_pos = ifd_pos + (count_len + tags.len() * entry_len + off_size) as u64;
+    let mut entries = Vec::new();
+    let mut extra = Vec::new();
+    let put = |buf: &mut Vec<u8>, v: u64, n: usize| buf.extend_from_slice(&v.to_le_bytes()[..n]);
+    for (tag, ty, values) in &tags {
+        let size = match ty {
+            3 => 2,
+            4 => 4,
+            _ => 8,
+        };
+        put(&mut entries, u64::from(*tag), 2);
+        put(&mut entries, u64::from(*ty), 2);
+        put(&mut entries, values.len() as u64, off_size);
+        let mut raw = Vec::new();
+        for &v in values {
+            put(&mut raw, v, size);
+        }
+        if raw.len() <= off_size {
+            raw.resize(off_size, 0);
+            entries.extend_from_slice(&raw);
+        } else {
+            put(&mut entries, extra_pos, off_size);
+            extra_pos += raw.len() as u64;
+            extra.extend_from_slice(&raw);
+        }
+    }
+
+    let f = File::create(path).with_context(|| format!("create {}", path))?;
+    let mut out = BufWriter::new(f);
+    let mut head = Vec::new();
+    head.extend_from_slice(b"II");
+    if big {
+        put(&mut head, 43, 2);
+        put(&mut head, 8, 2);
+        put(&mut head, 0, 2);
+        put(&mut head, ifd_pos, 8);
+    } else {
+        put(&mut head, 42, 2);
+        put(&mut head, ifd_pos, 4);
+    }
+    out.write_all(&head)?;
+    for s in &strips {
+        out.write_all(s)?;
+    }
+    if pos % 2 == 1 {
+        out.write_all(&[0])?;
+    }
+    let mut ifd = Vec::new();
+    put(&mut ifd, tags.len() as u64, count_len);
+    ifd.extend_from_slice(&entries);
+    put(&mut ifd, 0, off_size); // 没有下一页
+    out.write_all(&ifd)?;
+    out.write_all(&extra)?;
+    out.flush()?;
+    Ok(())
+}
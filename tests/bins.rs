@@ -0,0 +1,204 @@
+//! --bins：每个 bin 由 bin1 汇总后写入 /geneExp/binN、/wholeExp/binN 与 /wholeExpExon/binN；
+//! 已分箱的 GEM（#BinSize=N）按 bin1 坐标写入 binN 及其整数倍
+mod common;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+use common::{gem2gef, gem2gef_ok, synthetic_rows, write_gem, GemLayout, GemRow, TempDir};
+use hdf5::types::FixedAscii;
+use hdf5::H5Type;
+
+const META: &[&str] = &[
+    "BinType=Bin",
+    "BinSize=1",
+    "Omics=Transcriptomics",
+    "Stereo-seqChip=SS200000000TL_A1",
+];
+
+#[repr(C)]
+#[allow(non_snake_case)]
+#[derive(H5Type, Clone, Copy, Debug)]
+struct Gene {
+    geneID: FixedAscii<64>,
+    geneName: FixedAscii<64>,
+    offset: u32,
+    count: u32,
+}
+
+#[repr(C)]
+#[derive(H5Type, Clone, Copy, Debug)]
+struct Expr {
+    x: i32,
+    y: i32,
+    count: u32,
+}
+
+/// (geneID, x, y) -> (MIDCount, ExonCount)，坐标为 bin 左上角的 bin1 坐标
+type Binned = BTreeMap<(String, i32, i32), (u32, u32)>;
+
+/// 合成 GEM 按 `bin` 汇总的记录
+fn expected(bin: i32) -> Binned {
+    aggregate(&synthetic_rows(), 1, bin)
+}
+
+/// `#BinSize=gem_bin` 的记录换算为 bin1 坐标后按 `bin` 汇总
+fn aggregate(rows: &[GemRow], gem_bin: i32, bin: i32) -> Binned {
+    let mut want = Binned::new();
+    for r in rows {
+        let (x, y) = (r.x * gem_bin, r.y * gem_bin);
+        let key = (
+            r.gene_id.clone(),
+            x.div_euclid(bin) * bin,
+            y.div_euclid(bin) * bin,
+        );
+        let v = want.entry(key).or_default();
+        v.0 += r.mid;
+        v.1 += r.exon;
+    }
+    want
+}
+
+/// 按 `/geneExp/binN/gene` 的 offset/count 读回 expression 与 exon
+fn read_bin(bgef: &Path, bin: u32) -> Binned {
+    let f = hdf5::File::open(bgef).unwrap();
+    let g = format!("/geneExp/bin{}", bin);
+    let genes = f.dataset(&format!("{}/gene", g)).unwrap().read_raw::<Gene>().unwrap();
+    let expr = f.dataset(&format!("{}/expression", g)).unwrap().read_raw::<Expr>().unwrap();
+    let exon = f.dataset(&format!("{}/exon", g)).unwrap().read_raw::<u32>().unwrap();
+    assert_eq!(expr.len(), exon.len());
+
+    let mut got = Binned::new();
+    for gene in &genes {
+        let id = gene.geneID.as_str();
+        for i in gene.offset as usize..(gene.offset + gene.count) as usize {
+            let e = expr[i];
+            let old = got.insert((id.to_string(), e.x, e.y), (e.count, exon[i]));
+            assert!(
+                old.is_none(),
+                "bin{} 中 {} 在 ({}, {}) 有多条记录",
+                bin,
+                id,
+                e.x,
+                e.y
+            );
+        }
+    }
+    got
+}
+
+#[test]
+fn writes_every_requested_bin() {
+    let dir = TempDir::new("bins");
+    let gem = dir.join("in.gem");
+    let out = dir.join("out.bgef");
+    write_gem(&gem, META, GemLayout::WithExon, &synthetic_rows());
+    // 乱序且有重复：去重后按升序写出
+    gem2gef_ok(&[
+        "-i",
+        gem.to_str().unwrap(),
+        "-o",
+        out.to_str().unwrap(),
+        "-b",
+        "20,1,5,5",
+    ]);
+
+    let f = hdf5::File::open(&out).unwrap();
+    for group in ["/geneExp", "/wholeExp", "/wholeExpExon"] {
+        let mut names = f.group(group).unwrap().member_names().unwrap();
+        names.sort();
+        assert_eq!(names, ["bin1", "bin20", "bin5"], "{}", group);
+    }
+    for bin in [1, 5, 20] {
+        let want = expected(bin as i32);
+        assert_eq!(read_bin(&out, bin), want, "bin{}", bin);
+
+        let spots: BTreeSet<(i32, i32)> = want.keys().map(|k| (k.1, k.2)).collect();
+        let whole = f.dataset(&format!("/wholeExp/bin{}", bin)).unwrap();
+        let number = whole.attr("number").unwrap().read_scalar::<u64>().unwrap();
+        assert_eq!(number, spots.len() as u64, "bin{} number", bin);
+        let max_mid = whole.attr("maxMID").unwrap().read_scalar::<u32>().unwrap();
+        let mut per_spot = BTreeMap::<(i32, i32), u32>::new();
+        for ((_, x, y), (mid, _)) in &want {
+            *per_spot.entry((*x, *y)).or_default() += mid;
+        }
+        assert_eq!(
+            max_mid,
+            *per_spot.values().max().unwrap(),
+            "bin{} maxMID",
+            bin
+        );
+    }
+}
+
+/// 已分箱为 bin50 的 GEM：坐标为 bin 序号
+const META_BIN50: &[&str] = &[
+    "BinType=Bin",
+    "BinSize=50",
+    "Omics=Transcriptomics",
+    "Stereo-seqChip=SS200000000TL_A1",
+];
+
+fn bin50_rows() -> Vec<GemRow> {
+    synthetic_rows()
+        .into_iter()
+        .map(|mut r| {
+            r.x -= 100;
+            r.y -= 2000;
+            r
+        })
+        .collect()
+}
+
+#[test]
+fn pre_binned_gem_scales_to_bin1() {
+    let dir = TempDir::new("bins_bin50");
+    let gem = dir.join("in.gem");
+    write_gem(&gem, META_BIN50, GemLayout::WithExon, &bin50_rows());
+
+    // 不给 --bins 时只写 #BinSize；给出时可以是它的整数倍
+    for (name, bins) in [("default.bgef", None), ("coarser.bgef", Some("50,100"))] {
+        let out = dir.join(name);
+        let mut args = vec!["-i", gem.to_str().unwrap(), "-o", out.to_str().unwrap()];
+        if let Some(bins) = bins {
+            args.extend(["-b", bins]);
+        }
+        gem2gef_ok(&args);
+
+        let f = hdf5::File::open(&out).unwrap();
+        let mut names = f.group("/geneExp").unwrap().member_names().unwrap();
+        names.sort();
+        let want_bins: &[u32] = if bins.is_some() { &[50, 100] } else { &[50] };
+        let want_names: Vec<String> = want_bins.iter().map(|b| format!("bin{}", b)).collect();
+        assert_eq!(names, want_names, "{}", name);
+        for &bin in want_bins {
+            let want = aggregate(&bin50_rows(), 50, bin as i32);
+            assert_eq!(read_bin(&out, bin), want, "{} bin{}", name, bin);
+            let whole = f.dataset(&format!("/wholeExp/bin{}", bin)).unwrap();
+            let min_x = whole.attr("minX").unwrap().read_scalar::<i32>().unwrap();
+            assert_eq!(min_x, want.keys().map(|k| k.1).min().unwrap(), "{}", name);
+        }
+    }
+}
+
+#[test]
+fn pre_binned_gem_rejects_finer_bins() {
+    let dir = TempDir::new("bins_mixed");
+    let gem = dir.join("in.gem");
+    let out = dir.join("out.bgef");
+    write_gem(&gem, META_BIN50, GemLayout::WithExon, &bin50_rows());
+
+    let res = gem2gef(&[
+        "-i",
+        gem.to_str().unwrap(),
+        "-o",
+        out.to_str().unwrap(),
+        "-b",
+        "1,20,50,100",
+    ]);
+    assert!(!res.status.success());
+    let stderr = String::from_utf8_lossy(&res.stderr);
+    assert!(stderr.contains("#BinSize=50"), "{}", stderr);
+    assert!(stderr.contains("无法输出 bin 1,20"), "{}", stderr);
+    assert!(!out.exists());
+}
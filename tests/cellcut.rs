@@ -0,0 +1,662 @@
+//! bGEF + mask TIFF -> cGEF：按 mask 汇总的细胞表达与两种排列的一致性
+mod common;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::process::Output;
+
+use common::{
+    gem2gef, gem2gef_ok, read_gem, read_mask_tiff, synthetic_rows, write_gem, write_mask_tiff,
+    GemLayout, GemRow, TempDir,
+};
+use hdf5::filters::Filter;
+use hdf5::types::{FixedAscii, VarLenUnicode};
+use hdf5::H5Type;
+
+const META: &[&str] = &[
+    "BinType=Bin",
+    "BinSize=1",
+    "Omics=Transcriptomics",
+    "Stereo-seqChip=SS200000000TL_A1",
+];
+
+/// 运行 gem2gef 生成 bin1 bGEF，再按 mask 生成 cGEF；`extra` 为 cellcut 的附加参数，返回 cellcut 的输出
+fn bgef_to_cgef(
+    dir: &TempDir,
+    rows: &[GemRow],
+    width: u32,
+    height: u32,
+    pixels: &[u16],
+    extra: &[&str],
+) -> Output {
+    let (gem, bgef, tif, cgef) = (
+        dir.join("in.gem"),
+        dir.join("out.bgef"),
+        dir.join("mask.tif"),
+        dir.join("out.cgef"),
+    );
+    write_gem(&gem, META, GemLayout::WithExon, rows);
+    gem2gef_ok(&[
+        "-i",
+        gem.to_str().unwrap(),
+        "-o",
+        bgef.to_str().unwrap(),
+        "-b",
+        "1",
+    ]);
+    write_mask_tiff(&tif, width, height, pixels);
+    let mut args = vec![
+        "cellcut",
+        "-i",
+        bgef.to_str().unwrap(),
+        "-m",
+        tif.to_str().unwrap(),
+        "-o",
+        cgef.to_str().unwrap(),
+    ];
+    args.extend_from_slice(extra);
+    gem2gef_ok(&args)
+}
+
+/// 读取 cGEF 的 blockSize 与 blockIndex，并核对网格覆盖 bGEF 的表达区域（/wholeExp/bin1 的
+/// lenX x lenY，原点为 offsetX/offsetY）：blockIndex 为 ceil(lenX/256) * ceil(lenY/256) + 1 个元素
+fn block_grid(dir: &TempDir) -> (Vec<u32>, Vec<u32>) {
+    let bgef = hdf5::File::open(dir.join("out.bgef")).unwrap();
+    let shape = bgef.dataset("/wholeExp/bin1").unwrap().shape();
+    let (nx, ny) = (shape[0].div_ceil(256), shape[1].div_ceil(256));
+    let f = hdf5::File::open(dir.join("out.cgef")).unwrap();
+    let g = f.group("cellBin").unwrap();
+    let size = g.dataset("blockSize").unwrap().read_raw::<u32>().unwrap();
+    let index = g.dataset("blockIndex").unwrap().read_raw::<u32>().unwrap();
+    assert_eq!(size, vec![256, 256, nx as u32, ny as u32]);
+    assert_eq!(index.len(), nx * ny + 1);
+    (size, index)
+}
+
+/// 两个矩形细胞（bin1 坐标，含端）：合成 GEM 的 x 为 100..136，y 为 2000..2008
+const CELLS: [(i32, i32, i32, i32); 2] = [(100, 2000, 110, 2004), (120, 2003, 130, 2008)];
+
+#[repr(C)]
+#[allow(non_snake_case)]
+#[derive(H5Type, Clone, Copy, Debug)]
+struct Cell {
+    id: u32,
+    x: i32,
+    y: i32,
+    offset: u32,
+    geneCount: u16,
+    expCount: u16,
+    dnbCount: u16,
+    area: u16,
+    cellTypeID: u16,
+    clusterID: u16,
+}
+
+#[repr(C)]
+#[allow(non_snake_case)]
+#[derive(H5Type, Clone, Copy, Debug)]
+struct CellExp {
+    geneID: u32,
+    count: u16,
+}
+
+#[repr(C)]
+#[allow(non_snake_case)]
+#[derive(H5Type, Clone, Copy, Debug)]
+struct GeneExp {
+    cellID: u32,
+    count: u16,
+}
+
+#[repr(C)]
+#[allow(non_snake_case)]
+#[derive(H5Type, Clone, Copy, Debug)]
+struct Gene {
+    #[hdf5(name = "geneID")]
+    geneID: FixedAscii<64>,
+    #[hdf5(name = "geneName")]
+    geneName: FixedAscii<64>,
+    offset: u32,
+    cellCount: u32,
+    expCount: u32,
+    maxMIDcount: u16,
+}
+
+fn cell_of(x: i32, y: i32) -> Option<usize> {
+    CELLS.iter().position(|&(x0, y0, x1, y1)| (x0..=x1).contains(&x) && (y0..=y1).contains(&y))
+}
+
+/// 像素坐标即 bin1 坐标的 mask；`value(i)` 为第 i 个细胞的像素值
+fn mask_pixels(width: u32, height: u32, value: impl Fn(usize) -> u16) -> Vec<u16> {
+    let mut pixels = vec![0u16; (width * height) as usize];
+    for y in 0..height as i32 {
+        for x in 0..width as i32 {
+            if let Some(c) = cell_of(x, y) {
+                pixels[(y as u32 * width + x as u32) as usize] = value(c);
+            }
+        }
+    }
+    pixels
+}
+
+fn run_cellcut(tag: &str, value: impl Fn(usize) -> u16) {
+    let dir = TempDir::new(tag);
+    let (gem, cgef) = (dir.join("in.gem"), dir.join("out.cgef"));
+    let (width, height) = (140, 2010);
+    let pixels = mask_pixels(width, height, value);
+    bgef_to_cgef(&dir, &synthetic_rows(), width, height, &pixels, &[]);
+
+    // 期望值：GEM 记录按细胞汇总 -> geneID -> (MID, exon)，以及每个细胞有表达的 spot
+    let (_, records) = read_gem(&gem);
+    let mut want: Vec<BTreeMap<String, (u32, u32)>> = vec![BTreeMap::new(); CELLS.len()];
+    let mut spots: Vec<BTreeSet<(i32, i32)>> = vec![BTreeSet::new(); CELLS.len()];
+    for ((id, _, x, y), (mid, exon)) in records {
+        if let Some(c) = cell_of(x, y) {
+            let v = want[c].entry(id).or_default();
+            v.0 += mid;
+            v.1 += exon;
+            spots[c].insert((x, y));
+        }
+    }
+
+    let f = hdf5::File::open(&cgef).unwrap();
+    let bin_type = f.attr("bin_type").unwrap().read_scalar::<VarLenUnicode>().unwrap();
+    assert_eq!(bin_type.as_str(), "CellBin");
+    // 细胞坐标相对于表达区域左上角 (offsetX, offsetY)
+    let off_x = f.attr("offsetX").unwrap().read_scalar::<i32>().unwrap();
+    let off_y = f.attr("offsetY").unwrap().read_scalar::<i32>().unwrap();
+    assert_eq!((off_x, off_y), (100, 2000));
+    let g = f.group("cellBin").unwrap();
+    let cells = g.dataset("cell").unwrap().read_raw::<Cell>().unwrap();
+    let cell_exp = g.dataset("cellExp").unwrap().read_raw::<CellExp>().unwrap();
+    let cell_exp_exon = g.dataset("cellExpExon").unwrap().read_raw::<u16>().unwrap();
+    let cell_exon = g.dataset("cellExon").unwrap().read_raw::<u16>().unwrap();
+    let genes = g.dataset("gene").unwrap().read_raw::<Gene>().unwrap();
+    let gene_exp = g.dataset("geneExp").unwrap().read_raw::<GeneExp>().unwrap();
+    let gene_exp_exon = g.dataset("geneExpExon").unwrap().read_raw::<u16>().unwrap();
+    let gene_exon = g.dataset("geneExon").unwrap().read_raw::<u32>().unwrap();
+    let types = g.dataset("cellTypeList").unwrap().read_raw::<FixedAscii<32>>().unwrap();
+    assert_eq!(types[0].as_str(), "default");
+    assert_eq!(genes.len(), 5);
+    assert_eq!(cells.len(), CELLS.len());
+    // 分块网格覆盖表达区域（37 x 9），而非整张 mask（140 x 2010）
+    let (block_size, block_index) = block_grid(&dir);
+    assert_eq!(block_size, vec![256, 256, 1, 1]);
+    assert_eq!(block_index, vec![0, CELLS.len() as u32]);
+    let ds_border = g.dataset("cellBorder").unwrap();
+    assert_eq!(ds_border.shape(), vec![CELLS.len(), 32, 2]);
+    let borders = ds_border.read_raw::<i16>().unwrap();
+
+    for (c, cell) in cells.iter().enumerate() {
+        let (x0, y0, x1, y1) = CELLS[c];
+        assert_eq!(cell.id as usize, c);
+        assert_eq!(i32::from(cell.area), (x1 - x0 + 1) * (y1 - y0 + 1));
+        let (cx, cy) = ((x0 + x1 + 1) / 2, (y0 + y1 + 1) / 2);
+        assert_eq!((cell.x + off_x, cell.y + off_y), (cx, cy));
+        assert_eq!(cell.dnbCount as usize, spots[c].len());
+        assert_eq!(cell.geneCount as usize, want[c].len());
+
+        // 矩形细胞的轮廓简化为 4 个角点（相对质心），其余以 32767 填充
+        let border = &borders[c * 64..(c + 1) * 64];
+        let corners: BTreeSet<(i32, i32)> =
+            [(x0, y0), (x1, y0), (x1, y1), (x0, y1)].into_iter().collect();
+        let got: BTreeSet<(i32, i32)> =
+            border[..8].chunks(2).map(|v| (cx + i32::from(v[0]), cy + i32::from(v[1]))).collect();
+        assert_eq!(got, corners, "cell {} border", c);
+        assert!(border[8..].iter().all(|&v| v == i16::MAX));
+
+        let range = cell.offset as usize..cell.offset as usize + cell.geneCount as usize;
+        let got: BTreeMap<String, (u32, u32)> = range
+            .map(|k| {
+                let gene = &genes[cell_exp[k].geneID as usize];
+                let v = (u32::from(cell_exp[k].count), u32::from(cell_exp_exon[k]));
+                (gene.geneID.as_str().to_string(), v)
+            })
+            .collect();
+        assert_eq!(got, want[c], "cell {}", c);
+        let total: u32 = want[c].values().map(|v| v.0).sum();
+        assert_eq!(u32::from(cell.expCount), total);
+        let exon: u32 = want[c].values().map(|v| v.1).sum();
+        assert_eq!(u32::from(cell_exon[c]), exon);
+    }
+
+    // geneExp 与 cellExp 是同一批记录
+    for (gi, gene) in genes.iter().enumerate() {
+        let id = gene.geneID.as_str();
+        let range = gene.offset as usize..gene.offset as usize + gene.cellCount as usize;
+        let got: BTreeMap<usize, (u32, u32)> = range
+            .map(|k| {
+                let v = (u32::from(gene_exp[k].count), u32::from(gene_exp_exon[k]));
+                (gene_exp[k].cellID as usize, v)
+            })
+            .collect();
+        let expect: BTreeMap<usize, (u32, u32)> =
+            (0..CELLS.len()).filter_map(|c| want[c].get(id).map(|&v| (c, v))).collect();
+        assert_eq!(got, expect, "gene {}", id);
+        assert_eq!(gene.expCount, expect.values().map(|v| v.0).sum::<u32>());
+        assert_eq!(gene_exon[gi], expect.values().map(|v| v.1).sum::<u32>());
+        assert_eq!(
+            u32::from(gene.maxMIDcount),
+            expect.values().map(|v| v.0).max().unwrap_or(0)
+        );
+    }
+}
+
+#[test]
+fn cellcut_labeled_mask() {
+    // 标签值不连续：按升序重新编号
+    run_cellcut("cellcut-label", |c| [17, 301][c]);
+}
+
+#[test]
+fn cellcut_binary_mask() {
+    // 二值 mask：按连通域编号，扫描顺序与 CELLS 一致
+    run_cellcut("cellcut-binary", |_| 255);
+}
+
+#[test]
+fn cellcut_dataset_options() {
+    let dir = TempDir::new("cellcut_opts");
+    let (width, height) = (140, 2010);
+    let pixels = mask_pixels(width, height, |c| c as u16 + 1);
+    let cgef = dir.join("out.cgef");
+    let chunked = [
+        ("cell", "gene"),
+        ("cellExon", "gene"),
+        ("gene", "gene"),
+        ("geneExon", "gene"),
+        ("cellExp", "expr"),
+        ("cellExpExon", "expr"),
+        ("geneExp", "expr"),
+        ("geneExpExon", "expr"),
+    ];
+
+    // 默认与 bGEF 相同：deflate 4
+    bgef_to_cgef(&dir, &synthetic_rows(), width, height, &pixels, &[]);
+    let f = hdf5::File::open(&cgef).unwrap();
+    for (name, _) in chunked {
+        let ds = f.dataset(&format!("/cellBin/{}", name)).unwrap();
+        assert_eq!(ds.filters(), [Filter::Deflate(4)], "{}", name);
+    }
+    drop(f);
+
+    let extra = [
+        "--deflate",
+        "6",
+        "--shuffle",
+        "--gene-chunk",
+        "1",
+        "--expr-chunk",
+        "3",
+    ];
+    bgef_to_cgef(&dir, &synthetic_rows(), width, height, &pixels, &extra);
+    let f = hdf5::File::open(&cgef).unwrap();
+    for (name, kind) in chunked {
+        let ds = f.dataset(&format!("/cellBin/{}", name)).unwrap();
+        assert_eq!(
+            ds.filters(),
+            [Filter::Shuffle, Filter::Deflate(6)],
+            "{}",
+            name
+        );
+        let chunk = if kind == "gene" { 1 } else { 3 };
+        assert_eq!(ds.chunk(), Some(vec![chunk.min(ds.size())]), "{}", name);
+    }
+}
+
+#[test]
+fn cellcut_warns_on_clamped_counts() {
+    // 一个 2x1 的细胞：Actb 两个 spot 合计 80000，超出 cellExp/geneExp 与细胞 expCount 的 uint16
+    let rows = vec![
+        GemRow::new("Actb", 10, 10, 40000, 0),
+        GemRow::new("Actb", 11, 10, 40000, 0),
+        GemRow::new("Mbp", 11, 10, 5, 0),
+    ];
+    let (width, height) = (20, 20);
+    let mut pixels = vec![0u16; (width * height) as usize];
+    pixels[(10 * width + 10) as usize] = 1;
+    pixels[(10 * width + 11) as usize] = 1;
+    let dir = TempDir::new("cellcut-clamp");
+    let out = bgef_to_cgef(&dir, &rows, width, height, &pixels, &[]);
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    for field in ["cell.expCount=1", "cellExp.count=1", "geneExp.count=1"] {
+        assert!(stderr.contains(field), "缺少 {}: {}", field, stderr);
+    }
+    assert!(!stderr.contains("cell.geneCount"), "{}", stderr);
+
+    let f = hdf5::File::open(dir.join("out.cgef")).unwrap();
+    let g = f.group("cellBin").unwrap();
+    let cells = g.dataset("cell").unwrap().read_raw::<Cell>().unwrap();
+    let genes = g.dataset("gene").unwrap().read_raw::<Gene>().unwrap();
+    assert_eq!(cells.len(), 1);
+    assert_eq!((cells[0].expCount, cells[0].geneCount), (u16::MAX, 2));
+    // gene.expCount 为 uint32，不截断；maxMIDcount 随 geneExp.count 截到 65535
+    let actb = genes.iter().find(|g| g.geneID.as_str() == "Actb").unwrap();
+    assert_eq!((actb.expCount, actb.maxMIDcount), (80000, u16::MAX));
+}
+
+#[test]
+fn cellcut_block_index() {
+    // 4 x 3 个 5x5 细胞散布在 480 x 400 的 mask 中：表达区域为 455 x 345（原点 (20, 30)），
+    // 分块网格为 2 x 2，细胞按相对坐标归块
+    let mut rows = Vec::new();
+    let mut squares = Vec::new();
+    for j in 0..3 {
+        for i in 0..4 {
+            let (x, y) = (20 + 150 * i, 30 + 170 * j);
+            squares.push((x, y));
+            rows.push(GemRow::new("Actb", x, y, 1 + (i + j) as u32, 0));
+            rows.push(GemRow::new("Mbp", x + 4, y + 4, 2, 1));
+        }
+    }
+    let (width, height) = (480, 400);
+    let mut pixels = vec![0u16; (width * height) as usize];
+    for (k, &(x0, y0)) in squares.iter().enumerate() {
+        for y in y0..y0 + 5 {
+            for x in x0..x0 + 5 {
+                pixels[(y as u32 * width + x as u32) as usize] = k as u16 + 1;
+            }
+        }
+    }
+    let dir = TempDir::new("cellcut-block");
+    bgef_to_cgef(&dir, &rows, width, height, &pixels, &[]);
+
+    let (size, index) = block_grid(&dir);
+    let f = hdf5::File::open(dir.join("out.cgef")).unwrap();
+    let cells = f.dataset("cellBin/cell").unwrap().read_raw::<Cell>().unwrap();
+    let off = |a: &str| f.attr(a).unwrap().read_scalar::<i32>().unwrap();
+    assert_eq!((off("offsetX"), off("offsetY")), (20, 30));
+    assert_eq!(size, vec![256, 256, 2, 2]);
+    assert_eq!(index[0], 0);
+    assert_eq!(*index.last().unwrap() as usize, squares.len());
+    assert!(index.windows(2).all(|w| w[0] <= w[1]));
+    for b in 0..4 {
+        for (k, cell) in
+            cells.iter().enumerate().take(index[b + 1] as usize).skip(index[b] as usize)
+        {
+            assert_eq!(cell.id as usize, k);
+            let block = (cell.y as u32 / 256) * 2 + cell.x as u32 / 256;
+            assert_eq!(block as usize, b, "cell {:?}", cell);
+        }
+    }
+    // 每块内的细胞数（块号按行优先）
+    let per_block: Vec<u32> = index.windows(2).map(|w| w[1] - w[0]).collect();
+    assert_eq!(per_block, vec![4, 4, 2, 2]);
+}
+
+/// 期望的修正结果（按芯片坐标，宽 `width`）：窗口 `[x0, x1] x [y0, y1]` 内的背景像素归属最近的细胞核
+/// 像素，等距时取编号较小者，超过 `distance` 的保持 0；窗口外为 0。`nuclei` 为 (编号, 像素)
+fn expected_correction(
+    nuclei: &[(u32, (i32, i32))],
+    (x0, y0, x1, y1): (i32, i32, i32, i32),
+    width: u32,
+    distance: i32,
+) -> Vec<u32> {
+    let mut out = vec![0u32; (width as i32 * (y1 + 1)) as usize];
+    for y in y0..=y1 {
+        for x in x0..=x1 {
+            let best = nuclei
+                .iter()
+                .map(|&(l, (nx, ny))| ((x - nx) * (x - nx) + (y - ny) * (y - ny), l))
+                .min()
+                .filter(|&(d2, _)| d2 <= distance * distance);
+            out[(y * width as i32 + x) as usize] = best.map_or(0, |(_, l)| l);
+        }
+    }
+    out
+}
+
+/// 读回修正后的 mask，与期望逐像素比较
+fn check_corrected_mask(path: &Path, want: &[u32], width: u32) {
+    let (w, h, bits, compression, pixels) = read_mask_tiff(path);
+    assert_eq!((bits, compression), (16, 8));
+    assert_eq!(w, width);
+    assert_eq!(pixels.len(), want.len(), "{} x {}", w, h);
+    for (i, (&got, &want)) in pixels.iter().zip(want).enumerate() {
+        let (x, y) = (i as u32 % w, i as u32 / w);
+        assert_eq!(got, want, "修正后 mask 在 ({}, {}) 处不符", x, y);
+    }
+}
+
+#[test]
+fn cellcut_correct_expands_nuclei() {
+    // 两个单像素细胞核（标签 5 与 9）相距 6，扩展距离 4：x=103 与两者等距，归属编号较小的细胞
+    let nuclei = [(5u16, (100i32, 2000i32)), (9u16, (106, 2000))];
+    let distance2 = 16;
+    let rows = vec![
+        GemRow::new("Actb", 103, 2000, 2, 0),
+        GemRow::new("Mbp", 104, 2000, 3, 0),
+        GemRow::new("Gad1", 97, 2000, 1, 0),
+        // 表达区域的两个角，离细胞核都超过 4
+        GemRow::new("Xist", 90, 1990, 1, 0),
+        GemRow::new("Xist", 120, 2010, 1, 0),
+    ];
+    let (width, height) = (130, 2020);
+    let mut pixels = vec![0u16; (width * height) as usize];
+    for &(label, (x, y)) in &nuclei {
+        pixels[(y as u32 * width + x as u32) as usize] = label;
+    }
+    let dir = TempDir::new("cellcut-correct");
+    let mask = dir.join("corrected.tif");
+    bgef_to_cgef(
+        &dir,
+        &rows,
+        width,
+        height,
+        &pixels,
+        &[
+            "--correct",
+            "4",
+            "--corrected-mask",
+            mask.to_str().unwrap(),
+            "--threads",
+            "3",
+        ],
+    );
+
+    // 修正后的 mask 与芯片对齐、只覆盖表达区域，标签按升序重新编号为 1, 2
+    let renumbered = [(1, nuclei[0].1), (2, nuclei[1].1)];
+    let want = expected_correction(&renumbered, (90, 1990, 120, 2010), 121, 4);
+    check_corrected_mask(&mask, &want, 121);
+
+    // 期望面积：表达区域内离最近细胞核不超过 4 的像素，等距时归编号较小者
+    let mut area = [0u16; 2];
+    for y in 1990..=2010 {
+        for x in 90..=120 {
+            let d2: Vec<i32> = nuclei
+                .iter()
+                .map(|(_, (nx, ny))| (x - nx) * (x - nx) + (y - ny) * (y - ny))
+                .collect();
+            let best = if d2[0] <= d2[1] { 0 } else { 1 };
+            if d2[best] <= distance2 {
+                area[best] += 1;
+            }
+        }
+    }
+
+    let f = hdf5::File::open(dir.join("out.cgef")).unwrap();
+    let cells = f.dataset("cellBin/cell").unwrap().read_raw::<Cell>().unwrap();
+    assert_eq!(cells.len(), 2);
+    assert_eq!([cells[0].area, cells[1].area], area);
+    assert_eq!([cells[0].expCount, cells[1].expCount], [3, 3]);
+    assert_eq!([cells[0].dnbCount, cells[1].dnbCount], [2, 1]);
+    assert_eq!([cells[0].geneCount, cells[1].geneCount], [2, 1]);
+}
+
+#[test]
+fn cellcut_correct_across_thread_blocks() {
+    // 60 x 80 的表达区域分给 4 个线程：列块边界 x=15/30/45，行块边界 y=20/40/60；
+    // 细胞核跨越多条块边界，扩展后的区域也跨越块边界并彼此相接
+    let blobs: [(u16, (i32, i32, i32, i32)); 5] = [
+        (3, (5, 15, 8, 25)),
+        (7, (28, 38, 33, 44)),
+        (2, (50, 55, 52, 65)),
+        (11, (20, 70, 20, 70)),
+        (4, (12, 8, 17, 12)),
+    ];
+    let (width, height) = (60u32, 80u32);
+    let mut pixels = vec![0u16; (width * height) as usize];
+    let mut sites = Vec::new();
+    // 标签值按升序重新编号
+    let mut order: Vec<u16> = blobs.iter().map(|b| b.0).collect();
+    order.sort_unstable();
+    for &(label, (x0, y0, x1, y1)) in &blobs {
+        let id = order.iter().position(|&l| l == label).unwrap() as u32 + 1;
+        for y in y0..=y1 {
+            for x in x0..=x1 {
+                pixels[(y as u32 * width + x as u32) as usize] = label;
+                sites.push((id, (x, y)));
+            }
+        }
+    }
+    let rows = vec![
+        GemRow::new("Xist", 0, 0, 1, 0),
+        GemRow::new("Actb", 59, 79, 1, 0),
+    ];
+    let distance = 12;
+    let want = expected_correction(&sites, (0, 0, 59, 79), width, distance);
+    for threads in ["1", "4", "7"] {
+        let dir = TempDir::new(&format!("cellcut-correct-blocks-{}", threads));
+        let mask = dir.join("corrected.tif");
+        bgef_to_cgef(
+            &dir,
+            &rows,
+            width,
+            height,
+            &pixels,
+            &[
+                "--correct",
+                "12",
+                "--corrected-mask",
+                mask.to_str().unwrap(),
+                "--threads",
+                threads,
+            ],
+        );
+        check_corrected_mask(&mask, &want, width);
+    }
+}
+
+/// `cells --rect` 的输出：(id, x, y)
+fn query_cells(cgef: &Path, out: &Path, rect: &str) -> Vec<(u32, i32, i32)> {
+    gem2gef_ok(&[
+        "cells",
+        "-i",
+        cgef.to_str().unwrap(),
+        "--rect",
+        rect,
+        "-o",
+        out.to_str().unwrap(),
+    ]);
+    let text = std::fs::read_to_string(out).unwrap();
+    let mut lines = text.lines();
+    assert_eq!(
+        lines.next(),
+        Some("id\tx\ty\tgeneCount\texpCount\tdnbCount\tarea")
+    );
+    let mut got: Vec<(u32, i32, i32)> = lines
+        .map(|l| {
+            let v: Vec<&str> = l.split('\t').collect();
+            (
+                v[0].parse().unwrap(),
+                v[1].parse().unwrap(),
+                v[2].parse().unwrap(),
+            )
+        })
+        .collect();
+    got.sort_unstable();
+    got
+}
+
+#[test]
+fn cellcut_cells_in_rect() {
+    // 6 x 5 个 3x3 细胞铺在 700 x 600 的 mask 中；每个细胞一条表达。表达区域为 576 x 501
+    // （原点 (16, 11)），分块网格为 3 x 2，与芯片坐标的 256 边界错开
+    let mut rows = Vec::new();
+    let (width, height) = (700u32, 600u32);
+    let mut pixels = vec![0u16; (width * height) as usize];
+    let mut label = 0u16;
+    for j in 0..5 {
+        for i in 0..6 {
+            let (x, y) = (15 + 115 * i, 10 + 125 * j);
+            label += 1;
+            rows.push(GemRow::new("Actb", x + 1, y + 1, 1 + label as u32, 0));
+            for yy in y..y + 3 {
+                for xx in x..x + 3 {
+                    pixels[(yy as u32 * width + xx as u32) as usize] = label;
+                }
+            }
+        }
+    }
+    let dir = TempDir::new("cellcut-rect");
+    bgef_to_cgef(&dir, &rows, width, height, &pixels, &[]);
+    let cgef = dir.join("out.cgef");
+
+    let (size, _) = block_grid(&dir);
+    assert_eq!(size, vec![256, 256, 3, 2]);
+    let f = hdf5::File::open(&cgef).unwrap();
+    let cells = f.dataset("cellBin/cell").unwrap().read_raw::<Cell>().unwrap();
+    let off_x = f.attr("offsetX").unwrap().read_scalar::<i32>().unwrap();
+    let off_y = f.attr("offsetY").unwrap().read_scalar::<i32>().unwrap();
+    drop(f);
+    assert_eq!(cells.len(), 30);
+
+    // 逐个细胞判断（芯片坐标，左闭右开）
+    let brute = |(x0, y0, x1, y1): (i32, i32, i32, i32)| -> Vec<(u32, i32, i32)> {
+        let mut v: Vec<(u32, i32, i32)> = cells
+            .iter()
+            .map(|c| (c.id, c.x + off_x, c.y + off_y))
+            .filter(|&(_, x, y)| x >= x0 && x < x1 && y >= y0 && y < y1)
+            .collect();
+        v.sort_unstable();
+        v
+    };
+    let out = dir.join("cells.tsv");
+    for rect in [
+        // 跨越芯片坐标 x=272、x=528 与 y=267 的块边界（相对坐标 256、512 与 256）
+        (100, 100, 600, 400),
+        // 边界恰在细胞质心上：x0 含、x1 不含
+        (246, 136, 476, 386),
+        // 超出芯片范围与负坐标
+        (-50, -50, 1000, 1000),
+        // 空矩形
+        (300, 300, 300, 400),
+    ] {
+        let arg = format!("{},{},{},{}", rect.0, rect.1, rect.2, rect.3);
+        let want = brute(rect);
+        assert_eq!(query_cells(&cgef, &out, &arg), want, "--rect {}", arg);
+    }
+    assert!(!brute((100, 100, 600, 400)).is_empty());
+
+    // 只有块数个元素的 blockIndex（不含末尾的细胞总数）也能读取
+    let f = hdf5::File::open_rw(&cgef).unwrap();
+    let g = f.group("cellBin").unwrap();
+    let index = g.dataset("blockIndex").unwrap().read_raw::<u32>().unwrap();
+    g.unlink("blockIndex").unwrap();
+    g.new_dataset_builder().with_data(&index[..index.len() - 1]).create("blockIndex").unwrap();
+    drop(g);
+    drop(f);
+    assert_eq!(
+        query_cells(&cgef, &out, "100,100,600,400"),
+        brute((100, 100, 600, 400))
+    );
+
+    // 长度对不上时报错
+    let f = hdf5::File::open_rw(&cgef).unwrap();
+    let g = f.group("cellBin").unwrap();
+    g.unlink("blockIndex").unwrap();
+    g.new_dataset_builder().with_data(&index[..3]).create("blockIndex").unwrap();
+    drop(g);
+    drop(f);
+    let res = gem2gef(&[
+        "cells",
+        "-i",
+        cgef.to_str().unwrap(),
+        "--rect",
+        "0,0,10,10",
+        "-o",
+        out.to_str().unwrap(),
+    ]);
+    assert!(!res.status.success());
+    assert!(String::from_utf8_lossy(&res.stderr).contains("blockIndex"));
+}
@@ -0,0 +1,385 @@
+//! 按 `tests/data/bgef_schema.json` 检查 bGEF 的组结构、属性、dtype、shape 与轴向
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs,
+    path::Path,
+};
+
+use hdf5::{types::TypeDescriptor, Dataset, File as H5File, Group, H5Type, Location};
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+pub struct Schema {
+    pub root_attrs: BTreeMap<String, String>,
+    pub groups: Vec<String>,
+    pub datasets: BTreeMap<String, DatasetSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DatasetSpec {
+    pub ndim: usize,
+    pub dtype: DType,
+    #[serde(default)]
+    pub attrs: BTreeMap<String, String>,
+    /// 只有 GEM 含 ExonCount 时才存在
+    #[serde(default)]
+    pub exon_only: bool,
+    /// 各维长度分别等于这些属性的值
+    #[serde(default)]
+    pub shape_attrs: Option<Vec<String>>,
+    /// shape 与另一个数据集相同
+    #[serde(default)]
+    pub shape_like: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum DType {
+    Scalar(String),
+    /// 按顺序排列的 (字段名, 类型)
+    Compound(Vec<(String, String)>),
+}
+
+impl Schema {
+    pub fn load() -> Self {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/bgef_schema.json");
+        let s = fs::read_to_string(path).expect("read bgef_schema.json");
+        serde_json::from_str(&s).expect("parse bgef_schema.json")
+    }
+}
+
+/// HDF5 类型 -> schema 中的类型写法
+pub fn type_token(td: &TypeDescriptor) -> String {
+    match td {
+        TypeDescriptor::Integer(_) => format!("int{}", td.size() * 8),
+        TypeDescriptor::Unsigned(_) => format!("uint{}", td.size() * 8),
+        TypeDescriptor::Float(_) => format!("float{}", td.size() * 8),
+        TypeDescriptor::FixedAscii(n) | TypeDescriptor::FixedUnicode(n) => {
+            format!("string({})", n)
+        }
+        TypeDescriptor::VarLenAscii | TypeDescriptor::VarLenUnicode => "string".to_string(),
+        TypeDescriptor::FixedArray(t, n) => format!("{}[{}]", type_token(t), n),
+        other => other.to_string(),
+    }
+}
+
+/// `expected` 可用 `|` 列出多个可接受的类型；`string` 匹配任意字符串类型
+fn type_matches(expected: &str, actual: &str) -> bool {
+    expected.split('|').any(|e| e == actual || (e == "string" && actual.starts_with("string")))
+}
+
+/// 属性类型：长度为 1 的一维属性按标量处理（geftools 常这样写）
+fn attr_token(obj: &Location, name: &str) -> hdf5::Result<String> {
+    let attr = obj.attr(name)?;
+    let token = type_token(&attr.dtype()?.to_descriptor()?);
+    Ok(match attr.shape().as_slice() {
+        [] | [1] => token,
+        dims => format!(
+            "{}{}",
+            token,
+            dims.iter().map(|d| format!("[{}]", d)).collect::<String>()
+        ),
+    })
+}
+
+/// 读取整数属性（兼容标量与长度为 1 的数组）
+pub fn attr_u64(obj: &Location, name: &str) -> hdf5::Result<u64> {
+    Ok(obj.attr(name)?.read_raw::<u64>()?[0])
+}
+
+/// 本工具在根上额外写出、geftools 不认识的属性：可以缺省，存在时类型须一致
+const ROOT_EXTENSION_ATTRS: &[(&str, &str)] = &[
+    ("offsetX", "int32"),
+    ("offsetY", "int32"),
+    ("duplicate_policy", "string"),
+    ("duplicate_rows", "uint64"),
+    ("gem_offsetX", "int32"),
+    ("gem_offsetY", "int32"),
+];
+
+fn check_type(
+    obj: &Location,
+    path: &str,
+    name: &str,
+    ty: &str,
+    errors: &mut Vec<String>,
+) -> hdf5::Result<()> {
+    let got = attr_token(obj, name)?;
+    if !type_matches(ty, &got) {
+        errors.push(format!(
+            "{}@{}: 类型应为 {}，实际为 {}",
+            path, name, ty, got
+        ));
+    }
+    Ok(())
+}
+
+fn check_attrs(
+    obj: &Location,
+    path: &str,
+    expected: &BTreeMap<String, String>,
+    optional: &[(&str, &str)],
+    errors: &mut Vec<String>,
+) -> hdf5::Result<()> {
+    let actual: BTreeSet<String> = obj.attr_names()?.into_iter().collect();
+    for (name, ty) in expected {
+        if !actual.contains(name) {
+            errors.push(format!("{}: 缺少属性 {}", path, name));
+            continue;
+        }
+        check_type(obj, path, name, ty, errors)?;
+    }
+    for name in actual.iter().filter(|n| !expected.contains_key(*n)) {
+        match optional.iter().find(|(opt, _)| opt == name) {
+            Some((_, ty)) => check_type(obj, path, name, ty, errors)?,
+            None => errors.push(format!("{}: 多余的属性 {}", path, name)),
+        }
+    }
+    Ok(())
+}
+
+fn check_dtype(
+    ds: &Dataset,
+    path: &str,
+    expected: &DType,
+    errors: &mut Vec<String>,
+) -> hdf5::Result<()> {
+    let td = ds.dtype()?.to_descriptor()?;
+    match (expected, &td) {
+        (DType::Scalar(ty), _) => {
+            let got = type_token(&td);
+            if !type_matches(ty, &got) {
+                errors.push(format!("{}: dtype 应为 {}，实际为 {}", path, ty, got));
+            }
+        }
+        (DType::Compound(fields), TypeDescriptor::Compound(ct)) => {
+            let got: Vec<(String, String)> =
+                ct.fields.iter().map(|f| (f.name.clone(), type_token(&f.ty))).collect();
+            let names_ok = got.len() == fields.len()
+                && got.iter().zip(fields).all(|((gn, _), (en, _))| gn == en);
+            if !names_ok {
+                errors.push(format!("{}: 字段应为 {:?}，实际为 {:?}", path, fields, got));
+            } else {
+                for ((name, got_ty), (_, ty)) in got.iter().zip(fields) {
+                    if !type_matches(ty, got_ty) {
+                        errors.push(format!(
+                            "{}.{}: 类型应为 {}，实际为 {}",
+                            path, name, ty, got_ty
+                        ));
+                    }
+                }
+            }
+        }
+        (DType::Compound(_), _) => {
+            errors.push(format!(
+                "{}: dtype 应为复合类型，实际为 {}",
+                path,
+                type_token(&td)
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn walk(
+    g: &Group,
+    groups: &mut BTreeSet<String>,
+    datasets: &mut BTreeSet<String>,
+) -> hdf5::Result<()> {
+    for sub in g.groups()? {
+        groups.insert(sub.name());
+        walk(&sub, groups, datasets)?;
+    }
+    for ds in g.datasets()? {
+        datasets.insert(ds.name());
+    }
+    Ok(())
+}
+
+/// 把 `{N}` 模板展开到每个 bin 尺寸，同时返回对应的 bin
+fn expand(template: &str, bins: &[u32]) -> Vec<(String, Option<u32>)> {
+    if template.contains("{N}") {
+        bins.iter().map(|&b| (fill(template, Some(b)), Some(b))).collect()
+    } else {
+        vec![(template.to_string(), None)]
+    }
+}
+
+fn fill(template: &str, bin: Option<u32>) -> String {
+    match bin {
+        Some(b) => template.replace("{N}", &b.to_string()),
+        None => template.to_string(),
+    }
+}
+
+/// 按 schema 检查 `path`，返回全部不符合项（空表示通过）
+pub fn check_bgef(
+    path: &Path,
+    schema: &Schema,
+    bins: &[u32],
+    has_exon: bool,
+) -> hdf5::Result<Vec<String>> {
+    let f = H5File::open(path)?;
+    let mut errors = Vec::new();
+
+    // 1. 组/数据集树
+    let (mut groups, mut datasets) = (BTreeSet::new(), BTreeSet::new());
+    walk(&f, &mut groups, &mut datasets)?;
+    let want_groups: BTreeSet<String> =
+        schema.groups.iter().flat_map(|g| expand(g, bins)).map(|(p, _)| p).collect();
+    let want_datasets: BTreeMap<String, (Option<u32>, &DatasetSpec)> = schema
+        .datasets
+        .iter()
+        .filter(|(_, spec)| has_exon || !spec.exon_only)
+        .flat_map(|(t, spec)| expand(t, bins).into_iter().map(move |(p, b)| (p, (b, spec))))
+        .collect();
+    for g in want_groups.difference(&groups) {
+        errors.push(format!("缺少组 {}", g));
+    }
+    for g in groups.difference(&want_groups) {
+        errors.push(format!("多余的组 {}", g));
+    }
+    for d in want_datasets.keys().filter(|d| !datasets.contains(*d)) {
+        errors.push(format!("缺少数据集 {}", d));
+    }
+    for d in datasets.iter().filter(|d| !want_datasets.contains_key(*d)) {
+        errors.push(format!("多余的数据集 {}", d));
+    }
+
+    // 2. 根属性
+    check_attrs(
+        &f,
+        "/",
+        &schema.root_attrs,
+        ROOT_EXTENSION_ATTRS,
+        &mut errors,
+    )?;
+
+    // 3. 各数据集的 dtype、维数、属性与 shape
+    for (p, &(bin, spec)) in &want_datasets {
+        if !datasets.contains(p) {
+            continue;
+        }
+        let ds = f.dataset(p)?;
+        check_dtype(&ds, p, &spec.dtype, &mut errors)?;
+        let shape = ds.shape();
+        if shape.len() != spec.ndim {
+            errors.push(format!(
+                "{}: 维数应为 {}，实际 shape 为 {:?}",
+                p, spec.ndim, shape
+            ));
+        }
+        check_attrs(&ds, p, &spec.attrs, &[], &mut errors)?;
+        if let Some(names) = &spec.shape_attrs {
+            let want: Vec<usize> = names
+                .iter()
+                .map(|n| attr_u64(&ds, n).map(|v| v as usize))
+                .collect::<hdf5::Result<_>>()?;
+            if shape != want {
+                errors.push(format!(
+                    "{}: shape 应为 {:?} = {:?}，实际为 {:?}",
+                    p, names, want, shape
+                ));
+            }
+        }
+        if let Some(like) = &spec.shape_like {
+            let other = fill(like, bin);
+            let other_shape = f.dataset(&other)?.shape();
+            if shape != other_shape {
+                errors.push(format!(
+                    "{}: shape {:?} 应与 {} 的 {:?} 一致",
+                    p, shape, other, other_shape
+                ));
+            }
+        }
+    }
+
+    // 4. 轴向：wholeExp[(x - minX) / N, (y - minY) / N] 必须等于该 spot 的表达汇总
+    if errors.is_empty() {
+        for &bin in bins {
+            check_whole_exp_axes(&f, bin, has_exon, &mut errors)?;
+        }
+    }
+    Ok(errors)
+}
+
+#[repr(C)]
+#[derive(H5Type, Clone, Copy, Debug)]
+pub struct ExprRow {
+    pub x: i32,
+    pub y: i32,
+    pub count: u32,
+}
+
+#[repr(C)]
+#[allow(non_snake_case)]
+#[derive(H5Type, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SpotRow {
+    pub MIDcount: u32,
+    pub genecount: u16,
+}
+
+fn check_whole_exp_axes(
+    f: &H5File,
+    bin: u32,
+    has_exon: bool,
+    errors: &mut Vec<String>,
+) -> hdf5::Result<()> {
+    let expr = f.dataset(&format!("/geneExp/bin{}/expression", bin))?.read_raw::<ExprRow>()?;
+    let exon = if has_exon {
+        f.dataset(&format!("/geneExp/bin{}/exon", bin))?.read_raw::<u32>()?
+    } else {
+        vec![0; expr.len()]
+    };
+    // 每条 expression 记录是一个 (基因, spot)，spot 汇总即 MIDcount 之和与记录数
+    let mut spots: HashMap<(i32, i32), (SpotRow, u32)> = HashMap::new();
+    for (e, &ex) in expr.iter().zip(&exon) {
+        let v = spots.entry((e.x, e.y)).or_default();
+        v.0.MIDcount += e.count;
+        v.0.genecount += 1;
+        v.1 += ex;
+    }
+
+    let path = format!("/wholeExp/bin{}", bin);
+    let ds = f.dataset(&path)?;
+    let (min_x, min_y) = (attr_u64(&ds, "minX")? as i32, attr_u64(&ds, "minY")? as i32);
+    let whole = ds.read_2d::<SpotRow>()?;
+    let whole_exon = f.dataset(&format!("/wholeExpExon/bin{}", bin))?.read_2d::<u32>()?;
+    let step = bin as i32;
+    let number = whole.iter().filter(|s| s.MIDcount > 0).count() as u64;
+    if number != attr_u64(&ds, "number")? || number != spots.len() as u64 {
+        errors.push(format!(
+            "{}: number 属性 / 非零格数 / expression 中的 spot 数不一致: {} / {} / {}",
+            path,
+            attr_u64(&ds, "number")?,
+            number,
+            spots.len()
+        ));
+    }
+    for (&(x, y), &(want, want_exon)) in &spots {
+        let idx = [((x - min_x) / step) as usize, ((y - min_y) / step) as usize];
+        match whole.get(idx) {
+            Some(got) if *got == want => {}
+            got => {
+                errors.push(format!(
+                    "{}{:?}（spot x={} y={}）应为 {:?}，实际为 {:?}",
+                    path, idx, x, y, want, got
+                ));
+                break;
+            }
+        }
+        if whole_exon.get(idx) != Some(&want_exon) {
+            errors.push(format!(
+                "/wholeExpExon/bin{}{:?}（spot x={} y={}）应为 {}，实际为 {:?}",
+                bin,
+                idx,
+                x,
+                y,
+                want_exon,
+                whole_exon.get(idx)
+            ));
+            break;
+        }
+    }
+    Ok(())
+}
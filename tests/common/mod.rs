@@ -0,0 +1,282 @@
+//! 集成测试共用：临时目录、合成 GEM、运行 gem2gef
+#![allow(dead_code)]
+
+pub mod conformance;
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+    process::{self, Command, Output},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// 测试结束时自动删除的临时目录
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(tag: &str) -> Self {
+        static SEQ: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "gem2gef-{}-{}-{}",
+            tag,
+            process::id(),
+            SEQ.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&dir).expect("create temp dir");
+        Self(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// 一行 GEM 记录
+#[derive(Clone, Debug)]
+pub struct GemRow {
+    pub gene_id: String,
+    pub gene_name: String,
+    pub x: i32,
+    pub y: i32,
+    pub mid: u32,
+    pub exon: u32,
+}
+
+impl GemRow {
+    pub fn new(gene: &str, x: i32, y: i32, mid: u32, exon: u32) -> Self {
+        Self {
+            gene_id: gene.to_string(),
+            gene_name: gene.to_string(),
+            x,
+            y,
+            mid,
+            exon,
+        }
+    }
+}
+
+/// 写出 GEM 文件的列布局
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GemLayout {
+    /// geneID x y MIDCount
+    FourColumn,
+    /// geneID x y MIDCount ExonCount
+    WithExon,
+    /// geneID geneName x y MIDCount ExonCount（SAW 8）
+    SixColumn,
+}
+
+/// 把记录写成 GEM 文本；`meta` 为不带 `#` 的表头注释行（如 `BinSize=1`）
+pub fn write_gem(path: &Path, meta: &[&str], layout: GemLayout, rows: &[GemRow]) {
+    let mut s = String::new();
+    for m in meta {
+        s.push('#');
+        s.push_str(m);
+        s.push('\n');
+    }
+    s.push_str(match layout {
+        GemLayout::FourColumn => "geneID\tx\ty\tMIDCount\n",
+        GemLayout::WithExon => "geneID\tx\ty\tMIDCount\tExonCount\n",
+        GemLayout::SixColumn => "geneID\tgeneName\tx\ty\tMIDCount\tExonCount\n",
+    });
+    for r in rows {
+        let line = match layout {
+            GemLayout::FourColumn => format!("{}\t{}\t{}\t{}\n", r.gene_id, r.x, r.y, r.mid),
+            GemLayout::WithExon => {
+                format!("{}\t{}\t{}\t{}\t{}\n", r.gene_id, r.x, r.y, r.mid, r.exon)
+            }
+            GemLayout::SixColumn => format!(
+                "{}\t{}\t{}\t{}\t{}\t{}\n",
+                r.gene_id, r.gene_name, r.x, r.y, r.mid, r.exon
+            ),
+        };
+        s.push_str(&line);
+    }
+    fs::write(path, s).expect("write GEM");
+}
+
+/// 小型合成 GEM：x 与 y 的跨度不同（便于检查轴向），含重复坐标与跨 bin 边界的点
+pub fn synthetic_rows() -> Vec<GemRow> {
+    let mut rows = Vec::new();
+    let genes = ["Gad1", "Actb", "Mbp", "Snap25", "Xist"];
+    for (gi, gene) in genes.iter().enumerate() {
+        for k in 0..12 {
+            let x = 100 + ((k * 7 + gi * 3) % 37) as i32;
+            let y = 2000 + ((k * 5 + gi) % 9) as i32;
+            let mid = 1 + ((k + gi) % 4) as u32;
+            let exon = ((k + 2 * gi) % 3) as u32;
+            rows.push(GemRow::new(gene, x, y, mid, exon.min(mid)));
+        }
+    }
+    // 重复坐标：应被合并
+    rows.push(GemRow::new("Gad1", 100, 2000, 3, 1));
+    rows
+}
+
+/// GEM 中按 (geneID, geneName, x, y) 汇总的 (MIDCount, ExonCount)；坐标为 bin1 坐标
+pub type GemRecords = BTreeMap<(String, String, i32, i32), (u32, u32)>;
+
+/// 读取 GEM（可为 .gz）：返回 `#key=value` 表头与汇总后的记录。
+/// `#BinSize=N` 的坐标会乘以 N；只有一列基因标识时 geneName 取 geneID。
+pub fn read_gem(path: &Path) -> (BTreeMap<String, String>, GemRecords) {
+    let raw = fs::read(path).expect("read GEM");
+    let text = if path.extension().is_some_and(|e| e == "gz") {
+        let mut s = String::new();
+        flate2::read::GzDecoder::new(raw.as_slice()).read_to_string(&mut s).expect("gunzip GEM");
+        s
+    } else {
+        String::from_utf8(raw).expect("GEM is UTF-8")
+    };
+
+    let mut meta = BTreeMap::new();
+    let mut lines = text.lines();
+    let header = loop {
+        let line = lines.next().expect("GEM has no column header");
+        match line.strip_prefix('#') {
+            Some(kv) => {
+                let (k, v) = kv.split_once('=').unwrap_or((kv, ""));
+                meta.insert(k.to_string(), v.to_string());
+            }
+            None => break line,
+        }
+    };
+    let bin: i32 = meta.get("BinSize").map_or(1, |v| v.parse().unwrap());
+    let cols: Vec<&str> = header.split('\t').collect();
+    let col = |name: &str| cols.iter().position(|c| *c == name);
+    let (gid, gname) = (col("geneID").unwrap(), col("geneName"));
+    let (cx, cy, cm, ce) = (
+        col("x").unwrap(),
+        col("y").unwrap(),
+        col("MIDCount").unwrap(),
+        col("ExonCount"),
+    );
+
+    let mut records = GemRecords::new();
+    for line in lines.filter(|l| !l.is_empty()) {
+        let f: Vec<&str> = line.split('\t').collect();
+        let id = f[gid].to_string();
+        let name = gname.map_or_else(|| id.clone(), |c| f[c].to_string());
+        let x = f[cx].parse::<i32>().unwrap() * bin;
+        let y = f[cy].parse::<i32>().unwrap() * bin;
+        let v = records.entry((id, name, x, y)).or_default();
+        v.0 += f[cm].parse::<u32>().unwrap();
+        v.1 += ce.map_or(0, |c| f[c].parse::<u32>().unwrap());
+    }
+    (meta, records)
+}
+
+/// 运行编译好的 gem2gef
+pub fn gem2gef(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_gem2gef")).args(args).output().expect("run gem2gef")
+}
+
+/// 运行 gem2gef 并要求退出码为 0
+pub fn gem2gef_ok(args: &[&str]) -> Output {
+    let out = gem2gef(args);
+    assert!(
+        out.status.success(),
+        "gem2gef {:?} failed:\n--- stdout\n{}\n--- stderr\n{}",
+        args,
+        String::from_utf8_lossy(&out.stdout),
+        String::from_utf8_lossy(&out.stderr)
+    );
+    out
+}
+
+/// 写出无压缩、单 strip 的 16 位灰度 TIFF（little-endian）；`pixels[y * width + x]`
+pub fn write_mask_tiff(path: &Path, width: u32, height: u32, pixels: &[u16]) {
+    assert_eq!(pixels.len(), (width * height) as usize);
+    let data_len = pixels.len() as u32 * 2;
+    let ifd_pos = 8 + data_len + data_len % 2;
+    // (tag, type, value)：type 3 = SHORT，4 = LONG
+    let entries: [(u16, u16, u32); 9] = [
+        (256, 4, width),
+        (257, 4, height),
+        (258, 3, 16),
+        (259, 3, 1),
+        (262, 3, 1),
+        (273, 4, 8),
+        (277, 3, 1),
+        (278, 4, height),
+        (279, 4, data_len),
+    ];
+    let mut buf = Vec::new();
+    buf.extend_from_slice(b"II");
+    buf.extend_from_slice(&42u16.to_le_bytes());
+    buf.extend_from_slice(&ifd_pos.to_le_bytes());
+    for p in pixels {
+        buf.extend_from_slice(&p.to_le_bytes());
+    }
+    buf.resize(ifd_pos as usize, 0);
+    buf.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    for (tag, ty, value) in entries {
+        buf.extend_from_slice(&tag.to_le_bytes());
+        buf.extend_from_slice(&ty.to_le_bytes());
+        buf.extend_from_slice(&1u32.to_le_bytes());
+        if ty == 3 {
+            buf.extend_from_slice(&(value as u16).to_le_bytes());
+            buf.extend_from_slice(&[0, 0]);
+        } else {
+            buf.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+    buf.extend_from_slice(&0u32.to_le_bytes());
+    fs::write(path, buf).expect("write TIFF");
+}
+
+/// 读取 little-endian、单 strip 的经典 TIFF（gem2gef 写出的小图，无压缩或 Deflate）：
+/// 返回 (width, height, bits, compression, pixels)
+pub fn read_mask_tiff(path: &Path) -> (u32, u32, u32, u32, Vec<u32>) {
+    let buf = fs::read(path).expect("read TIFF");
+    assert_eq!(&buf[..4], b"II*\0", "不是 little-endian 经典 TIFF");
+    let u16_at = |p: usize| u16::from_le_bytes([buf[p], buf[p + 1]]) as u32;
+    let u32_at = |p: usize| u32::from_le_bytes(buf[p..p + 4].try_into().unwrap());
+    let ifd = u32_at(4) as usize;
+    let mut tags = BTreeMap::new();
+    for i in 0..u16_at(ifd) as usize {
+        let e = ifd + 2 + i * 12;
+        assert_eq!(u32_at(e + 4), 1, "标签 {} 不是单值", u16_at(e));
+        let value = if u16_at(e + 2) == 3 {
+            u16_at(e + 8)
+        } else {
+            u32_at(e + 8)
+        };
+        tags.insert(u16_at(e), value);
+    }
+    let (width, height, bits) = (tags[&256], tags[&257], tags[&258]);
+    let (offset, count, bytes) = (tags[&273] as usize, tags[&279] as usize, bits as usize / 8);
+    let strip = match tags[&259] {
+        1 => buf[offset..offset + count].to_vec(),
+        8 => {
+            let mut out = Vec::new();
+            flate2::read::ZlibDecoder::new(&buf[offset..offset + count])
+                .read_to_end(&mut out)
+                .expect("inflate TIFF strip");
+            out
+        }
+        c => panic!("不支持的 TIFF 压缩方式 {}", c),
+    };
+    let pixels = (0..(width * height) as usize)
+        .map(|i| {
+            let b = &strip[i * bytes..(i + 1) * bytes];
+            match bytes {
+                1 => b[0] as u32,
+                2 => u16::from_le_bytes([b[0], b[1]]) as u32,
+                _ => u32::from_le_bytes(b.try_into().unwrap()),
+            }
+        })
+        .collect();
+    (width, height, bits, tags[&259], pixels)
+}
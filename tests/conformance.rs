@@ -0,0 +1,104 @@
+//! 用合成 GEM 生成 bGEF，并与 `tests/data/bgef_schema.json` 描述的 geftools 布局逐项比对
+mod common;
+
+use common::{
+    conformance::{check_bgef, Schema},
+    gem2gef_ok, synthetic_rows, write_gem, GemLayout, TempDir,
+};
+
+/// 转换合成 GEM 并检查；`extra` 为附加的命令行参数
+fn convert_and_check(layout: GemLayout, meta: &[&str], bins: &[u32], extra: &[&str]) {
+    let dir = TempDir::new("conformance");
+    let gem = dir.join("in.gem");
+    let out = dir.join("out.bgef");
+    write_gem(&gem, meta, layout, &synthetic_rows());
+
+    let bins_arg = bins.iter().map(|b| b.to_string()).collect::<Vec<_>>().join(",");
+    let mut args = vec![
+        "-i",
+        gem.to_str().unwrap(),
+        "-o",
+        out.to_str().unwrap(),
+        "-b",
+        bins_arg.as_str(),
+    ];
+    args.extend_from_slice(extra);
+    gem2gef_ok(&args);
+
+    let has_exon = layout != GemLayout::FourColumn;
+    let errors = check_bgef(&out, &Schema::load(), bins, has_exon).expect("read bGEF");
+    assert!(
+        errors.is_empty(),
+        "bGEF 与 schema 不一致:\n  {}",
+        errors.join("\n  ")
+    );
+}
+
+const META: &[&str] = &[
+    "FileFormat=GEMv0.1",
+    "SortedBy=None",
+    "BinType=Bin",
+    "BinSize=1",
+    "Omics=Transcriptomics",
+    "Stereo-seqChip=SS200000000TL_A1",
+    "OffsetX=0",
+    "OffsetY=0",
+];
+
+#[test]
+fn gem_with_exon_matches_schema() {
+    convert_and_check(GemLayout::WithExon, META, &[1, 2, 5], &[]);
+}
+
+#[test]
+fn gem_without_exon_matches_schema() {
+    convert_and_check(GemLayout::FourColumn, META, &[1, 10], &[]);
+}
+
+#[test]
+fn six_column_gem_matches_schema() {
+    convert_and_check(GemLayout::SixColumn, META, &[1, 3], &[]);
+}
+
+#[test]
+fn bounded_memory_output_matches_schema() {
+    convert_and_check(GemLayout::WithExon, META, &[1, 2, 5], &["--max-mem", "1"]);
+}
+
+#[test]
+fn uncompressed_output_matches_schema() {
+    convert_and_check(
+        GemLayout::WithExon,
+        META,
+        &[1, 4],
+        &["--deflate", "0", "--whole-chunk", "3"],
+    );
+}
+
+#[test]
+fn whole_exp_is_x_major() {
+    // x 跨 37 个 spot、y 只跨 9 个：shape 必须是 [lenX, lenY] = [37, 9]
+    let dir = TempDir::new("axes");
+    let gem = dir.join("in.gem");
+    let out = dir.join("out.bgef");
+    write_gem(&gem, META, GemLayout::WithExon, &synthetic_rows());
+    gem2gef_ok(&[
+        "-i",
+        gem.to_str().unwrap(),
+        "-o",
+        out.to_str().unwrap(),
+        "-b",
+        "1",
+    ]);
+
+    let f = hdf5::File::open(&out).unwrap();
+    let ds = f.dataset("/wholeExp/bin1").unwrap();
+    assert_eq!(ds.shape(), vec![37, 9]);
+    let exon = f.dataset("/wholeExpExon/bin1").unwrap();
+    assert_eq!(exon.shape(), vec![37, 9]);
+    assert!(
+        exon.attr("maxExon").is_ok(),
+        "maxExon 应写在 /wholeExpExon/bin1 上"
+    );
+    assert!(ds.attr("maxExon").is_err(), "/wholeExp/bin1 不应带 maxExon");
+}
@@ -0,0 +1,142 @@
+//! cropmask：按 bGEF bin1 表达范围裁剪配准后的 mask TIFF
+mod common;
+
+use common::{
+    gem2gef, gem2gef_ok, read_mask_tiff, synthetic_rows, write_gem, write_mask_tiff, GemLayout,
+    TempDir,
+};
+
+const META: &[&str] = &[
+    "BinType=Bin",
+    "BinSize=1",
+    "Omics=Transcriptomics",
+    "Stereo-seqChip=SS200000000TL_A1",
+];
+
+/// 生成 bin1 bGEF 与 `width x height` 的 mask（像素值由坐标决定），返回 cropmask 的输出
+fn run_cropmask(
+    dir: &TempDir,
+    width: u32,
+    height: u32,
+    value: impl Fn(u32, u32) -> u16,
+) -> std::process::Output {
+    let (gem, bgef, tif, out) = (
+        dir.join("in.gem"),
+        dir.join("out.bgef"),
+        dir.join("mask.tif"),
+        dir.join("roi.tif"),
+    );
+    write_gem(&gem, META, GemLayout::WithExon, &synthetic_rows());
+    gem2gef_ok(&[
+        "-i",
+        gem.to_str().unwrap(),
+        "-o",
+        bgef.to_str().unwrap(),
+        "-b",
+        "1",
+    ]);
+    let pixels: Vec<u16> = (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .map(|(x, y)| value(x, y))
+        .collect();
+    write_mask_tiff(&tif, width, height, &pixels);
+    gem2gef(&[
+        "cropmask",
+        "-i",
+        bgef.to_str().unwrap(),
+        "-m",
+        tif.to_str().unwrap(),
+        "-o",
+        out.to_str().unwrap(),
+    ])
+}
+
+/// 表达坐标的范围 (minX, minY, maxX, maxY)；合成 GEM 从 (100, 2000) 开始，不在原点
+fn expression_extent() -> (u32, u32, u32, u32) {
+    let rows = synthetic_rows();
+    (
+        rows.iter().map(|r| r.x).min().unwrap() as u32,
+        rows.iter().map(|r| r.y).min().unwrap() as u32,
+        rows.iter().map(|r| r.x).max().unwrap() as u32,
+        rows.iter().map(|r| r.y).max().unwrap() as u32,
+    )
+}
+
+#[test]
+fn cropmask_expression_extent() {
+    let dir = TempDir::new("cropmask_extent");
+    let (min_x, min_y, max_x, max_y) = expression_extent();
+    assert!(min_x > 0 && min_y > 0);
+    let value = |x: u32, y: u32| ((x * 7 + y * 3) % 256) as u16;
+    let out = run_cropmask(&dir, max_x + 20, max_y + 10, value);
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+
+    let (width, height, bits, compression, pixels) = read_mask_tiff(&dir.join("roi.tif"));
+    assert_eq!(
+        (width, height),
+        (max_x - min_x + 1, max_y - min_y + 1),
+        "含端裁剪 [minX, maxX] x [minY, maxY]"
+    );
+    assert_eq!((bits, compression), (8, 1), "无压缩 uint8");
+    // 输出的 (0, 0) 即 mask 的 (minX, minY)
+    for y in 0..height {
+        for x in 0..width {
+            assert_eq!(
+                pixels[(y * width + x) as usize],
+                u32::from(value(min_x + x, min_y + y)),
+                "({}, {})",
+                min_x + x,
+                min_y + y
+            );
+        }
+    }
+}
+
+#[test]
+fn cropmask_keeps_wide_labels() {
+    // 标签超过 255 时不能截断成 uint8
+    let dir = TempDir::new("cropmask_wide");
+    let (min_x, min_y, max_x, max_y) = expression_extent();
+    let value = |x: u32, y: u32| {
+        if x == max_x && y == max_y {
+            40000
+        } else {
+            (x % 3) as u16
+        }
+    };
+    let out = run_cropmask(&dir, max_x + 1, max_y + 1, value);
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+
+    let (width, height, bits, _, pixels) = read_mask_tiff(&dir.join("roi.tif"));
+    assert_eq!(
+        (width, height, bits),
+        (max_x - min_x + 1, max_y - min_y + 1, 16)
+    );
+    assert_eq!(pixels.last(), Some(&40000));
+    assert_eq!(pixels[1], (min_x + 1) % 3);
+}
+
+#[test]
+fn cropmask_out_of_bounds() {
+    let (_, _, max_x, max_y) = expression_extent();
+    for (tag, width, height) in [("x", max_x, max_y + 1), ("y", max_x + 1, max_y)] {
+        let dir = TempDir::new(&format!("cropmask_oob_{}", tag));
+        let out = run_cropmask(&dir, width, height, |_, _| 1);
+        assert!(
+            !out.status.success(),
+            "ROI 超出 mask 的 {} 方向时应失败",
+            tag
+        );
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(stderr.contains("超出 mask 范围"), "{}", stderr);
+        assert!(!dir.join("roi.tif").exists());
+    }
+}
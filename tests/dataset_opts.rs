@@ -0,0 +1,104 @@
+//! --deflate/--shuffle 与 --*-chunk：按命令行设置各数据集的过滤器与 chunk，数据本身不变；
+//! 默认与 geftools 一致（deflate 4，不 shuffle）
+mod common;
+
+use std::path::Path;
+
+use common::{gem2gef_ok, synthetic_rows, write_gem, GemLayout, TempDir};
+use hdf5::filters::Filter;
+use hdf5::H5Type;
+
+const META: &[&str] = &[
+    "BinType=Bin",
+    "BinSize=1",
+    "Omics=Transcriptomics",
+    "Stereo-seqChip=SS200000000TL_A1",
+];
+
+const MODES: [(&str, &[&str]); 2] = [("mem", &[]), ("spill", &["--max-mem", "1"])];
+
+/// 一维数据集及其 chunk 对应的选项
+const ROWS: [(&str, &str); 4] = [
+    ("/geneExp/bin1/expression", "--expr-chunk"),
+    ("/geneExp/bin1/exon", "--expr-chunk"),
+    ("/geneExp/bin1/gene", "--gene-chunk"),
+    ("/stat/gene", "--gene-chunk"),
+];
+const MATRICES: [&str; 2] = ["/wholeExp/bin1", "/wholeExpExon/bin1"];
+
+#[repr(C)]
+#[derive(H5Type, Clone, Copy, Debug, PartialEq)]
+struct Expr {
+    x: i32,
+    y: i32,
+    count: u32,
+}
+
+fn convert(gem: &Path, out: &Path, extra: &[&str]) -> hdf5::File {
+    let mut args = vec![
+        "-i",
+        gem.to_str().unwrap(),
+        "-o",
+        out.to_str().unwrap(),
+        "-b",
+        "1",
+    ];
+    args.extend_from_slice(extra);
+    gem2gef_ok(&args);
+    hdf5::File::open(out).unwrap()
+}
+
+#[test]
+fn dataset_filters_and_chunks_follow_options() {
+    let dir = TempDir::new("dataset_opts");
+    let gem = dir.join("in.gem");
+    write_gem(&gem, META, GemLayout::WithExon, &synthetic_rows());
+
+    for (mode, extra) in MODES {
+        let default = convert(&gem, &dir.join(&format!("{}_default.bgef", mode)), extra);
+        let mut args = extra.to_vec();
+        args.extend([
+            "--deflate",
+            "6",
+            "--shuffle",
+            "--expr-chunk",
+            "7",
+            "--gene-chunk",
+            "2",
+            "--whole-chunk",
+            "5",
+        ]);
+        let tuned = convert(&gem, &dir.join(&format!("{}_tuned.bgef", mode)), &args);
+
+        for path in ROWS.iter().map(|r| r.0).chain(MATRICES) {
+            let ds = default.dataset(path).unwrap();
+            assert_eq!(ds.filters(), [Filter::Deflate(4)], "{} {}", mode, path);
+            let ds = tuned.dataset(path).unwrap();
+            assert_eq!(
+                ds.filters(),
+                [Filter::Shuffle, Filter::Deflate(6)],
+                "{} {}",
+                mode,
+                path
+            );
+        }
+        for (path, opt) in ROWS {
+            let want = if opt == "--expr-chunk" { 7 } else { 2 };
+            let chunk = tuned.dataset(path).unwrap().chunk();
+            assert_eq!(chunk, Some(vec![want]), "{} {}", mode, path);
+        }
+        for path in MATRICES {
+            let chunk = tuned.dataset(path).unwrap().chunk();
+            assert_eq!(chunk, Some(vec![5, 5]), "{} {}", mode, path);
+        }
+
+        // 压缩与分块不改变数据
+        let expr = |f: &hdf5::File| {
+            let ds = f.dataset("/geneExp/bin1/expression").unwrap();
+            ds.read_raw::<Expr>().unwrap()
+        };
+        assert_eq!(expr(&tuned), expr(&default), "{}", mode);
+        let whole = |f: &hdf5::File| f.dataset("/wholeExpExon/bin1").unwrap().read_2d::<u32>();
+        assert_eq!(whole(&tuned).unwrap(), whole(&default).unwrap(), "{}", mode);
+    }
+}
@@ -0,0 +1,123 @@
+//! --duplicates：同一 (gene, x, y) 多行时的合并策略，以及记录在根属性中的策略与行数
+mod common;
+
+use std::collections::BTreeMap;
+
+use common::{gem2gef, gem2gef_ok, read_gem, write_gem, GemLayout, GemRow, TempDir};
+use hdf5::types::VarLenUnicode;
+
+const META: &[&str] = &[
+    "BinType=Bin",
+    "BinSize=1",
+    "Omics=Transcriptomics",
+    "Stereo-seqChip=SS200000000TL_A1",
+];
+
+/// 共 3 行重复：Gad1@(100,2000) 三行，Actb@(101,2000) 两行
+fn rows() -> Vec<GemRow> {
+    vec![
+        GemRow::new("Gad1", 100, 2000, 3, 1),
+        GemRow::new("Actb", 101, 2000, 1, 0),
+        GemRow::new("Gad1", 100, 2000, 5, 2),
+        GemRow::new("Mbp", 102, 2001, 7, 3),
+        GemRow::new("Gad1", 100, 2000, 5, 0),
+        GemRow::new("Actb", 101, 2000, 4, 1),
+    ]
+}
+
+/// (geneID, x, y) -> (MID, exon)
+type Records = BTreeMap<(String, i32, i32), (u32, u32)>;
+
+/// 转换（`extra` 为附加参数）后导出 GEM，返回导出的记录与 duplicate_policy/duplicate_rows
+fn convert(tag: &str, extra: &[&str]) -> (Records, String, u64) {
+    let dir = TempDir::new(tag);
+    let (gem, bgef, back) = (
+        dir.join("in.gem"),
+        dir.join("out.bgef"),
+        dir.join("back.gem"),
+    );
+    write_gem(&gem, META, GemLayout::WithExon, &rows());
+    let mut args = vec![
+        "-i",
+        gem.to_str().unwrap(),
+        "-o",
+        bgef.to_str().unwrap(),
+        "-b",
+        "1",
+    ];
+    args.extend_from_slice(extra);
+    gem2gef_ok(&args);
+    gem2gef_ok(&[
+        "gef2gem",
+        "-i",
+        bgef.to_str().unwrap(),
+        "-o",
+        back.to_str().unwrap(),
+    ]);
+
+    let f = hdf5::File::open(&bgef).unwrap();
+    let policy = f.attr("duplicate_policy").unwrap().read_scalar::<VarLenUnicode>().unwrap();
+    let count = f.attr("duplicate_rows").unwrap().read_scalar::<u64>().unwrap();
+    let records = read_gem(&back).1.into_iter().map(|((id, _, x, y), v)| ((id, x, y), v)).collect();
+    (records, policy.as_str().to_string(), count)
+}
+
+#[test]
+fn duplicate_policies() {
+    let key = |g: &str, x: i32, y: i32| (g.to_string(), x, y);
+    for (policy, gad1, actb) in [
+        ("sum", (13, 3), (5, 1)),
+        ("keep-first", (3, 1), (1, 0)),
+        ("keep-max", (5, 2), (4, 1)),
+    ] {
+        for (mode, extra) in [("mem", vec![]), ("spill", vec!["--max-mem", "1"])] {
+            let mut args = vec!["--duplicates", policy];
+            args.extend(extra);
+            let (records, name, count) = convert(&format!("dup_{}_{}", policy, mode), &args);
+            assert_eq!(name, policy, "{} {}", policy, mode);
+            assert_eq!(count, 3, "{} {}", policy, mode);
+            assert_eq!(records.len(), 3, "{} {}", policy, mode);
+            for (k, want) in [
+                (key("Gad1", 100, 2000), gad1),
+                (key("Actb", 101, 2000), actb),
+                (key("Mbp", 102, 2001), (7, 3)),
+            ] {
+                assert_eq!(records[&k], want, "{} {} {:?}", policy, mode, k);
+            }
+        }
+    }
+}
+
+#[test]
+fn duplicate_default_is_sum() {
+    let (records, name, count) = convert("dup_default", &[]);
+    assert_eq!((name.as_str(), count), ("sum", 3));
+    assert_eq!(records[&("Gad1".to_string(), 100, 2000)], (13, 3));
+}
+
+#[test]
+fn duplicate_error_policy() {
+    for (mode, extra) in [("mem", vec![]), ("spill", vec!["--max-mem", "1"])] {
+        let dir = TempDir::new(&format!("dup_error_{}", mode));
+        let (gem, bgef) = (dir.join("in.gem"), dir.join("out.bgef"));
+        write_gem(&gem, META, GemLayout::WithExon, &rows());
+        let mut args = vec![
+            "-i",
+            gem.to_str().unwrap(),
+            "-o",
+            bgef.to_str().unwrap(),
+            "--duplicates",
+            "error",
+        ];
+        args.extend(extra);
+        let out = gem2gef(&args);
+        assert!(!out.status.success(), "{}: 有重复行时应失败", mode);
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(stderr.contains("重复的 (gene, x, y) 记录"), "{}", stderr);
+        assert!(stderr.contains("x=100 y=2000"), "{}", stderr);
+        if mode == "mem" {
+            // 表头 4 行 + 列名 1 行，第一个重复行是第 3 条记录
+            assert!(stderr.contains("第 8 行"), "{}", stderr);
+        }
+    }
+}
@@ -0,0 +1,230 @@
+//! GEM -> bGEF -> GEM 往返必须无损
+mod common;
+
+use std::path::Path;
+
+use common::{gem2gef_ok, read_gem, synthetic_rows, write_gem, GemLayout, GemRow, TempDir};
+
+const META: &[&str] = &[
+    "FileFormat=GEMv0.1",
+    "SortedBy=None",
+    "BinType=Bin",
+    "BinSize=1",
+    "Omics=Transcriptomics",
+    "Stereo-seqChip=SS200000000TL_A1",
+    "OffsetX=0",
+    "OffsetY=0",
+];
+
+/// 转换为 bGEF 再导出 GEM，比较汇总后的记录与表头
+fn round_trip(layout: GemLayout, rows: &[GemRow], exported: &str) {
+    let dir = TempDir::new("gef2gem");
+    let gem = dir.join("in.gem");
+    let bgef = dir.join("out.bgef");
+    let back = dir.join(exported);
+    write_gem(&gem, META, layout, rows);
+
+    gem2gef_ok(&[
+        "-i",
+        gem.to_str().unwrap(),
+        "-o",
+        bgef.to_str().unwrap(),
+        "-b",
+        "1,10",
+    ]);
+    gem2gef_ok(&[
+        "gef2gem",
+        "-i",
+        bgef.to_str().unwrap(),
+        "-o",
+        back.to_str().unwrap(),
+    ]);
+
+    let (meta_in, want) = read_gem(&gem);
+    let (meta_out, got) = read_gem(&back);
+    assert_eq!(got, want);
+    for key in [
+        "BinType",
+        "BinSize",
+        "Omics",
+        "Stereo-seqChip",
+        "OffsetX",
+        "OffsetY",
+    ] {
+        assert_eq!(meta_out.get(key), meta_in.get(key), "#{} 不一致", key);
+    }
+}
+
+#[test]
+fn round_trip_with_exon() {
+    round_trip(GemLayout::WithExon, &synthetic_rows(), "back.gem");
+}
+
+#[test]
+fn round_trip_without_exon() {
+    round_trip(GemLayout::FourColumn, &synthetic_rows(), "back.gem");
+}
+
+#[test]
+fn round_trip_six_column_gzipped() {
+    let rows: Vec<GemRow> = synthetic_rows()
+        .into_iter()
+        .map(|mut r| {
+            r.gene_name = format!("{}-name", r.gene_id);
+            r.gene_id = format!("ENSMUSG_{}", r.gene_id.to_uppercase());
+            r
+        })
+        .collect();
+    round_trip(GemLayout::SixColumn, &rows, "back.gem.gz");
+}
+
+#[test]
+fn export_binned_level() {
+    // 导出 bin10：#BinSize=10，坐标为 bin 序号；乘回 10 后等于按 bin10 汇总的原始记录
+    let dir = TempDir::new("gef2gem-bin");
+    let gem = dir.join("in.gem");
+    let bgef = dir.join("out.bgef");
+    let back = dir.join("bin10.gem");
+    write_gem(&gem, META, GemLayout::WithExon, &synthetic_rows());
+    gem2gef_ok(&[
+        "-i",
+        gem.to_str().unwrap(),
+        "-o",
+        bgef.to_str().unwrap(),
+        "-b",
+        "1,10",
+    ]);
+    gem2gef_ok(&[
+        "gef2gem",
+        "-i",
+        bgef.to_str().unwrap(),
+        "-o",
+        back.to_str().unwrap(),
+        "--bin",
+        "10",
+    ]);
+
+    let (_, bin1) = read_gem(&gem);
+    let mut want = common::GemRecords::new();
+    for ((id, name, x, y), (mid, exon)) in bin1 {
+        let v = want.entry((id, name, x.div_euclid(10) * 10, y.div_euclid(10) * 10)).or_default();
+        v.0 += mid;
+        v.1 += exon;
+    }
+    let (meta, got) = read_gem(&back);
+    assert_eq!(meta.get("BinSize").map(String::as_str), Some("10"));
+    assert_eq!(got, want);
+
+    // 再转回 bGEF 时 bin10 的 GEM 只能输出 10 的倍数
+    let again = dir.join("again.bgef");
+    gem2gef_ok(&["-i", back.to_str().unwrap(), "-o", again.to_str().unwrap()]);
+}
+
+/// 表头平移不是 20 的倍数，导出 bin20 时需要拆成整 bin 部分与余数
+const META_OFFSET: &[&str] = &[
+    "FileFormat=GEMv0.1",
+    "SortedBy=None",
+    "BinType=Bin",
+    "BinSize=1",
+    "Omics=Transcriptomics",
+    "Stereo-seqChip=SS200000000TL_A1",
+    "OffsetX=7",
+    "OffsetY=13",
+];
+const OFFSET: (i32, i32) = (7, 13);
+
+/// 导出 GEM 的表头平移与芯片坐标（`x * BinSize + #OffsetX`）下的记录
+fn chip_records(path: &Path) -> ((i32, i32), common::GemRecords) {
+    let (meta, records) = read_gem(path);
+    let off = |key: &str| meta[key].parse::<i32>().unwrap();
+    let (ox, oy) = (off("OffsetX"), off("OffsetY"));
+    let shifted =
+        records.into_iter().map(|((id, name, x, y), v)| ((id, name, x + ox, y + oy), v)).collect();
+    ((ox, oy), shifted)
+}
+
+/// 转换为 bGEF（bin1、bin20）后分别导出两个 bin，再用同样的平移选项转换导出的 bin20 并再次导出
+fn offset_round_trip(apply: bool) {
+    let dir = TempDir::new(if apply {
+        "gef2gem-applied"
+    } else {
+        "gef2gem-offset"
+    });
+    let gem = dir.join("in.gem");
+    let bgef = dir.join("out.bgef");
+    write_gem(&gem, META_OFFSET, GemLayout::WithExon, &synthetic_rows());
+    let convert = |input: &Path, output: &Path, bins: &str| {
+        let mut args = vec![
+            "-i",
+            input.to_str().unwrap(),
+            "-o",
+            output.to_str().unwrap(),
+            "-b",
+            bins,
+        ];
+        if apply {
+            args.push("--apply-offset");
+        }
+        gem2gef_ok(&args);
+    };
+    let export = |input: &Path, output: &Path, bin: &str| {
+        gem2gef_ok(&[
+            "gef2gem",
+            "-i",
+            input.to_str().unwrap(),
+            "-o",
+            output.to_str().unwrap(),
+            "--bin",
+            bin,
+        ]);
+    };
+    convert(&gem, &bgef, "1,20");
+
+    // bin1：表头与记录都与源 GEM 相同
+    let bin1 = dir.join("bin1.gem");
+    export(&bgef, &bin1, "1");
+    let (meta_in, want) = read_gem(&gem);
+    let (meta_out, got) = read_gem(&bin1);
+    assert_eq!(got, want);
+    for key in ["OffsetX", "OffsetY"] {
+        assert_eq!(meta_out.get(key), meta_in.get(key), "#{} 不一致", key);
+    }
+
+    // bin20：bGEF 按（是否平移后的）坐标分箱；导出后换算回芯片坐标应等于 bin 左上角
+    let applied = if apply { OFFSET } else { (0, 0) };
+    let mut want = common::GemRecords::new();
+    for ((id, name, x, y), (mid, exon)) in read_gem(&gem).1 {
+        let bx = (x + applied.0).div_euclid(20) * 20 - applied.0 + OFFSET.0;
+        let by = (y + applied.1).div_euclid(20) * 20 - applied.1 + OFFSET.1;
+        let v = want.entry((id, name, bx, by)).or_default();
+        v.0 += mid;
+        v.1 += exon;
+    }
+    let bin20 = dir.join("bin20.gem");
+    export(&bgef, &bin20, "20");
+    let (header, got) = chip_records(&bin20);
+    assert_eq!(got, want);
+    let header_want = if apply {
+        (OFFSET.0 - OFFSET.0 % 20, OFFSET.1 - OFFSET.1 % 20)
+    } else {
+        OFFSET
+    };
+    assert_eq!(header, header_want);
+
+    // 用同样的选项再次转换，bGEF 坐标不变，导出结果相同
+    let again = dir.join("again.bgef");
+    convert(&bin20, &again, "20");
+    let bin20_again = dir.join("bin20_again.gem");
+    export(&again, &bin20_again, "20");
+    assert_eq!(read_gem(&bin20_again), read_gem(&bin20));
+}
+
+#[test]
+fn round_trip_keeps_unapplied_offset() {
+    offset_round_trip(false);
+}
+
+#[test]
+fn round_trip_with_applied_offset() {
+    offset_round_trip(true);
+}
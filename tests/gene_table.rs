@@ -0,0 +1,167 @@
+//! --gtf / --gene-table：基因标识解析为 geneID/geneName，解析为同一基因的标识归并，基因按解析结果排序
+mod common;
+
+use std::fs;
+use std::path::Path;
+
+use common::{gem2gef_ok, read_gem, write_gem, GemLayout, GemRecords, GemRow, TempDir};
+use hdf5::types::{FixedAscii, VarLenUnicode};
+use hdf5::H5Type;
+
+const META: &[&str] = &["BinType=Bin", "BinSize=1", "Omics=Transcriptomics"];
+
+/// 只有 feature == "gene" 的行参与建表；transcript 行中的 gene_name 不应覆盖
+const GTF: &str = "\
+#!genome-build GRCm39
+1\thavana\tgene\t100\t900\t.\t+\t.\tgene_id \"ENSMUSG01\"; gene_version \"3\"; gene_name \"Actb\"; gene_biotype \"protein_coding\";
+1\thavana\ttranscript\t100\t900\t.\t+\t.\tgene_id \"ENSMUSG01\"; transcript_id \"ENSMUST01\"; gene_name \"Actb-201\";
+1\thavana\tgene\t1000\t1900\t.\t-\t.\tgene_id \"ENSMUSG02\"; gene_name \"Gad1\";
+2\thavana\tgene\t100\t900\t.\t+\t.\tgene_id \"ENSMUSG00\"; gene_name \"Zfp1\";
+";
+
+#[repr(C)]
+#[allow(non_snake_case)]
+#[derive(H5Type, Clone, Copy, Debug)]
+struct Gene {
+    geneID: FixedAscii<64>,
+    geneName: FixedAscii<64>,
+    offset: u32,
+    count: u32,
+}
+
+#[repr(C)]
+#[allow(non_snake_case)]
+#[derive(H5Type, Clone, Copy, Debug)]
+struct StatGene {
+    geneID: FixedAscii<64>,
+    geneName: FixedAscii<64>,
+    MIDcount: u32,
+    E10: f32,
+}
+
+/// GEM 只有一列基因标识：symbol（大小写不一）、geneID 与表中没有的标识混用
+fn rows() -> Vec<GemRow> {
+    vec![
+        GemRow::new("Zfp1", 4, 4, 5, 1),
+        GemRow::new("Actb", 1, 1, 2, 1),
+        GemRow::new("ACTB", 1, 1, 3, 0),
+        GemRow::new("ACTB", 2, 2, 1, 1),
+        GemRow::new("ENSMUSG02", 3, 3, 4, 2),
+        GemRow::new("Unknown", 5, 5, 1, 0),
+    ]
+}
+
+fn expected() -> GemRecords {
+    let rec = |id: &str, name: &str, x, y, mid, exon| {
+        ((id.to_string(), name.to_string(), x, y), (mid, exon))
+    };
+    [
+        rec("ENSMUSG00", "Zfp1", 4, 4, 5, 1),
+        rec("ENSMUSG01", "Actb", 1, 1, 5, 1),
+        rec("ENSMUSG01", "Actb", 2, 2, 1, 1),
+        rec("ENSMUSG02", "Gad1", 3, 3, 4, 2),
+        rec("Unknown", "Unknown", 5, 5, 1, 0),
+    ]
+    .into_iter()
+    .collect()
+}
+
+fn check(bgef: &Path, back: &Path) {
+    let f = hdf5::File::open(bgef).unwrap();
+    let genes: Vec<(String, String)> = f
+        .dataset("/geneExp/bin1/gene")
+        .unwrap()
+        .read_raw::<Gene>()
+        .unwrap()
+        .iter()
+        .map(|g| {
+            (
+                g.geneID.as_str().to_string(),
+                g.geneName.as_str().to_string(),
+            )
+        })
+        .collect();
+    let want: Vec<(String, String)> = [
+        ("ENSMUSG00", "Zfp1"),
+        ("ENSMUSG01", "Actb"),
+        ("ENSMUSG02", "Gad1"),
+        ("Unknown", "Unknown"),
+    ]
+    .iter()
+    .map(|(a, b)| (a.to_string(), b.to_string()))
+    .collect();
+    assert_eq!(
+        genes, want,
+        "每个 geneID 只有一行，且按解析后的 geneID 排序"
+    );
+
+    // Actb 与 ACTB 在 (1, 1) 上是同一基因的重复行
+    let rows = f.attr("duplicate_rows").unwrap().read_scalar::<u64>().unwrap();
+    assert_eq!(rows, 1);
+    let policy = f.attr("duplicate_policy").unwrap().read_scalar::<VarLenUnicode>().unwrap();
+    assert_eq!(policy.as_str(), "sum");
+
+    let stats = f.dataset("/stat/gene").unwrap().read_raw::<StatGene>().unwrap();
+    let actb = stats.iter().find(|s| s.geneID.as_str() == "ENSMUSG01").unwrap();
+    assert_eq!((actb.geneName.as_str(), actb.MIDcount), ("Actb", 6));
+    assert_eq!(stats.len(), 4);
+
+    gem2gef_ok(&[
+        "gef2gem",
+        "-i",
+        bgef.to_str().unwrap(),
+        "-o",
+        back.to_str().unwrap(),
+    ]);
+    assert_eq!(read_gem(back).1, expected());
+}
+
+#[test]
+fn resolve_with_gtf_and_gene_table() {
+    for (mode, extra) in [("mem", vec![]), ("spill", vec!["--max-mem", "1"])] {
+        let dir = TempDir::new(&format!("gene_table_{}", mode));
+        let (gem, gtf, table) = (
+            dir.join("in.gem"),
+            dir.join("genes.gtf"),
+            dir.join("genes.json"),
+        );
+        write_gem(&gem, META, GemLayout::WithExon, &rows());
+        fs::write(&gtf, GTF).unwrap();
+
+        let convert = |out: &Path, genes: &[&str]| {
+            let mut args = vec![
+                "-i",
+                gem.to_str().unwrap(),
+                "-o",
+                out.to_str().unwrap(),
+                "-b",
+                "1",
+            ];
+            args.extend_from_slice(genes);
+            args.extend_from_slice(&extra);
+            gem2gef_ok(&args);
+        };
+
+        // 只给 --gtf
+        let out = dir.join("gtf.bgef");
+        convert(&out, &["--gtf", gtf.to_str().unwrap()]);
+        check(&out, &dir.join("gtf.gem"));
+
+        // --gtf 与 --gene-table 同时给出时重建对照表，之后单独使用该表
+        let out = dir.join("rebuild.bgef");
+        convert(
+            &out,
+            &[
+                "--gtf",
+                gtf.to_str().unwrap(),
+                "--gene-table",
+                table.to_str().unwrap(),
+            ],
+        );
+        check(&out, &dir.join("rebuild.gem"));
+        assert!(table.exists());
+        let out = dir.join("table.bgef");
+        convert(&out, &["--gene-table", table.to_str().unwrap()]);
+        check(&out, &dir.join("table.gem"));
+    }
+}
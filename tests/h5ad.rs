@@ -0,0 +1,105 @@
+//! bGEF -> .h5ad：检查 AnnData 编码与 CSR 内容
+mod common;
+
+use std::collections::BTreeMap;
+
+use common::{gem2gef_ok, read_gem, synthetic_rows, write_gem, GemLayout, TempDir};
+use hdf5::types::VarLenUnicode;
+
+const META: &[&str] = &[
+    "BinType=Bin",
+    "BinSize=1",
+    "Omics=Transcriptomics",
+    "Stereo-seqChip=SS200000000TL_A1",
+];
+
+fn encoding(loc: &hdf5::Location) -> String {
+    loc.attr("encoding-type").unwrap().read_scalar::<VarLenUnicode>().unwrap().to_string()
+}
+
+#[test]
+fn export_bin10_to_h5ad() {
+    let dir = TempDir::new("h5ad");
+    let gem = dir.join("in.gem");
+    let bgef = dir.join("out.bgef");
+    let h5ad = dir.join("bin10.h5ad");
+    write_gem(&gem, META, GemLayout::WithExon, &synthetic_rows());
+    gem2gef_ok(&[
+        "-i",
+        gem.to_str().unwrap(),
+        "-o",
+        bgef.to_str().unwrap(),
+        "-b",
+        "1,10",
+    ]);
+    gem2gef_ok(&[
+        "gef2h5ad",
+        "-i",
+        bgef.to_str().unwrap(),
+        "-o",
+        h5ad.to_str().unwrap(),
+        "--bin",
+        "10",
+    ]);
+
+    // 期望值：原始 GEM 按 bin10 汇总到 (x, y) -> geneID -> MID
+    let (_, records) = read_gem(&gem);
+    let mut want: BTreeMap<(i32, i32), BTreeMap<String, u32>> = BTreeMap::new();
+    for ((id, _, x, y), (mid, _)) in records {
+        let spot = (x.div_euclid(10) * 10, y.div_euclid(10) * 10);
+        *want.entry(spot).or_default().entry(id).or_default() += mid;
+    }
+
+    let f = hdf5::File::open(&h5ad).unwrap();
+    assert_eq!(encoding(&f), "anndata");
+    for g in ["obsm", "uns", "layers", "obsp", "varm", "varp"] {
+        assert_eq!(encoding(&f.group(g).unwrap()), "dict", "/{}", g);
+    }
+
+    let x = f.group("X").unwrap();
+    assert_eq!(encoding(&x), "csr_matrix");
+    let shape = x.attr("shape").unwrap().read_raw::<i64>().unwrap();
+    let data = x.dataset("data").unwrap().read_raw::<f32>().unwrap();
+    let indices = x.dataset("indices").unwrap().read_raw::<i64>().unwrap();
+    let indptr = x.dataset("indptr").unwrap().read_raw::<i64>().unwrap();
+    assert_eq!(shape, vec![want.len() as i64, 5]);
+    assert_eq!(indptr.len(), want.len() + 1);
+
+    let read_strings = |path: &str| -> Vec<String> {
+        let v = f.dataset(path).unwrap().read_raw::<VarLenUnicode>().unwrap();
+        v.iter().map(|s| s.to_string()).collect()
+    };
+    let obs = f.group("obs").unwrap();
+    assert_eq!(encoding(&obs), "dataframe");
+    let gene_ids = read_strings("var/_index");
+    assert_eq!(read_strings("var/geneID"), gene_ids);
+    let xs = obs.dataset("x").unwrap().read_raw::<i32>().unwrap();
+    let ys = obs.dataset("y").unwrap().read_raw::<i32>().unwrap();
+    let totals = obs.dataset("total_counts").unwrap().read_raw::<u64>().unwrap();
+    let spatial = f.dataset("obsm/spatial").unwrap().read_2d::<i32>().unwrap();
+    assert_eq!(spatial.shape(), &[want.len(), 2]);
+
+    for (row, ((&x, &y), (spot, genes))) in xs.iter().zip(&ys).zip(&want).enumerate() {
+        assert_eq!((x, y), *spot);
+        assert_eq!((spatial[[row, 0]], spatial[[row, 1]]), *spot);
+        let (a, b) = (indptr[row] as usize, indptr[row + 1] as usize);
+        let got: BTreeMap<String, u32> =
+            (a..b).map(|k| (gene_ids[indices[k] as usize].clone(), data[k] as u32)).collect();
+        assert_eq!(&got, genes, "spot {:?}", spot);
+        assert!(indices[a..b].windows(2).all(|w| w[0] < w[1]), "列号需升序");
+        assert_eq!(
+            totals[row],
+            genes.values().map(|&v| u64::from(v)).sum::<u64>()
+        );
+    }
+
+    let uns = f.group("uns").unwrap();
+    assert_eq!(
+        uns.dataset("bin_size").unwrap().read_scalar::<u32>().unwrap(),
+        10
+    );
+    assert_eq!(
+        uns.dataset("sn").unwrap().read_scalar::<VarLenUnicode>().unwrap().as_str(),
+        "SS200000000TL_A1"
+    );
+}
@@ -0,0 +1,297 @@
+//! merge：多个 GEM/bGEF 按各自的坐标变换合并为一个 bGEF，/wholeExpSample 记录每个 spot 的来源样本
+mod common;
+
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use common::{
+    gem2gef, gem2gef_ok, read_gem, synthetic_rows, write_gem, GemLayout, GemRecords, GemRow,
+    TempDir,
+};
+use hdf5::types::{FixedAscii, VarLenUnicode};
+use hdf5::H5Type;
+use ndarray::Array2;
+
+const META: &[&str] = &[
+    "BinType=Bin",
+    "BinSize=1",
+    "Omics=Transcriptomics",
+    "Stereo-seqChip=SS200000000TL_A1",
+];
+
+#[repr(C)]
+#[allow(non_snake_case)]
+#[derive(H5Type, Clone, Copy, Debug)]
+struct Gene {
+    geneID: FixedAscii<64>,
+    geneName: FixedAscii<64>,
+    offset: u32,
+    count: u32,
+}
+
+#[repr(C)]
+#[allow(non_snake_case)]
+#[derive(H5Type, Clone, Copy, Debug)]
+struct Sample {
+    name: FixedAscii<64>,
+    transform: [f64; 6],
+    spots: u64,
+    MIDcount: u64,
+}
+
+/// 第二个样本：部分基因与第一个样本相同，另有一个新基因
+fn second_rows() -> Vec<GemRow> {
+    vec![
+        GemRow::new("Gad1", 10, 20, 4, 1),
+        GemRow::new("Gad1", 11, 20, 2, 0),
+        GemRow::new("Olig2", 10, 20, 6, 2),
+        GemRow::new("Olig2", 12, 25, 1, 1),
+        GemRow::new("Mbp", 15, 21, 3, 0),
+    ]
+}
+
+/// 把记录平移或变换后汇总
+fn placed(rows: &[GemRow], f: impl Fn(i32, i32) -> (i32, i32)) -> GemRecords {
+    let mut records = GemRecords::new();
+    for r in rows {
+        let (x, y) = f(r.x, r.y);
+        let v = records.entry((r.gene_id.clone(), r.gene_name.clone(), x, y)).or_default();
+        v.0 += r.mid;
+        v.1 += r.exon;
+    }
+    records
+}
+
+fn union(a: GemRecords, b: GemRecords) -> GemRecords {
+    let mut out = a;
+    for (k, v) in b {
+        assert!(out.insert(k, v).is_none());
+    }
+    out
+}
+
+/// 写两个样本的 GEM，返回 (a.gem, b.gem)
+fn write_samples(dir: &TempDir) -> (String, String) {
+    let (a, b) = (dir.join("a.gem"), dir.join("b.gem"));
+    write_gem(&a, META, GemLayout::WithExon, &synthetic_rows());
+    let mut meta = META.to_vec();
+    meta[3] = "Stereo-seqChip=SS200000000TL_B2";
+    write_gem(&b, &meta, GemLayout::WithExon, &second_rows());
+    (
+        a.to_str().unwrap().to_string(),
+        b.to_str().unwrap().to_string(),
+    )
+}
+
+fn export(bgef: &Path, gem: &Path, bin: &str) -> GemRecords {
+    gem2gef_ok(&[
+        "gef2gem",
+        "-i",
+        bgef.to_str().unwrap(),
+        "-o",
+        gem.to_str().unwrap(),
+        "-b",
+        bin,
+    ]);
+    read_gem(gem).1
+}
+
+/// `/wholeExpSample/binN` 与其左上角 (minX, minY)
+fn sample_map(bgef: &Path, bin: u32) -> (Array2<u16>, i32, i32) {
+    let f = hdf5::File::open(bgef).unwrap();
+    let whole = f.dataset(&format!("/wholeExp/bin{}", bin)).unwrap();
+    let min_x = whole.attr("minX").unwrap().read_scalar::<u32>().unwrap() as i32;
+    let min_y = whole.attr("minY").unwrap().read_scalar::<u32>().unwrap() as i32;
+    let ds = f.dataset(&format!("/wholeExpSample/bin{}", bin)).unwrap();
+    assert_eq!(ds.shape(), whole.shape());
+    (ds.read_2d::<u16>().unwrap(), min_x, min_y)
+}
+
+#[test]
+fn merge_with_translations() {
+    let dir = TempDir::new("merge_translate");
+    let (a, b) = write_samples(&dir);
+    let out = dir.join("merged.bgef");
+    gem2gef_ok(&[
+        "merge",
+        "-i",
+        &a,
+        "-i",
+        &b,
+        "-t",
+        "0,0",
+        "-t",
+        "1000,-10",
+        "--name",
+        "left",
+        "--name",
+        "right",
+        "-o",
+        out.to_str().unwrap(),
+        "-b",
+        "1,20",
+        "--deflate",
+        "0",
+        "--whole-chunk",
+        "3",
+    ]);
+
+    let want = union(
+        placed(&synthetic_rows(), |x, y| (x, y)),
+        placed(&second_rows(), |x, y| (x + 1000, y - 10)),
+    );
+    assert_eq!(export(&out, &dir.join("back.gem"), "1"), want);
+
+    // 基因列表为两个样本的并集，按 geneID 对齐
+    let f = hdf5::File::open(&out).unwrap();
+    let genes: Vec<String> = f
+        .dataset("/geneExp/bin1/gene")
+        .unwrap()
+        .read_raw::<Gene>()
+        .unwrap()
+        .iter()
+        .map(|g| g.geneID.as_str().to_string())
+        .collect();
+    assert_eq!(genes, ["Actb", "Gad1", "Mbp", "Olig2", "Snap25", "Xist"]);
+    let sn = f.attr("sn").unwrap().read_scalar::<VarLenUnicode>().unwrap();
+    assert_eq!(sn.as_str(), "SS200000000TL_A1,SS200000000TL_B2");
+
+    // 压缩与分块参数按命令行写出
+    let whole = f.dataset("/wholeExp/bin1").unwrap();
+    assert_eq!(whole.chunk(), Some(vec![3, 3]));
+    assert!(whole.filters().is_empty(), "{:?}", whole.filters());
+
+    // 每个 spot 的来源样本
+    let (map, min_x, min_y) = sample_map(&out, 1);
+    for (_, _, x, y) in want.keys() {
+        let code = map[[(x - min_x) as usize, (y - min_y) as usize]];
+        assert_eq!(code, if *x >= 1000 { 2 } else { 1 }, "({}, {})", x, y);
+    }
+    let spots: BTreeSet<(i32, i32)> = want.keys().map(|k| (k.2, k.3)).collect();
+    assert_eq!(map.iter().filter(|&&c| c != 0).count(), spots.len());
+
+    let samples = f.dataset("/samples").unwrap().read_raw::<Sample>().unwrap();
+    assert_eq!(samples.len(), 2);
+    assert_eq!(samples[0].name.as_str(), "left");
+    assert_eq!(samples[1].name.as_str(), "right");
+    assert_eq!(samples[1].transform, [1.0, 0.0, 1000.0, 0.0, 1.0, -10.0]);
+    let right_spots = spots.iter().filter(|p| p.0 >= 1000).count() as u64;
+    assert_eq!(samples[1].spots, right_spots);
+    assert_eq!(
+        samples[1].MIDcount,
+        second_rows().iter().map(|r| u64::from(r.mid)).sum::<u64>()
+    );
+}
+
+#[test]
+fn merge_side_by_side_with_bgef_input() {
+    // 不给 --transform：各样本左上角对齐到 y = 0，沿 x 依次排开
+    let dir = TempDir::new("merge_layout");
+    let (a, b) = write_samples(&dir);
+    let a_bgef = dir.join("a.bgef");
+    gem2gef_ok(&["-i", &a, "-o", a_bgef.to_str().unwrap(), "-b", "1,20"]);
+    let out = dir.join("merged.bgef");
+    gem2gef_ok(&[
+        "merge",
+        "-i",
+        a_bgef.to_str().unwrap(),
+        "-i",
+        &b,
+        "--gap",
+        "10",
+        "-o",
+        out.to_str().unwrap(),
+        "-b",
+        "1",
+    ]);
+
+    let rows = synthetic_rows();
+    let (ax0, ax1) = (
+        rows.iter().map(|r| r.x).min().unwrap(),
+        rows.iter().map(|r| r.x).max().unwrap(),
+    );
+    let ay0 = rows.iter().map(|r| r.y).min().unwrap();
+    let b_x0 = ax1 - ax0 + 1 + 10;
+    let want = union(
+        placed(&rows, |x, y| (x - ax0, y - ay0)),
+        placed(&second_rows(), |x, y| (x - 10 + b_x0, y - 20)),
+    );
+    assert_eq!(export(&out, &dir.join("back.gem"), "1"), want);
+}
+
+#[test]
+fn merge_affine() {
+    // 旋转 90°：x' = -y + 3000，y' = x
+    let dir = TempDir::new("merge_affine");
+    let (a, b) = write_samples(&dir);
+    let out = dir.join("merged.bgef");
+    gem2gef_ok(&[
+        "merge",
+        "-i",
+        &a,
+        "-i",
+        &b,
+        "-t",
+        "0,0",
+        "-t",
+        "0,-1,3000,1,0,0",
+        "-o",
+        out.to_str().unwrap(),
+        "-b",
+        "1",
+    ]);
+    let want = union(
+        placed(&synthetic_rows(), |x, y| (x, y)),
+        placed(&second_rows(), |x, y| (3000 - y, x)),
+    );
+    assert_eq!(export(&out, &dir.join("back.gem"), "1"), want);
+}
+
+#[test]
+fn merge_marks_mixed_bins() {
+    // 第二个样本紧挨第一个样本的右侧（y 同为 2000 起）：bin1 不重叠，但共用一个 bin20 spot
+    let dir = TempDir::new("merge_mixed");
+    let (a, b) = write_samples(&dir);
+    let out = dir.join("merged.bgef");
+    let ax1 = synthetic_rows().iter().map(|r| r.x).max().unwrap();
+    let shift = format!("{},1980", ax1 + 1 - 10);
+    gem2gef_ok(&[
+        "merge",
+        "-i",
+        &a,
+        "-i",
+        &b,
+        "-t",
+        "0,0",
+        "-t",
+        &shift,
+        "-o",
+        out.to_str().unwrap(),
+        "-b",
+        "1,20",
+    ]);
+    let (map, ..) = sample_map(&out, 20);
+    assert!(map.iter().any(|&c| c == u16::MAX), "{:?}", map);
+    let (map, ..) = sample_map(&out, 1);
+    assert!(map.iter().all(|&c| c <= 2));
+}
+
+#[test]
+fn merge_errors() {
+    let dir = TempDir::new("merge_errors");
+    let (a, b) = write_samples(&dir);
+    let out = dir.join("merged.bgef");
+    let out = out.to_str().unwrap();
+    for (tag, extra, message) in [
+        ("overlap", vec!["-t", "0,0", "-t", "90,1980"], "重叠"),
+        ("count", vec!["-t", "0,0"], "--transform 有 1 个"),
+        ("negative", vec!["-t", "0,0", "-t", "-50,0"], "不能为负"),
+    ] {
+        let mut args = vec!["merge", "-i", &a, "-i", &b, "-o", out];
+        args.extend(extra);
+        let res = gem2gef(&args);
+        assert!(!res.status.success(), "{}: 应失败", tag);
+        let stderr = String::from_utf8_lossy(&res.stderr);
+        assert!(stderr.contains(message), "{}: {}", tag, stderr);
+    }
+}
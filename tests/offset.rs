@@ -0,0 +1,118 @@
+//! --apply-offset：坐标加上 #OffsetX/#OffsetY（可为负），平移量记录在根属性中
+mod common;
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use common::{gem2gef_ok, synthetic_rows, write_gem, GemLayout, TempDir};
+use hdf5::types::FixedAscii;
+use hdf5::H5Type;
+
+/// 平移后 x、y 都为负
+const META: &[&str] = &[
+    "BinType=Bin",
+    "BinSize=1",
+    "Omics=Transcriptomics",
+    "Stereo-seqChip=SS200000000TL_A1",
+    "OffsetX=-150",
+    "OffsetY=-2100",
+];
+const OFFSET: (i32, i32) = (-150, -2100);
+
+#[repr(C)]
+#[allow(non_snake_case)]
+#[derive(H5Type, Clone, Copy, Debug)]
+struct Gene {
+    geneID: FixedAscii<64>,
+    geneName: FixedAscii<64>,
+    offset: u32,
+    count: u32,
+}
+
+#[repr(C)]
+#[derive(H5Type, Clone, Copy, Debug)]
+struct Expr {
+    x: i32,
+    y: i32,
+    count: u32,
+}
+
+/// (geneID, x, y) -> MIDCount
+type Records = BTreeMap<(String, i32, i32), u32>;
+
+fn expected(bin: i32, shift: (i32, i32)) -> Records {
+    let mut want = Records::new();
+    for r in synthetic_rows() {
+        let (x, y) = (r.x + shift.0, r.y + shift.1);
+        let key = (r.gene_id, x.div_euclid(bin) * bin, y.div_euclid(bin) * bin);
+        *want.entry(key).or_default() += r.mid;
+    }
+    want
+}
+
+fn read_bin(f: &hdf5::File, bin: u32) -> Records {
+    let g = format!("/geneExp/bin{}", bin);
+    let genes = f.dataset(&format!("{}/gene", g)).unwrap().read_raw::<Gene>().unwrap();
+    let expr = f.dataset(&format!("{}/expression", g)).unwrap().read_raw::<Expr>().unwrap();
+    let mut got = Records::new();
+    for gene in &genes {
+        for e in &expr[gene.offset as usize..(gene.offset + gene.count) as usize] {
+            got.insert((gene.geneID.as_str().to_string(), e.x, e.y), e.count);
+        }
+    }
+    got
+}
+
+fn root_i32(f: &hdf5::File, name: &str) -> i32 {
+    f.attr(name).unwrap().read_scalar::<i32>().unwrap()
+}
+
+fn convert(gem: &Path, out: &Path, extra: &[&str]) -> hdf5::File {
+    let mut args = vec![
+        "-i",
+        gem.to_str().unwrap(),
+        "-o",
+        out.to_str().unwrap(),
+        "-b",
+        "1,20",
+    ];
+    args.extend_from_slice(extra);
+    gem2gef_ok(&args);
+    hdf5::File::open(out).unwrap()
+}
+
+#[test]
+fn apply_negative_offset() {
+    let dir = TempDir::new("offset");
+    let gem = dir.join("in.gem");
+    write_gem(&gem, META, GemLayout::WithExon, &synthetic_rows());
+
+    for (name, extra, applied) in [
+        ("plain.bgef", &[][..], (0, 0)),
+        ("applied.bgef", &["--apply-offset"][..], OFFSET),
+    ] {
+        let f = convert(&gem, &dir.join(name), extra);
+        // offsetX/offsetY 为已应用的平移，gem_offsetX/gem_offsetY 始终为表头的值
+        assert_eq!(root_i32(&f, "offsetX"), applied.0, "{}", name);
+        assert_eq!(root_i32(&f, "offsetY"), applied.1, "{}", name);
+        assert_eq!(root_i32(&f, "gem_offsetX"), OFFSET.0, "{}", name);
+        assert_eq!(root_i32(&f, "gem_offsetY"), OFFSET.1, "{}", name);
+
+        for bin in [1, 20] {
+            let want = expected(bin as i32, applied);
+            assert_eq!(read_bin(&f, bin), want, "{} bin{}", name, bin);
+            // wholeExp 的 minX/minY 为 bin 左上角，平移后为负
+            let whole = f.dataset(&format!("/wholeExp/bin{}", bin)).unwrap();
+            let min = |attr: &str| whole.attr(attr).unwrap().read_scalar::<i32>().unwrap();
+            let min_x = want.keys().map(|k| k.1).min().unwrap();
+            let min_y = want.keys().map(|k| k.2).min().unwrap();
+            assert_eq!(
+                (min("minX"), min("minY")),
+                (min_x, min_y),
+                "{} bin{}",
+                name,
+                bin
+            );
+        }
+    }
+}
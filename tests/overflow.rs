@@ -0,0 +1,143 @@
+//! 聚合溢出与空输入：报出明确的错误，而不是回绕或写出错误的数值
+mod common;
+
+use std::fs;
+
+use common::{gem2gef, gem2gef_ok, write_gem, GemLayout, GemRow, TempDir};
+
+const META: &[&str] = &[
+    "BinType=Bin",
+    "BinSize=1",
+    "Omics=Transcriptomics",
+    "Stereo-seqChip=SS200000000TL_A1",
+];
+
+const MODES: [(&str, &[&str]); 2] = [("mem", &[]), ("spill", &["--max-mem", "1"])];
+
+/// 转换 `rows`（`extra` 为附加参数），期望失败并返回 stderr
+fn convert_err(tag: &str, rows: &[GemRow], extra: &[&str]) -> String {
+    let dir = TempDir::new(tag);
+    let (gem, bgef) = (dir.join("in.gem"), dir.join("out.bgef"));
+    write_gem(&gem, META, GemLayout::WithExon, rows);
+    let mut args = vec!["-i", gem.to_str().unwrap(), "-o", bgef.to_str().unwrap()];
+    args.extend_from_slice(extra);
+    let out = gem2gef(&args);
+    assert!(!out.status.success(), "{}: 应失败", tag);
+    String::from_utf8_lossy(&out.stderr).into_owned()
+}
+
+#[test]
+fn duplicate_sum_overflow() {
+    let rows = [
+        GemRow::new("Gad1", 10, 20, u32::MAX, 0),
+        GemRow::new("Gad1", 10, 20, 1, 0),
+    ];
+    for (mode, extra) in MODES {
+        let stderr = convert_err(&format!("ovf_dup_{}", mode), &rows, extra);
+        assert!(
+            stderr.contains("重复行相加溢出 uint32"),
+            "{}: {}",
+            mode,
+            stderr
+        );
+        assert!(stderr.contains("x=10 y=20"), "{}: {}", mode, stderr);
+    }
+}
+
+#[test]
+fn bin_merge_overflow() {
+    // 两个 bin1 坐标落在同一个 bin20 内，合并后超出 uint32；
+    // 该基因的总数必然同样溢出，spill 模式下 bin1 的 /stat/gene 会先报错
+    let rows = [
+        GemRow::new("Gad1", 0, 0, 4_000_000_000, 0),
+        GemRow::new("Gad1", 5, 5, 4_000_000_000, 0),
+    ];
+    for (mode, extra) in MODES {
+        let mut args = vec!["-b", "1,20"];
+        args.extend_from_slice(extra);
+        let stderr = convert_err(&format!("ovf_bin_{}", mode), &rows, &args);
+        assert!(stderr.contains("溢出 uint32"), "{}: {}", mode, stderr);
+        if mode == "mem" {
+            assert!(stderr.contains("bin20"), "{}", stderr);
+        }
+    }
+}
+
+#[test]
+fn spot_midcount_overflow() {
+    // 同一坐标上两个基因的 MIDcount 之和超出 uint32
+    let rows = [
+        GemRow::new("Gad1", 3, 4, 3_000_000_000, 0),
+        GemRow::new("Actb", 3, 4, 3_000_000_000, 0),
+    ];
+    for (mode, extra) in MODES {
+        let mut args = vec!["-b", "1"];
+        args.extend_from_slice(extra);
+        let stderr = convert_err(&format!("ovf_spot_{}", mode), &rows, &args);
+        assert!(stderr.contains("溢出"), "{}: {}", mode, stderr);
+        assert!(stderr.contains("(3, 4)"), "{}: {}", mode, stderr);
+    }
+}
+
+#[test]
+fn spot_genecount_limit() {
+    // wholeExp 的 genecount 为 uint16：一个 spot 最多 65535 个基因
+    let rows = |n: usize| -> Vec<GemRow> {
+        (0..n).map(|i| GemRow::new(&format!("G{}", i), 7, 7, 1, 0)).collect()
+    };
+    let stderr = convert_err("ovf_genecount", &rows(65536), &["-b", "1"]);
+    assert!(stderr.contains("65535"), "{}", stderr);
+
+    let dir = TempDir::new("ovf_genecount_ok");
+    let (gem, bgef) = (dir.join("in.gem"), dir.join("out.bgef"));
+    write_gem(&gem, META, GemLayout::WithExon, &rows(65535));
+    gem2gef_ok(&[
+        "-i",
+        gem.to_str().unwrap(),
+        "-o",
+        bgef.to_str().unwrap(),
+        "-b",
+        "1",
+    ]);
+    let f = hdf5::File::open(&bgef).unwrap();
+    let max_gene =
+        f.dataset("/wholeExp/bin1").unwrap().attr("maxGene").unwrap().read_scalar::<u32>().unwrap();
+    assert_eq!(max_gene, 65535);
+}
+
+#[test]
+fn stat_gene_overflow() {
+    // 各 spot 都不溢出，但同一基因的总 MIDcount 超出 uint32
+    let rows = [
+        GemRow::new("Gad1", 0, 0, 3_000_000_000, 0),
+        GemRow::new("Gad1", 1000, 0, 3_000_000_000, 0),
+    ];
+    let stderr = convert_err("ovf_stat", &rows, &["-b", "1"]);
+    assert!(stderr.contains("/stat/gene"), "{}", stderr);
+    assert!(stderr.contains("Gad1"), "{}", stderr);
+}
+
+#[test]
+fn scaled_coordinate_overflow() {
+    // BinSize 换算到 bin1 坐标后超出 int32，报出行号
+    let dir = TempDir::new("ovf_binsize");
+    let (gem, bgef) = (dir.join("in.gem"), dir.join("out.bgef"));
+    fs::write(
+        &gem,
+        "#BinSize=100\ngeneID\tx\ty\tMIDCount\nGad1\t1\t2\t3\nActb\t30000000\t2\t3\n",
+    )
+    .unwrap();
+    let out = gem2gef(&["-i", gem.to_str().unwrap(), "-o", bgef.to_str().unwrap()]);
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("第 4 行"), "{}", stderr);
+    assert!(stderr.contains("溢出"), "{}", stderr);
+}
+
+#[test]
+fn empty_gem_is_an_error() {
+    for (mode, extra) in MODES {
+        let stderr = convert_err(&format!("ovf_empty_{}", mode), &[], extra);
+        assert!(stderr.contains("没有表达记录"), "{}: {}", mode, stderr);
+    }
+}
@@ -0,0 +1,302 @@
+//! --roi / crop：按 GeoJSON 多边形 ROI 裁剪 GEM 或 bGEF，属性按子集重算
+mod common;
+
+use std::fs;
+use std::path::Path;
+
+use common::{
+    gem2gef, gem2gef_ok, read_gem, synthetic_rows, write_gem, GemLayout, GemRecords, GemRow,
+    TempDir,
+};
+
+const META: &[&str] = &[
+    "BinType=Bin",
+    "BinSize=1",
+    "Omics=Transcriptomics",
+    "Stereo-seqChip=SS200000000TL_A1",
+];
+
+/// 带洞的矩形 [105,125]x[2001,2006]（洞 [110,115]x[2002,2005]），加一个 MultiPolygon 矩形
+const ROI: &str = r#"{
+  "type": "FeatureCollection",
+  "features": [
+    {"type": "Feature", "properties": {"name": "cortex"}, "geometry": {"type": "Polygon", "coordinates": [
+      [[105, 2001], [125, 2001], [125, 2006], [105, 2006], [105, 2001]],
+      [[110, 2002], [115, 2002], [115, 2005], [110, 2005], [110, 2002]]
+    ]}},
+    {"type": "Feature", "properties": {}, "geometry": {"type": "MultiPolygon", "coordinates": [
+      [[[130, 2007], [132, 2007], [132, 2008], [130, 2008], [130, 2007]]]
+    ]}}
+  ]
+}"#;
+
+/// 与 `ROI` 一致的判断：边界算在内，洞的边界也算在内
+fn in_roi(x: i32, y: i32) -> bool {
+    let in_rect = |x0, x1, y0, y1| (x0..=x1).contains(&x) && (y0..=y1).contains(&y);
+    let in_hole = x > 110 && x < 115 && y > 2002 && y < 2005;
+    (in_rect(105, 125, 2001, 2006) && !in_hole) || in_rect(130, 132, 2007, 2008)
+}
+
+/// 合成数据中落在 ROI 内的记录（重复行相加）
+fn expected() -> GemRecords {
+    let mut records = GemRecords::new();
+    for r in synthetic_rows().into_iter().filter(|r| in_roi(r.x, r.y)) {
+        let v = records.entry((r.gene_id, r.gene_name, r.x, r.y)).or_default();
+        v.0 += r.mid;
+        v.1 += r.exon;
+    }
+    records
+}
+
+fn export(bgef: &Path, gem: &Path, extra: &[&str]) -> GemRecords {
+    let mut args = vec![
+        "gef2gem",
+        "-i",
+        bgef.to_str().unwrap(),
+        "-o",
+        gem.to_str().unwrap(),
+    ];
+    args.extend_from_slice(extra);
+    gem2gef_ok(&args);
+    read_gem(gem).1
+}
+
+fn attr<T: hdf5::H5Type>(bgef: &Path, path: &str, name: &str) -> T {
+    let f = hdf5::File::open(bgef).unwrap();
+    f.dataset(path).unwrap().attr(name).unwrap().read_scalar::<T>().unwrap()
+}
+
+/// 检查 bin1 的范围、最大值与 spot 数是按 `records` 重算的
+fn check_attrs(bgef: &Path, records: &GemRecords) {
+    let xs = records.keys().map(|k| k.2);
+    let ys = records.keys().map(|k| k.3);
+    let (min_x, max_x) = (xs.clone().min().unwrap(), xs.max().unwrap());
+    let (min_y, max_y) = (ys.clone().min().unwrap(), ys.max().unwrap());
+    let max_exp = records.values().map(|v| v.0).max().unwrap();
+    let mut spots = std::collections::BTreeMap::<(i32, i32), u32>::new();
+    for (k, v) in records {
+        *spots.entry((k.2, k.3)).or_default() += v.0;
+    }
+
+    let expr = "/geneExp/bin1/expression";
+    assert_eq!(attr::<i32>(bgef, expr, "minX"), min_x);
+    assert_eq!(attr::<i32>(bgef, expr, "minY"), min_y);
+    assert_eq!(attr::<i32>(bgef, expr, "maxX"), max_x);
+    assert_eq!(attr::<i32>(bgef, expr, "maxY"), max_y);
+    assert_eq!(attr::<u32>(bgef, expr, "maxExp"), max_exp);
+    let whole = "/wholeExp/bin1";
+    assert_eq!(attr::<u32>(bgef, whole, "minX"), min_x as u32);
+    assert_eq!(attr::<u32>(bgef, whole, "minY"), min_y as u32);
+    assert_eq!(attr::<u64>(bgef, whole, "number"), spots.len() as u64);
+    assert_eq!(
+        attr::<u32>(bgef, whole, "maxMID"),
+        *spots.values().max().unwrap()
+    );
+}
+
+#[test]
+fn roi_gem_to_bgef() {
+    let want = expected();
+    assert!(want.len() > 5 && want.len() < synthetic_rows().len() / 2);
+    for (mode, extra) in [("mem", vec![]), ("spill", vec!["--max-mem", "1"])] {
+        let dir = TempDir::new(&format!("roi_gem_{}", mode));
+        let (gem, roi, bgef, back) = (
+            dir.join("in.gem"),
+            dir.join("roi.geojson"),
+            dir.join("out.bgef"),
+            dir.join("back.gem"),
+        );
+        write_gem(&gem, META, GemLayout::WithExon, &synthetic_rows());
+        fs::write(&roi, ROI).unwrap();
+        let mut args = vec![
+            "-i",
+            gem.to_str().unwrap(),
+            "-o",
+            bgef.to_str().unwrap(),
+            "-b",
+            "1,5",
+            "--roi",
+            roi.to_str().unwrap(),
+        ];
+        args.extend(extra);
+        gem2gef_ok(&args);
+        assert_eq!(export(&bgef, &back, &[]), want, "{}", mode);
+        check_attrs(&bgef, &want);
+    }
+}
+
+#[test]
+fn roi_uses_chip_coordinates() {
+    // 裁剪过的 GEM：坐标减去了 #OffsetX/#OffsetY，ROI 仍按芯片坐标给出
+    let dir = TempDir::new("roi_offset");
+    let (gem, roi, bgef, back) = (
+        dir.join("in.gem"),
+        dir.join("roi.geojson"),
+        dir.join("out.bgef"),
+        dir.join("back.gem"),
+    );
+    let local: Vec<GemRow> = synthetic_rows()
+        .into_iter()
+        .map(|r| GemRow {
+            x: r.x - 100,
+            y: r.y - 2000,
+            ..r
+        })
+        .collect();
+    let mut meta = META.to_vec();
+    meta.extend(["OffsetX=100", "OffsetY=2000"]);
+    write_gem(&gem, &meta, GemLayout::WithExon, &local);
+    fs::write(&roi, ROI).unwrap();
+    gem2gef_ok(&[
+        "-i",
+        gem.to_str().unwrap(),
+        "-o",
+        bgef.to_str().unwrap(),
+        "-b",
+        "1",
+        "--roi",
+        roi.to_str().unwrap(),
+    ]);
+    // 未 --apply-offset：输出仍为局部坐标
+    let got = export(&bgef, &back, &[]);
+    let want: GemRecords = expected()
+        .into_iter()
+        .map(|((id, name, x, y), v)| ((id, name, x - 100, y - 2000), v))
+        .collect();
+    assert_eq!(got, want);
+}
+
+#[test]
+fn roi_crop_bgef_matches_gem_path() {
+    let dir = TempDir::new("roi_crop");
+    let (gem, roi, full, cropped, direct) = (
+        dir.join("in.gem"),
+        dir.join("roi.geojson"),
+        dir.join("full.bgef"),
+        dir.join("cropped.bgef"),
+        dir.join("direct.bgef"),
+    );
+    write_gem(&gem, META, GemLayout::WithExon, &synthetic_rows());
+    fs::write(&roi, ROI).unwrap();
+    let convert = |out: &Path, extra: &[&str]| {
+        let mut args = vec![
+            "-i",
+            gem.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+            "-b",
+            "1,5",
+        ];
+        args.extend_from_slice(extra);
+        gem2gef_ok(&args);
+    };
+    convert(&full, &[]);
+    convert(&direct, &["--roi", roi.to_str().unwrap()]);
+    gem2gef_ok(&[
+        "crop",
+        "-i",
+        full.to_str().unwrap(),
+        "-r",
+        roi.to_str().unwrap(),
+        "-o",
+        cropped.to_str().unwrap(),
+        "--deflate",
+        "0",
+        "--expr-chunk",
+        "4",
+    ]);
+
+    let want = expected();
+    check_attrs(&cropped, &want);
+    for bin in ["1", "5"] {
+        let a = export(&cropped, &dir.join("a.gem"), &["-b", bin]);
+        let b = export(&direct, &dir.join("b.gem"), &["-b", bin]);
+        assert_eq!(a, b, "bin{}", bin);
+        let whole = format!("/wholeExp/bin{}", bin);
+        for name in ["number", "maxMID", "maxGene"] {
+            let (a, b) = if name == "number" {
+                (
+                    attr::<u64>(&cropped, &whole, name),
+                    attr::<u64>(&direct, &whole, name),
+                )
+            } else {
+                (
+                    u64::from(attr::<u32>(&cropped, &whole, name)),
+                    u64::from(attr::<u32>(&direct, &whole, name)),
+                )
+            };
+            assert_eq!(a, b, "bin{} {}", bin, name);
+        }
+    }
+    // 由已有 bGEF 裁剪时不知道原始的重复行数
+    let f = hdf5::File::open(&cropped).unwrap();
+    assert!(f.attr("duplicate_rows").is_err());
+    // 压缩与分块参数按命令行写出
+    let ds = f.dataset("/geneExp/bin1/expression").unwrap();
+    assert_eq!(ds.chunk(), Some(vec![4]));
+    assert!(ds.filters().is_empty(), "{:?}", ds.filters());
+}
+
+#[test]
+fn roi_gef2gem() {
+    let dir = TempDir::new("roi_gef2gem");
+    let (gem, roi, bgef) = (
+        dir.join("in.gem"),
+        dir.join("roi.geojson"),
+        dir.join("out.bgef"),
+    );
+    write_gem(&gem, META, GemLayout::WithExon, &synthetic_rows());
+    fs::write(&roi, ROI).unwrap();
+    gem2gef_ok(&[
+        "-i",
+        gem.to_str().unwrap(),
+        "-o",
+        bgef.to_str().unwrap(),
+        "-b",
+        "1",
+    ]);
+    let got = export(
+        &bgef,
+        &dir.join("back.gem"),
+        &["--roi", roi.to_str().unwrap()],
+    );
+    assert_eq!(got, expected());
+}
+
+#[test]
+fn roi_errors() {
+    let dir = TempDir::new("roi_errors");
+    let (gem, bgef) = (dir.join("in.gem"), dir.join("out.bgef"));
+    write_gem(&gem, META, GemLayout::WithExon, &synthetic_rows());
+    for (tag, geojson, message) in [
+        (
+            "empty",
+            r#"{"type": "Polygon", "coordinates": [[[0, 0], [10, 0], [10, 10], [0, 0]]]}"#,
+            "没有表达记录",
+        ),
+        (
+            "point",
+            r#"{"type": "Point", "coordinates": [100, 2000]}"#,
+            "不支持的几何类型",
+        ),
+        (
+            "ring",
+            r#"{"type": "Polygon", "coordinates": [[[0, 0], [10, 0], [0, 0]]]}"#,
+            "少于 3 个顶点",
+        ),
+    ] {
+        let roi = dir.join(&format!("{}.geojson", tag));
+        fs::write(&roi, geojson).unwrap();
+        let out = gem2gef(&[
+            "-i",
+            gem.to_str().unwrap(),
+            "-o",
+            bgef.to_str().unwrap(),
+            "--roi",
+            roi.to_str().unwrap(),
+        ]);
+        assert!(!out.status.success(), "{}: 应失败", tag);
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(stderr.contains(message), "{}: {}", tag, stderr);
+    }
+}
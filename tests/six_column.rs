@@ -0,0 +1,89 @@
+//! SAW 8 六列 GEM（geneID geneName x y MIDCount ExonCount）：两个基因标识都写入 /geneExp/binN/gene
+mod common;
+
+use std::collections::BTreeMap;
+
+use common::{gem2gef_ok, synthetic_rows, write_gem, GemLayout, GemRow, TempDir};
+use hdf5::types::FixedAscii;
+use hdf5::H5Type;
+
+const META: &[&str] = &[
+    "BinType=Bin",
+    "BinSize=1",
+    "Omics=Transcriptomics",
+    "Stereo-seqChip=SS200000000TL_A1",
+];
+
+#[repr(C)]
+#[allow(non_snake_case)]
+#[derive(H5Type, Clone, Copy, Debug)]
+struct Gene {
+    geneID: FixedAscii<64>,
+    geneName: FixedAscii<64>,
+    offset: u32,
+    count: u32,
+}
+
+#[repr(C)]
+#[derive(H5Type, Clone, Copy, Debug)]
+struct Expr {
+    x: i32,
+    y: i32,
+    count: u32,
+}
+
+/// (geneID, geneName) -> (x, y) -> (MIDCount, ExonCount)
+type GeneSpots = BTreeMap<(String, String), BTreeMap<(i32, i32), (u32, u32)>>;
+
+#[test]
+fn six_column_keeps_gene_id_and_name() {
+    let rows: Vec<GemRow> = synthetic_rows()
+        .into_iter()
+        .map(|mut r| {
+            r.gene_name = r.gene_id.clone();
+            r.gene_id = format!("ENSMUSG_{}", r.gene_id.to_uppercase());
+            r
+        })
+        .collect();
+    let dir = TempDir::new("six_column");
+    let gem = dir.join("in.gem");
+    let out = dir.join("out.bgef");
+    write_gem(&gem, META, GemLayout::SixColumn, &rows);
+    gem2gef_ok(&[
+        "-i",
+        gem.to_str().unwrap(),
+        "-o",
+        out.to_str().unwrap(),
+        "-b",
+        "1",
+    ]);
+
+    let mut want = GeneSpots::new();
+    for r in &rows {
+        let v = want
+            .entry((r.gene_id.clone(), r.gene_name.clone()))
+            .or_default()
+            .entry((r.x, r.y))
+            .or_default();
+        v.0 += r.mid;
+        v.1 += r.exon;
+    }
+
+    let f = hdf5::File::open(&out).unwrap();
+    let genes = f.dataset("/geneExp/bin1/gene").unwrap().read_raw::<Gene>().unwrap();
+    let expr = f.dataset("/geneExp/bin1/expression").unwrap().read_raw::<Expr>().unwrap();
+    let exon = f.dataset("/geneExp/bin1/exon").unwrap().read_raw::<u32>().unwrap();
+    let mut got = GeneSpots::new();
+    for g in &genes {
+        let spots = got
+            .entry((
+                g.geneID.as_str().to_string(),
+                g.geneName.as_str().to_string(),
+            ))
+            .or_default();
+        for i in g.offset as usize..(g.offset + g.count) as usize {
+            spots.insert((expr[i].x, expr[i].y), (expr[i].count, exon[i]));
+        }
+    }
+    assert_eq!(got, want);
+}
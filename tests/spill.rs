@@ -0,0 +1,273 @@
+//! --max-mem：切分成多个 run 的外部排序结果与内存路径逐项一致，spot 统计不超过上限，
+//! 失败时不留下输出文件
+mod common;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use common::{
+    conformance::{ExprRow, SpotRow},
+    gem2gef, gem2gef_ok, synthetic_rows, write_gem, GemLayout, TempDir,
+};
+use hdf5::types::{FixedAscii, VarLenUnicode};
+use hdf5::{H5Type, Location};
+
+const META: &[&str] = &[
+    "BinType=Bin",
+    "BinSize=1",
+    "Omics=Transcriptomics",
+    "Stereo-seqChip=SS200000000TL_A1",
+];
+
+const BINS: [u32; 3] = [1, 5, 20];
+
+/// 每个 run 的记录数：合成 GEM 的 61 行切成 8 个 run，末尾的重复行与其原记录落在不同 run
+const SPILL_RECORDS: &str = "8";
+
+#[repr(C)]
+#[allow(non_snake_case)]
+#[derive(H5Type, Clone, Copy, Debug)]
+struct Gene {
+    geneID: FixedAscii<64>,
+    geneName: FixedAscii<64>,
+    offset: u32,
+    count: u32,
+}
+
+#[repr(C)]
+#[allow(non_snake_case)]
+#[derive(H5Type, Clone, Copy, Debug)]
+struct StatGene {
+    geneID: FixedAscii<64>,
+    geneName: FixedAscii<64>,
+    MIDcount: u32,
+    E10: f32,
+}
+
+/// 对象的全部属性：数值属性按 f64 读出，其余按字符串
+fn attrs(obj: &Location) -> String {
+    let mut names = obj.attr_names().unwrap();
+    names.sort();
+    names
+        .iter()
+        .map(|name| {
+            let a = obj.attr(name).unwrap();
+            let v = a
+                .read_raw::<f64>()
+                .map(|v| format!("{:?}", v))
+                .or_else(|_| a.read_scalar::<VarLenUnicode>().map(|s| s.as_str().to_string()))
+                .unwrap_or_else(|_| format!("{:?}", a.dtype().and_then(|t| t.to_descriptor())));
+            format!("{}={}", name, v)
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// 路径 -> 属性与数据的文本形式
+fn dump(bgef: &Path) -> BTreeMap<String, String> {
+    let f = hdf5::File::open(bgef).unwrap();
+    let mut out = BTreeMap::new();
+    out.insert("/".to_string(), attrs(&f));
+    for bin in BINS {
+        let g = format!("/geneExp/bin{}", bin);
+        let expr = f.dataset(&format!("{}/expression", g)).unwrap();
+        out.insert(
+            format!("{}/expression", g),
+            format!("{} {:?}", attrs(&expr), expr.read_raw::<ExprRow>().unwrap()),
+        );
+        let exon = f.dataset(&format!("{}/exon", g)).unwrap();
+        out.insert(
+            format!("{}/exon", g),
+            format!("{} {:?}", attrs(&exon), exon.read_raw::<u32>().unwrap()),
+        );
+        let genes = f.dataset(&format!("{}/gene", g)).unwrap().read_raw::<Gene>().unwrap();
+        out.insert(format!("{}/gene", g), format!("{:?}", genes));
+
+        let whole = f.dataset(&format!("/wholeExp/bin{}", bin)).unwrap();
+        out.insert(
+            format!("/wholeExp/bin{}", bin),
+            format!(
+                "{} {:?}",
+                attrs(&whole),
+                whole.read_2d::<SpotRow>().unwrap()
+            ),
+        );
+        let whole_exon = f.dataset(&format!("/wholeExpExon/bin{}", bin)).unwrap();
+        out.insert(
+            format!("/wholeExpExon/bin{}", bin),
+            format!(
+                "{} {:?}",
+                attrs(&whole_exon),
+                whole_exon.read_2d::<u32>().unwrap()
+            ),
+        );
+    }
+    let stats = f.dataset("/stat/gene").unwrap().read_raw::<StatGene>().unwrap();
+    out.insert("/stat/gene".to_string(), format!("{:?}", stats));
+    out
+}
+
+/// 日志中 `runs=N` 的 N
+fn runs(stderr: &str) -> usize {
+    field(stderr, "", "runs")
+}
+
+/// 日志中以 `line` 开头的那一行里 `key=N` 的 N
+fn field(stderr: &str, line: &str, key: &str) -> usize {
+    let start = stderr.find(line).unwrap_or_else(|| panic!("日志中没有 {}", line));
+    let rest = &stderr[start..];
+    let pat = format!("{}=", key);
+    let rest =
+        &rest[rest.find(&pat).unwrap_or_else(|| panic!("{} 中没有 {}", line, pat)) + pat.len()..];
+    rest.split_whitespace().next().unwrap().parse().unwrap()
+}
+
+fn args<'a>(gem: &'a Path, out: &'a Path, extra: &[&'a str]) -> Vec<&'a str> {
+    let mut args = vec![
+        "-i",
+        gem.to_str().unwrap(),
+        "-o",
+        out.to_str().unwrap(),
+        "-b",
+        "1,5,20",
+    ];
+    args.extend_from_slice(extra);
+    args
+}
+
+#[test]
+fn multi_run_spill_matches_memory() {
+    let dir = TempDir::new("spill_runs");
+    let gem = dir.join("in.gem");
+    write_gem(&gem, META, GemLayout::WithExon, &synthetic_rows());
+
+    let mem = dir.join("mem.bgef");
+    gem2gef_ok(&args(&gem, &mem, &[]));
+    let spill = dir.join("spill.bgef");
+    let log = gem2gef_ok(&args(
+        &gem,
+        &spill,
+        &["--max-mem", "1", "--spill-records", SPILL_RECORDS],
+    ));
+    let n = runs(&String::from_utf8_lossy(&log.stderr));
+    assert!(n > 1, "应切分成多个 run，实际 runs={}", n);
+
+    let (want, got) = (dump(&mem), dump(&spill));
+    assert_eq!(
+        want.keys().collect::<Vec<_>>(),
+        got.keys().collect::<Vec<_>>()
+    );
+    for (path, w) in &want {
+        assert_eq!(&got[path], w, "{}: 多 run 输出与内存路径不一致", path);
+    }
+}
+
+/// 每个 bin 在内存中累计的 spot 数上限：大于单个基因的 spot 数（12），小于 bin1 的 spot 总数
+const SPILL_SPOTS: usize = 16;
+
+#[test]
+fn spot_statistics_stay_within_limit() {
+    let dir = TempDir::new("spill_spots");
+    let gem = dir.join("in.gem");
+    write_gem(&gem, META, GemLayout::WithExon, &synthetic_rows());
+    let mem = dir.join("mem.bgef");
+    gem2gef_ok(&args(&gem, &mem, &[]));
+
+    // 默认上限由 --max-mem 推算：三份预算合计不超过 --max-mem
+    let spill = dir.join("spill.bgef");
+    let log = gem2gef_ok(&args(&gem, &spill, &["--max-mem", "1"]));
+    let stderr = String::from_utf8_lossy(&log.stderr);
+    let budget: usize = ["records", "append", "spots"]
+        .iter()
+        .map(|key| field(&stderr, "memory budget:", key))
+        .sum();
+    assert!(budget <= 1024, "预算合计 {} KiB 超过 --max-mem", budget);
+
+    // spot 统计分多次落盘：内存中累计的 spot 数不超过上限，归并结果与内存路径一致
+    let spilled = spill_spots(&gem, &spill, "1,5,20");
+    for bin in BINS {
+        let line = format!("spot statistics bin{}:", bin);
+        assert_eq!(field(&spilled, &line, "limit"), SPILL_SPOTS);
+        let peak = field(&spilled, &line, "peak");
+        assert!(
+            peak <= SPILL_SPOTS,
+            "bin{} 内存中最多有 {} 个 spot",
+            bin,
+            peak
+        );
+    }
+    let n = field(&spilled, "spot statistics bin1:", "runs");
+    assert!(n > 1, "bin1 的 spot 统计应落盘成多个 run，实际 runs={}", n);
+    assert_eq!(dump(&spill), dump(&mem));
+
+    // 最小输出 bin 不是 bin1 时，gef_area 用的 bin1 spot 也分批计数
+    let (mem, spill) = (dir.join("mem_5.bgef"), dir.join("spill_5.bgef"));
+    let mut mem_args = args(&gem, &mem, &[]);
+    mem_args[5] = "5,20";
+    gem2gef_ok(&mem_args);
+    let spilled = spill_spots(&gem, &spill, "5,20");
+    let line = "spot statistics base-bin1:";
+    assert!(field(&spilled, line, "runs") > 1);
+    assert!(field(&spilled, line, "peak") <= SPILL_SPOTS);
+    let area = |p: &Path| {
+        let f = hdf5::File::open(p).unwrap();
+        f.attr("gef_area").unwrap().read_scalar::<f32>().unwrap()
+    };
+    assert_eq!(area(&spill), area(&mem));
+}
+
+/// 以 `SPILL_SPOTS` 为 spot 上限转换，返回日志
+fn spill_spots(gem: &Path, out: &Path, bins: &str) -> String {
+    let limit = SPILL_SPOTS.to_string();
+    let mut args = args(gem, out, &["--max-mem", "1", "--spill-spots", &limit]);
+    args[5] = bins;
+    String::from_utf8_lossy(&gem2gef_ok(&args).stderr).into_owned()
+}
+
+#[test]
+fn failed_spill_leaves_no_output() {
+    let dir = TempDir::new("spill_fail");
+    let gem = dir.join("in.gem");
+    write_gem(&gem, META, GemLayout::WithExon, &synthetic_rows());
+    let out = dir.join("out.bgef");
+
+    // 重复行与原记录在不同 run 中，归并（已创建输出之后）才会报错
+    for existing in [false, true] {
+        if existing {
+            fs::write(&out, b"old").unwrap();
+        }
+        let res = gem2gef(&args(
+            &gem,
+            &out,
+            &[
+                "--max-mem",
+                "1",
+                "--spill-records",
+                SPILL_RECORDS,
+                "--duplicates",
+                "error",
+            ],
+        ));
+        assert!(!res.status.success());
+        let stderr = String::from_utf8_lossy(&res.stderr);
+        assert!(stderr.contains("重复的 (gene, x, y) 记录"), "{}", stderr);
+
+        // 已有的输出保持原样，临时文件与 run 目录都已删除
+        if existing {
+            assert_eq!(fs::read(&out).unwrap(), b"old");
+        } else {
+            assert!(!out.exists(), "失败后不应留下 {}", out.display());
+        }
+        let mut left: Vec<String> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        left.sort();
+        let mut want = vec!["in.gem".to_string()];
+        if existing {
+            want.push("out.bgef".to_string());
+        }
+        assert_eq!(left, want);
+    }
+}
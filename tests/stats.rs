@@ -0,0 +1,134 @@
+//! /stat/gene 与 gef_area 由最小 bin（bin1）统计，与 --bins 写出哪些 bin 无关
+mod common;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+use common::{gem2gef_ok, read_gem, synthetic_rows, write_gem, GemLayout, TempDir};
+use hdf5::types::FixedAscii;
+use hdf5::H5Type;
+
+const META: &[&str] = &[
+    "BinType=Bin",
+    "BinSize=1",
+    "Omics=Transcriptomics",
+    "Stereo-seqChip=SS200000000TL_A1",
+];
+
+const MODES: [(&str, &[&str]); 2] = [("mem", &[]), ("spill", &["--max-mem", "1"])];
+
+#[repr(C)]
+#[allow(non_snake_case)]
+#[derive(H5Type, Clone, Copy, Debug)]
+struct StatGene {
+    geneID: FixedAscii<64>,
+    geneName: FixedAscii<64>,
+    MIDcount: u32,
+    E10: f32,
+}
+
+fn convert(gem: &Path, out: &Path, bins: &str, extra: &[&str]) {
+    let mut args = vec![
+        "-i",
+        gem.to_str().unwrap(),
+        "-o",
+        out.to_str().unwrap(),
+        "-b",
+        bins,
+    ];
+    args.extend_from_slice(extra);
+    gem2gef_ok(&args);
+}
+
+/// geneID -> (MIDcount, E10)
+fn stat_gene(bgef: &Path) -> Vec<(String, u32, f32)> {
+    let f = hdf5::File::open(bgef).unwrap();
+    let stats = f.dataset("/stat/gene").unwrap().read_raw::<StatGene>().unwrap();
+    stats.iter().map(|s| (s.geneID.as_str().to_string(), s.MIDcount, s.E10)).collect()
+}
+
+fn gef_area(bgef: &Path) -> f32 {
+    let f = hdf5::File::open(bgef).unwrap();
+    f.attr("gef_area").unwrap().read_scalar::<f32>().unwrap()
+}
+
+#[test]
+fn stat_gene_ignores_requested_bins() {
+    // bin1 的 MID 都小于 10（E10 为 0），聚合到 bin50 后会超过 10
+    let dir = TempDir::new("stats_bins");
+    let gem = dir.join("in.gem");
+    write_gem(&gem, META, GemLayout::WithExon, &synthetic_rows());
+
+    // 由 GEM 直接统计：每个基因的总 MID 与 MID >= 10 的 spot 占比
+    let mut per_gene: BTreeMap<String, (u32, usize, usize)> = BTreeMap::new();
+    for ((id, _, _, _), (mid, _)) in read_gem(&gem).1 {
+        let v = per_gene.entry(id).or_default();
+        v.0 += mid;
+        v.1 += usize::from(mid >= 10);
+        v.2 += 1;
+    }
+    let mut want: Vec<(String, u32, f32)> = per_gene
+        .into_iter()
+        .map(|(id, (mid, e10, n))| (id, mid, e10 as f32 * 100.0 / n as f32))
+        .collect();
+    want.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+    for (mode, extra) in MODES {
+        for bins in ["1,50", "50,100"] {
+            let out = dir.join(&format!("{}_{}.bgef", mode, bins.replace(',', "_")));
+            convert(&gem, &out, bins, extra);
+            assert_eq!(stat_gene(&out), want, "{} --bins {}", mode, bins);
+        }
+    }
+}
+
+#[test]
+fn gef_area_ignores_requested_bins() {
+    let dir = TempDir::new("stats_area");
+    let gem = dir.join("in.gem");
+    write_gem(&gem, META, GemLayout::WithExon, &synthetic_rows());
+
+    // 默认 resolution=500nm：每个 bin1 spot 占 (500e-6 mm)²
+    let spots: BTreeSet<(i32, i32)> = read_gem(&gem).1.keys().map(|&(_, _, x, y)| (x, y)).collect();
+    let want = (spots.len() as f64 * 500e-6 * 500e-6) as f32;
+
+    for (mode, extra) in MODES {
+        for bins in ["1,50", "50,100"] {
+            let out = dir.join(&format!("{}_{}.bgef", mode, bins.replace(',', "_")));
+            convert(&gem, &out, bins, extra);
+            let got = gef_area(&out);
+            assert!(
+                (got - want).abs() <= want * 1e-5,
+                "{} --bins {}: gef_area={} want {}",
+                mode,
+                bins,
+                got,
+                want
+            );
+        }
+    }
+}
+
+#[test]
+fn gef_area_uses_resolution() {
+    let dir = TempDir::new("stats_resolution");
+    let gem = dir.join("in.gem");
+    write_gem(&gem, META, GemLayout::WithExon, &synthetic_rows());
+    let spots: BTreeSet<(i32, i32)> = read_gem(&gem).1.keys().map(|&(_, _, x, y)| (x, y)).collect();
+
+    // spot 边长为 resolution（nm）：面积 = spot 数 × (resolution × 1e-6 mm)²
+    for resolution in ["500", "715"] {
+        let out = dir.join(&format!("res{}.bgef", resolution));
+        convert(&gem, &out, "1", &["--resolution", resolution]);
+        let side = resolution.parse::<f64>().unwrap() * 1e-6;
+        let want = (spots.len() as f64 * side * side) as f32;
+        let got = gef_area(&out);
+        assert!(
+            (got - want).abs() <= want * 1e-5,
+            "resolution={}: gef_area={} want {}",
+            resolution,
+            got,
+            want
+        );
+    }
+}
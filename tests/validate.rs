@@ -0,0 +1,126 @@
+//! validate：逐行报出 GEM 中的问题，最后一行为 JSON 汇总
+mod common;
+
+use std::fs;
+
+use common::{gem2gef, gem2gef_ok, synthetic_rows, write_gem, GemLayout, TempDir};
+use serde_json::Value;
+
+const META: &[&str] = &[
+    "BinType=Bin",
+    "BinSize=1",
+    "Omics=Transcriptomics",
+    "Stereo-seqChip=SS200000000TL_A1",
+];
+
+/// stdout 的最后一行（JSON 汇总）
+fn summary(stdout: &[u8]) -> Value {
+    let text = String::from_utf8_lossy(stdout);
+    let last = text.lines().last().expect("validate 没有输出");
+    serde_json::from_str(last).unwrap_or_else(|e| panic!("汇总不是 JSON（{}）: {}", e, last))
+}
+
+#[test]
+fn validate_clean_gem() {
+    let dir = TempDir::new("validate_clean");
+    let gem = dir.join("in.gem");
+    let rows = synthetic_rows();
+    write_gem(&gem, META, GemLayout::WithExon, &rows);
+    let out = gem2gef_ok(&["validate", "-i", gem.to_str().unwrap()]);
+    let s = summary(&out.stdout);
+    assert_eq!(s["valid"], true);
+    assert_eq!(s["problems"], 0);
+    assert_eq!(s["records"], rows.len());
+    assert_eq!(String::from_utf8_lossy(&out.stdout).lines().count(), 1);
+}
+
+#[test]
+fn validate_reports_every_problem() {
+    let dir = TempDir::new("validate_problems");
+    let gem = dir.join("bad.gem");
+    let text = [
+        "#FileFormat=GEMv0.1",               // 1
+        "#BinSize=1",                        // 2
+        "#OffsetX=10",                       // 3
+        "#Colour=blue",                      // 4 unknown_header_key
+        "#OffsetY=abc",                      // 5 bad_header_value
+        "geneID\tx\ty\tMIDCount\tExonCount", // 6
+        "Gad1\t1\t2\t3\t1",                  // 7 正常
+        "Actb\t-20\t2\t3",                   // 8 column_count + missing_field + negative_coordinate
+        " \t5\t5\t1\t0",                     // 9 blank_gene_id
+        "Mbp\tx1\t5\tmany\t0",               // 10 non_numeric x2
+        "Xist\t2147483647\t5\t1\t0",         // 11 coordinate_overflow
+        "Snap25\t100\t200\t1\t0",            // 12 out_of_chip x2
+        "Gad1\t4\t4\t1\t0",                  // 13 正常
+    ]
+    .join("\n")
+        + "\n";
+    fs::write(&gem, text).unwrap();
+    let path = gem.to_str().unwrap();
+    let out = gem2gef(&["validate", "-i", path, "--chip-size", "100,150"]);
+    assert!(!out.status.success(), "有问题时退出码应非 0");
+
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    for (line, kind) in [
+        (4, "unknown_header_key"),
+        (5, "bad_header_value"),
+        (8, "column_count"),
+        (8, "missing_field"),
+        (8, "negative_coordinate"),
+        (9, "blank_gene_id"),
+        (10, "non_numeric"),
+        (11, "coordinate_overflow"),
+        (12, "out_of_chip"),
+    ] {
+        let prefix = format!("{}:{}: {}: ", path, line, kind);
+        assert!(stdout.contains(&prefix), "缺少 {}\n{}", prefix, stdout);
+    }
+    assert!(!stdout.contains(&format!("{}:7:", path)), "第 7 行没有问题");
+    assert!(
+        !stdout.contains(&format!("{}:13:", path)),
+        "第 13 行没有问题"
+    );
+
+    let s = summary(&out.stdout);
+    assert_eq!(s["valid"], false);
+    assert_eq!(s["lines"], 13);
+    assert_eq!(s["records"], 7);
+    assert_eq!(s["problems"], 11);
+    assert_eq!(s["problem_lines"], 7);
+    assert_eq!(s["by_kind"]["non_numeric"], 2);
+    assert_eq!(s["by_kind"]["out_of_chip"], 2);
+    assert_eq!(s["first_line"]["column_count"], 8);
+}
+
+#[test]
+fn validate_missing_columns() {
+    let dir = TempDir::new("validate_columns");
+    let gem = dir.join("bad.gem");
+    fs::write(&gem, "#BinSize=1\ngeneID\tx\tcount\nGad1\t1\t3\n").unwrap();
+    let out = gem2gef(&["validate", "-i", gem.to_str().unwrap()]);
+    assert!(!out.status.success());
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.contains(":2: missing_columns: "), "{}", stdout);
+    assert_eq!(summary(&out.stdout)["by_kind"]["missing_columns"], 1);
+}
+
+#[test]
+fn gem2gef_error_has_line_number() {
+    // 转换遇到坏行时报出文件名、行号与字段
+    let dir = TempDir::new("validate_convert");
+    let (gem, bgef) = (dir.join("bad.gem"), dir.join("out.bgef"));
+    fs::write(
+        &gem,
+        "#BinSize=1\ngeneID\tx\ty\tMIDCount\nGad1\t1\t2\t3\nActb\t1\tQ\t3\n",
+    )
+    .unwrap();
+    let out = gem2gef(&["-i", gem.to_str().unwrap(), "-o", bgef.to_str().unwrap()]);
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("第 4 行"), "{}", stderr);
+    assert!(
+        stderr.contains("y=\\\"Q\\\"") || stderr.contains("y=\"Q\""),
+        "{}",
+        stderr
+    );
+}
@@ -0,0 +1,120 @@
+//! /wholeExp/binN 与 /wholeExpExon/binN 为分块数据集，按 tile 写入被占据的区域；
+//! 读出的矩阵与由 GEM 直接铺开的稠密矩阵一致，未被占据的 tile 为 0
+mod common;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+use common::{gem2gef_ok, write_gem, GemLayout, GemRow, TempDir};
+use hdf5::H5Type;
+use ndarray::Array2;
+
+const META: &[&str] = &[
+    "BinType=Bin",
+    "BinSize=1",
+    "Omics=Transcriptomics",
+    "Stereo-seqChip=SS200000000TL_A1",
+];
+
+const MODES: [(&str, &[&str]); 2] = [("mem", &[]), ("spill", &["--max-mem", "1"])];
+
+#[repr(C)]
+#[allow(non_snake_case)]
+#[derive(H5Type, Clone, Copy, Debug, Default, PartialEq)]
+struct SpotRow {
+    MIDcount: u32,
+    genecount: u16,
+}
+
+/// bin1 下铺满 3 x 3 个 256 x 256 tile 的 5 个 spot，其中 5 个 tile 没有 spot
+fn spread_rows() -> Vec<GemRow> {
+    let spots = [(100, 50), (105, 53), (401, 50), (400, 650), (800, 653)];
+    let mut rows = Vec::new();
+    for (gi, gene) in ["Gad1", "Actb", "Mbp"].iter().enumerate() {
+        for (k, &(x, y)) in spots.iter().enumerate().skip(gi) {
+            let mid = 1 + (k + gi) as u32;
+            rows.push(GemRow::new(gene, x, y, mid, mid / 2));
+        }
+    }
+    rows
+}
+
+/// 按 `bin` 铺开的 (MIDcount, genecount) 与 exon 稠密矩阵，下标为 [x, y]
+fn dense(rows: &[GemRow], bin: i32) -> (Array2<SpotRow>, Array2<u32>) {
+    let origin = |v: i32| v.div_euclid(bin) * bin;
+    let mut spots: BTreeMap<(i32, i32), (u32, BTreeSet<&str>, u32)> = BTreeMap::new();
+    for r in rows {
+        let s = spots.entry((origin(r.x), origin(r.y))).or_default();
+        s.0 += r.mid;
+        s.1.insert(&r.gene_id);
+        s.2 += r.exon;
+    }
+    let min_x = spots.keys().map(|k| k.0).min().unwrap();
+    let min_y = spots.keys().map(|k| k.1).min().unwrap();
+    let len_x = ((spots.keys().map(|k| k.0).max().unwrap() - min_x) / bin + 1) as usize;
+    let len_y = ((spots.keys().map(|k| k.1).max().unwrap() - min_y) / bin + 1) as usize;
+    let mut mid = Array2::<SpotRow>::default((len_x, len_y));
+    let mut exon = Array2::<u32>::zeros((len_x, len_y));
+    for ((x, y), (m, genes, e)) in spots {
+        let idx = [((x - min_x) / bin) as usize, ((y - min_y) / bin) as usize];
+        mid[idx] = SpotRow {
+            MIDcount: m,
+            genecount: genes.len() as u16,
+        };
+        exon[idx] = e;
+    }
+    (mid, exon)
+}
+
+fn convert(gem: &Path, out: &Path, extra: &[&str]) -> hdf5::File {
+    let mut args = vec![
+        "-i",
+        gem.to_str().unwrap(),
+        "-o",
+        out.to_str().unwrap(),
+        "-b",
+        "1,10",
+    ];
+    args.extend_from_slice(extra);
+    gem2gef_ok(&args);
+    hdf5::File::open(out).unwrap()
+}
+
+#[test]
+fn whole_exp_tiles_match_dense_matrix() {
+    let dir = TempDir::new("whole_exp_tiles");
+    let gem = dir.join("in.gem");
+    let rows = spread_rows();
+    write_gem(&gem, META, GemLayout::WithExon, &rows);
+
+    for (mode, extra) in MODES {
+        let f = convert(&gem, &dir.join(&format!("{}.bgef", mode)), extra);
+        for bin in [1, 10] {
+            let (want_mid, want_exon) = dense(&rows, bin);
+            let whole = f.dataset(&format!("/wholeExp/bin{}", bin)).unwrap();
+            let exon = f.dataset(&format!("/wholeExpExon/bin{}", bin)).unwrap();
+            let (len_x, len_y) = want_mid.dim();
+            let chunk = vec![len_x.min(256), len_y.min(256)];
+            assert_eq!(whole.chunk(), Some(chunk.clone()), "{} bin{}", mode, bin);
+            assert_eq!(exon.chunk(), Some(chunk), "{} bin{}", mode, bin);
+
+            assert_eq!(
+                whole.read_2d::<SpotRow>().unwrap(),
+                want_mid,
+                "{} bin{}",
+                mode,
+                bin
+            );
+            assert_eq!(
+                exon.read_2d::<u32>().unwrap(),
+                want_exon,
+                "{} bin{}",
+                mode,
+                bin
+            );
+            let number = whole.attr("number").unwrap().read_scalar::<u64>().unwrap();
+            let occupied = want_mid.iter().filter(|s| s.MIDcount > 0).count() as u64;
+            assert_eq!(number, occupied, "{} bin{}", mode, bin);
+        }
+    }
+}